@@ -0,0 +1,421 @@
+#[cfg(feature = "mllp")]
+use hl7_ls::escapes::{self, truncation_character};
+use hl7_ls::{
+    spec,
+    utils::{file_path, std_range_to_lsp_range, PositionEncoding},
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, Location, Uri};
+#[cfg(feature = "mllp")]
+use serde::Serialize;
+use tracing::instrument;
+
+/// Acknowledgment codes (table 0008)
+const ACKNOWLEDGMENT_CODE_TABLE: u16 = 8;
+
+/// Whether a message is an acknowledgement of another one
+pub fn is_acknowledgement(message: &Message) -> bool {
+    message.segment("MSA").is_some()
+}
+
+/// Summarise the acknowledgements of a message that are open in other
+/// documents (those whose MSA-2 is the message's MSH-10) on its control ID, so
+/// that the response to a message sent to an engine can be seen next to it
+#[instrument(level = "debug", skip(message, documents))]
+pub fn acknowledgement_diagnostics(
+    uri: &Uri,
+    text: &str,
+    message: &Message,
+    documents: &TextDocuments,
+    encoding: PositionEncoding,
+) -> Vec<Diagnostic> {
+    if is_acknowledgement(message) {
+        return Vec::new();
+    }
+    let Some(control_id) = message
+        .query("MSH.10")
+        .filter(|id| !id.raw_value().is_empty())
+    else {
+        return Vec::new();
+    };
+
+    let mut diagnostics = Vec::new();
+    for (ack_uri, document) in documents.documents() {
+        if ack_uri == uri {
+            continue;
+        }
+        let ack_text = document.get_content(None);
+        let Ok(ack) = parse_message_with_lenient_newlines(ack_text) else {
+            continue;
+        };
+        let Some(msa) = ack.segment("MSA") else {
+            continue;
+        };
+        let field = |fi: usize| {
+            msa.field(fi)
+                .map(|field| field.raw_value())
+                .filter(|value| !value.is_empty())
+        };
+        if field(2) != Some(control_id.raw_value()) {
+            continue;
+        }
+
+        let code = field(1).unwrap_or("?");
+        let status = match spec::table_value_description(ACKNOWLEDGMENT_CODE_TABLE, code) {
+            Some(description) => format!("`{code}` ({description})"),
+            None => format!("`{code}`"),
+        };
+        let text_message = field(3)
+            .map(|text_message| format!(": {text_message}"))
+            .unwrap_or_default();
+        diagnostics.push(Diagnostic {
+            range: std_range_to_lsp_range(text, control_id.range(), encoding),
+            severity: Some(DiagnosticSeverity::INFORMATION),
+            code: Some(lsp_types::NumberOrString::String(
+                "acknowledgement".to_string(),
+            )),
+            message: format!(
+                "Acknowledged with {status} in {document}{text_message}",
+                document = document_name(ack_uri)
+            ),
+            related_information: Some(vec![DiagnosticRelatedInformation {
+                location: Location {
+                    uri: ack_uri.clone(),
+                    range: std_range_to_lsp_range(ack_text, msa.range.clone(), encoding),
+                },
+                message: "Acknowledgement".to_string(),
+            }]),
+            ..Default::default()
+        });
+    }
+    diagnostics
+}
+
+/// Whether the receiver of a message accepted it, by its acknowledgement code
+/// (MSA-1)
+#[cfg(feature = "mllp")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AcknowledgementStatus {
+    /// `AA`, or `CA` for a commit acknowledgement
+    Accepted,
+    /// `AE` or `CE`
+    Error,
+    /// `AR` or `CR`
+    Rejected,
+    /// The response isn't an acknowledgement, or its code isn't one of these
+    Unknown,
+}
+
+/// What the response to a message that was sent says about it
+#[cfg(feature = "mllp")]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Acknowledgement {
+    pub status: AcknowledgementStatus,
+    /// MSA-1
+    pub code: Option<String>,
+    /// MSA-2, the control ID of the message being acknowledged
+    pub control_id: Option<String>,
+    /// Whether MSA-2 is the control ID of the message that was sent, which
+    /// it must be for the acknowledgement to be of that message
+    pub control_id_matches: bool,
+    /// MSA-3, decoded
+    pub text: Option<String>,
+    /// What each ERR segment says went wrong, decoded
+    pub errors: Vec<String>,
+    /// The whole response, with its segments on separate lines
+    pub raw: String,
+    /// The application acknowledgement that followed a commit acknowledgement,
+    /// in enhanced mode
+    pub application: Option<Box<Acknowledgement>>,
+}
+
+/// When the sender of a message asks for an acknowledgement of it (table
+/// 0155)
+#[cfg(feature = "mllp")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcknowledgementCondition {
+    /// `AL`
+    Always,
+    /// `NE`, or left empty
+    Never,
+    /// `ER`, only if the message couldn't be accepted or processed
+    Error,
+    /// `SU`, only if the message was accepted or processed
+    Success,
+}
+
+#[cfg(feature = "mllp")]
+impl AcknowledgementCondition {
+    fn from_code(code: &str) -> Self {
+        match code {
+            "AL" => AcknowledgementCondition::Always,
+            "ER" => AcknowledgementCondition::Error,
+            "SU" => AcknowledgementCondition::Success,
+            _ => AcknowledgementCondition::Never,
+        }
+    }
+
+    /// Whether the acknowledgement is asked for, given how it went
+    pub fn applies(self, success: bool) -> bool {
+        match self {
+            AcknowledgementCondition::Always => true,
+            AcknowledgementCondition::Never => false,
+            AcknowledgementCondition::Error => !success,
+            AcknowledgementCondition::Success => success,
+        }
+    }
+}
+
+/// The acknowledgements the sender of a message asks for in enhanced mode: a
+/// commit acknowledgement (`CA`) once the message has been received, and an
+/// application acknowledgement (`AA`) once it has been processed
+#[cfg(feature = "mllp")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcknowledgementMode {
+    /// MSH-15
+    pub accept: AcknowledgementCondition,
+    /// MSH-16
+    pub application: AcknowledgementCondition,
+}
+
+/// The acknowledgements a message asks for, or `None` in original mode (when
+/// neither MSH-15 nor MSH-16 are filled in), where only an application
+/// acknowledgement is sent
+#[cfg(feature = "mllp")]
+pub fn acknowledgement_mode(message: &Message) -> Option<AcknowledgementMode> {
+    let value = |path: &str| {
+        message
+            .query(path)
+            .map(|value| value.raw_value())
+            .unwrap_or_default()
+    };
+    let (accept, application) = (value("MSH.15"), value("MSH.16"));
+    if accept.is_empty() && application.is_empty() {
+        return None;
+    }
+    Some(AcknowledgementMode {
+        accept: AcknowledgementCondition::from_code(accept),
+        application: AcknowledgementCondition::from_code(application),
+    })
+}
+
+/// Read the response to a message that was sent with the given control ID
+/// (MSH-10) as an acknowledgement
+#[cfg(feature = "mllp")]
+pub fn read_acknowledgement(response: &str, sent_control_id: Option<&str>) -> Acknowledgement {
+    let raw = response.replace("\r\n", "\n").replace('\r', "\n");
+    let unknown = Acknowledgement {
+        status: AcknowledgementStatus::Unknown,
+        code: None,
+        control_id: None,
+        control_id_matches: false,
+        text: None,
+        errors: Vec::new(),
+        raw: raw.clone(),
+        application: None,
+    };
+    let Ok(ack) = parse_message_with_lenient_newlines(response) else {
+        return unknown;
+    };
+    let Some(msa) = ack.segment("MSA") else {
+        return unknown;
+    };
+    let decode = |value: &str| {
+        Some(escapes::decode(
+            value,
+            &ack.separators,
+            truncation_character(&ack),
+        ))
+        .filter(|value| !value.is_empty())
+    };
+    let field = |fi: usize| {
+        msa.field(fi)
+            .map(|field| field.raw_value())
+            .filter(|value| !value.is_empty())
+    };
+
+    let code = field(1);
+    let status = match code {
+        Some("AA" | "CA") => AcknowledgementStatus::Accepted,
+        Some("AE" | "CE") => AcknowledgementStatus::Error,
+        Some("AR" | "CR") => AcknowledgementStatus::Rejected,
+        _ => AcknowledgementStatus::Unknown,
+    };
+    let control_id = field(2);
+    // the user message (ERR-8) of v2.5 on, or else the text of the error code
+    // (ERR-3.2), or the code of the error location (ERR-1.4.2) before v2.5
+    let errors = ack
+        .segments()
+        .filter(|segment| segment.name == "ERR")
+        .filter_map(|err| {
+            let value = |fi: usize, ci: usize, si: usize| {
+                let field = err.field(fi)?;
+                let value = match ci {
+                    0 => field.raw_value(),
+                    _ => field
+                        .repeat(1)?
+                        .component(ci)?
+                        .subcomponent(si)?
+                        .raw_value(),
+                };
+                decode(value)
+            };
+            value(8, 0, 0)
+                .or_else(|| value(3, 2, 1))
+                .or_else(|| value(1, 4, 2))
+        })
+        .collect();
+
+    Acknowledgement {
+        status,
+        code: code.map(str::to_string),
+        control_id: control_id.map(str::to_string),
+        control_id_matches: control_id.is_some() && control_id == sent_control_id,
+        text: field(3).and_then(decode),
+        errors,
+        raw,
+        application: None,
+    }
+}
+
+/// An acknowledgement of a message with the given code (e.g. `AA`), from its
+/// receiver back to its sender, written with the message's separators
+#[cfg(feature = "mllp")]
+pub fn acknowledge(message: &Message, code: &str, control_id: &str, now: &str) -> String {
+    let separators = &message.separators;
+    let value = |path: &str| {
+        message
+            .query(path)
+            .map(|value| value.raw_value())
+            .unwrap_or_default()
+    };
+    let message_type = match value("MSH.9.2") {
+        "" => "ACK".to_string(),
+        event => format!("ACK{c}{event}{c}ACK", c = separators.component),
+    };
+
+    let header = [
+        "MSH",
+        &format!(
+            "{component}{repetition}{escape}{subcomponent}{truncation}",
+            component = separators.component,
+            repetition = separators.repetition,
+            escape = separators.escape,
+            subcomponent = separators.subcomponent,
+            truncation = truncation_character(message)
+                .map(String::from)
+                .unwrap_or_default(),
+        ),
+        // the sender and receiver swap places
+        value("MSH.5"),
+        value("MSH.6"),
+        value("MSH.3"),
+        value("MSH.4"),
+        now,
+        "",
+        &message_type,
+        control_id,
+        value("MSH.11"),
+        value("MSH.12"),
+    ]
+    .join(&separators.field.to_string());
+    let msa = ["MSA", code, value("MSH.10")].join(&separators.field.to_string());
+    format!("{header}\r{msa}")
+}
+
+/// The file name of a document, or its whole URI if it isn't a file
+pub fn document_name(uri: &Uri) -> String {
+    file_path(uri)
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| uri.as_str().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{
+        notification::{DidOpenTextDocument, Notification},
+        DidOpenTextDocumentParams, TextDocumentItem,
+    };
+
+    fn open(documents: &mut TextDocuments, uri: &str, text: &str) {
+        let params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.parse().unwrap(),
+                language_id: "hl7".to_string(),
+                version: 1,
+                text: text.to_string(),
+            },
+        };
+        documents.listen(
+            DidOpenTextDocument::METHOD,
+            &serde_json::to_value(params).unwrap(),
+        );
+    }
+
+    #[test]
+    fn acknowledgements_are_reported_on_the_messages_they_acknowledge() {
+        let message = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|123|P|2.5.1\rPID|1";
+        let mut documents = TextDocuments::new();
+        open(&mut documents, "file:///tmp/message.hl7", message);
+        open(
+            &mut documents,
+            "file:///tmp/ack.hl7",
+            "MSH|^~\\&|Rcv|RcvFac|App|Fac|20240102030406||ACK|456|P|2.5.1\rMSA|AE|123|Unknown patient",
+        );
+        open(
+            &mut documents,
+            "file:///tmp/other-ack.hl7",
+            "MSH|^~\\&|Rcv|RcvFac|App|Fac|20240102030406||ACK|789|P|2.5.1\rMSA|AA|999",
+        );
+
+        let uri = "file:///tmp/message.hl7".parse().unwrap();
+        let parsed = parse_message_with_lenient_newlines(message).unwrap();
+        let diagnostics = acknowledgement_diagnostics(
+            &uri,
+            message,
+            &parsed,
+            &documents,
+            PositionEncoding::default(),
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "Acknowledged with `AE` in ack.hl7: Unknown patient"
+        );
+        let start = message.find("|123|").unwrap() as u32 + 1;
+        assert_eq!(diagnostics[0].range.start.character, start);
+    }
+
+    #[cfg(feature = "mllp")]
+    #[test]
+    fn responses_are_read_as_acknowledgements() {
+        let ack = read_acknowledgement(
+            "MSH|^~\\&|Rcv|RcvFac|App|Fac|20240102030406||ACK|456|P|2.5.1\rMSA|AE|123|Patient \\T\\ visit\rERR|||204^Unknown key identifier|E||||Unknown patient",
+            Some("123"),
+        );
+        assert_eq!(ack.status, AcknowledgementStatus::Error);
+        assert_eq!(ack.code.as_deref(), Some("AE"));
+        assert!(ack.control_id_matches);
+        assert_eq!(ack.text.as_deref(), Some("Patient & visit"));
+        assert_eq!(ack.errors, vec!["Unknown patient"]);
+        assert!(ack.raw.contains("\nMSA|AE|123|"));
+
+        let other = read_acknowledgement(
+            "MSH|^~\\&|Rcv|RcvFac|App|Fac|20240102030406||ACK|789|P|2.5.1\rMSA|CA|999",
+            Some("123"),
+        );
+        assert_eq!(other.status, AcknowledgementStatus::Accepted);
+        assert!(!other.control_id_matches);
+
+        let garbage = read_acknowledgement("not an ack", Some("123"));
+        assert_eq!(garbage.status, AcknowledgementStatus::Unknown);
+        assert_eq!(garbage.raw, "not an ack");
+    }
+}
@@ -0,0 +1,166 @@
+use color_eyre::Result;
+use crossbeam_channel::Receiver;
+use hl7_ls::utils::RequestCancelled;
+use lsp_server::{Message, RequestId};
+use lsp_types::{
+    notification::{Cancel, DidChangeTextDocument, Notification},
+    CancelParams, NumberOrString, Uri,
+};
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Messages received from the client that haven't been handled yet
+///
+/// Requests are answered on worker threads while the main thread keeps
+/// receiving messages, so a `$/cancelRequest` is noticed as soon as it
+/// arrives. Requests stay in flight from when they are taken out of the inbox
+/// until their [CancellationToken] is finished, and only cancellations of
+/// pending or in-flight requests are remembered.
+pub struct Inbox {
+    receiver: Receiver<Message>,
+    pending: RefCell<VecDeque<Message>>,
+    requests: Arc<Mutex<Requests>>,
+}
+
+/// The requests that have been taken out of the inbox but not answered yet,
+/// shared with the workers answering them
+#[derive(Default)]
+struct Requests {
+    in_flight: HashSet<RequestId>,
+    cancelled: HashSet<RequestId>,
+}
+
+impl Inbox {
+    pub fn new(receiver: Receiver<Message>) -> Self {
+        Inbox {
+            receiver,
+            pending: Default::default(),
+            requests: Default::default(),
+        }
+    }
+
+    fn requests(&self) -> MutexGuard<'_, Requests> {
+        lock(&self.requests)
+    }
+
+    /// Queue a message that was received from the channel elsewhere
+    pub fn push(&self, msg: Message) {
+        match msg {
+            Message::Notification(not) if not.method == Cancel::METHOD => {
+                let Ok(params) = serde_json::from_value::<CancelParams>(not.params) else {
+                    tracing::warn!("malformed cancellation params");
+                    return;
+                };
+                let id = match params.id {
+                    NumberOrString::Number(id) => RequestId::from(id),
+                    NumberOrString::String(id) => RequestId::from(id),
+                };
+
+                // only remember cancellations for requests we haven't answered
+                // yet, otherwise they would pile up forever
+                let is_pending = self
+                    .pending
+                    .borrow()
+                    .iter()
+                    .any(|msg| matches!(msg, Message::Request(req) if req.id == id));
+                let mut requests = self.requests();
+                if is_pending || requests.in_flight.contains(&id) {
+                    tracing::debug!(?id, "request cancelled");
+                    requests.cancelled.insert(id);
+                }
+            }
+            msg => self.pending.borrow_mut().push_back(msg),
+        }
+    }
+
+    /// Pull everything that is waiting in the channel into the inbox
+    fn poll(&self) {
+        for msg in self.receiver.try_iter() {
+            self.push(msg);
+        }
+    }
+
+    /// Take the next message to handle, if any are waiting
+    ///
+    /// Requests are considered in flight until [Inbox::finish] or
+    /// [CancellationToken::finish] is called.
+    pub fn pop(&self) -> Option<Message> {
+        self.poll();
+        let msg = self.pending.borrow_mut().pop_front();
+        if let Some(Message::Request(req)) = &msg {
+            self.requests().in_flight.insert(req.id.clone());
+        }
+        msg
+    }
+
+    /// Mark a request as answered, forgetting any cancellation of it
+    pub fn finish(&self, id: &RequestId) {
+        self.requests().finish(id);
+    }
+
+    pub fn is_cancelled(&self, id: &RequestId) -> bool {
+        self.poll();
+        self.requests().cancelled.contains(id)
+    }
+
+    /// Whether a newer change to the given document is waiting to be handled,
+    /// making any work on the current version of it stale
+    pub fn has_pending_change(&self, uri: &Uri) -> bool {
+        self.poll();
+        self.pending.borrow().iter().any(|msg| match msg {
+            Message::Notification(not) => {
+                not.method == DidChangeTextDocument::METHOD
+                    && not.params["textDocument"]["uri"].as_str() == Some(uri.as_str())
+            }
+            _ => false,
+        })
+    }
+
+    pub fn token(&self, id: RequestId) -> CancellationToken {
+        CancellationToken {
+            requests: self.requests.clone(),
+            id,
+        }
+    }
+}
+
+impl Requests {
+    fn finish(&mut self, id: &RequestId) {
+        self.in_flight.remove(id);
+        self.cancelled.remove(id);
+    }
+}
+
+/// A worker that panicked while holding the lock can't have left the sets in
+/// a state worse than a missed cancellation, so carry on regardless
+fn lock(requests: &Mutex<Requests>) -> MutexGuard<'_, Requests> {
+    requests
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Lets a request handler check whether the client has cancelled its request
+#[derive(Clone)]
+pub struct CancellationToken {
+    requests: Arc<Mutex<Requests>>,
+    id: RequestId,
+}
+
+impl CancellationToken {
+    /// Bail out with [RequestCancelled] if the client cancelled the request
+    pub fn check(&self) -> Result<()> {
+        if lock(&self.requests).cancelled.contains(&self.id) {
+            Err(RequestCancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Mark the request as answered, forgetting any cancellation of it
+    pub fn finish(&self) {
+        lock(&self.requests).finish(&self.id);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{cli::MessageSource, diagnostics::parse_error_diagnostics};
+use color_eyre::Result;
+use hl7_ls::{utils::PositionEncoding, workspace::load_specs, Opts};
+use lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString};
+
+/// Validate a message outside of an editor, printing each problem found to
+/// standard output as `name:line:column: severity: message`
+///
+/// The specs in the current directory are applied, as they would be to a
+/// workspace opened there. Returns whether any errors were found.
+pub fn check_message(source: &MessageSource, opts: &Opts) -> Result<bool> {
+    let (name, uri, text) = source.read()?;
+    let specs = load_specs(&[std::env::current_dir()?], opts)?;
+
+    // columns are counted in characters, as most terminals and editors do
+    let opts = Opts {
+        position_encoding: PositionEncoding::Utf32,
+        ..opts.clone()
+    };
+    let mut diagnostics = match hl7_ls::validate_text(&uri, &text, Some(&specs), &opts) {
+        Ok(errors) => errors
+            .into_iter()
+            .map(|error| error.into_diagnostic(&uri, &text, opts.position_encoding))
+            .collect(),
+        Err(error) => parse_error_diagnostics(&text, error, &opts),
+    };
+    diagnostics.sort_by_key(|diagnostic| {
+        (
+            diagnostic.range.start.line,
+            diagnostic.range.start.character,
+        )
+    });
+
+    for diagnostic in diagnostics.iter() {
+        println!("{}", format_diagnostic(&name, diagnostic));
+    }
+    Ok(diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Some(DiagnosticSeverity::ERROR)))
+}
+
+fn format_diagnostic(name: &str, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Some(DiagnosticSeverity::WARNING) => "warning",
+        Some(DiagnosticSeverity::INFORMATION) => "info",
+        Some(DiagnosticSeverity::HINT) => "hint",
+        _ => "error",
+    };
+    let code = match &diagnostic.code {
+        Some(NumberOrString::String(code)) => format!(" [{code}]"),
+        Some(NumberOrString::Number(code)) => format!(" [{code}]"),
+        None => String::new(),
+    };
+    format!(
+        "{name}:{line}:{column}: {severity}: {message}{code}",
+        line = diagnostic.range.start.line + 1,
+        column = diagnostic.range.start.character + 1,
+        message = diagnostic.message,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, Range};
+
+    #[test]
+    fn diagnostics_are_formatted_with_one_based_positions() {
+        let diagnostic = Diagnostic {
+            range: Range {
+                start: Position::new(1, 4),
+                end: Position::new(1, 7),
+            },
+            severity: Some(DiagnosticSeverity::WARNING),
+            code: Some(NumberOrString::String("length".to_string())),
+            message: "Field is too long (max: 2)".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_diagnostic("<stdin>", &diagnostic),
+            "<stdin>:2:5: warning: Field is too long (max: 2) [length]"
+        );
+    }
+}
@@ -1,5 +1,8 @@
 use clap::{ColorChoice, Parser, Subcommand};
-use std::path::PathBuf;
+use color_eyre::{eyre::Context, Result};
+use hl7_ls::{utils::file_uri, NonFileSpecs, SegmentTerminator, Severity, TimeZone};
+use lsp_types::Uri;
+use std::{convert::Infallible, io::Read, path::PathBuf, str::FromStr};
 
 #[derive(Parser, Debug)]
 #[command(author = clap::crate_authors!(), version, about, long_about = None, help_template = "\
@@ -36,11 +39,146 @@ pub struct Cli {
     #[arg(long)]
     pub disable_std_table_validations: bool,
 
+    /// Log a structured summary of every validation pass
+    ///
+    /// Each summary includes the message type, HL7 version, validation profile
+    /// (if any), the number of findings per validation code, and how long the
+    /// pass took, so that the logs can be aggregated to see which rules fire
+    /// most often.
+    #[arg(long)]
+    pub log_validation_stats: bool,
+
+    /// Stop validating a message after this many milliseconds
+    ///
+    /// The problems found so far are reported, along with a note on the first
+    /// segment that wasn't checked, so that huge messages don't leave the
+    /// editor waiting on the whole message to be validated.
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub validation_budget: Option<u64>,
+
+    /// HL7 version to use when a message's version is unknown or missing
+    ///
+    /// By default, messages declaring a version that isn't known are validated
+    /// against the nearest known version, and messages without a version are
+    /// validated against 2.7.1.
+    #[arg(long, value_parser = parse_known_version)]
+    pub fallback_version: Option<String>,
+
+    /// Line ending to convert segment terminators to when a message is saved
+    ///
+    /// The HL7 standard separates segments with a carriage return, and many
+    /// interface engines reject messages separated by anything else.
+    #[arg(long, value_enum, default_value = "cr")]
+    pub segment_terminator: SegmentTerminator,
+
+    /// Severity of the diagnostics reported for messages that can't be parsed
+    #[arg(long, value_enum, default_value = "error")]
+    pub parse_error_severity: Severity,
+
+    /// Don't report parse errors for documents matching the glob
+    ///
+    /// Useful for directories of partial message fragments. `*` and `?` match
+    /// within a path component and `**` matches any number of components;
+    /// globs that don't start with `/` can match anywhere in the path (e.g.,
+    /// `fragments/**`). May be given multiple times.
+    #[arg(long, value_name = "GLOB")]
+    pub suppress_parse_errors: Vec<String>,
+
+    /// Field holding an identifier to regenerate when cloning a message with
+    /// `hl7.cloneMessage`
+    ///
+    /// Regenerates the first component of each repeat of a field (e.g. the ID
+    /// of `PID.3`, keeping its assigning authority), or exactly the component
+    /// given (e.g. `PV1.19.1`). May be given multiple times.
+    #[arg(
+        long,
+        value_name = "FIELD",
+        default_values = ["PID.3", "PID.18", "PV1.19"]
+    )]
+    pub clone_identifier: Vec<String>,
+
+    /// Trim trailing separators from segments edited by commands
+    ///
+    /// Edits such as `hl7.setTimestampToNow` and `hl7.generateControlId`
+    /// replace the whole segment they are made in, dropping any empty fields,
+    /// repeats, or components from its end (e.g. `PV1|1|||` becomes `PV1|1`).
+    #[arg(long)]
+    pub trim_trailing_separators: bool,
+
+    /// Timezone to show timestamps in when hovering over them
+    ///
+    /// Either `local`, `utc`, or a fixed offset such as `+05:30`. Timestamps
+    /// are always shown in UTC as well.
+    #[arg(long, value_name = "TIMEZONE", default_value = "local")]
+    pub display_timezone: TimeZone,
+
+    /// Timezone to write generated timestamps in
+    ///
+    /// Used by `hl7.setTimestampToNow`, for the new timestamp of messages
+    /// copied with `hl7.cloneMessage`, and for the timestamps of inserted
+    /// snippets. Either `local`, `utc`, or a fixed offset such as `+05:30`.
+    #[arg(long, value_name = "TIMEZONE", default_value = "utc")]
+    pub output_timezone: TimeZone,
+
+    /// Validate documents matching a glob with the named workspace spec only
+    ///
+    /// Given as `GLOB=PROFILE`, where the profile is the `name` of a workspace
+    /// spec, which then applies to matching documents wherever it is in the
+    /// workspace (and specs in the documents' folders don't). Globs match as
+    /// with `--suppress-parse-errors`, and the first matching glob is used.
+    /// The profile of an open document can be changed with
+    /// `hl7.setValidationProfile`. May be given multiple times.
+    #[arg(long, value_name = "GLOB=PROFILE", value_parser = parse_validation_profile)]
+    pub validation_profile: Vec<(String, String)>,
+
+    /// Workspace specs that apply to documents that aren't files
+    ///
+    /// Unsaved (`untitled:`) documents and documents on remote filesystems
+    /// aren't in any workspace folder, so can't be matched to the specs in
+    /// their folder.
+    #[arg(long, value_enum, default_value = "workspace-root")]
+    pub non_file_specs: NonFileSpecs,
+
+    /// File to record the edits made by commands in, as JSON lines
+    ///
+    /// Each line records the command, the time, and the values replaced in
+    /// each document, so that automated changes to test data can be explained
+    /// later. Relative paths are relative to the first workspace folder. The
+    /// edits made since the server started can also be reviewed with
+    /// `hl7.editHistory`.
+    #[arg(long, value_name = "FILE")]
+    pub edit_history: Option<PathBuf>,
+
+    /// Report separators and whitespace that don't mean anything
+    ///
+    /// Whitespace and empty fields at the end of segments, and empty
+    /// components and repeats at the end of fields, are reported as hints
+    /// with a quick fix to remove them.
+    #[arg(long)]
+    pub lint_style: bool,
+
+    /// Report coded elements whose coding system isn't in table 0396
+    ///
+    /// Local coding systems (`L` and `99zzz`) and HL7 tables (`HL7nnnn`) are
+    /// always allowed. The table's values can be defined in the workspace
+    /// specs, otherwise the standard's are used.
+    #[arg(long)]
+    pub check_coding_systems: bool,
+
+    /// Report messages whose control ID (MSH-10) is used by another message
+    /// in the workspace
+    ///
+    /// Some interface engines silently drop messages with a control ID they
+    /// have already seen. Every HL7 file in the workspace folders is indexed
+    /// when the server starts.
+    #[arg(long)]
+    pub unique_control_ids: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     /// Log outout to standard error (default)
     LogToStderr,
@@ -52,6 +190,83 @@ pub enum Commands {
         /// Log file will be created if it does not exist and appended to if it does.
         log_file: PathBuf,
     },
+    /// Validate a message and print the problems found, instead of running
+    /// the language server
+    ///
+    /// Exits with a non-zero status if any errors are found.
+    Check {
+        /// Path to the message, or `-` to read it from standard input
+        message: MessageSource,
+    },
+    /// Print what each field of a message holds, instead of running the
+    /// language server
+    Describe {
+        /// Path to the message, or `-` to read it from standard input
+        #[arg(long, value_name = "MESSAGE")]
+        from_message: MessageSource,
+    },
+}
+
+/// Where a command-line mode reads its message from
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageSource {
+    Stdin,
+    File(PathBuf),
+}
+
+impl MessageSource {
+    /// Read the message, returning the name to report problems under, the
+    /// URI to validate it as, and its text
+    pub fn read(&self) -> Result<(String, Uri, String)> {
+        match self {
+            MessageSource::Stdin => {
+                let mut text = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut text)
+                    .wrap_err("Failed to read message from standard input")?;
+                let uri: Uri = "untitled:stdin".parse().expect("valid uri");
+                Ok(("<stdin>".to_string(), uri, text))
+            }
+            MessageSource::File(path) => {
+                let text = std::fs::read_to_string(path)
+                    .wrap_err_with(|| format!("Failed to read message: {path:?}"))?;
+                let absolute = std::fs::canonicalize(path)
+                    .wrap_err_with(|| format!("Failed to resolve path: {path:?}"))?;
+                Ok((path.display().to_string(), file_uri(&absolute)?, text))
+            }
+        }
+    }
+}
+
+impl FromStr for MessageSource {
+    type Err = Infallible;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Ok(match source {
+            "-" => MessageSource::Stdin,
+            path => MessageSource::File(PathBuf::from(path)),
+        })
+    }
+}
+
+fn parse_known_version(version: &str) -> Result<String, String> {
+    if hl7_definitions::VERSIONS.contains(&version) {
+        Ok(version.to_string())
+    } else {
+        Err(format!(
+            "unknown HL7 version, expected one of: {versions}",
+            versions = hl7_definitions::VERSIONS.join(", ")
+        ))
+    }
+}
+
+fn parse_validation_profile(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((glob, profile)) if !glob.is_empty() && !profile.is_empty() => {
+            Ok((glob.to_string(), profile.to_string()))
+        }
+        _ => Err("expected GLOB=PROFILE".to_string()),
+    }
 }
 
 pub fn cli() -> Cli {
@@ -1,56 +1,235 @@
-use crate::{
-    commands::{
-        CMD_DECODE_SELECTION, CMD_ENCODE_SELECTION, CMD_GENERATE_CONTROL_ID, CMD_SET_TO_NOW,
-    },
-    spec,
-    utils::{lsp_range_to_std_range, std_range_to_lsp_range},
+use crate::commands::{
+    self, CommandResult, CMD_COPY_PATH, CMD_DECODE_SELECTION, CMD_ENCODE_SELECTION,
+    CMD_EXTRACT_MESSAGE, CMD_FRESHEN_MESSAGE, CMD_GENERATE_CONTROL_ID, CMD_RENUMBER_SET_IDS,
+    CMD_SET_TO_NOW, CMD_WRAP_IN_BATCH,
 };
 use color_eyre::{eyre::ContextCompat, Result};
-use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use hl7_ls::{
+    escapes::{broken_observations, truncation_character},
+    messages::{message_at, split_messages, DocumentMessage},
+    spec,
+    utils::{
+        clamp_offset, line_ranges, lsp_range_to_std_range, position_to_offset, slice_text,
+        std_range_to_lsp_range,
+    },
+    validation::{
+        canonical_order, is_batch_file, segment_template, structure_segments, DiagnosticData,
+        ValidationCode,
+    },
+    workspace::{snippets::line_ending, specs::WorkspaceSpecs},
+    Opts,
+};
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines, Message};
 use lsp_textdocument::TextDocuments;
 use lsp_types::{
     CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, CodeActionResponse, Command,
-    Range, Uri,
+    Diagnostic, ExecuteCommandParams, Range, TextEdit, Uri, WorkspaceEdit,
 };
+use std::collections::HashMap;
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(params, documents))]
+/// Handle a code action request
+///
+/// If `resolve_edits` is set, the client supports lazily resolving edits, so
+/// actions are returned without their command; the command is stashed in the
+/// action's `data` and turned into an edit in [handle_code_action_resolve_request]
+/// only once the user picks the action.
+#[instrument(level = "debug", skip(params, documents, workspace_specs, opts))]
 pub fn handle_code_actions_request(
     params: CodeActionParams,
     documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+    resolve_edits: bool,
 ) -> Result<Option<CodeActionResponse>> {
     let uri = params.text_document.uri;
     let text = documents
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
 
+    // only the message the range starts in is needed, if there are several
+    let start = position_to_offset(
+        text,
+        params.range.start.line,
+        params.range.start.character,
+        opts.position_encoding,
+    )
+    .unwrap_or_default();
+    let document_message = message_at(text, start, opts.position_encoding);
+    let Some(range) = document_message.range_in_message(params.range) else {
+        return Ok(None);
+    };
+
+    // pasted text with line breaks usually stops the message being parsed, so
+    // is looked for first
+    let line_breaks = escape_line_breaks(&range, &uri, &document_message, opts);
+
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
-    let Ok(message) = parse_message_with_lenient_newlines(text) else {
-        return Ok(None);
+    let Ok(message) = parse_message_with_lenient_newlines(document_message.text) else {
+        if line_breaks.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(
+            line_breaks
+                .into_iter()
+                .map(CodeActionOrCommand::CodeAction)
+                .collect(),
+        ));
     };
     drop(_parse_span_guard);
 
     let code_actions = [
-        generate_control_id(&params.range, &uri, &message),
-        set_time_to_now(&params.range, &uri, &message),
-        encode(&params.range, &uri, &message),
-        decode(&params.range, &uri, &message),
+        copy_path(&range, &uri, &message, &document_message, opts),
+        generate_control_id(&range, &uri, &message, &document_message, opts),
+        freshen_message(&range, &uri, &message, &document_message, opts),
+        set_time_to_now(
+            &range,
+            &uri,
+            &message,
+            &document_message,
+            workspace_specs,
+            opts,
+        ),
+        encode(&range, &uri, &message, &document_message, opts),
+        decode(&range, &uri, &message, &document_message, opts),
+        renumber_set_ids(&uri, &document_message, &params.context.diagnostics),
+        wrap_in_batch(&uri, text),
+        extract_message(&range, &uri, text, &document_message, opts),
+        reorder_segments(
+            &uri,
+            &message,
+            &document_message,
+            &params.context.diagnostics,
+            opts,
+        ),
     ]
     .into_iter()
     .flatten()
+    .chain(line_breaks)
+    .chain(replace_table_value(&uri, &params.context.diagnostics))
+    .chain(apply_fixes(&uri, &params.context.diagnostics, opts))
+    .chain(insert_segments(
+        params.range,
+        &uri,
+        text,
+        &message,
+        &params.context.diagnostics,
+        workspace_specs,
+        opts,
+    ))
+    .chain(repair_timestamps(
+        &uri,
+        text,
+        &params.context.diagnostics,
+        opts,
+    ))
+    .map(|action| {
+        if resolve_edits {
+            defer_command(action)
+        } else {
+            action
+        }
+    })
     .map(CodeActionOrCommand::CodeAction)
     .collect::<Vec<_>>();
 
     Ok(Some(code_actions))
 }
 
-#[instrument(level = "trace", skip(uri, message))]
-fn generate_control_id(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
+/// Move an action's command into its `data` so that it can be resolved into an
+/// edit later
+fn defer_command(mut action: CodeAction) -> CodeAction {
+    if let Some(command) = action.command.take() {
+        action.data = Some(serde_json::to_value(command).expect("can serialize command"));
+    }
+    action
+}
+
+/// Resolve a code action that was returned without an edit by computing the
+/// edit for the command stashed in its `data`
+#[instrument(level = "debug", skip(action, documents, opts))]
+pub fn handle_code_action_resolve_request(
+    mut action: CodeAction,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<CodeAction> {
+    let Some(data) = action.data.take() else {
+        return Ok(action);
+    };
+    let command: Command = serde_json::from_value(data)
+        .map_err(|e| color_eyre::eyre::eyre!("Invalid code action data: {e}"))?;
+
+    let params = ExecuteCommandParams {
+        command: command.command.clone(),
+        arguments: command.arguments.clone().unwrap_or_default(),
+        work_done_progress_params: Default::default(),
+    };
+    match commands::handle_execute_command_request(params, documents, None, opts)? {
+        Some(CommandResult::WorkspaceEdit { edit, .. }) => {
+            action.edit = Some(edit);
+        }
+        _ => {
+            // not something that can be expressed as an edit, fall back to
+            // letting the client execute the command
+            action.command = Some(command);
+        }
+    }
+
+    Ok(action)
+}
+
+/// Offer the query path of the element at the start of the range (e.g.
+/// `PID.3[2].4.1`), for the client to copy
+#[instrument(level = "trace", skip(uri, message, document_message, opts))]
+fn copy_path(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let action_range = lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
+    let path = message
+        .locate_cursor(clamp_offset(message.raw_value(), action_range.start))?
+        .to_string();
+    if path.is_empty() {
+        return None;
+    }
+
+    Some(CodeAction {
+        title: format!("Copy path `{path}`"),
+        kind: None,
+        diagnostics: None,
+        edit: None,
+        command: Some(Command {
+            title: format!("Copy path `{path}`"),
+            command: CMD_COPY_PATH.to_string(),
+            arguments: Some(vec![
+                serde_json::to_value(uri.clone()).expect("can serialize uri"),
+                serde_json::to_value(document_message.position_in_document(range.start))
+                    .expect("can serialize position"),
+            ]),
+        }),
+        data: None,
+        is_preferred: None,
+        disabled: None,
+    })
+}
+
+#[instrument(level = "trace", skip(uri, message, document_message))]
+fn generate_control_id(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
     // only available if MSH.10 is present
     message.query("MSH.10").and_then(|existing_control_id| {
         // only if the action range is within the existing control ID
-        let action_range = lsp_range_to_std_range(message.raw_value(), *range)?;
+        let action_range =
+            lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
         let existing_range = existing_control_id.range();
         if action_range.start < existing_range.start || action_range.end > existing_range.end {
             return None;
@@ -65,7 +244,9 @@ fn generate_control_id(range: &Range, uri: &Uri, message: &Message) -> Option<Co
                 title: "Generate new control ID".to_string(),
                 command: CMD_GENERATE_CONTROL_ID.to_string(),
                 arguments: Some(vec![
-                    serde_json::to_value(uri.clone()).expect("can serialize uri")
+                    serde_json::to_value(uri.clone()).expect("can serialize uri"),
+                    serde_json::to_value(document_message.position_in_document(range.start))
+                        .expect("can serialize position"),
                 ]),
             }),
             data: None,
@@ -75,16 +256,122 @@ fn generate_control_id(range: &Range, uri: &Uri, message: &Message) -> Option<Co
     })
 }
 
-#[instrument(level = "trace", skip(uri, message))]
-fn set_time_to_now(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
-    let version = message
-        .query("MSH.12")
-        .map(|msh_12| msh_12.raw_value())
-        .unwrap_or("2.7.1");
+/// Offer to regenerate the control ID and timestamp from anywhere in the
+/// message header, as is done before sending a test message again, also
+/// bumping the sequence number if the message has one
+#[instrument(level = "trace", skip(uri, message, document_message))]
+fn freshen_message(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let header = message.segment("MSH")?;
+    let action_range = lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
+    if action_range.start < header.range.start || action_range.end > header.range.end {
+        return None;
+    }
+    let bump_sequence_number = message
+        .query("MSH.13")
+        .is_some_and(|sequence_number| !sequence_number.raw_value().is_empty());
+
+    Some(CodeAction {
+        title: "Freshen message".to_string(),
+        kind: Some(CodeActionKind::REFACTOR),
+        diagnostics: None,
+        edit: None,
+        command: Some(Command {
+            title: "Freshen message".to_string(),
+            command: CMD_FRESHEN_MESSAGE.to_string(),
+            arguments: Some(vec![
+                serde_json::to_value(uri.clone()).expect("can serialize uri"),
+                serde_json::to_value(document_message.position_in_document(range.start))
+                    .expect("can serialize position"),
+                serde_json::Value::Bool(bump_sequence_number),
+            ]),
+        }),
+        data: None,
+        is_preferred: None,
+        disabled: None,
+    })
+}
+
+/// Offer to move the message into a new file of its own, if the document has
+/// other messages
+#[instrument(level = "trace", skip(uri, text, document_message, opts))]
+fn extract_message(
+    range: &Range,
+    uri: &Uri,
+    text: &str,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    if split_messages(text, opts.position_encoding).len() < 2 {
+        return None;
+    }
+    Some(CodeAction {
+        title: "Extract message to new file".to_string(),
+        kind: Some(CodeActionKind::REFACTOR_EXTRACT),
+        diagnostics: None,
+        edit: None,
+        command: Some(Command {
+            title: "Extract message to new file".to_string(),
+            command: CMD_EXTRACT_MESSAGE.to_string(),
+            arguments: Some(vec![
+                serde_json::to_value(uri.clone()).expect("can serialize uri"),
+                serde_json::to_value(document_message.position_in_document(range.start))
+                    .expect("can serialize position"),
+            ]),
+        }),
+        data: None,
+        is_preferred: None,
+        disabled: None,
+    })
+}
+
+/// Offer to wrap the messages of a document that isn't a batch file in file
+/// and batch headers and trailers
+#[instrument(level = "trace", skip(uri, text))]
+fn wrap_in_batch(uri: &Uri, text: &str) -> Option<CodeAction> {
+    if is_batch_file(text) {
+        return None;
+    }
+    Some(CodeAction {
+        title: "Wrap in batch".to_string(),
+        kind: Some(CodeActionKind::SOURCE),
+        diagnostics: None,
+        edit: None,
+        command: Some(Command {
+            title: "Wrap in batch".to_string(),
+            command: CMD_WRAP_IN_BATCH.to_string(),
+            arguments: Some(vec![
+                serde_json::to_value(uri.clone()).expect("can serialize uri")
+            ]),
+        }),
+        data: None,
+        is_preferred: None,
+        disabled: None,
+    })
+}
+
+#[instrument(
+    level = "trace",
+    skip(uri, message, document_message, workspace_specs, opts)
+)]
+fn set_time_to_now(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let version = opts.message_version(uri, message, workspace_specs).version;
 
     tracing::trace!(message_version=?version, "locating cursor");
-    let range = lsp_range_to_std_range(message.raw_value(), *range)?;
-    let cursor_location = message.locate_cursor(range.start)?;
+    let range = lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
+    let cursor_location = message.locate_cursor(clamp_offset(message.raw_value(), range.start))?;
 
     let (segment_name, _si, _segment) = cursor_location.segment?;
     let (fi, _field) = cursor_location.field?;
@@ -93,7 +380,11 @@ fn set_time_to_now(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAc
     tracing::trace!(?segment_name, field_index=?fi, "checking if field is a timestamp");
     if spec::is_field_a_timestamp(version, segment_name, fi) {
         tracing::trace!("field is a timestamp, generating code action");
-        let range = std_range_to_lsp_range(message.raw_value(), repeat.range.clone());
+        let range = document_message.range_in_document(std_range_to_lsp_range(
+            message.raw_value(),
+            repeat.range.clone(),
+            opts.position_encoding,
+        ));
         Some(CodeAction {
             title: format!("Set {cursor_location} to now"),
             kind: Some(CodeActionKind::REFACTOR),
@@ -117,9 +408,16 @@ fn set_time_to_now(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAc
     }
 }
 
-#[instrument(level = "trace", skip(uri, message))]
-fn encode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
-    let selection_range = lsp_range_to_std_range(message.raw_value(), *range)?;
+#[instrument(level = "trace", skip(uri, message, document_message))]
+fn encode(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let selection_range =
+        lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
     if selection_range.len() == 0 {
         return None;
     }
@@ -128,14 +426,19 @@ fn encode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
     // check if any of the separators are present in the selection, if not, return
     // None
     let separators = message.separators;
+    let truncation = truncation_character(message);
     let is_separator = |c: char| {
         separators.field == c
             || separators.component == c
             || separators.subcomponent == c
             || separators.repetition == c
             || separators.escape == c
+            || truncation == Some(c)
     };
-    let requires_encoding = text[selection_range.clone()].chars().any(is_separator);
+    let requires_encoding = slice_text(text, selection_range)
+        .ok()?
+        .chars()
+        .any(is_separator);
     if !requires_encoding {
         return None;
     }
@@ -150,7 +453,8 @@ fn encode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
             command: CMD_ENCODE_SELECTION.to_string(),
             arguments: Some(vec![
                 serde_json::to_value(uri.clone()).expect("can serialize uri"),
-                serde_json::to_value(range).expect("can serialize range"),
+                serde_json::to_value(document_message.range_in_document(*range))
+                    .expect("can serialize range"),
             ]),
         }),
         is_preferred: None,
@@ -159,9 +463,16 @@ fn encode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
     })
 }
 
-#[instrument(level = "trace", skip(uri, message))]
-fn decode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
-    let selection_range = lsp_range_to_std_range(message.raw_value(), *range)?;
+#[instrument(level = "trace", skip(uri, message, document_message))]
+fn decode(
+    range: &Range,
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let selection_range =
+        lsp_range_to_std_range(message.raw_value(), *range, opts.position_encoding)?;
     if selection_range.len() == 0 {
         return None;
     }
@@ -170,7 +481,10 @@ fn decode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
     // check if any of the separators are present in the selection, if not, return
     // None
     let escape = message.separators.escape;
-    let requires_decoding = text[selection_range.clone()].chars().any(|c| c == escape);
+    let requires_decoding = slice_text(text, selection_range)
+        .ok()?
+        .chars()
+        .any(|c| c == escape);
     if !requires_decoding {
         return None;
     }
@@ -185,11 +499,770 @@ fn decode(range: &Range, uri: &Uri, message: &Message) -> Option<CodeAction> {
             command: CMD_DECODE_SELECTION.to_string(),
             arguments: Some(vec![
                 serde_json::to_value(uri.clone()).expect("can serialize uri"),
-                serde_json::to_value(range).expect("can serialize range"),
+                serde_json::to_value(document_message.range_in_document(*range))
+                    .expect("can serialize range"),
+            ]),
+        }),
+        is_preferred: None,
+        disabled: None,
+        data: None,
+    })
+}
+
+/// The most table values offered as replacements for an invalid one when none
+/// of them are close to it, beyond which a menu of them would be unusable
+const MAX_TABLE_VALUE_ACTIONS: usize = 20;
+
+/// Offer to renumber the Set IDs of a message that has Set IDs out of
+/// sequence, with the `hl7.renumberSetIds` command
+#[instrument(level = "trace", skip(uri, document_message, diagnostics))]
+fn renumber_set_ids(
+    uri: &Uri,
+    document_message: &DocumentMessage,
+    diagnostics: &[Diagnostic],
+) -> Option<CodeAction> {
+    let code = lsp_types::NumberOrString::String(ValidationCode::InvalidSetId.to_string());
+    let set_ids: Vec<Diagnostic> = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_ref() == Some(&code))
+        .cloned()
+        .collect();
+    if set_ids.is_empty() {
+        return None;
+    }
+    let position = document_message.position_in_document(lsp_types::Position::new(0, 0));
+
+    Some(CodeAction {
+        title: "Renumber Set IDs".to_string(),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: Some(set_ids),
+        edit: None,
+        command: Some(Command {
+            title: "Renumber Set IDs".to_string(),
+            command: CMD_RENUMBER_SET_IDS.to_string(),
+            arguments: Some(vec![
+                serde_json::to_value(uri.clone()).expect("can serialize uri"),
+                serde_json::to_value(position).expect("can serialize position"),
             ]),
         }),
+        is_preferred: Some(true),
+        disabled: None,
+        data: None,
+    })
+}
+
+/// Offer to put the segments of a message with out of place segments into the
+/// order its message structure calls for, see [canonical_order]
+#[instrument(
+    level = "trace",
+    skip(uri, message, document_message, diagnostics, opts)
+)]
+fn reorder_segments(
+    uri: &Uri,
+    message: &Message,
+    document_message: &DocumentMessage,
+    diagnostics: &[Diagnostic],
+    opts: &Opts,
+) -> Option<CodeAction> {
+    let code = lsp_types::NumberOrString::String(ValidationCode::SegmentStructure.to_string());
+    let out_of_place: Vec<Diagnostic> = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_ref() == Some(&code))
+        .cloned()
+        .collect();
+    if out_of_place.is_empty() {
+        return None;
+    }
+    let order = canonical_order(message)?;
+    if order.windows(2).all(|pair| pair[0] < pair[1]) {
+        return None;
+    }
+
+    let text = message.raw_value();
+    let segments = message.segments().collect::<Vec<_>>();
+    let terminator = match segments.get(1) {
+        Some(second) => &text[segments[0].range.end..second.range.start],
+        None => "\r",
+    };
+    let new_text = order
+        .iter()
+        .map(|&si| segments[si].raw_value())
+        .collect::<Vec<_>>()
+        .join(terminator);
+    let range = document_message.range_in_document(std_range_to_lsp_range(
+        text,
+        0..text.len(),
+        opts.position_encoding,
+    ));
+    Some(CodeAction {
+        title: "Reorder segments".to_string(),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: Some(out_of_place),
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri.clone(),
+                vec![TextEdit { range, new_text }],
+            )])),
+            ..Default::default()
+        }),
+        command: None,
         is_preferred: None,
         disabled: None,
         data: None,
     })
 }
+
+/// Offer to insert a skeleton (see [segment_template]) of each required
+/// segment the message is missing, after the segment it should follow, and,
+/// on an empty line, of each segment of the message structure that isn't in
+/// the message yet
+#[instrument(
+    level = "trace",
+    skip(uri, text, message, diagnostics, workspace_specs, opts)
+)]
+fn insert_segments(
+    range: Range,
+    uri: &Uri,
+    text: &str,
+    message: &Message,
+    diagnostics: &[Diagnostic],
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Vec<CodeAction> {
+    let version = opts.message_version(uri, message, workspace_specs).version;
+    let line_ending = line_ending(text);
+    let now: TimeStamp = opts.output_timezone.now().into();
+    let template = |segment: &str| {
+        segment_template(
+            uri,
+            segment,
+            version,
+            &workspace_specs,
+            message.separators.field,
+            &now.to_string(),
+        )
+    };
+    let action = |segment: &str, at: lsp_types::Position, new_text: String| CodeAction {
+        title: format!("Insert {segment} segment"),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri.clone(),
+                vec![TextEdit {
+                    range: Range::new(at, at),
+                    new_text,
+                }],
+            )])),
+            ..Default::default()
+        }),
+        command: None,
+        is_preferred: None,
+        disabled: None,
+        data: None,
+    };
+
+    let code = lsp_types::NumberOrString::String(ValidationCode::SegmentStructure.to_string());
+    let mut offered = Vec::new();
+    let mut actions = Vec::new();
+    for diagnostic in diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_ref() == Some(&code))
+    {
+        let Some(segment) = diagnostic
+            .data
+            .clone()
+            .and_then(|data| serde_json::from_value::<DiagnosticData>(data).ok())
+            .and_then(|data| data.missing_segment)
+        else {
+            continue;
+        };
+        let Some(template) = template(&segment) else {
+            continue;
+        };
+        let mut insertion = action(
+            &segment,
+            diagnostic.range.end,
+            format!("{line_ending}{template}"),
+        );
+        insertion.diagnostics = Some(vec![diagnostic.clone()]);
+        insertion.is_preferred = Some(true);
+        actions.push(insertion);
+        offered.push(segment);
+    }
+
+    let is_empty_line = line_ranges(text)
+        .nth(range.start.line as usize)
+        .is_some_and(|line| text[line].trim().is_empty());
+    if is_empty_line {
+        let at = lsp_types::Position::new(range.start.line, 0);
+        for segment in structure_segments(message) {
+            if offered.iter().any(|offered| offered == segment)
+                || message.segment(segment).is_some()
+            {
+                continue;
+            }
+            if let Some(template) = template(segment) {
+                let mut insertion = action(segment, at, format!("{template}{line_ending}"));
+                insertion.kind = Some(CodeActionKind::REFACTOR);
+                actions.push(insertion);
+            }
+        }
+    }
+    actions
+}
+
+/// Offer to keep an observation value that was pasted in with line breaks in
+/// its segment, either by escaping the line breaks or by making each line a
+/// repeat, see [broken_observations]
+#[instrument(level = "trace", skip(uri, document_message, opts))]
+fn escape_line_breaks(
+    range: &Range,
+    uri: &Uri,
+    document_message: &DocumentMessage,
+    opts: &Opts,
+) -> Vec<CodeAction> {
+    let text = document_message.text;
+    let Some(action_range) = lsp_range_to_std_range(text, *range, opts.position_encoding) else {
+        return Vec::new();
+    };
+    let Some(broken) = broken_observations(text).into_iter().find(|broken| {
+        // anywhere on the lines the value is spread over
+        let segment_start = text[..broken.range.start]
+            .rfind(['\r', '\n'])
+            .map_or(0, |i| i + 1);
+        segment_start <= action_range.start && action_range.start <= broken.range.end
+    }) else {
+        return Vec::new();
+    };
+
+    let edit_range = document_message.range_in_document(std_range_to_lsp_range(
+        text,
+        broken.range,
+        opts.position_encoding,
+    ));
+    [
+        ("Escape the line breaks in OBX.5", broken.escaped, true),
+        ("Split OBX.5 into a repeat per line", broken.repeats, false),
+    ]
+    .into_iter()
+    .map(|(title, new_text, is_preferred)| CodeAction {
+        title: title.to_string(),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri.clone(),
+                vec![TextEdit {
+                    range: edit_range,
+                    new_text,
+                }],
+            )])),
+            ..Default::default()
+        }),
+        command: None,
+        is_preferred: Some(is_preferred),
+        disabled: None,
+        data: None,
+    })
+    .collect()
+}
+
+/// Offer to replace an invalid table value with the values closest to it, or
+/// with each of the table's values if none are close
+#[instrument(level = "trace", skip(uri, diagnostics))]
+fn replace_table_value(uri: &Uri, diagnostics: &[Diagnostic]) -> Vec<CodeAction> {
+    let code = lsp_types::NumberOrString::String(ValidationCode::InvalidTableValue.to_string());
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_ref() == Some(&code))
+        .flat_map(|diagnostic| {
+            let data = diagnostic
+                .data
+                .clone()
+                .and_then(|data| serde_json::from_value::<DiagnosticData>(data).ok())
+                .unwrap_or_default();
+            let describe = |value: &str| {
+                data.table
+                    .and_then(|table| spec::table_value_description(table, value))
+            };
+            let replacements = if !data.suggestions.is_empty() {
+                data.suggestions
+                    .iter()
+                    .map(|value| (value.clone(), describe(value)))
+                    .collect()
+            } else {
+                data.table
+                    .and_then(hl7_definitions::table_values)
+                    .filter(|values| values.len() <= MAX_TABLE_VALUE_ACTIONS)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(value, description)| (value.to_string(), Some(*description)))
+                    .collect::<Vec<_>>()
+            };
+
+            let is_preferred = replacements.len() == 1;
+            replacements
+                .into_iter()
+                .map(move |(value, description)| CodeAction {
+                    title: match description {
+                        Some(description) => format!("Replace with `{value}` ({description})"),
+                        None => format!("Replace with `{value}`"),
+                    },
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(
+                            uri.clone(),
+                            vec![TextEdit {
+                                range: diagnostic.range,
+                                new_text: value,
+                            }],
+                        )])),
+                        ..Default::default()
+                    }),
+                    command: None,
+                    is_preferred: Some(is_preferred),
+                    disabled: None,
+                    data: None,
+                })
+        })
+        .collect()
+}
+
+/// Offer to repair invalid timestamps, dates, and times: by setting them to
+/// now, by padding them out to full precision (dropping any digit of a
+/// component that's only partly there), or by removing a timezone offset that
+/// isn't valid
+///
+/// Only repairs that give a valid value are offered.
+#[instrument(level = "trace", skip(uri, text, diagnostics, opts))]
+fn repair_timestamps(
+    uri: &Uri,
+    text: &str,
+    diagnostics: &[Diagnostic],
+    opts: &Opts,
+) -> Vec<CodeAction> {
+    let code = lsp_types::NumberOrString::String(ValidationCode::InvalidTimestamp.to_string());
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_ref() == Some(&code))
+        .flat_map(|diagnostic| {
+            let datatype = diagnostic
+                .data
+                .clone()
+                .and_then(|data| serde_json::from_value::<DiagnosticData>(data).ok())
+                .and_then(|data| data.datatype);
+            let value = lsp_range_to_std_range(text, diagnostic.range, opts.position_encoding)
+                .and_then(|range| slice_text(text, range).ok());
+            let repairs = match (datatype, value) {
+                (Some(datatype), Some(value)) => {
+                    let now: TimeStamp = opts.output_timezone.now().into();
+                    timestamp_repairs(&datatype, value, &now.to_string())
+                }
+                _ => Vec::new(),
+            };
+            repairs
+                .into_iter()
+                .map(move |(title, new_text)| CodeAction {
+                    title,
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(HashMap::from([(
+                            uri.clone(),
+                            vec![TextEdit {
+                                range: diagnostic.range,
+                                new_text,
+                            }],
+                        )])),
+                        ..Default::default()
+                    }),
+                    command: None,
+                    is_preferred: None,
+                    disabled: None,
+                    data: None,
+                })
+        })
+        .collect()
+}
+
+/// The titles and replacements of the repairs that make an invalid value of a
+/// date/time datatype valid, given the current time as a timestamp
+fn timestamp_repairs(datatype: &str, value: &str, now: &str) -> Vec<(String, String)> {
+    // the defaults of each component, to pad with
+    let (full, now) = match datatype {
+        "DT" => ("00000101", &now[..8.min(now.len())]),
+        "TM" => ("000000", now.get(8..).unwrap_or_default()),
+        _ => ("00000101000000", now),
+    };
+    let is_valid = |value: &str| match datatype {
+        "DT" => hl7_parser::datetime::parse_date(value, false).is_ok(),
+        "TM" => hl7_parser::datetime::parse_time(value, false).is_ok(),
+        _ => hl7_parser::datetime::parse_timestamp(value, false).is_ok(),
+    };
+
+    let mut repairs = Vec::new();
+    if is_valid(now) {
+        repairs.push(("Set to now".to_string(), now.to_string()));
+    }
+
+    let (body, offset) = match value
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| matches!(c, '+' | '-'))
+    {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let digits = body
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(body, |end| &body[..end]);
+    // the year is the only component with 4 digits
+    let first = if datatype == "TM" { 2 } else { 4 };
+    let complete = match digits.len() {
+        len if len < first => 0,
+        len => len - (len - first) % 2,
+    }
+    .min(full.len());
+    if complete > 0 && complete < full.len() {
+        let padded = format!("{}{}", &digits[..complete], &full[complete..]);
+        let padded = match format!("{padded}{offset}") {
+            with_offset if is_valid(&with_offset) => with_offset,
+            _ => padded,
+        };
+        if padded != value && is_valid(&padded) {
+            repairs.push((format!("Pad to `{padded}`"), padded));
+        }
+    }
+
+    if !offset.is_empty() && is_valid(body) {
+        repairs.push((
+            format!("Remove the timezone offset `{offset}`"),
+            body.to_string(),
+        ));
+    }
+    repairs
+}
+
+/// Offer the quick fixes attached to diagnostics, which replace the range of
+/// the diagnostic with its first suggestion (followed by the current time, for
+/// fixes that fill in a timestamp)
+#[instrument(level = "trace", skip(uri, diagnostics, opts))]
+fn apply_fixes(uri: &Uri, diagnostics: &[Diagnostic], opts: &Opts) -> Vec<CodeAction> {
+    diagnostics
+        .iter()
+        .filter_map(|diagnostic| {
+            let data = serde_json::from_value::<DiagnosticData>(diagnostic.data.clone()?).ok()?;
+            let title = data.fix?;
+            let mut new_text = data.suggestions.into_iter().next().unwrap_or_default();
+            if data.fix_inserts_now {
+                let now: TimeStamp = opts.output_timezone.now().into();
+                new_text.push_str(&now.to_string());
+            }
+            Some(CodeAction {
+                title,
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        uri.clone(),
+                        vec![TextEdit {
+                            range: diagnostic.range,
+                            new_text,
+                        }],
+                    )])),
+                    ..Default::default()
+                }),
+                command: None,
+                is_preferred: Some(true),
+                disabled: None,
+                data: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hl7_ls::validation::ValidationError;
+    use lsp_types::DiagnosticSeverity;
+
+    #[test]
+    fn invalid_timestamps_can_be_repaired() {
+        let now = "20240601123456+0000";
+        let repairs = |datatype: &str, value: &str| {
+            timestamp_repairs(datatype, value, now)
+                .into_iter()
+                .map(|(_, replacement)| replacement)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            repairs("DTM", "2024011"),
+            vec![now.to_string(), "20240101000000".to_string()]
+        );
+        assert_eq!(
+            repairs("DTM", "202401021+0500"),
+            vec![now.to_string(), "20240102000000+0500".to_string(),]
+        );
+        assert_eq!(
+            repairs("DTM", "20240102+25"),
+            vec![
+                now.to_string(),
+                "20240102000000".to_string(),
+                "20240102".to_string(),
+            ]
+        );
+        assert_eq!(repairs("DT", "2024010"), vec!["20240601", "20240101"]);
+    }
+
+    #[test]
+    fn invalid_table_values_can_be_replaced() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let text = "PID|1||||||Q";
+        let diagnostic = |suggestions: Vec<String>| {
+            ValidationError::new(
+                ValidationCode::InvalidTableValue,
+                "Invalid table value".to_string(),
+                11..12,
+                DiagnosticSeverity::INFORMATION,
+            )
+            .with_suggestions(suggestions)
+            .with_table(1)
+            .into_diagnostic(&uri, text, Default::default())
+        };
+        let titles = |diagnostic: Diagnostic| {
+            replace_table_value(&uri, &[diagnostic])
+                .into_iter()
+                .map(|action| action.title)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            titles(diagnostic(vec!["M".to_string()])),
+            vec!["Replace with `M` (Male)"]
+        );
+        assert_eq!(
+            titles(diagnostic(Vec::new())),
+            vec![
+                "Replace with `F` (Female)",
+                "Replace with `M` (Male)",
+                "Replace with `U` (Unknown)"
+            ]
+        );
+    }
+
+    #[test]
+    fn set_ids_are_renumbered_from_the_start_of_their_message() {
+        let uri: Uri = "file:///messages.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App\nOBX|1\n\nMSH|^~\\&|App\nOBX|2";
+        let second = message_at(text, text.rfind("MSH").unwrap(), Default::default());
+        let diagnostic = |code: ValidationCode| {
+            ValidationError::new(
+                code,
+                "Problem".to_string(),
+                4..5,
+                DiagnosticSeverity::WARNING,
+            )
+            .into_diagnostic(&uri, second.text, Default::default())
+        };
+
+        assert!(renumber_set_ids(&uri, &second, &[]).is_none());
+        assert!(
+            renumber_set_ids(&uri, &second, &[diagnostic(ValidationCode::MessageHeader)]).is_none()
+        );
+
+        let action = renumber_set_ids(
+            &uri,
+            &second,
+            &[
+                diagnostic(ValidationCode::MessageHeader),
+                diagnostic(ValidationCode::InvalidSetId),
+            ],
+        )
+        .unwrap();
+        assert_eq!(action.diagnostics.unwrap().len(), 1);
+        let arguments = action.command.unwrap().arguments.unwrap();
+        assert_eq!(arguments[0], serde_json::json!("file:///messages.hl7"));
+        assert_eq!(
+            arguments[1],
+            serde_json::json!({ "line": 3, "character": 0 })
+        );
+    }
+
+    #[test]
+    fn segments_are_inserted_where_the_cursor_or_diagnostic_is() {
+        let uri: Uri = "file:///messages.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A04|1|P|2.5.1\r\n\
+                    EVN|A04\r\nPID|1\r\nPV1|1\r\n\r\n\
+                    MSH|^~\\&|𝄞|Fac|Rcv|RcvFac|20240102030405||ADT^A04|2|P|2.5.1\r\nPID|1\r\nPV1|1";
+        let opts = Opts::default();
+        let insertions = |range: Range, diagnostics: &[Diagnostic]| {
+            let document_message = message_at(
+                text,
+                lsp_range_to_std_range(text, range, opts.position_encoding)
+                    .unwrap()
+                    .start,
+                opts.position_encoding,
+            );
+            let message = parse_message_with_lenient_newlines(document_message.text).unwrap();
+            insert_segments(range, &uri, text, &message, diagnostics, None, &opts)
+                .into_iter()
+                .map(|action| {
+                    let edit = action.edit.unwrap().changes.unwrap().remove(&uri).unwrap();
+                    (action.title, edit[0].range.start, edit[0].new_text.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the second message is missing its EVN, which goes at the end of the
+        // header, counted in UTF-16 code units
+        let header_end = lsp_types::Position::new(5, 60);
+        let missing = Diagnostic {
+            range: Range::new(lsp_types::Position::new(5, 0), header_end),
+            code: Some(lsp_types::NumberOrString::String(
+                ValidationCode::SegmentStructure.to_string(),
+            )),
+            data: Some(
+                serde_json::to_value(DiagnosticData {
+                    missing_segment: Some("EVN".to_string()),
+                    ..Default::default()
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let cursor = Range::new(
+            lsp_types::Position::new(6, 2),
+            lsp_types::Position::new(6, 2),
+        );
+        let inserted = insertions(cursor, &[missing]);
+        assert_eq!(inserted.len(), 1);
+        assert_eq!(inserted[0].0, "Insert EVN segment");
+        assert_eq!(inserted[0].1, header_end);
+        assert!(inserted[0].2.starts_with("\r\nEVN||"));
+
+        // on the blank line after the first message, the segments it doesn't
+        // have yet
+        let blank = Range::new(
+            lsp_types::Position::new(4, 0),
+            lsp_types::Position::new(4, 0),
+        );
+        let inserted = insertions(blank, &[]);
+        assert!(!inserted.is_empty());
+        for (title, at, new_text) in inserted.iter() {
+            assert_eq!(*at, blank.start, "{title}");
+            assert!(new_text.ends_with("\r\n"), "{title}");
+        }
+        let titles = inserted
+            .iter()
+            .map(|(title, _, _)| title.as_str())
+            .collect::<Vec<_>>();
+        for present in ["MSH", "EVN", "PID", "PV1"] {
+            assert!(!titles.contains(&format!("Insert {present} segment").as_str()));
+        }
+    }
+
+    #[test]
+    fn line_breaks_are_escaped_in_the_message_at_the_cursor() {
+        use hl7_ls::utils::PositionEncoding;
+
+        let uri: Uri = "file:///messages.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App|Fac|||20240102||ORU^R01|1|P|2.5.1\nOBX|1|TX|||fine\n\n\
+                    MSH|^~\\&|App|Fac|||20240102||ORU^R01|2|P|2.5.1\nOBX|1|TX|||first\n𝄞 second|||F";
+        let actions = |text: &str, at: lsp_types::Position, encoding: PositionEncoding| {
+            let opts = Opts {
+                position_encoding: encoding,
+                ..Default::default()
+            };
+            let start = position_to_offset(text, at.line, at.character, encoding).unwrap();
+            let document_message = message_at(text, start, encoding);
+            let range = document_message
+                .range_in_message(Range::new(at, at))
+                .unwrap();
+            escape_line_breaks(&range, &uri, &document_message, &opts)
+                .into_iter()
+                .map(|action| {
+                    let edit = action.edit.unwrap().changes.unwrap().remove(&uri).unwrap();
+                    (edit[0].range, edit[0].new_text.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the value's range is in the document, counting the non-BMP
+        // character as 2 UTF-16 code units or 4 UTF-8 bytes
+        let value = |end: u32| {
+            Range::new(
+                lsp_types::Position::new(4, 11),
+                lsp_types::Position::new(5, end),
+            )
+        };
+        let on_continuation = lsp_types::Position::new(5, 2);
+        assert_eq!(
+            actions(text, on_continuation, PositionEncoding::Utf16),
+            vec![
+                (value(9), "first\\X0A\\𝄞 second".to_string()),
+                (value(9), "first~𝄞 second".to_string()),
+            ]
+        );
+        let on_continuation = lsp_types::Position::new(5, 4);
+        assert_eq!(
+            actions(text, on_continuation, PositionEncoding::Utf8)[0].0,
+            value(11)
+        );
+
+        // not in the broken value, or in the other message
+        assert!(actions(
+            text,
+            lsp_types::Position::new(3, 0),
+            PositionEncoding::Utf16
+        )
+        .is_empty());
+        assert!(actions(
+            text,
+            lsp_types::Position::new(1, 12),
+            PositionEncoding::Utf16
+        )
+        .is_empty());
+
+        // without a message header, the default separators are used
+        let headless = "OBX|1|TX|||first\nsecond";
+        assert_eq!(
+            actions(
+                headless,
+                lsp_types::Position::new(1, 0),
+                PositionEncoding::Utf16
+            )[0]
+            .1,
+            "first\\X0A\\second"
+        );
+    }
+
+    #[test]
+    fn paths_are_copied_from_the_document_position() {
+        let uri: Uri = "file:///messages.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App\nPID|1\n\nMSH|^~\\&|App\nPID|1||𝄞^x";
+        let opts = Opts::default();
+        let at = lsp_types::Position::new(4, 10);
+        let document_message = message_at(
+            text,
+            position_to_offset(text, at.line, at.character, opts.position_encoding).unwrap(),
+            opts.position_encoding,
+        );
+        let range = document_message
+            .range_in_message(Range::new(at, at))
+            .unwrap();
+        let message = parse_message_with_lenient_newlines(document_message.text).unwrap();
+
+        let action = copy_path(&range, &uri, &message, &document_message, &opts).unwrap();
+        assert_eq!(action.title, "Copy path `PID.3.2.1`");
+        let arguments = action.command.unwrap().arguments.unwrap();
+        assert_eq!(arguments[0], serde_json::json!("file:///messages.hl7"));
+        assert_eq!(
+            arguments[1],
+            serde_json::json!({ "line": 4, "character": 10 })
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::commands::CMD_EXPLAIN_SELECTION;
+use chrono::{DateTime, Datelike, Utc};
+use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    spec, utils::std_range_to_lsp_range, workspace::specs::WorkspaceSpecs, Opts, TimeZone,
+};
+use hl7_parser::{datetime::parse_timestamp, message::Segment, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{CodeLens, CodeLensParams, Command};
+use tracing::instrument;
+
+/// The separator between the facts in a summary
+const SEPARATOR: &str = " · ";
+
+/// Summaries of the message header and each patient above their segments, so
+/// that the key facts of a message can be seen without reading it field by
+/// field
+///
+/// Clicking a summary explains its segment with `hl7.explainSelection`.
+#[instrument(level = "debug", skip(params, documents, workspace_specs, opts))]
+pub fn handle_code_lens_request(
+    params: CodeLensParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<Vec<CodeLens>>> {
+    let uri = params.text_document.uri;
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {uri:?}"))?;
+
+    let message = match hl7_parser::parse_message_with_lenient_newlines(text) {
+        Ok(message) => message,
+        Err(e) => {
+            tracing::debug!(error = %e, "Failed to parse message");
+            return Ok(None);
+        }
+    };
+
+    let version = opts
+        .message_version(&uri, &message, workspace_specs)
+        .version;
+    let lenses = message
+        .segments()
+        .filter_map(|segment| {
+            let summary = match segment.name {
+                "MSH" => summarize_header(&message, opts.display_timezone),
+                "PID" => summarize_patient(segment, version, opts.display_timezone),
+                _ => return None,
+            };
+            if summary.is_empty() {
+                return None;
+            }
+            let range = std_range_to_lsp_range(text, segment.range.clone(), opts.position_encoding);
+            Some(CodeLens {
+                range,
+                command: Some(Command {
+                    title: summary,
+                    command: CMD_EXPLAIN_SELECTION.to_string(),
+                    arguments: Some(vec![
+                        serde_json::json!(uri.as_str()),
+                        serde_json::to_value(range).expect("can serialize range"),
+                    ]),
+                }),
+                data: None,
+            })
+        })
+        .collect();
+    Ok(Some(lenses))
+}
+
+/// The message's type, when it was sent, and its control ID
+fn summarize_header(message: &Message, timezone: TimeZone) -> String {
+    let value = |path: &str| {
+        message
+            .query(path)
+            .map(|value| value.raw_value())
+            .filter(|value| !value.is_empty())
+    };
+    [
+        value("MSH.9").map(str::to_string),
+        value("MSH.7").map(|timestamp| format_timestamp(timestamp, timezone)),
+        value("MSH.10").map(|control_id| format!("control ID {control_id}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(SEPARATOR)
+}
+
+/// The patient's name, date of birth and age, sex, and primary MRN
+fn summarize_patient(segment: &Segment, version: &str, timezone: TimeZone) -> String {
+    let component = |field: usize, repeat: usize, component: usize| {
+        segment
+            .field(field)
+            .and_then(|f| f.repeats().nth(repeat))
+            .and_then(|r| r.component(component))
+            .map(|c| c.raw_value())
+            .filter(|value| !value.is_empty())
+    };
+
+    let name = match (component(5, 0, 1), component(5, 0, 2)) {
+        (Some(family), Some(given)) => Some(format!("{family}, {given}")),
+        (family, given) => family.or(given).map(str::to_string),
+    };
+    let birth = segment
+        .field(7)
+        .map(|f| f.raw_value())
+        .filter(|value| !value.is_empty())
+        .map(|dob| describe_birth(dob, timezone));
+    let sex = segment
+        .field(8)
+        .map(|f| f.raw_value())
+        .filter(|value| !value.is_empty())
+        .map(|sex| {
+            spec::field_table(version, "PID", 8)
+                .and_then(|table| spec::table_value_description(table, sex))
+                .unwrap_or(sex)
+                .to_string()
+        });
+    // the MRN is the identifier typed as one, or else the first identifier
+    let identifiers = segment.field(3).map(|f| f.repeats().count()).unwrap_or(0);
+    let mrn = (0..identifiers)
+        .find(|&repeat| component(3, repeat, 5) == Some("MR"))
+        .or(Some(0))
+        .and_then(|repeat| component(3, repeat, 1))
+        .map(|mrn| format!("MRN {mrn}"));
+
+    [name, birth, sex, mrn]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(SEPARATOR)
+}
+
+/// A date of birth, with the patient's age today if the date can be parsed
+fn describe_birth(dob: &str, timezone: TimeZone) -> String {
+    let Ok(ts) = parse_timestamp(dob, false) else {
+        return format!("DOB {dob}");
+    };
+    let (Some(month), Some(day)) = (ts.month, ts.day) else {
+        return format!("DOB {year}", year = ts.year);
+    };
+    let today = timezone.now();
+    let had_birthday = (today.month(), today.day()) >= (month as u32, day as u32);
+    let age = today.year() - ts.year as i32 - if had_birthday { 0 } else { 1 };
+    format!(
+        "DOB {year:04}-{month:02}-{day:02} ({age} y)",
+        year = ts.year
+    )
+}
+
+/// A timestamp in the given timezone, or as it's written if it can't be
+/// parsed
+fn format_timestamp(value: &str, timezone: TimeZone) -> String {
+    let ts: Option<DateTime<Utc>> = parse_timestamp(value, false)
+        .ok()
+        .and_then(|ts| ts.try_into().ok());
+    match ts {
+        Some(ts) => format!(
+            "{time} {zone}",
+            time = timezone.convert(ts).format("%Y-%m-%d %H:%M"),
+            zone = timezone.label()
+        ),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patients_are_summarized() {
+        let message = hl7_parser::parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|||20240102030405||ADT^A01|CTRL1|P|2.5.1\rPID|1||ABC^^^Gov^SS~12345^^^Hosp^MR||Doe^John||19800102|M",
+        )
+        .unwrap();
+        let pid = message.segment("PID").unwrap();
+
+        let summary = summarize_patient(pid, "2.5.1", TimeZone::Utc);
+        assert!(summary.starts_with("Doe, John · DOB 1980-01-02 ("));
+        assert!(summary.ends_with(" y) · Male · MRN 12345"));
+
+        assert_eq!(
+            summarize_header(&message, TimeZone::Utc),
+            "ADT^A01 · 2024-01-02 03:04 UTC · control ID CTRL1"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    messages::split_messages,
+    utils::std_range_to_lsp_range,
+    validation::RulePath,
+    workspace::specs::WorkspaceSpecs,
+    Opts,
+};
+use hl7_parser::{message::Separators, parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
+};
+use tracing::instrument;
+
+/// The PHI that's replaced unless the workspace adds to it: the patient's
+/// identifiers, names, birth date, addresses, phone numbers, and SSN, and the
+/// same for their next of kin, insured, and guarantors
+const DEFAULT_PHI: &[&str] = &[
+    "PID.2", "PID.3", "PID.4", "PID.5", "PID.6", "PID.7", "PID.11", "PID.13", "PID.14", "PID.18",
+    "PID.19", "PID.20", "NK1.2", "NK1.4", "NK1.5", "NK1.6", "NK1.33", "IN1.16", "IN1.18", "IN1.19",
+    "IN1.49", "GT1.2", "GT1.3", "GT1.5", "GT1.6", "GT1.7", "GT1.8", "GT1.12",
+];
+
+const FAMILY_NAMES: &[&str] = &[
+    "Smith", "Johnson", "Williams", "Brown", "Jones", "Garcia", "Miller", "Davis", "Martinez",
+    "Wilson", "Anderson", "Taylor", "Thomas", "Moore", "Jackson", "Martin", "Lee", "Thompson",
+];
+const GIVEN_NAMES: &[&str] = &[
+    "James",
+    "Mary",
+    "Robert",
+    "Patricia",
+    "John",
+    "Jennifer",
+    "Michael",
+    "Linda",
+    "David",
+    "Elizabeth",
+    "William",
+    "Barbara",
+    "Richard",
+    "Susan",
+    "Joseph",
+    "Jessica",
+    "Thomas",
+    "Sarah",
+];
+const STREETS: &[&str] = &[
+    "Main St",
+    "Oak Ave",
+    "Maple Dr",
+    "Cedar Ln",
+    "Elm St",
+    "Pine Rd",
+    "Lakeview Dr",
+    "Park Ave",
+    "Hillcrest Rd",
+    "River Rd",
+];
+const CITIES: &[&str] = &[
+    "Springfield",
+    "Riverside",
+    "Fairview",
+    "Franklin",
+    "Greenville",
+    "Clinton",
+    "Madison",
+    "Georgetown",
+];
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_anonymize_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!("Expected 1 argument for anonymize command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let workspace_phi = workspace_specs
+        .map(|specs| specs.phi_paths(&uri))
+        .unwrap_or_default();
+    let paths = DEFAULT_PHI
+        .iter()
+        .copied()
+        .chain(workspace_phi.iter().map(String::as_str))
+        .map(RulePath::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut edits = Vec::new();
+    for document_message in split_messages(text, opts.position_encoding) {
+        let message = parse_message_with_lenient_newlines(document_message.text)
+            .wrap_err_with(|| "Failed to parse HL7 message")?;
+        let version = opts
+            .message_version(&uri, &message, workspace_specs)
+            .version;
+        edits.extend(
+            anonymize(&message, version, &paths)
+                .into_iter()
+                .map(|(range, new_text)| {
+                    let range = range.start + document_message.range.start
+                        ..range.end + document_message.range.start;
+                    TextEdit {
+                        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+                        new_text,
+                    }
+                }),
+        );
+    }
+    if edits.is_empty() {
+        return Ok(None);
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, edits);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Anonymize",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// The edits that replace the values at the PHI paths in a message with fake
+/// ones, keeping each value's repeats, components, and sub-components
+///
+/// Fakes are made from the values they replace, so that a value that appears
+/// several times (such as an MRN) is replaced with the same fake each time.
+fn anonymize(message: &Message, version: &str, paths: &[RulePath]) -> Vec<(Range<usize>, String)> {
+    let separators = &message.separators;
+    let truncation = truncation_character(message);
+
+    let mut edits = Vec::new();
+    for segment in message.segments() {
+        for path in paths.iter().filter(|path| path.segment == segment.name) {
+            let Some(field) = segment.field(path.field) else {
+                continue;
+            };
+            let datatype = match path.component {
+                Some(_) => None,
+                None => hl7_definitions::get_segment(version, segment.name)
+                    .and_then(|definition| definition.fields.get(path.field - 1))
+                    .map(|field| field.datatype),
+            };
+            for repeat in field.repeats() {
+                let Some(component) = path.component else {
+                    edits.push((
+                        repeat.range.clone(),
+                        fake_repeat(repeat.raw_value(), datatype, separators, truncation),
+                    ));
+                    continue;
+                };
+                let Some(component) = repeat.component(component) else {
+                    continue;
+                };
+                let (range, value) = match path
+                    .sub_component
+                    .map(|sub_component| component.subcomponent(sub_component))
+                {
+                    None => (component.range.clone(), component.raw_value()),
+                    Some(Some(sub_component)) => {
+                        (sub_component.range.clone(), sub_component.raw_value())
+                    }
+                    Some(None) => continue,
+                };
+                let fake = fake_repeat(value, None, separators, truncation);
+                edits.push((range, fake));
+            }
+        }
+    }
+
+    // the same value may be named by several paths (e.g. `PID.3` by default
+    // and `PID.3.1` by the workspace), in which case the widest is kept
+    edits.retain(|(range, fake)| !range.is_empty() && !fake.is_empty());
+    edits.sort_by_key(|(range, _)| (range.start, std::cmp::Reverse(range.end)));
+    let mut end = 0;
+    edits.retain(|(range, _)| {
+        let keep = range.start >= end;
+        end = end.max(range.end);
+        keep
+    });
+    edits
+}
+
+/// A fake for a repeat of a field (or a component of one), replacing the
+/// identifying parts of its datatype and keeping the others (such as the
+/// assigning authority of an identifier)
+fn fake_repeat(
+    value: &str,
+    datatype: Option<&str>,
+    separators: &Separators,
+    truncation: Option<char>,
+) -> String {
+    // the null value is kept, as it means something different from empty
+    if value.is_empty() || value == "\"\"" {
+        return String::new();
+    }
+    let identifying = |component: usize| match datatype {
+        Some("CX") => component == 1,
+        Some("XPN") => component <= 3,
+        Some("XCN") => (1..=4).contains(&component),
+        Some("XAD") => matches!(component, 1 | 2 | 3 | 5),
+        Some("XTN") => matches!(component, 1 | 4 | 6 | 7 | 12),
+        _ => true,
+    };
+    let kind = |component: usize| match (datatype, component) {
+        (Some("XPN"), 1) | (Some("XCN"), 2) => Fake::FamilyName,
+        (Some("XPN"), 2 | 3) | (Some("XCN"), 3 | 4) => Fake::GivenName,
+        (Some("XAD"), 1) => Fake::Street,
+        (Some("XAD"), 3) => Fake::City,
+        (Some("XTN"), 4) => Fake::Email,
+        (Some("TS" | "DTM" | "DT"), 1) => Fake::Date,
+        _ => Fake::Mask,
+    };
+
+    value
+        .split(separators.component)
+        .enumerate()
+        .map(|(ci, component)| {
+            if !identifying(ci + 1) {
+                return component.to_string();
+            }
+            component
+                .split(separators.subcomponent)
+                .map(|sub_component| {
+                    let decoded = escapes::decode(sub_component, separators, truncation);
+                    let fake = kind(ci + 1).generate(&decoded);
+                    escapes::encode(&fake, separators, truncation)
+                })
+                .collect::<Vec<_>>()
+                .join(&separators.subcomponent.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(&separators.component.to_string())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fake {
+    FamilyName,
+    GivenName,
+    Street,
+    City,
+    Email,
+    /// A birth date, keeping the precision of the value it replaces
+    Date,
+    /// Random digits and letters in place of the value's, keeping its format
+    Mask,
+}
+
+impl Fake {
+    fn generate(self, value: &str) -> String {
+        if value.is_empty() || value == "\"\"" {
+            return value.to_string();
+        }
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let mut rng = StdRng::seed_from_u64(hasher.finish());
+        let pick =
+            |rng: &mut StdRng, values: &[&str]| values[rng.gen_range(0..values.len())].to_string();
+
+        match self {
+            Fake::FamilyName => pick(&mut rng, FAMILY_NAMES),
+            Fake::GivenName => pick(&mut rng, GIVEN_NAMES),
+            Fake::Street => {
+                let number = rng.gen_range(100..10000);
+                format!("{number} {}", pick(&mut rng, STREETS))
+            }
+            Fake::City => pick(&mut rng, CITIES),
+            Fake::Email => {
+                let given = pick(&mut rng, GIVEN_NAMES);
+                let family = pick(&mut rng, FAMILY_NAMES);
+                format!("{given}.{family}@example.com").to_lowercase()
+            }
+            Fake::Date => {
+                let date = format!(
+                    "{:04}{:02}{:02}{:02}{:02}{:02}",
+                    rng.gen_range(1940..2010),
+                    rng.gen_range(1..=12),
+                    rng.gen_range(1..=28),
+                    rng.gen_range(0..24),
+                    rng.gen_range(0..60),
+                    rng.gen_range(0..60),
+                );
+                // anything after the digits (e.g. a time zone) is kept
+                let digits = value
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(value.len())
+                    .min(date.len());
+                format!("{}{}", &date[..digits], &value[digits..])
+            }
+            Fake::Mask => value
+                .chars()
+                .map(|c| match c {
+                    '0'..='9' => char::from(b'0' + rng.gen_range(0..10)),
+                    'a'..='z' => char::from(b'a' + rng.gen_range(0..26)),
+                    'A'..='Z' => char::from(b'A' + rng.gen_range(0..26)),
+                    c => c,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phi_is_replaced_keeping_the_message_structure() {
+        let text = "MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1\rPID|1||123456^^^Hosp^MR~123456^^^Other||O\\T\\Brien^Mary^^^Dr||19800102|F|||1 Real St^^Town^ON^A1B2C3||(555)123-4567\rOBX|1|ST|||Secret";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let paths = DEFAULT_PHI
+            .iter()
+            .chain(&["PID.3.1", "OBX.5"])
+            .map(|path| RulePath::parse(path).unwrap())
+            .collect::<Vec<_>>();
+        let mut anonymized = text.to_string();
+        for (range, fake) in anonymize(&message, "2.5.1", &paths).into_iter().rev() {
+            anonymized.replace_range(range, &fake);
+        }
+        let anonymized = parse_message_with_lenient_newlines(&anonymized).unwrap();
+        let value = |path: &str| anonymized.query(path).unwrap().raw_value().to_string();
+
+        assert_eq!(value("MSH.9"), "ADT^A01");
+        // the same MRN is replaced with the same fake
+        let mrn = value("PID.3[1].1");
+        assert_ne!(mrn, "123456");
+        assert_eq!(mrn.len(), 6);
+        assert_eq!(value("PID.3[2].1"), mrn);
+        assert_eq!(value("PID.3[1].4"), "Hosp");
+        assert_eq!(value("PID.3[2].4"), "Other");
+        assert!(FAMILY_NAMES.contains(&value("PID.5.1").as_str()));
+        assert_eq!(value("PID.5.5"), "Dr");
+        assert_ne!(value("PID.7"), "19800102");
+        assert_eq!(value("PID.7").len(), 8);
+        assert_eq!(value("PID.8"), "F");
+        assert_ne!(value("PID.11.5"), "A1B2C3");
+        assert_eq!(value("PID.13").len(), "(555)123-4567".len());
+        assert_ne!(value("OBX.5"), "Secret");
+    }
+}
@@ -0,0 +1,196 @@
+use super::{
+    set_value::{set_value, ValuePath},
+    CommandResult,
+};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_definitions::FieldOptionality;
+use hl7_ls::{
+    messages::split_messages, utils::std_range_to_lsp_range, workspace::specs::WorkspaceSpecs, Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use serde::Serialize;
+use std::collections::HashMap;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_change_version_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for change version command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+    let target = params.arguments[1]
+        .as_str()
+        .map(str::trim)
+        .wrap_err("Expected target version as second argument")?;
+    if hl7_definitions::get_definition(target).is_none() {
+        return Err(eyre!("Unknown HL7 version `{target}`"));
+    }
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let mut edits = Vec::new();
+    let mut changes = Vec::new();
+    for document_message in split_messages(text, opts.position_encoding) {
+        let message = parse_message_with_lenient_newlines(document_message.text)
+            .wrap_err_with(|| "Failed to parse HL7 message")?;
+        let version = opts
+            .message_version(&uri, &message, workspace_specs)
+            .version;
+        for change in version_changes(&message, version, target) {
+            if !changes.contains(&change) {
+                changes.push(change);
+            }
+        }
+
+        // only the version ID, keeping any internationalization code
+        let path = ValuePath {
+            segment: "MSH".to_string(),
+            occurrence: 1,
+            field: 12,
+            repeat: 1,
+            component: Some(1),
+            sub_component: None,
+        };
+        let (range, new_text) = set_value(&message, &path, target)?;
+        let range =
+            range.start + document_message.range.start..range.end + document_message.range.start;
+        edits.push(TextEdit {
+            range: std_range_to_lsp_range(text, range, opts.position_encoding),
+            new_text,
+        });
+    }
+    if edits.is_empty() {
+        return Ok(None);
+    }
+    tracing::debug!(?changes, "changing version");
+
+    #[allow(clippy::mutable_key_type)]
+    let mut document_changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    document_changes.insert(uri, edits);
+    Ok(Some(CommandResult::ValueResponseWithEdit {
+        value: serde_json::to_value(&changes).wrap_err("Failed to serialize changes")?,
+        label: "Change version",
+        edit: WorkspaceEdit {
+            changes: Some(document_changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// A difference between two versions that affects a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct VersionChange {
+    /// The segment or field, e.g. `PID` or `PID.3`
+    path: String,
+    description: String,
+}
+
+/// What changes for the message when it moves from one version to another:
+/// its segments and filled in fields that the target version doesn't define,
+/// and the fields of its segments whose optionality is different
+fn version_changes(message: &Message, from: &str, to: &str) -> Vec<VersionChange> {
+    let mut changes: Vec<VersionChange> = Vec::new();
+    for segment in message.segments() {
+        let Some(from_definition) = hl7_definitions::get_segment(from, segment.name) else {
+            continue;
+        };
+        if changes.iter().any(|change| change.path == segment.name) {
+            continue;
+        }
+        let Some(to_definition) = hl7_definitions::get_segment(to, segment.name) else {
+            changes.push(VersionChange {
+                path: segment.name.to_string(),
+                description: format!("{} isn't defined in HL7 {to}", segment.name),
+            });
+            continue;
+        };
+
+        for (fi, field) in segment.fields().enumerate() {
+            let path = format!("{}.{}", segment.name, fi + 1);
+            if field.is_empty() || fi < to_definition.fields.len() {
+                continue;
+            }
+            if !changes.iter().any(|change| change.path == path) {
+                changes.push(VersionChange {
+                    description: format!("{path} isn't defined in HL7 {to}"),
+                    path,
+                });
+            }
+        }
+        for (fi, (before, after)) in from_definition
+            .fields
+            .iter()
+            .zip(&to_definition.fields)
+            .enumerate()
+        {
+            let path = format!("{}.{}", segment.name, fi + 1);
+            if before.optionality == after.optionality
+                || changes.iter().any(|change| change.path == path)
+            {
+                continue;
+            }
+            changes.push(VersionChange {
+                description: format!(
+                    "{path} ({description}) is {after} in HL7 {to}, but was {before} in {from}",
+                    description = after.description,
+                    after = optionality_name(after.optionality),
+                    before = optionality_name(before.optionality),
+                ),
+                path,
+            });
+        }
+    }
+    changes
+}
+
+fn optionality_name(optionality: FieldOptionality) -> &'static str {
+    match optionality {
+        FieldOptionality::Required => "required",
+        FieldOptionality::Optional => "optional",
+        FieldOptionality::Conditional => "conditional",
+        FieldOptionality::BackwardCompatibility => "only for backwards compatibility",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_include_fields_the_target_version_does_not_define() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1\rPID|1||123||Doe|||||||||||||||||||||||||||||||||||X",
+        )
+        .unwrap();
+        let changes = version_changes(&message, "2.3", "2.5.1");
+        assert!(changes.contains(&VersionChange {
+            path: "PID.40".to_string(),
+            description: "PID.40 isn't defined in HL7 2.5.1".to_string(),
+        }));
+        // nothing else changes within a version
+        assert_eq!(
+            version_changes(&message, "2.5.1", "2.5.1")
+                .into_iter()
+                .map(|change| change.path)
+                .collect::<Vec<_>>(),
+            vec!["PID.40"]
+        );
+    }
+}
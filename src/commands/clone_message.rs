@@ -0,0 +1,240 @@
+use super::{generate_control_id::new_control_id, CommandResult};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{utils::file_path, Opts, TimeZone};
+use hl7_parser::{
+    datetime::TimeStamp, parse_message_with_lenient_newlines, query::LocationQueryResult, Message,
+};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{
+    CreateFile, CreateFileOptions, DocumentChangeOperation, DocumentChanges, ExecuteCommandParams,
+    OneOf, OptionalVersionedTextDocumentIdentifier, Range, ResourceOp, TextDocumentEdit, TextEdit,
+    Uri, WorkspaceEdit,
+};
+use std::ops::Range as StdRange;
+use tracing::instrument;
+
+/// How many `-copy-N` names to try before giving up on finding a free one
+const MAX_COPIES: usize = 100;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_clone_message_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 2 {
+        return Err(eyre!("Expected 1 or 2 arguments for clone message command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let regenerate_identifiers = match params.arguments.get(1) {
+        Some(arg) => arg
+            .as_bool()
+            .wrap_err("Expected boolean as second argument")?,
+        None => false,
+    };
+
+    if uri
+        .scheme()
+        .is_some_and(|scheme| scheme.as_str() == "untitled")
+    {
+        return Err(eyre!(
+            "Can't clone an untitled document as there's nowhere to put the copy, save it first"
+        ));
+    }
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let parse_span = tracing::trace_span!("parse message");
+    let _parse_span_guard = parse_span.enter();
+    let message = parse_message_with_lenient_newlines(text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    drop(_parse_span_guard);
+
+    let identifiers: &[String] = if regenerate_identifiers {
+        &opts.clone_identifiers
+    } else {
+        &[]
+    };
+    let clone = clone_message(&message, identifiers, opts.output_timezone);
+
+    let clone_uri = copy_uri(&uri, documents, "copy")?;
+    tracing::debug!(?clone_uri, "cloning message");
+
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Clone message",
+        edit: WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: clone_uri.clone(),
+                    options: Some(CreateFileOptions {
+                        overwrite: Some(false),
+                        ignore_if_exists: Some(false),
+                    }),
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: clone_uri,
+                        version: None,
+                    },
+                    edits: vec![OneOf::Left(TextEdit {
+                        range: Range::default(),
+                        new_text: clone,
+                    })],
+                }),
+            ])),
+            change_annotations: None,
+        },
+    }))
+}
+
+/// Copy the message with a new control ID and timestamp (in the given
+/// timezone), regenerating the values of the given identifier fields (e.g.
+/// `PID.3` or `PV1.19.1`)
+fn clone_message(message: &Message, identifiers: &[String], timezone: TimeZone) -> String {
+    let mut replacements: Vec<(StdRange<usize>, String)> = Vec::new();
+
+    if let Some(control_id) = message.query("MSH.10") {
+        replacements.push((control_id.range(), new_control_id()));
+    }
+    if let Some(timestamp) = message.query("MSH.7") {
+        let now: TimeStamp = timezone.now().into();
+        replacements.push((timestamp.range(), now.to_string()));
+    }
+
+    for identifier in identifiers {
+        let ranges = match message.query(identifier) {
+            // regenerate the ID itself, keeping e.g. the assigning authority
+            Some(LocationQueryResult::Field(field)) => field
+                .repeats()
+                .filter_map(|repeat| repeat.component(1).map(|c| c.range.clone()))
+                .collect(),
+            Some(LocationQueryResult::Repeat(repeat)) => repeat
+                .component(1)
+                .map(|c| c.range.clone())
+                .into_iter()
+                .collect(),
+            Some(LocationQueryResult::Component(component)) => vec![component.range.clone()],
+            Some(LocationQueryResult::Subcomponent(sub_component)) => {
+                vec![sub_component.range.clone()]
+            }
+            Some(LocationQueryResult::Segment(_)) => {
+                tracing::warn!(identifier, "can't regenerate a whole segment");
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        replacements.extend(
+            ranges
+                .into_iter()
+                .filter(|range| !range.is_empty())
+                .map(|range| {
+                    let value = regenerate_identifier(&message.raw_value()[range.clone()]);
+                    (range, value)
+                }),
+        );
+    }
+
+    // apply from the back so that earlier ranges stay valid, skipping any
+    // identifiers that overlap something that was already replaced
+    replacements.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut clone = message.raw_value().to_string();
+    let mut replaced_from = usize::MAX;
+    for (range, value) in replacements {
+        if range.end > replaced_from {
+            continue;
+        }
+        replaced_from = range.start;
+        clone.replace_range(range, &value);
+    }
+    clone
+}
+
+/// A random identifier shaped like the existing one, so that it still passes
+/// any length or format checks the original did
+fn regenerate_identifier(existing: &str) -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    let mut rng = rand::thread_rng();
+    let length = existing.chars().count();
+    if existing.chars().all(|c| c.is_ascii_digit()) {
+        (0..length)
+            .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+            .collect()
+    } else {
+        (0..length)
+            .map(|_| char::from(rng.sample(Alphanumeric)))
+            .collect()
+    }
+}
+
+/// A URI next to the original for a copy, e.g. `message-copy.hl7` for
+/// `message.hl7` given the suffix `copy`, that isn't already open or on disk
+pub(super) fn copy_uri(uri: &Uri, documents: &TextDocuments, suffix: &str) -> Result<Uri> {
+    let original = uri.as_str();
+    let name_start = original.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let extension_start = original[name_start..]
+        .rfind('.')
+        .filter(|&i| i > 0)
+        .map(|i| name_start + i)
+        .unwrap_or(original.len());
+    let (stem, extension) = original.split_at(extension_start);
+
+    for copy in 1..=MAX_COPIES {
+        let numbered = if copy == 1 {
+            format!("-{suffix}")
+        } else {
+            format!("-{suffix}-{copy}")
+        };
+        let candidate: Uri = format!("{stem}{numbered}{extension}")
+            .parse()
+            .wrap_err("Failed to build uri for the copy")?;
+        let is_open = documents.get_document(&candidate).is_some();
+        let is_on_disk = file_path(&candidate).is_some_and(|path| path.exists());
+        if !is_open && !is_on_disk {
+            return Ok(candidate);
+        }
+    }
+    Err(eyre!("Too many copies of {original} already exist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_get_new_identifiers() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|CONTROL|P|2.5.1\rPID|1||12345^^^Hosp^MR~ABC^^^Gov^SS||Doe^John",
+        )
+        .unwrap();
+
+        let clone = clone_message(&message, &[], TimeZone::Utc);
+        let clone = parse_message_with_lenient_newlines(&clone).unwrap();
+        assert_ne!(clone.query("MSH.10").unwrap().raw_value(), "CONTROL");
+        assert_ne!(clone.query("MSH.7").unwrap().raw_value(), "20240102030405");
+        assert_eq!(
+            clone.query("PID.3").unwrap().raw_value(),
+            "12345^^^Hosp^MR~ABC^^^Gov^SS"
+        );
+
+        let clone = clone_message(&message, &["PID.3".to_string()], TimeZone::Utc);
+        let clone = parse_message_with_lenient_newlines(&clone).unwrap();
+        let mrn = clone.query("PID.3[1].1").unwrap().raw_value();
+        assert_eq!(mrn.len(), 5);
+        assert!(mrn.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(clone.query("PID.3[1].4").unwrap().raw_value(), "Hosp");
+        assert_eq!(clone.query("PID.3[2].1").unwrap().raw_value().len(), 3);
+        assert_eq!(clone.query("PID.5").unwrap().raw_value(), "Doe^John");
+    }
+}
@@ -0,0 +1,132 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::message_at,
+    utils::{clamp_offset, position_to_offset},
+    Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, Uri};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_copy_path_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for copy path command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let position: Position = serde_json::from_value(params.arguments[1].clone())
+        .wrap_err("Expected position as second argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err("Invalid position")?;
+    let document_message = message_at(text, offset, opts.position_encoding);
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+
+    // e.g. `PID.3[2].4.1`, as the query paths of interface engines are written
+    let offset = clamp_offset(
+        document_message.text,
+        offset.saturating_sub(document_message.range.start),
+    );
+    let path = message
+        .locate_cursor(offset)
+        .map(|location| location.to_string())
+        .filter(|path| !path.is_empty())
+        .wrap_err("Nothing to copy the path of at the position")?;
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: serde_json::Value::String(path),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hl7_ls::utils::PositionEncoding;
+    use lsp_types::notification::Notification;
+
+    const TEXT: &str = "MSH|^~\\&|App\nPID|1||a^b\n\nMSH|^~\\&|App\nPID|1||𝄞𝄞^x~y";
+
+    fn copy_path(text: &str, position: Position, encoding: PositionEncoding) -> Result<String> {
+        let mut documents = TextDocuments::new();
+        documents.listen(
+            lsp_types::notification::DidOpenTextDocument::METHOD,
+            &serde_json::json!({
+                "textDocument": {
+                    "uri": "file:///message.hl7",
+                    "languageId": "hl7",
+                    "version": 1,
+                    "text": text,
+                },
+            }),
+        );
+        let opts = Opts {
+            position_encoding: encoding,
+            ..Default::default()
+        };
+        let params = ExecuteCommandParams {
+            command: super::super::CMD_COPY_PATH.to_string(),
+            arguments: vec![
+                serde_json::json!("file:///message.hl7"),
+                serde_json::json!(position),
+            ],
+            work_done_progress_params: Default::default(),
+        };
+        match handle_copy_path_command(params, &documents, &opts)? {
+            Some(CommandResult::ValueResponse {
+                value: serde_json::Value::String(path),
+            }) => Ok(path),
+            _ => Err(eyre!("Expected the path as a value")),
+        }
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_message_at_the_position() {
+        assert_eq!(
+            copy_path(TEXT, Position::new(1, 10), PositionEncoding::Utf16).unwrap(),
+            "PID.3.2.1"
+        );
+        // past the non-BMP characters, which are 2 UTF-16 code units or 4
+        // UTF-8 bytes each
+        assert_eq!(
+            copy_path(TEXT, Position::new(4, 14), PositionEncoding::Utf16).unwrap(),
+            "PID.3[2].1.1"
+        );
+        assert_eq!(
+            copy_path(TEXT, Position::new(4, 18), PositionEncoding::Utf8).unwrap(),
+            "PID.3[2].1.1"
+        );
+        assert_eq!(
+            copy_path(TEXT, Position::new(3, 0), PositionEncoding::Utf16).unwrap(),
+            "MSH"
+        );
+    }
+
+    #[test]
+    fn paths_cant_be_copied_without_a_message() {
+        assert!(copy_path("PID|1||a^b", Position::new(0, 8), PositionEncoding::Utf16).is_err());
+        assert!(copy_path(TEXT, Position::new(9, 0), PositionEncoding::Utf16).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use super::{
+    explain_selection::{explain, to_markdown},
+    CommandResult,
+};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::message_at, utils::position_to_offset, workspace::specs::WorkspaceSpecs, Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, Uri};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_describe_message_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 3 {
+        return Err(eyre!(
+            "Expected 1 to 3 arguments for describe message command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    // the message to describe, if the document has several
+    let position: Option<Position> = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as second argument")?,
+        ),
+    };
+    let as_json = match params.arguments.get(2) {
+        None | Some(serde_json::Value::Null) => false,
+        Some(format) => match format.as_str() {
+            Some("markdown") => false,
+            Some("json") => true,
+            _ => {
+                return Err(eyre!(
+                    "Expected format as third argument, `markdown` or `json`"
+                ))
+            }
+        },
+    };
+
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    let version = opts
+        .message_version(&uri, &message, workspace_specs)
+        .version;
+
+    // a selection of the whole message explains all of it
+    let explanation = explain(&message, version, &(0..document_message.text.len()));
+    let value = match as_json {
+        true => serde_json::to_value(&explanation).wrap_err("Failed to serialize description")?,
+        false => serde_json::Value::String(to_markdown(&explanation, true)),
+    };
+    Ok(Some(CommandResult::ValueResponse { value }))
+}
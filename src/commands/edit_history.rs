@@ -0,0 +1,33 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::workspace::Workspace;
+use lsp_types::{ExecuteCommandParams, Uri};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(workspace))]
+pub fn handle_edit_history_command(
+    params: ExecuteCommandParams,
+    workspace: Option<&Workspace>,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() > 1 {
+        return Err(eyre!("Expected 0 or 1 arguments for edit history command"));
+    }
+
+    let uri: Option<Uri> = match params.arguments.first() {
+        None | Some(serde_json::Value::Null) => None,
+        Some(uri) => Some(
+            uri.as_str()
+                .and_then(|s| s.parse().ok())
+                .wrap_err("Expected uri as first argument")?,
+        ),
+    };
+
+    let workspace = workspace.wrap_err("No workspace folders are open")?;
+    let records = workspace.history.records(uri.as_ref());
+    Ok(Some(CommandResult::ValueResponse {
+        value: serde_json::to_value(records).wrap_err("Failed to serialize edit history")?,
+    }))
+}
@@ -1,6 +1,11 @@
 use std::collections::HashMap;
 
-use crate::utils::lsp_range_to_std_range;
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    messages::message_at,
+    utils::{lsp_range_to_std_range, slice_text},
+    Opts,
+};
 
 use super::CommandResult;
 use color_eyre::{eyre::ContextCompat, Result};
@@ -9,10 +14,11 @@ use lsp_textdocument::TextDocuments;
 use lsp_types::{ExecuteCommandParams, Range, TextEdit, Uri, WorkspaceEdit};
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(documents))]
+#[instrument(level = "debug", skip(documents, opts))]
 pub fn handle_encode_selection_command(
     params: ExecuteCommandParams,
     documents: &TextDocuments,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
     assert_eq!(
         params.arguments.len(),
@@ -34,18 +40,21 @@ pub fn handle_encode_selection_command(
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
 
+    let Some(std_range) = lsp_range_to_std_range(text, range, opts.position_encoding) else {
+        return Err(color_eyre::eyre::eyre!("Invalid range"));
+    };
+
+    // the separators of the message the selection is in, if there are several
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
-    let separators = parse_message_with_lenient_newlines(text)
-        .ok()
-        .map(|message| message.separators)
-        .unwrap_or_default();
+    let (separators, truncation) = parse_message_with_lenient_newlines(
+        message_at(text, std_range.start, opts.position_encoding).text,
+    )
+    .ok()
+    .map(|message| (message.separators, truncation_character(&message)))
+    .unwrap_or_default();
     drop(_parse_span_guard);
-
-    let Some(std_range) = lsp_range_to_std_range(text, range) else {
-        return Err(color_eyre::eyre::eyre!("Invalid range"));
-    };
-    let encoded = separators.encode(&text[std_range.clone()]).to_string();
+    let encoded = escapes::encode(slice_text(text, std_range)?, &separators, truncation);
 
     let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
     changes.insert(
@@ -66,10 +75,11 @@ pub fn handle_encode_selection_command(
     }))
 }
 
-#[instrument(level = "debug", skip(documents))]
+#[instrument(level = "debug", skip(documents, opts))]
 pub fn handle_decode_selection_command(
     params: ExecuteCommandParams,
     documents: &TextDocuments,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
     assert_eq!(
         params.arguments.len(),
@@ -91,18 +101,21 @@ pub fn handle_decode_selection_command(
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
 
+    let Some(std_range) = lsp_range_to_std_range(text, range, opts.position_encoding) else {
+        return Err(color_eyre::eyre::eyre!("Invalid range"));
+    };
+
+    // the separators of the message the selection is in, if there are several
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
-    let separators = parse_message_with_lenient_newlines(text)
-        .ok()
-        .map(|message| message.separators)
-        .unwrap_or_default();
+    let (separators, truncation) = parse_message_with_lenient_newlines(
+        message_at(text, std_range.start, opts.position_encoding).text,
+    )
+    .ok()
+    .map(|message| (message.separators, truncation_character(&message)))
+    .unwrap_or_default();
     drop(_parse_span_guard);
-
-    let Some(std_range) = lsp_range_to_std_range(text, range) else {
-        return Err(color_eyre::eyre::eyre!("Invalid range"));
-    };
-    let encoded = separators.decode(&text[std_range.clone()]).to_string();
+    let encoded = escapes::decode(slice_text(text, std_range)?, &separators, truncation);
 
     let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
     changes.insert(
@@ -1,5 +1,6 @@
 use super::CommandResult;
 use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::escapes::{self, truncation_character};
 use hl7_parser::parse_message_with_lenient_newlines;
 use lsp_textdocument::TextDocuments;
 use lsp_types::{ExecuteCommandParams, Uri};
@@ -24,13 +25,13 @@ pub fn handle_encode_text_command(
         .arguments
         .get(1)
         .and_then(|v| v.as_str().map(|s| s.parse().ok()).flatten());
-    let separators = uri
+    let (separators, truncation) = uri
         .and_then(|uri| documents.get_document_content(&uri, None))
         .and_then(|text| parse_message_with_lenient_newlines(text).ok())
-        .map(|message| message.separators.clone())
+        .map(|message| (message.separators.clone(), truncation_character(&message)))
         .unwrap_or_default();
 
-    let encoded = separators.encode(text).to_string();
+    let encoded = escapes::encode(text, &separators, truncation);
 
     Ok(Some(CommandResult::ValueResponse {
         value: serde_json::Value::String(encoded),
@@ -56,13 +57,13 @@ pub fn handle_decode_text_command(
         .arguments
         .get(1)
         .and_then(|v| v.as_str().map(|s| s.parse().ok()).flatten());
-    let separators = uri
+    let (separators, truncation) = uri
         .and_then(|uri| documents.get_document_content(&uri, None))
         .and_then(|text| parse_message_with_lenient_newlines(text).ok())
-        .map(|message| message.separators.clone())
+        .map(|message| (message.separators.clone(), truncation_character(&message)))
         .unwrap_or_default();
 
-    let decoded = separators.decode(text).to_string();
+    let decoded = escapes::decode(text, &separators, truncation);
     Ok(Some(CommandResult::ValueResponse {
         value: serde_json::Value::String(decoded),
     }))
@@ -0,0 +1,275 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{spec, utils::lsp_range_to_std_range, workspace::specs::WorkspaceSpecs, Opts};
+use hl7_parser::{message::Separators, parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Range, Uri};
+use serde::Serialize;
+use std::ops::Range as StdRange;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_explain_selection_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for explain selection command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let range: Range = params.arguments[1]
+        .as_object()
+        .and_then(|obj| serde_json::from_value(serde_json::Value::Object(obj.clone())).ok())
+        .wrap_err("Expected range as second argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let parse_span = tracing::trace_span!("parse message");
+    let _parse_span_guard = parse_span.enter();
+    let message = parse_message_with_lenient_newlines(text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    drop(_parse_span_guard);
+
+    let selection =
+        lsp_range_to_std_range(text, range, opts.position_encoding).wrap_err("Invalid range")?;
+    let version = opts
+        .message_version(&uri, &message, workspace_specs)
+        .version;
+
+    let explain_span = tracing::trace_span!("explain selection");
+    let _explain_span_guard = explain_span.enter();
+    let explanation = explain(&message, version, &selection);
+    drop(_explain_span_guard);
+
+    if explanation.is_empty() {
+        return Err(eyre!("Nothing to explain in the selection"));
+    }
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: serde_json::Value::String(to_markdown(&explanation, false)),
+    }))
+}
+
+/// A segment of a message, and the populated fields in it that were explained
+#[derive(Debug, Serialize)]
+pub(super) struct ExplainedSegment {
+    /// The segment's name, and which of the segments with that name it is if
+    /// there are several, e.g. `OBX[2]`
+    pub segment: String,
+    pub description: String,
+    pub fields: Vec<ExplainedValue>,
+}
+
+/// A repeat of a field, or a component of one, and what it means
+#[derive(Debug, Serialize)]
+pub(super) struct ExplainedValue {
+    pub path: String,
+    pub name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datatype: Option<&'static str>,
+    pub value: String,
+    /// The description of the value in the table it comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meaning: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<ExplainedValue>,
+}
+
+/// Explain each segment, field, and component in the selected range of the
+/// message with its name, datatype, decoded value, and the meaning of the value
+/// if it comes from a table
+pub(super) fn explain(
+    message: &Message,
+    version: &str,
+    selection: &StdRange<usize>,
+) -> Vec<ExplainedSegment> {
+    let mut explanation = Vec::new();
+    let mut segment_counts = std::collections::HashMap::new();
+    for segment in message.segments() {
+        let count = segment_counts.entry(segment.name).or_insert(0);
+        *count += 1;
+        if !overlaps(selection, &segment.range) {
+            continue;
+        }
+
+        let occurrence = if message.segment_count(segment.name) > 1 {
+            format!("[{count}]")
+        } else {
+            "".to_string()
+        };
+        let mut fields = Vec::new();
+        for (fi, field) in segment.fields().enumerate() {
+            let fi = fi + 1;
+            if field.is_empty() || !overlaps(selection, &field.range) {
+                continue;
+            }
+            let field_def = hl7_definitions::get_segment(version, segment.name)
+                .and_then(|s| s.fields.get(fi - 1));
+            let field_name = field_def.map(|f| f.description).unwrap_or("Unknown field");
+
+            // the encoding characters would be mangled by decoding them
+            let is_encoding_field = segment.name == "MSH" && fi <= 2;
+
+            for (ri, repeat) in field.repeats().enumerate() {
+                if repeat.is_empty() || !overlaps(selection, &repeat.range) {
+                    continue;
+                }
+                let path = if field.has_repeats() {
+                    format!(
+                        "{segment}.{fi}[{repeat}]",
+                        segment = segment.name,
+                        repeat = ri + 1
+                    )
+                } else {
+                    format!("{segment}.{fi}", segment = segment.name)
+                };
+                let value = if is_encoding_field {
+                    repeat.raw_value().to_string()
+                } else {
+                    decode(&message.separators, repeat.raw_value())
+                };
+                let meaning = spec::field_table(version, segment.name, fi)
+                    .and_then(|table| spec::table_value_description(table, &value));
+
+                let mut components = Vec::new();
+                if repeat.has_components() && !is_encoding_field {
+                    for (ci, component) in repeat.components().enumerate() {
+                        let ci = ci + 1;
+                        if component.is_empty() || !overlaps(selection, &component.range) {
+                            continue;
+                        }
+                        let component_def = field_def
+                            .and_then(|f| hl7_definitions::get_field(version, f.datatype))
+                            .and_then(|f| f.subfields.get(ci - 1));
+                        let value = decode(&message.separators, component.raw_value());
+                        let meaning = spec::component_table(version, segment.name, fi, ci)
+                            .and_then(|table| spec::table_value_description(table, &value));
+                        components.push(ExplainedValue {
+                            path: format!("{path}.{ci}"),
+                            name: component_def
+                                .map(|c| c.description)
+                                .unwrap_or("Unknown component"),
+                            datatype: component_def.map(|c| c.datatype),
+                            value,
+                            meaning,
+                            components: Vec::new(),
+                        });
+                    }
+                }
+                fields.push(ExplainedValue {
+                    path,
+                    name: field_name,
+                    datatype: field_def.map(|f| f.datatype),
+                    value,
+                    meaning,
+                    components,
+                });
+            }
+        }
+        explanation.push(ExplainedSegment {
+            segment: format!("{}{occurrence}", segment.name),
+            description: spec::segment_description(version, segment.name),
+            fields,
+        });
+    }
+    explanation
+}
+
+/// Render an explanation as Markdown, with a heading for each segment and a
+/// list of its fields and their components
+pub(super) fn to_markdown(explanation: &[ExplainedSegment], with_datatypes: bool) -> String {
+    let mut lines = Vec::new();
+    for segment in explanation {
+        lines.push(format!(
+            "### `{segment}`: {description}",
+            segment = segment.segment,
+            description = segment.description,
+        ));
+        for field in segment.fields.iter() {
+            lines.push(describe(field, with_datatypes, 0));
+            for component in field.components.iter() {
+                lines.push(describe(component, with_datatypes, 1));
+            }
+        }
+        lines.push("".to_string());
+    }
+    lines.join("\n").trim_end().to_string()
+}
+
+/// Whether a part of the message is (at least partially) selected; an empty
+/// selection is treated as a cursor, selecting whatever it is in
+fn overlaps(selection: &StdRange<usize>, range: &StdRange<usize>) -> bool {
+    if selection.is_empty() {
+        range.start <= selection.start && selection.start <= range.end
+    } else {
+        selection.start < range.end && range.start < selection.end
+    }
+}
+
+fn decode(separators: &Separators, value: &str) -> String {
+    separators.decode(value).to_string()
+}
+
+fn describe(explained: &ExplainedValue, with_datatype: bool, depth: usize) -> String {
+    let indent = "  ".repeat(depth);
+    let ExplainedValue {
+        path, name, value, ..
+    } = explained;
+    let name = match explained.datatype {
+        Some(datatype) if with_datatype => format!("{name} ({datatype})"),
+        _ => name.to_string(),
+    };
+    let value = value.replace('`', "'");
+    match explained.meaning {
+        Some(meaning) => format!("{indent}- `{path}` {name}: `{value}` ({meaning})"),
+        None => format!("{indent}- `{path}` {name}: `{value}`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_tell_what_is_selected() {
+        assert!(overlaps(&(2..5), &(4..8)));
+        assert!(!overlaps(&(2..4), &(4..8)));
+        assert!(overlaps(&(4..4), &(4..8)));
+        assert!(overlaps(&(8..8), &(4..8)));
+        assert!(!overlaps(&(9..9), &(4..8)));
+    }
+
+    #[test]
+    fn whole_messages_can_be_explained() {
+        let text = "MSH|^~\\&|App\rPID|1||123^^^Hosp||Doe^Jane||19800102|F";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let explanation = explain(&message, "2.5.1", &(0..text.len()));
+
+        assert_eq!(explanation.len(), 2);
+        let pid = &explanation[1];
+        assert_eq!(
+            pid.fields
+                .iter()
+                .map(|field| field.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["PID.1", "PID.3", "PID.5", "PID.7", "PID.8"]
+        );
+        assert_eq!(pid.fields[2].datatype, Some("XPN"));
+        assert_eq!(pid.fields[2].components[1].value, "Jane");
+        assert!(
+            to_markdown(&explanation, true).contains("- `PID.5` Patient Name (XPN): `Doe^Jane`")
+        );
+    }
+}
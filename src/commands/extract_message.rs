@@ -0,0 +1,156 @@
+use super::{clone_message::copy_uri, CommandResult};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::split_messages,
+    utils::{position_to_offset, range_from_offsets, PositionEncoding},
+    Opts,
+};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{
+    CreateFile, CreateFileOptions, DocumentChangeOperation, DocumentChanges, ExecuteCommandParams,
+    OneOf, OptionalVersionedTextDocumentIdentifier, Position, Range, ResourceOp, TextDocumentEdit,
+    TextEdit, Uri, WorkspaceEdit,
+};
+use std::ops::Range as StdRange;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_extract_message_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for extract message command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+    let position: Position = serde_json::from_value(params.arguments[1].clone())
+        .wrap_err("Expected position as second argument")?;
+
+    if uri
+        .scheme()
+        .is_some_and(|scheme| scheme.as_str() == "untitled")
+    {
+        return Err(eyre!(
+            "Can't extract from an untitled document as there's nowhere to put the message, save it first"
+        ));
+    }
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err("Invalid position")?;
+    let (message, removed) = extract_message(text, offset, opts.position_encoding)
+        .wrap_err("The document has no other messages to extract the message from")?;
+
+    let extracted_uri = copy_uri(&uri, documents, "extracted")?;
+    tracing::debug!(?extracted_uri, "extracting message");
+
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Extract message to new file",
+        edit: WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: extracted_uri.clone(),
+                    options: Some(CreateFileOptions {
+                        overwrite: Some(false),
+                        ignore_if_exists: Some(false),
+                    }),
+                    annotation_id: None,
+                })),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: extracted_uri,
+                        version: None,
+                    },
+                    edits: vec![OneOf::Left(TextEdit {
+                        range: Range::default(),
+                        new_text: message.to_string(),
+                    })],
+                }),
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                    edits: vec![OneOf::Left(TextEdit {
+                        range: range_from_offsets(
+                            text,
+                            removed.start,
+                            removed.end,
+                            opts.position_encoding,
+                        ),
+                        new_text: String::new(),
+                    })],
+                }),
+            ])),
+            change_annotations: None,
+        },
+    }))
+}
+
+/// The message at an offset in a document with several messages, and the
+/// range to remove from the document to take it out, along with the lines
+/// that separate it from the next message (or from the previous one, if it's
+/// the last)
+///
+/// Returns `None` if the document only has the one message.
+fn extract_message(
+    text: &str,
+    offset: usize,
+    encoding: PositionEncoding,
+) -> Option<(&str, StdRange<usize>)> {
+    let messages = split_messages(text, encoding);
+    if messages.len() < 2 {
+        return None;
+    }
+    let index = messages
+        .iter()
+        .rposition(|message| message.range.start <= offset)
+        .unwrap_or_default();
+    let message = &messages[index];
+    let removed = match (messages.get(index + 1), index.checked_sub(1)) {
+        (Some(next), _) => message.range.start..next.range.start,
+        (None, Some(previous)) => messages[previous].range.end..message.range.end,
+        (None, None) => return None,
+    };
+    Some((message.text, removed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_extracted_with_their_separating_lines() {
+        let text = "MSH|^~\\&|A\nPID|1\n\nMSH|^~\\&|B\n\nMSH|^~\\&|C\n";
+        let extract = |message: &str| {
+            let (message, removed) =
+                extract_message(text, text.find(message).unwrap(), PositionEncoding::Utf16)
+                    .unwrap();
+            (message, &text[removed])
+        };
+
+        assert_eq!(
+            extract("MSH|^~\\&|A"),
+            ("MSH|^~\\&|A\nPID|1", "MSH|^~\\&|A\nPID|1\n\n")
+        );
+        assert_eq!(extract("MSH|^~\\&|B"), ("MSH|^~\\&|B", "MSH|^~\\&|B\n\n"));
+        assert_eq!(extract("MSH|^~\\&|C"), ("MSH|^~\\&|C", "\n\nMSH|^~\\&|C"));
+        assert_eq!(
+            extract_message("MSH|^~\\&|A", 0, PositionEncoding::Utf16),
+            None
+        );
+    }
+}
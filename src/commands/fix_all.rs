@@ -0,0 +1,303 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::split_messages,
+    spec,
+    utils::{file_uri, line_ranges, range_from_offsets, trim_trailing_separators},
+    validation::renumber_set_ids,
+    workspace::{hl7_files, specs::WorkspaceSpecs, Workspace},
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{
+    AnnotatedTextEdit, ChangeAnnotation, DocumentChanges, ExecuteCommandParams, OneOf,
+    OptionalVersionedTextDocumentIdentifier, TextDocumentEdit, TextEdit, Uri, WorkspaceEdit,
+};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+/// The annotation every edit is made under, which asks the client to have the
+/// user review the edits before they are applied
+const ANNOTATION_ID: &str = "hl7.fixAllInWorkspace";
+
+#[instrument(level = "debug", skip(documents, workspace, opts))]
+pub fn handle_fix_all_in_workspace_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace: Option<&Workspace>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if !params.arguments.is_empty() {
+        return Err(eyre!(
+            "Expected no arguments for fix all in workspace command"
+        ));
+    }
+    let workspace = workspace.wrap_err("No workspace folders are open")?;
+
+    let mut edits = Vec::new();
+    for path in hl7_files(&workspace.folders()) {
+        let uri = match file_uri(&path) {
+            Ok(uri) => uri,
+            Err(e) => {
+                tracing::warn!(?path, "Skipping file: {e:#}");
+                continue;
+            }
+        };
+        // open documents may have changes that haven't been saved yet
+        let (text, version) = match documents.get_document(&uri) {
+            Some(document) => (
+                document.get_content(None).to_string(),
+                Some(document.version()),
+            ),
+            None => match std::fs::read_to_string(&path) {
+                Ok(text) => (text, None),
+                Err(e) => {
+                    tracing::warn!(?path, "Skipping file that can't be read: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let Some(fixed) = fix_document(&uri, &text, Some(&workspace.specs), opts) else {
+            tracing::debug!(?path, "Skipping file that can't be parsed");
+            continue;
+        };
+        if fixed == text {
+            continue;
+        }
+        edits.push(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier { uri, version },
+            edits: vec![OneOf::Right(AnnotatedTextEdit {
+                text_edit: TextEdit {
+                    range: range_from_offsets(&text, 0, text.len(), opts.position_encoding),
+                    new_text: fixed,
+                },
+                annotation_id: ANNOTATION_ID.to_string(),
+            })],
+        });
+    }
+    tracing::debug!(files = edits.len(), "fixing files");
+
+    let annotation = ChangeAnnotation {
+        label: "Fix all in workspace".to_string(),
+        needs_confirmation: Some(true),
+        description: Some(format!(
+            "Fix table value casing, Set IDs, segment terminators, and trailing separators in {} files",
+            edits.len()
+        )),
+    };
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Fix all in workspace",
+        edit: WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Edits(edits)),
+            change_annotations: Some(HashMap::from([(ANNOTATION_ID.to_string(), annotation)])),
+        },
+    }))
+}
+
+/// Apply every fix that can't change what a document's messages mean,
+/// returning `None` if none of them can be parsed
+///
+/// Each message is fixed on its own, leaving any that can't be parsed and the
+/// lines between messages (e.g. batch headers) as they are. Segments are
+/// separated by the configured terminator (or the first one in the document,
+/// if line endings are preserved).
+fn fix_document(
+    uri: &Uri,
+    text: &str,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Option<String> {
+    let terminator = opts.segment_terminator.as_str().unwrap_or_else(|| {
+        match text.find(['\r', '\n']).map(|i| &text[i..]) {
+            Some(rest) if rest.starts_with("\r\n") => "\r\n",
+            Some(rest) if rest.starts_with('\n') => "\n",
+            _ => "\r",
+        }
+    });
+
+    let mut messages = split_messages(text, opts.position_encoding)
+        .into_iter()
+        .peekable();
+    let mut segments = Vec::new();
+    let mut parsed_any = false;
+    let mut lines = line_ranges(text);
+    while let Some(line) = lines.next() {
+        let Some(message) = messages.next_if(|message| message.range.start == line.start) else {
+            segments.push(text[line].to_string());
+            continue;
+        };
+        // the message's first line is the one just read
+        let rest = line_ranges(message.text).count() - 1;
+        lines.by_ref().take(rest).for_each(drop);
+
+        match fix_message(uri, message.text, workspace_specs, opts) {
+            Some(fixed) => {
+                parsed_any = true;
+                segments.extend(fixed);
+            }
+            None => segments
+                .extend(line_ranges(message.text).map(|line| message.text[line].to_string())),
+        }
+    }
+    parsed_any.then(|| segments.join(terminator))
+}
+
+/// Apply every fix that can't change what the message means, returning its
+/// segments or `None` if it can't be parsed
+///
+/// Set IDs are renumbered from 1, table values that only differ from a value
+/// in their table by case are replaced with it, and trailing separators are
+/// trimmed.
+fn fix_message(
+    uri: &Uri,
+    text: &str,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Option<Vec<String>> {
+    let message = parse_message_with_lenient_newlines(text).ok()?;
+    let version = opts.message_version(uri, &message, workspace_specs).version;
+
+    let mut replacements = renumber_set_ids(&message);
+    replacements.extend(table_value_casing(
+        uri,
+        &message,
+        version,
+        workspace_specs,
+        opts,
+    ));
+    replacements.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut fixed = text.to_string();
+    let mut replaced_from = usize::MAX;
+    for (range, value) in replacements {
+        if range.end > replaced_from {
+            continue;
+        }
+        replaced_from = range.start;
+        fixed.replace_range(range, &value);
+    }
+
+    Some(
+        line_ranges(&fixed)
+            .map(|line| trim_trailing_separators(&fixed[line], &message.separators).to_string())
+            .collect(),
+    )
+}
+
+/// Replace table values that only differ from a single value in their table
+/// by case with the value from the table
+fn table_value_casing(
+    uri: &Uri,
+    message: &Message,
+    version: &str,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Vec<(Range<usize>, String)> {
+    let mut replacements = Vec::new();
+    for segment in message.segments() {
+        for (fi, field) in segment.fields().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+
+            let workspace_table_values = workspace_specs
+                .map(|specs| specs.table_values(uri, segment.name, fi + 1))
+                .unwrap_or_default();
+            let table_values: Vec<String> = if !workspace_table_values.is_empty() {
+                workspace_table_values.into_iter().map(|v| v.0).collect()
+            } else if opts.disable_std_table_validations {
+                continue;
+            } else {
+                spec::field_table_values(version, segment.name, fi + 1)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|v| v.0)
+                    .collect()
+            };
+
+            for repeat in field.repeats() {
+                let value = repeat.raw_value();
+                if table_values.iter().any(|v| v == value) {
+                    continue;
+                }
+                let mut matches = table_values
+                    .iter()
+                    .filter(|v| v.eq_ignore_ascii_case(value));
+                if let (Some(canonical), None) = (matches.next(), matches.next()) {
+                    replacements.push((repeat.range.clone(), canonical.clone()));
+                }
+            }
+        }
+    }
+    replacements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hl7_ls::SegmentTerminator;
+
+    #[test]
+    fn safe_fixes_are_applied() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let opts = Opts {
+            segment_terminator: SegmentTerminator::Cr,
+            ..Default::default()
+        };
+        let text = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|1|P|2.5.1|||\r\n\
+                    PID|1||123||Doe^John||19700101|f\n\
+                    OBR|1\r\n\
+                    OBX|2|st|||\r\n\
+                    OBX|2|NM\r\n";
+
+        assert_eq!(
+            fix_document(&uri, text, None, &opts).unwrap(),
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|1|P|2.5.1\r\
+             PID|1||123||Doe^John||19700101|F\r\
+             OBR|1\r\
+             OBX|1|ST\r\
+             OBX|2|NM\r"
+        );
+
+        // already tidy messages are left alone
+        let tidy = "MSH|^~\\&|App\rPID|1";
+        assert_eq!(fix_document(&uri, tidy, None, &opts).unwrap(), tidy);
+    }
+
+    #[test]
+    fn each_message_is_fixed_on_its_own() {
+        let uri: Uri = "file:///messages.hl7".parse().unwrap();
+        let opts = Opts::default();
+        let text = "BHS|^~\\&|App||||20240102\n\
+                    MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|1|P|2.5.1\n\
+                    OBX|1|st\n\
+                    OBX|3|ST||\n\
+                    MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|2|P|2.5.1\n\
+                    OBX|2|NM\n\
+                    BTS|2|||\n";
+
+        assert_eq!(
+            fix_document(&uri, text, None, &opts).unwrap(),
+            "BHS|^~\\&|App||||20240102\n\
+             MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|1|P|2.5.1\n\
+             OBX|1|ST\n\
+             OBX|2|ST\n\
+             MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|2|P|2.5.1\n\
+             OBX|1|NM\n\
+             BTS|2|||\n"
+        );
+
+        // messages without a header can't be parsed, so are left alone
+        let broken = "MSH|^~\\&|App\nOBX|2\n\nEVN|1\nOBX|2";
+        assert_eq!(
+            fix_document(&uri, broken, None, &opts).unwrap(),
+            "MSH|^~\\&|App\nOBX|1\n\nEVN|1\nOBX|2"
+        );
+        assert!(fix_document(&uri, "PID|1", None, &opts).is_none());
+    }
+}
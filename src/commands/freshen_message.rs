@@ -0,0 +1,170 @@
+use super::{generate_control_id::new_control_id, CommandResult};
+use color_eyre::{
+    eyre::{Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::message_at,
+    utils::{position_to_offset, std_range_to_lsp_range, trim_edited_segment},
+    Opts, TimeZone,
+};
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_freshen_message_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 3 {
+        return Err(color_eyre::eyre::eyre!(
+            "Expected 1 to 3 arguments for freshen message command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    // the message to freshen, if the document has several
+    let position: Option<Position> = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as second argument")?,
+        ),
+    };
+    let bump_sequence_number = match params.arguments.get(2) {
+        Some(arg) => arg
+            .as_bool()
+            .wrap_err("Expected boolean as third argument")?,
+        None => false,
+    };
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+
+    let parse_span = tracing::trace_span!("parse message");
+    let _parse_span_guard = parse_span.enter();
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    drop(_parse_span_guard);
+
+    let Some((range, header)) =
+        freshen_header(&message, bump_sequence_number, opts.output_timezone)
+    else {
+        return Ok(None);
+    };
+    let range =
+        range.start + document_message.range.start..range.end + document_message.range.start;
+    let mut edit = TextEdit {
+        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+        new_text: header,
+    };
+    if opts.trim_trailing_separators {
+        edit = trim_edited_segment(text, edit, &message.separators, opts.position_encoding);
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, vec![edit]);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Freshen message",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// The message header with a new control ID (MSH.10), the current time (in
+/// the given timezone) as its timestamp (MSH.7), and, if asked for, the next
+/// sequence number (MSH.13), along with the range of the header it replaces
+///
+/// Fields that aren't in the header are left out rather than added, as are
+/// sequence numbers that aren't numbers.
+fn freshen_header(
+    message: &Message,
+    bump_sequence_number: bool,
+    timezone: TimeZone,
+) -> Option<(Range<usize>, String)> {
+    let header = message.segment("MSH")?;
+    let mut replacements: Vec<(Range<usize>, String)> = Vec::new();
+
+    if let Some(timestamp) = message.query("MSH.7") {
+        let now: TimeStamp = timezone.now().into();
+        replacements.push((timestamp.range(), now.to_string()));
+    }
+    if let Some(control_id) = message.query("MSH.10") {
+        replacements.push((control_id.range(), new_control_id()));
+    }
+    if bump_sequence_number {
+        let next = message.query("MSH.13").and_then(|sequence_number| {
+            let next = sequence_number
+                .raw_value()
+                .parse::<u64>()
+                .ok()?
+                .checked_add(1)?;
+            Some((sequence_number.range(), next.to_string()))
+        });
+        replacements.extend(next);
+    }
+    if replacements.is_empty() {
+        return None;
+    }
+
+    // apply from the back so that earlier ranges stay valid
+    replacements.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut freshened = header.raw_value().to_string();
+    for (range, value) in replacements {
+        let range = range.start - header.range.start..range.end - header.range.start;
+        freshened.replace_range(range, &value);
+    }
+    Some((header.range.clone(), freshened))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freshened_messages_get_a_new_control_id_and_timestamp() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|CONTROL|P|2.5.1|41\rPID|1||12345",
+        )
+        .unwrap();
+
+        let (range, header) = freshen_header(&message, true, TimeZone::Utc).unwrap();
+        assert_eq!(range, message.segment("MSH").unwrap().range);
+        let freshened = parse_message_with_lenient_newlines(&header).unwrap();
+        assert_ne!(freshened.query("MSH.10").unwrap().raw_value(), "CONTROL");
+        assert_ne!(
+            freshened.query("MSH.7").unwrap().raw_value(),
+            "20240102030405"
+        );
+        assert_eq!(freshened.query("MSH.9").unwrap().raw_value(), "ADT^A01");
+        assert_eq!(freshened.query("MSH.13").unwrap().raw_value(), "42");
+
+        let (_, header) = freshen_header(&message, false, TimeZone::Utc).unwrap();
+        let freshened = parse_message_with_lenient_newlines(&header).unwrap();
+        assert_eq!(freshened.query("MSH.13").unwrap().raw_value(), "41");
+    }
+}
@@ -0,0 +1,283 @@
+use super::{
+    set_value::{set_value, ValuePath},
+    CommandResult,
+};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    validation::canonical_order,
+    Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_types::ExecuteCommandParams;
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(opts))]
+pub fn handle_from_json_command(
+    params: ExecuteCommandParams,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!("Expected 1 argument for from JSON command"));
+    }
+
+    let messages = match &params.arguments[0] {
+        // the output of `hl7.toJson`
+        Value::Array(messages) => messages
+            .iter()
+            .map(|message| {
+                message
+                    .as_object()
+                    .wrap_err("Expected each message to be an object")
+                    .and_then(message_from_json)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Value::Object(message) if message.contains_key("segments") => {
+            vec![message_from_json(message)?]
+        }
+        Value::Object(values) => vec![message_from_paths(values)?],
+        _ => {
+            return Err(eyre!(
+                "Expected a message or messages as JSON as first argument"
+            ))
+        }
+    };
+
+    let terminator = opts.segment_terminator.as_str().unwrap_or("\r");
+    Ok(Some(CommandResult::ValueResponse {
+        value: Value::String(messages.join("\r").replace('\r', terminator)),
+    }))
+}
+
+/// Build a message from the JSON `hl7.toJson` gives for one, keeping the
+/// order of its segments
+fn message_from_json(message: &Map<String, Value>) -> Result<String> {
+    let segments = message
+        .get("segments")
+        .and_then(Value::as_array)
+        .wrap_err("Expected the message to have an array of segments")?;
+
+    let mut header = None;
+    let mut values = Vec::new();
+    let mut occurrences: Vec<(&str, usize)> = Vec::new();
+    for segment in segments {
+        let name = segment
+            .get("segment")
+            .and_then(Value::as_str)
+            .wrap_err("Expected each segment to have a name")?;
+        let occurrence = match occurrences.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, count)) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                occurrences.push((name, 1));
+                1
+            }
+        };
+        let fields = segment
+            .get("fields")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for field in fields {
+            let number = field
+                .get("field")
+                .and_then(Value::as_u64)
+                .wrap_err_with(|| format!("Expected each field of {name} to be numbered"))?
+                as usize;
+            if name == "MSH" && number <= 2 {
+                let value = field
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let (field_separator, encoding) = header.get_or_insert(('|', "^~\\&"));
+                match number {
+                    1 => *field_separator = value.chars().next().unwrap_or('|'),
+                    _ => *encoding = value,
+                }
+                continue;
+            }
+            let repeats = field
+                .get("repeats")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            for (ri, repeat) in repeats.iter().enumerate() {
+                let path = |component: Option<&Value>, sub_component: Option<&Value>| {
+                    let index = |part: Option<&Value>, key: &str| {
+                        part.map(|part| {
+                            part.get(key)
+                                .and_then(Value::as_u64)
+                                .map(|n| n as usize)
+                                .wrap_err_with(|| format!("Expected each {key} to be numbered"))
+                        })
+                        .transpose()
+                    };
+                    Ok::<_, color_eyre::Report>(ValuePath {
+                        segment: name.to_string(),
+                        occurrence,
+                        field: number,
+                        repeat: ri + 1,
+                        component: index(component, "component")?,
+                        sub_component: index(sub_component, "subcomponent")?,
+                    })
+                };
+                let Some(components) = repeat.get("components").and_then(Value::as_array) else {
+                    values.push((path(None, None)?, text_value(repeat.get("value"))?));
+                    continue;
+                };
+                for component in components {
+                    let Some(sub_components) =
+                        component.get("subcomponents").and_then(Value::as_array)
+                    else {
+                        let value = text_value(component.get("value"))?;
+                        values.push((path(Some(component), None)?, value));
+                        continue;
+                    };
+                    for sub_component in sub_components {
+                        let value = text_value(sub_component.get("value"))?;
+                        values.push((path(Some(component), Some(sub_component))?, value));
+                    }
+                }
+            }
+        }
+    }
+
+    let (field_separator, encoding) = header.unwrap_or(('|', "^~\\&"));
+    build_message(field_separator, encoding, &values)
+}
+
+/// Build a message from a map of paths (e.g. `PID.5.1` or `OBX[2].5`) to
+/// values, putting its segments in the order of its message structure
+fn message_from_paths(paths: &Map<String, Value>) -> Result<String> {
+    let mut field_separator = '|';
+    let mut encoding = "^~\\&";
+    let mut values = Vec::new();
+    for (path, value) in paths {
+        match path.trim() {
+            "MSH.1" => {
+                field_separator = value
+                    .as_str()
+                    .and_then(|value| value.chars().next())
+                    .wrap_err("Expected MSH.1 to be the field separator")?;
+            }
+            "MSH.2" => {
+                encoding = value
+                    .as_str()
+                    .wrap_err("Expected MSH.2 to be the encoding characters")?;
+            }
+            _ => values.push((ValuePath::parse(path)?, text_value(Some(value))?)),
+        }
+    }
+
+    let text = build_message(field_separator, encoding, &values)?;
+    let message =
+        parse_message_with_lenient_newlines(&text).wrap_err("Failed to parse built message")?;
+    let Some(order) = canonical_order(&message) else {
+        return Ok(text);
+    };
+    let segments = message.segments().collect::<Vec<_>>();
+    Ok(order
+        .into_iter()
+        .map(|si| segments[si].raw_value())
+        .collect::<Vec<_>>()
+        .join("\r"))
+}
+
+/// The text of a value in the JSON, which may also be given as a number
+fn text_value(value: Option<&Value>) -> Result<String> {
+    match value {
+        None | Some(Value::Null) => Ok(String::new()),
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(Value::Number(value)) => Ok(value.to_string()),
+        Some(value) => Err(eyre!("Expected a string value, not `{value}`")),
+    }
+}
+
+/// Build a message from its separators and the (decoded) values at paths in
+/// it, adding segments as they're needed
+fn build_message(
+    field_separator: char,
+    encoding: &str,
+    values: &[(ValuePath, String)],
+) -> Result<String> {
+    let mut text = format!("MSH{field_separator}{encoding}");
+    for (path, value) in values {
+        let parse = |text: &str| -> Result<usize> {
+            let message =
+                parse_message_with_lenient_newlines(text).wrap_err("Invalid separators")?;
+            Ok(message
+                .segments()
+                .filter(|segment| segment.name == path.segment)
+                .count())
+        };
+        for _ in parse(&text)?..path.occurrence {
+            text.push('\r');
+            text.push_str(&path.segment);
+        }
+
+        let message = parse_message_with_lenient_newlines(&text).wrap_err("Invalid separators")?;
+        let encoded = escapes::encode(value, &message.separators, truncation_character(&message));
+        let (range, new_text) = set_value(&message, path, &encoded)?;
+        drop(message);
+        text.replace_range(range, &new_text);
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn messages_are_built_from_paths_and_values() {
+        let paths = json!({
+            "PID.5.1": "O'Brien & Sons",
+            "PID.3[2].1": 42,
+            "MSH.9.1": "ADT",
+            "MSH.9.2": "A01",
+            "OBX[2].5": "b",
+            "OBX.5": "a",
+            "EVN.1": "A01",
+        });
+        let text = message_from_paths(paths.as_object().unwrap()).unwrap();
+        assert_eq!(
+            text.split('\r').collect::<Vec<_>>(),
+            vec![
+                "MSH|^~\\&|||||||ADT^A01",
+                "EVN|A01",
+                "PID|||~42||O'Brien \\T\\ Sons",
+                "OBX|||||a",
+                "OBX|||||b",
+            ]
+        );
+
+        let message = json!({
+            "segments": [
+                { "segment": "MSH", "fields": [
+                    { "field": 1, "value": "|" },
+                    { "field": 2, "value": "^~\\&" },
+                    { "field": 3, "repeats": [{ "value": "App" }] },
+                ]},
+                { "segment": "PID", "fields": [
+                    { "field": 3, "repeats": [{ "components": [
+                        { "component": 1, "value": "123" },
+                        { "component": 4, "subcomponents": [
+                            { "subcomponent": 2, "value": "1.2.3" },
+                        ]},
+                    ]}]},
+                ]},
+            ]
+        });
+        assert_eq!(
+            message_from_json(message.as_object().unwrap()).unwrap(),
+            "MSH|^~\\&|App\rPID|||123^^^&1.2.3"
+        );
+    }
+}
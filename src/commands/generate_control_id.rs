@@ -1,25 +1,30 @@
 use super::CommandResult;
-use crate::utils::std_range_to_lsp_range;
 use color_eyre::{
     eyre::{Context, ContextCompat},
     Result,
 };
+use hl7_ls::{
+    messages::message_at,
+    utils::{position_to_offset, std_range_to_lsp_range, trim_edited_segment},
+    Opts,
+};
 use hl7_parser::parse_message_with_lenient_newlines;
 use lsp_textdocument::TextDocuments;
-use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
 use std::collections::HashMap;
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(documents))]
+#[instrument(level = "debug", skip(documents, opts))]
 pub fn handle_generate_control_id_command(
     params: ExecuteCommandParams,
     documents: &TextDocuments,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
-    assert_eq!(
-        params.arguments.len(),
-        1,
-        "Expected 1 argument for generate control id command"
-    );
+    if params.arguments.is_empty() || params.arguments.len() > 2 {
+        return Err(color_eyre::eyre::eyre!(
+            "Expected 1 or 2 arguments for generate control id command"
+        ));
+    }
 
     let uri: Uri = params.arguments[0]
         .as_str()
@@ -30,26 +35,46 @@ pub fn handle_generate_control_id_command(
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
 
+    // the message to update, if the document has several
+    let position: Option<Position> = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as second argument")?,
+        ),
+    };
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
-    let message = parse_message_with_lenient_newlines(text)
+    let message = parse_message_with_lenient_newlines(document_message.text)
         .wrap_err_with(|| "Failed to parse HL7 message")?;
     drop(_parse_span_guard);
 
     let changes = message.query("MSH.10").map(|existing_control_id| {
-        use rand::distributions::{Alphanumeric, DistString};
-        let new_control_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 20);
-
         let range = existing_control_id.range();
+        let range =
+            range.start + document_message.range.start..range.end + document_message.range.start;
+        let mut edit = TextEdit {
+            range: std_range_to_lsp_range(text, range, opts.position_encoding),
+            new_text: new_control_id(),
+        };
+        if opts.trim_trailing_separators {
+            edit = trim_edited_segment(text, edit, &message.separators, opts.position_encoding);
+        }
         #[allow(clippy::mutable_key_type)]
         let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
-        changes.insert(
-            uri.clone(),
-            vec![TextEdit {
-                range: std_range_to_lsp_range(message.raw_value(), range),
-                new_text: new_control_id,
-            }],
-        );
+        changes.insert(uri.clone(), vec![edit]);
         changes
     });
 
@@ -62,3 +87,9 @@ pub fn handle_generate_control_id_command(
         },
     }))
 }
+
+/// A random 20-character alphanumeric control ID
+pub fn new_control_id() -> String {
+    use rand::distributions::{Alphanumeric, DistString};
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 20)
+}
@@ -0,0 +1,148 @@
+use super::{generate_control_id::new_control_id, CommandResult};
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use hl7_definitions::FieldOptionality;
+use hl7_ls::{spec, validation::required_segments, Opts};
+use hl7_parser::datetime::TimeStamp;
+use lsp_types::ExecuteCommandParams;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(opts))]
+pub fn handle_generate_sample_command(
+    params: ExecuteCommandParams,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for generate sample command"));
+    }
+
+    let version = params.arguments[0]
+        .as_str()
+        .wrap_err("Expected version as first argument")?;
+    let message_type = params.arguments[1]
+        .as_str()
+        .wrap_err("Expected message type as second argument")?;
+
+    let now: TimeStamp = opts.output_timezone.now().into();
+    let segments = generate_sample(version, message_type, &now.to_string())?;
+    let terminator = opts.segment_terminator.as_str().unwrap_or("\r");
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: serde_json::Value::String(segments.join(terminator)),
+    }))
+}
+
+/// The segments of a skeleton message of a type (e.g. `ADT^A04`): each
+/// segment its structure requires, with their required fields filled in with
+/// made up but plausible values, and timestamps set to `now`
+fn generate_sample(version: &str, message_type: &str, now: &str) -> Result<Vec<String>> {
+    if hl7_definitions::get_definition(version).is_none() {
+        return Err(eyre!("Unknown HL7 version `{version}`"));
+    }
+    let (code, event) = message_type.split_once('^').wrap_err_with(|| {
+        format!("Invalid message type `{message_type}`, expected e.g. `ADT^A04`")
+    })?;
+    let (structure, names) = required_segments(code, event)
+        .wrap_err_with(|| format!("Unknown message structure for `{message_type}`"))?;
+
+    let mut segments = vec![format!(
+        "MSH|^~\\&|SendingApp|SendingFacility|ReceivingApp|ReceivingFacility|{now}||{code}^{event}^{structure}|{control_id}|P|{version}",
+        control_id = new_control_id()
+    )];
+    for name in names.into_iter().filter(|name| *name != "MSH") {
+        let Some(definition) = hl7_definitions::get_segment(version, name) else {
+            segments.push(name.to_string());
+            continue;
+        };
+        let last_required = definition
+            .fields
+            .iter()
+            .rposition(|field| field.optionality == FieldOptionality::Required)
+            .map_or(0, |i| i + 1);
+        let mut segment = name.to_string();
+        for (fi, field) in definition.fields[..last_required].iter().enumerate() {
+            segment.push('|');
+            if field.optionality == FieldOptionality::Required {
+                segment.push_str(&sample_value(
+                    version,
+                    (name, fi + 1),
+                    field.datatype,
+                    event,
+                    now,
+                ));
+            }
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// A made up value for a required field that looks like a real one
+fn sample_value(
+    version: &str,
+    (segment, field): (&str, usize),
+    datatype: &str,
+    event: &str,
+    now: &str,
+) -> String {
+    if (segment, field) == ("EVN", 1) {
+        return event.to_string();
+    }
+    if spec::is_field_a_timestamp(version, segment, field) {
+        return now.to_string();
+    }
+    let table_value = spec::field_table(version, segment, field)
+        .and_then(hl7_definitions::table_values)
+        .and_then(|values| values.first())
+        .map(|(value, _)| *value);
+    if let Some(value) = table_value {
+        return value.to_string();
+    }
+    let value = match datatype {
+        "SI" | "NM" => "1",
+        "DT" => &now[..now.len().min(8)],
+        "CX" => "123456^^^Hospital^MR",
+        "XPN" => "Doe^Jane",
+        "XCN" => "1234^Smith^John",
+        "XAD" => "123 Main St^^Springfield^IL^62701",
+        "XTN" => "^PRN^PH^^^555^5551234",
+        "PL" => "Ward^101^1",
+        "CE" | "CNE" | "CWE" => "CODE^Sample code^L",
+        "EI" => "123456",
+        _ => "Sample",
+    };
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_have_the_required_segments_and_fields() {
+        let segments = generate_sample("2.5.1", "ADT^A04", "20240102030405").unwrap();
+        let text = segments.join("\r");
+        let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+
+        assert_eq!(
+            message
+                .segments()
+                .map(|segment| segment.name)
+                .collect::<Vec<_>>(),
+            vec!["MSH", "EVN", "PID", "PV1"]
+        );
+        assert_eq!(
+            message.query("MSH.9").unwrap().raw_value(),
+            "ADT^A04^ADT_A01"
+        );
+        assert_eq!(message.query("MSH.12").unwrap().raw_value(), "2.5.1");
+        assert_eq!(
+            message.query("EVN.2").unwrap().raw_value(),
+            "20240102030405"
+        );
+        assert_eq!(message.query("PID.5").unwrap().raw_value(), "Doe^Jane");
+        assert!(generate_sample("2.5.1", "ZZZ^Z01", "").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    utils::{position_from_offset, position_to_offset},
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, Uri};
+use serde_json::json;
+use tracing::instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Next,
+    Previous,
+}
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_goto_field_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    direction: Direction,
+    populated_only: bool,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for go to field command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let position: Position = serde_json::from_value(params.arguments[1].clone())
+        .wrap_err("Expected position as second argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    let message = parse_message_with_lenient_newlines(text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err("Invalid position")?;
+
+    // there's nowhere to go from the first or last field, which clients can
+    // treat as a no-op
+    let value = match find_field(&message, offset, direction, populated_only) {
+        Some((path, start)) => json!({
+            "path": path,
+            "position": position_from_offset(text, start, opts.position_encoding),
+        }),
+        None => serde_json::Value::Null,
+    };
+    Ok(Some(CommandResult::ValueResponse { value }))
+}
+
+/// The path and start of the field after (or before) the offset, optionally
+/// skipping empty fields
+///
+/// Moving backwards from the middle of a field goes to the start of it first,
+/// the same as moving by words does in most editors.
+fn find_field(
+    message: &Message,
+    offset: usize,
+    direction: Direction,
+    populated_only: bool,
+) -> Option<(String, usize)> {
+    let mut fields = message.segments().flat_map(|segment| {
+        segment
+            .fields()
+            .enumerate()
+            .filter(move |(_, field)| !populated_only || !field.is_empty())
+            .map(move |(fi, field)| (format!("{}.{}", segment.name, fi + 1), field.range.start))
+    });
+    match direction {
+        Direction::Next => fields.find(|(_, start)| *start > offset),
+        Direction::Previous => fields.take_while(|(_, start)| *start < offset).last(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_are_found_in_either_direction() {
+        let text = "MSH|^~\\&|App\rPID|1||123||Doe^John\rPV1";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let find = |offset, direction, populated_only| {
+            find_field(&message, offset, direction, populated_only).map(|(path, _)| path)
+        };
+
+        let pid_1 = text.find("1||").unwrap();
+        assert_eq!(
+            find(pid_1, Direction::Next, false).as_deref(),
+            Some("PID.2")
+        );
+        assert_eq!(find(pid_1, Direction::Next, true).as_deref(), Some("PID.3"));
+        assert_eq!(
+            find(pid_1, Direction::Previous, true).as_deref(),
+            Some("MSH.3")
+        );
+
+        let john = text.find("John").unwrap();
+        assert_eq!(
+            find(john, Direction::Previous, false).as_deref(),
+            Some("PID.5")
+        );
+        assert_eq!(find(john, Direction::Next, true), None);
+    }
+}
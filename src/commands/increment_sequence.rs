@@ -0,0 +1,149 @@
+use super::{
+    set_value::{set_value, ValuePath},
+    CommandResult,
+};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::message_at,
+    utils::{position_to_offset, std_range_to_lsp_range},
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_increment_sequence_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 2 {
+        return Err(eyre!(
+            "Expected 1 or 2 arguments for increment sequence command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    // the message to update, if the document has several
+    let position: Option<Position> = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as second argument")?,
+        ),
+    };
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+
+    let (range, new_text) = increment_sequence(&message)?;
+    let range =
+        range.start + document_message.range.start..range.end + document_message.range.start;
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Increment sequence number",
+        edit: sequence_edit(text, uri, range, new_text, opts),
+    }))
+}
+
+/// The edit that gives the message the next sequence number (MSH.13), which
+/// starts at 1 if the message doesn't have one yet
+pub(super) fn increment_sequence(message: &Message) -> Result<(Range<usize>, String)> {
+    let current = message
+        .query("MSH.13")
+        .map(|sequence_number| sequence_number.raw_value().trim())
+        .unwrap_or_default();
+    let next = match current {
+        "" => 1,
+        current => current
+            .parse::<u64>()
+            .ok()
+            .and_then(|current| current.checked_add(1))
+            .wrap_err_with(|| format!("The sequence number `{current}` isn't a number"))?,
+    };
+
+    let path = ValuePath {
+        segment: "MSH".to_string(),
+        occurrence: 1,
+        field: 13,
+        repeat: 1,
+        component: None,
+        sub_component: None,
+    };
+    set_value(message, &path, &next.to_string())
+}
+
+/// The edit to the document for an edit to one of its messages
+pub(super) fn sequence_edit(
+    text: &str,
+    uri: Uri,
+    range: Range<usize>,
+    new_text: String,
+    opts: &Opts,
+) -> WorkspaceEdit {
+    let edit = TextEdit {
+        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+        new_text,
+    };
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, vec![edit]);
+    WorkspaceEdit {
+        changes: Some(changes),
+        document_changes: None,
+        change_annotations: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_numbers_are_incremented_or_started() {
+        let incremented = |text: &str| {
+            let message = parse_message_with_lenient_newlines(text).unwrap();
+            let (range, new_text) = increment_sequence(&message)?;
+            let mut text = text.to_string();
+            text.replace_range(range, &new_text);
+            Ok::<_, color_eyre::Report>(text)
+        };
+
+        assert_eq!(
+            incremented("MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1|41\rPID|1").unwrap(),
+            "MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1|42\rPID|1"
+        );
+        assert_eq!(
+            incremented("MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1\rPID|1").unwrap(),
+            "MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1|1\rPID|1"
+        );
+        assert_eq!(
+            incremented("MSH|^~\\&|App").unwrap(),
+            "MSH|^~\\&|App||||||||||1"
+        );
+        assert!(incremented("MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1|abc").is_err());
+    }
+}
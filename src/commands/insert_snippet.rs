@@ -0,0 +1,147 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    utils::{line_ranges, position_to_offset, range_from_offsets},
+    workspace::{
+        snippets::{line_ending, Snippet},
+        Workspace,
+    },
+    Opts,
+};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
+use std::collections::HashMap;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, workspace, opts))]
+pub fn handle_insert_snippet_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace: Option<&Workspace>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() < 2 || params.arguments.len() > 3 {
+        return Err(eyre!(
+            "Expected 2 or 3 arguments for insert snippet command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let name = params.arguments[1]
+        .as_str()
+        .wrap_err("Expected snippet name as second argument")?;
+
+    let position: Option<Position> = match params.arguments.get(2) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as third argument")?,
+        ),
+    };
+
+    let workspace = workspace.wrap_err("No workspace folders are open")?;
+    let snippet = workspace
+        .specs
+        .snippets(&uri)
+        .into_iter()
+        .find(|(snippet, _)| snippet == name)
+        .map(|(_, snippet)| snippet)
+        .wrap_err_with(|| format!("No snippet named `{name}` applies to this document"))?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    let offset = position
+        .map(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+            .wrap_err("Invalid position")
+        })
+        .transpose()?;
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, vec![insert_snippet(text, offset, &snippet, opts)]);
+
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Insert snippet",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// Insert a snippet after the segment at `offset`, or after the last segment
+/// if there's no offset
+fn insert_snippet(text: &str, offset: Option<usize>, snippet: &Snippet, opts: &Opts) -> TextEdit {
+    let line_ending = line_ending(text);
+    let line = match offset {
+        Some(offset) => line_ranges(text).find(|line| line.start <= offset && offset <= line.end),
+        None => line_ranges(text)
+            .filter(|line| !text[line.clone()].trim().is_empty())
+            .last(),
+    };
+
+    let (at, new_text) = match line {
+        Some(line) => {
+            let before = format!("{}{line_ending}", &text[..line.end]);
+            let segments = snippet.adjust(
+                &before,
+                &text[line.end..],
+                line_ending,
+                opts.fallback_version.as_deref(),
+                opts.output_timezone,
+            );
+            (line.end, format!("{line_ending}{segments}"))
+        }
+        None => {
+            let segments = snippet.adjust(
+                "",
+                "",
+                line_ending,
+                opts.fallback_version.as_deref(),
+                opts.output_timezone,
+            );
+            (text.len(), segments)
+        }
+    };
+    TextEdit {
+        range: range_from_offsets(text, at, at, opts.position_encoding),
+        new_text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_are_inserted_after_the_segment() {
+        let text = "MSH|^~\\&|App|Fac|||20240102||ADT^A01|1|P|2.5.1\nPID|1||123\nPV1|1|I";
+        let snippet = Snippet {
+            description: None,
+            segments: "NTE|1||A note".to_string(),
+        };
+        let opts = Opts::default();
+
+        let edit = insert_snippet(text, text.find("PID"), &snippet, &opts);
+        assert_eq!(edit.range.start, Position::new(1, 10));
+        assert_eq!(edit.new_text, "\nNTE|1||A note");
+
+        let edit = insert_snippet(text, None, &snippet, &opts);
+        assert_eq!(edit.range.start, Position::new(2, 7));
+    }
+}
@@ -0,0 +1,48 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::workspace::specs::WorkspaceSpecs;
+use lsp_types::{ExecuteCommandParams, Uri};
+use serde_json::Value;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(workspace_specs))]
+pub fn handle_list_connections_command(
+    params: ExecuteCommandParams,
+    workspace_specs: Option<&WorkspaceSpecs>,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() > 1 {
+        return Err(eyre!(
+            "Expected at most 1 argument for list connections command"
+        ));
+    }
+
+    let uri: Option<Uri> = match params.arguments.first() {
+        None | Some(Value::Null) => None,
+        Some(uri) => Some(
+            uri.as_str()
+                .and_then(|s| s.parse().ok())
+                .wrap_err("Expected uri as first argument")?,
+        ),
+    };
+
+    // hosts and ports are listed as they're written, without interpolating
+    // any environment variables into them
+    let connections = workspace_specs
+        .map(|specs| specs.connections(uri.as_ref()))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, connection)| {
+            let mut json =
+                serde_json::to_value(connection).wrap_err("Failed to serialize connection")?;
+            json["name"] = Value::String(name);
+            Ok(json)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: Value::Array(connections),
+    }))
+}
@@ -0,0 +1,463 @@
+use super::{
+    generate_control_id::new_control_id,
+    send_message::{framed, parse_frame, parse_port, read_framed},
+    sinks::{parse_sinks, Outputs, Sink},
+    CommandResult,
+};
+use crate::{
+    acknowledgements::{acknowledge, acknowledgement_mode, is_acknowledgement},
+    listeners::{Listener, Listeners},
+};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use crossbeam_channel::Sender;
+use hl7_ls::{utils::interpolate_env, workspace::connections::Framing, Opts, TimeZone};
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines};
+use lsp_server::Message;
+use lsp_types::ExecuteCommandParams;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    io::{ErrorKind, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::instrument;
+
+/// Where listeners listen unless told otherwise, so that only this machine
+/// can send them messages
+const DEFAULT_HOST: &str = "127.0.0.1";
+
+/// How often listeners check whether they've been stopped, while waiting for
+/// connections and messages
+pub(super) const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for the rest of a message once it has started, in seconds
+const MESSAGE_TIMEOUT: f64 = 5.0;
+
+/// Custom notification sent to the client with each message a listener
+/// receives, so that it can be shown (e.g. in an untitled document)
+pub enum MessageReceived {}
+
+impl lsp_types::notification::Notification for MessageReceived {
+    type Params = ReceivedMessage;
+    const METHOD: &'static str = "hl7/messageReceived";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    /// The port of the listener that received the message
+    pub port: u16,
+    /// The address of the sender
+    pub peer: String,
+    /// The message, with its segments on separate lines
+    pub message: String,
+    /// The acknowledgements sent back, with their segments on separate lines
+    pub acknowledgements: Vec<String>,
+}
+
+#[instrument(level = "debug", skip(listeners, sender, opts))]
+pub fn handle_start_listener_command(
+    params: ExecuteCommandParams,
+    listeners: &Listeners,
+    sender: &Sender<Message>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 5 {
+        return Err(eyre!(
+            "Expected 1 to 5 arguments for start listener command"
+        ));
+    }
+
+    let port = match &params.arguments[0] {
+        Value::String(port) => parse_port(port).wrap_err("Expected port as first argument")?,
+        port => port
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .wrap_err("Expected port as first argument")?,
+    };
+    let acknowledge = match params.arguments.get(1) {
+        None | Some(Value::Null) => true,
+        Some(arg) => arg
+            .as_bool()
+            .wrap_err("Expected boolean as second argument")?,
+    };
+    let host = match params.arguments.get(2) {
+        None | Some(Value::Null) => DEFAULT_HOST.to_string(),
+        Some(host) => host
+            .as_str()
+            .wrap_err("Expected address as third argument")
+            .and_then(interpolate_env)?,
+    };
+    let frame = match params.arguments.get(3) {
+        None | Some(Value::Null) => Framing::default(),
+        Some(frame) => parse_frame(frame).wrap_err("Expected frame object as fourth argument")?,
+    };
+    let sinks = match params.arguments.get(4) {
+        None | Some(Value::Null) => vec![Sink::Editor],
+        Some(sinks) => parse_sinks(sinks).wrap_err("Expected array of sinks as fifth argument")?,
+    };
+
+    let listener = TcpListener::bind((host.as_str(), port))
+        .wrap_err_with(|| format!("Failed to listen on {host}:{port}"))?;
+    // so that the listener can notice it's been stopped between connections
+    listener
+        .set_nonblocking(true)
+        .wrap_err("Failed to configure listener")?;
+    let address = listener
+        .local_addr()
+        .wrap_err("Failed to read listener address")?;
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let (outputs, forwarders) = Outputs::start(sinks, sender, &stopping, opts.output_timezone)?;
+    let thread = {
+        let stopping = stopping.clone();
+        let outputs = Arc::new(outputs);
+        let timezone = opts.output_timezone;
+        thread::spawn(move || {
+            serve(listener, acknowledge, frame, &stopping, outputs, timezone);
+            for forwarder in forwarders {
+                if forwarder.join().is_err() {
+                    tracing::warn!("Forwarding messages panicked");
+                }
+            }
+        })
+    };
+    listeners.insert(Listener {
+        address,
+        stopping,
+        thread,
+    });
+    tracing::info!(%address, "Listening for messages");
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: json!({
+            "address": address.to_string(),
+            "port": address.port(),
+        }),
+    }))
+}
+
+#[instrument(level = "debug", skip(listeners))]
+pub fn handle_stop_listener_command(
+    params: ExecuteCommandParams,
+    listeners: &Listeners,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() > 1 {
+        return Err(eyre!(
+            "Expected at most 1 argument for stop listener command"
+        ));
+    }
+
+    let port = match params.arguments.first() {
+        None | Some(Value::Null) => None,
+        Some(port) => Some(
+            port.as_u64()
+                .and_then(|port| u16::try_from(port).ok())
+                .wrap_err("Expected port as first argument")?,
+        ),
+    };
+
+    let stopped = listeners.stop(port);
+    if stopped.is_empty() {
+        return Err(match port {
+            Some(port) => eyre!("No listener is running on port {port}"),
+            None => eyre!("No listeners are running"),
+        });
+    }
+    Ok(Some(CommandResult::ValueResponse {
+        value: json!(stopped),
+    }))
+}
+
+/// Accept connections until the listener is stopped, reading from each on its
+/// own thread so that senders that keep their connection open don't hold up
+/// the others
+fn serve(
+    listener: TcpListener,
+    acknowledge: bool,
+    frame: Framing,
+    stopping: &Arc<AtomicBool>,
+    outputs: Arc<Outputs>,
+    timezone: TimeZone,
+) {
+    let port = listener.local_addr().map(|address| address.port());
+    while !stopping.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                tracing::debug!(%peer, "Accepted connection");
+                let stopping = stopping.clone();
+                let outputs = outputs.clone();
+                let frame = frame.clone();
+                thread::spawn(move || {
+                    if let Err(e) =
+                        receive(stream, acknowledge, &frame, &stopping, &outputs, timezone)
+                    {
+                        tracing::warn!(%peer, "Connection closed: {e:#}");
+                    }
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {e}");
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+    tracing::info!(?port, "Stopped listening");
+}
+
+/// Read messages from a connection until it's closed or the listener is
+/// stopped, acknowledging each one and passing it on to the sinks
+fn receive(
+    mut stream: TcpStream,
+    acknowledge: bool,
+    frame: &Framing,
+    stopping: &AtomicBool,
+    outputs: &Outputs,
+    timezone: TimeZone,
+) -> Result<()> {
+    let port = stream
+        .local_addr()
+        .wrap_err("Failed to read address")?
+        .port();
+    let peer = stream.peer_addr().wrap_err("Failed to read peer address")?;
+    stream
+        .set_nonblocking(false)
+        .wrap_err("Failed to configure connection")?;
+
+    loop {
+        // wait for the next message, checking whether to stop in between
+        stream
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .wrap_err("Failed to set read timeout")?;
+        match stream.peek(&mut [0u8; 1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if stopping.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e).wrap_err("Failed to read from connection"),
+        }
+        if stopping.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        stream
+            .set_read_timeout(Some(Duration::from_secs_f64(MESSAGE_TIMEOUT)))
+            .wrap_err("Failed to set read timeout")?;
+        let message = read_framed(&mut stream, frame, MESSAGE_TIMEOUT)?;
+        tracing::debug!(%peer, "Received message");
+
+        let acknowledgements = match acknowledge {
+            true => replies(&message, timezone),
+            false => Vec::new(),
+        };
+        for acknowledgement in &acknowledgements {
+            stream
+                .write_all(framed(acknowledgement, frame).as_bytes())
+                .wrap_err("Failed to send acknowledgement")?;
+        }
+
+        let received = ReceivedMessage {
+            port,
+            peer: peer.to_string(),
+            message: on_separate_lines(&message),
+            acknowledgements: acknowledgements
+                .iter()
+                .map(|acknowledgement| on_separate_lines(acknowledgement))
+                .collect(),
+        };
+        outputs.deliver(&message, &received);
+    }
+}
+
+/// The acknowledgements to reply to a message with, accepting it
+///
+/// In original mode, that's an `AA` application acknowledgement. In enhanced
+/// mode, it's a `CA` commit acknowledgement and then an `AA`, as asked for by
+/// MSH-15 and MSH-16. Acknowledgements are only committed to, and messages
+/// that can't be parsed aren't acknowledged as there's nothing to acknowledge
+/// them with.
+pub(super) fn replies(message: &str, timezone: TimeZone) -> Vec<String> {
+    let message = match parse_message_with_lenient_newlines(message) {
+        Ok(message) => message,
+        Err(e) => {
+            tracing::warn!("Received a message that can't be parsed: {e}");
+            return Vec::new();
+        }
+    };
+    let (commit, application) = match acknowledgement_mode(&message) {
+        Some(mode) => (mode.accept.applies(true), mode.application.applies(true)),
+        None => (false, true),
+    };
+
+    let now: TimeStamp = timezone.now().into();
+    let mut codes = Vec::new();
+    if commit {
+        codes.push("CA");
+    }
+    if application && !is_acknowledgement(&message) {
+        codes.push("AA");
+    }
+    codes
+        .into_iter()
+        .map(|code| acknowledge(&message, code, &new_control_id(), &now.to_string()))
+        .collect()
+}
+
+fn on_separate_lines(message: &str) -> String {
+    message.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::notification::Notification as _;
+    use std::fs;
+
+    #[test]
+    fn received_messages_are_acknowledged_and_passed_on() {
+        let listeners = Listeners::default();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let frame = Framing {
+            start: "<<".to_string(),
+            end: ">>".to_string(),
+        };
+        let params = ExecuteCommandParams {
+            command: super::super::CMD_START_LISTENER.to_string(),
+            arguments: vec![json!(0), json!(true), Value::Null, json!(frame)],
+            work_done_progress_params: Default::default(),
+        };
+        let Some(CommandResult::ValueResponse { value }) =
+            handle_start_listener_command(params, &listeners, &sender, &Opts::default()).unwrap()
+        else {
+            panic!("expected the listener's address");
+        };
+        let port = value["port"].as_u64().unwrap() as u16;
+
+        let mut stream = TcpStream::connect((DEFAULT_HOST, port)).unwrap();
+        let message = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|123|P|2.5.1\rPID|1";
+        stream
+            .write_all(framed(message, &frame).as_bytes())
+            .unwrap();
+        let ack = read_framed(&mut stream, &frame, 5.0).unwrap();
+        assert!(ack.starts_with("MSH|^~\\&|Rcv|RcvFac|App|Fac|"));
+        assert!(ack.contains("||ACK^A01^ACK|"));
+        assert!(ack.ends_with("|P|2.5.1\rMSA|AA|123"));
+
+        let Ok(Message::Notification(notification)) = receiver.recv_timeout(Duration::from_secs(5))
+        else {
+            panic!("expected the message to be passed on");
+        };
+        assert_eq!(notification.method, MessageReceived::METHOD);
+        let received: ReceivedMessage = serde_json::from_value(notification.params).unwrap();
+        assert_eq!(received.port, port);
+        assert!(received.message.ends_with("|2.5.1\nPID|1"));
+        assert_eq!(received.acknowledgements, vec![on_separate_lines(&ack)]);
+
+        // enhanced mode, with a commit acknowledgement before the application
+        // acknowledgement
+        let enhanced =
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|456|P|2.5.1|||AL|AL\rPID|1";
+        stream
+            .write_all(framed(enhanced, &frame).as_bytes())
+            .unwrap();
+        let commit = read_framed(&mut stream, &frame, 5.0).unwrap();
+        assert!(commit.ends_with("\rMSA|CA|456"));
+        let application = read_framed(&mut stream, &frame, 5.0).unwrap();
+        assert!(application.ends_with("\rMSA|AA|456"));
+
+        assert_eq!(listeners.stop(Some(port)), vec![port]);
+        assert!(listeners.stop(None).is_empty());
+    }
+
+    #[test]
+    fn received_messages_are_passed_on_to_sinks() {
+        let listeners = Listeners::default();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let start = |arguments| {
+            let params = ExecuteCommandParams {
+                command: super::super::CMD_START_LISTENER.to_string(),
+                arguments,
+                work_done_progress_params: Default::default(),
+            };
+            let Some(CommandResult::ValueResponse { value }) =
+                handle_start_listener_command(params, &listeners, &sender, &Opts::default())
+                    .unwrap()
+            else {
+                panic!("expected the listener's address");
+            };
+            value["port"].as_u64().unwrap() as u16
+        };
+
+        // the listener messages are forwarded to, which opens them in the
+        // editor
+        let destination = start(vec![json!(0)]);
+        let path = std::env::temp_dir().join(format!("hl7-ls-sink-{}.hl7", std::process::id()));
+        let rolled = path.with_extension("hl7.1");
+        let relay = start(vec![
+            json!(0),
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            json!([
+                { "type": "file", "path": path, "maxSize": 100 },
+                { "type": "forward", "host": DEFAULT_HOST, "port": destination.to_string() },
+                { "type": "discard" },
+            ]),
+        ]);
+
+        let mut stream = TcpStream::connect((DEFAULT_HOST, relay)).unwrap();
+        for control_id in ["123", "456"] {
+            let message = format!(
+                "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|{control_id}|P|2.5.1\rPID|1"
+            );
+            stream
+                .write_all(framed(&message, &Framing::default()).as_bytes())
+                .unwrap();
+            // acknowledged by the relay, before the message is forwarded
+            let ack = read_framed(&mut stream, &Framing::default(), 5.0).unwrap();
+            assert!(ack.ends_with(&format!("\rMSA|AA|{control_id}")));
+        }
+
+        for control_id in ["123", "456"] {
+            let Ok(Message::Notification(notification)) =
+                receiver.recv_timeout(Duration::from_secs(5))
+            else {
+                panic!("expected the message to be forwarded");
+            };
+            let received: ReceivedMessage = serde_json::from_value(notification.params).unwrap();
+            assert_eq!(received.port, destination);
+            assert!(received
+                .message
+                .contains(&format!("|ADT^A01|{control_id}|")));
+        }
+        // only the destination passes messages on to the client
+        assert!(receiver.try_recv().is_err());
+
+        // the second message doesn't fit in the file alongside the first
+        let first = fs::read_to_string(&rolled).unwrap();
+        let second = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&rolled);
+        assert!(first.contains("|ADT^A01|123|P|2.5.1\nPID|1\n"));
+        assert!(second.contains("|ADT^A01|456|P|2.5.1\nPID|1\n"));
+
+        assert_eq!(
+            listeners.stop(None),
+            vec![destination.min(relay), destination.max(relay)]
+        );
+    }
+}
@@ -1,21 +1,741 @@
+#[cfg(feature = "mllp")]
+use crate::listeners::Listeners;
 use color_eyre::Result;
+#[cfg(feature = "mllp")]
+use crossbeam_channel::Sender;
+use goto_field::Direction;
+use hl7_ls::{workspace::Workspace, Opts};
+#[cfg(feature = "mllp")]
+use lsp_server::Message;
 use lsp_textdocument::TextDocuments;
 use lsp_types::{ExecuteCommandParams, WorkspaceEdit};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tracing::instrument;
 
+mod anonymize;
+mod change_version;
+mod clone_message;
+mod copy_path;
+mod describe_message;
+mod edit_history;
 mod encode_decode_selection;
 mod encode_decode_text;
+mod explain_selection;
+mod extract_message;
+mod fix_all;
+mod freshen_message;
+mod from_json;
 mod generate_control_id;
+mod generate_sample;
+mod goto_field;
+mod increment_sequence;
+mod insert_snippet;
+#[cfg(feature = "mllp")]
+mod list_connections;
+#[cfg(feature = "mllp")]
+mod listener;
+mod normalize_delimiters;
+mod query_value;
+mod renumber_set_ids;
+#[cfg(feature = "mllp")]
 mod send_message;
 mod set_to_now;
+mod set_validation_profile;
+mod set_value;
+mod shift_dates;
+#[cfg(feature = "mllp")]
+mod sinks;
+mod split_batch;
+mod to_json;
+mod wrap_in_batch;
 
 pub const CMD_SET_TO_NOW: &str = "hl7.setTimestampToNow";
+pub const CMD_SHIFT_DATES: &str = "hl7.shiftDates";
+#[cfg(feature = "mllp")]
 pub const CMD_SEND_MESSAGE: &str = "hl7.sendMessage";
+#[cfg(feature = "mllp")]
+pub const CMD_LIST_CONNECTIONS: &str = "hl7.listConnections";
+#[cfg(feature = "mllp")]
+pub const CMD_START_LISTENER: &str = "hl7.startListener";
+#[cfg(feature = "mllp")]
+pub const CMD_STOP_LISTENER: &str = "hl7.stopListener";
 pub const CMD_GENERATE_CONTROL_ID: &str = "hl7.generateControlId";
+pub const CMD_GENERATE_SAMPLE: &str = "hl7.generateSample";
+pub const CMD_RENUMBER_SET_IDS: &str = "hl7.renumberSetIds";
 pub const CMD_ENCODE_TEXT: &str = "hl7.encodeText";
 pub const CMD_DECODE_TEXT: &str = "hl7.decodeText";
 pub const CMD_ENCODE_SELECTION: &str = "hl7.encodeSelection";
 pub const CMD_DECODE_SELECTION: &str = "hl7.decodeSelection";
+pub const CMD_EXPLAIN_SELECTION: &str = "hl7.explainSelection";
+pub const CMD_DESCRIBE_MESSAGE: &str = "hl7.describeMessage";
+pub const CMD_COPY_PATH: &str = "hl7.copyPath";
+pub const CMD_TO_JSON: &str = "hl7.toJson";
+pub const CMD_FROM_JSON: &str = "hl7.fromJson";
+pub const CMD_QUERY_VALUE: &str = "hl7.queryValue";
+pub const CMD_SET_VALUE: &str = "hl7.setValue";
+pub const CMD_ANONYMIZE: &str = "hl7.anonymize";
+pub const CMD_CLONE_MESSAGE: &str = "hl7.cloneMessage";
+pub const CMD_EXTRACT_MESSAGE: &str = "hl7.extractMessage";
+pub const CMD_CHANGE_VERSION: &str = "hl7.changeVersion";
+pub const CMD_NORMALIZE_DELIMITERS: &str = "hl7.normalizeDelimiters";
+pub const CMD_FRESHEN_MESSAGE: &str = "hl7.freshenMessage";
+pub const CMD_INCREMENT_SEQUENCE: &str = "hl7.incrementSequence";
+pub const CMD_WRAP_IN_BATCH: &str = "hl7.wrapInBatch";
+pub const CMD_SPLIT_BATCH: &str = "hl7.splitBatch";
+pub const CMD_FIX_ALL_IN_WORKSPACE: &str = "hl7.fixAllInWorkspace";
+pub const CMD_GOTO_NEXT_FIELD: &str = "hl7.gotoNextField";
+pub const CMD_GOTO_PREV_FIELD: &str = "hl7.gotoPrevField";
+pub const CMD_GOTO_NEXT_POPULATED_FIELD: &str = "hl7.gotoNextPopulatedField";
+pub const CMD_GOTO_PREV_POPULATED_FIELD: &str = "hl7.gotoPrevPopulatedField";
+pub const CMD_SET_VALIDATION_PROFILE: &str = "hl7.setValidationProfile";
+pub const CMD_EDIT_HISTORY: &str = "hl7.editHistory";
+pub const CMD_INSERT_SNIPPET: &str = "hl7.insertSnippet";
+
+/// Custom request listing the commands the server supports, along with enough
+/// metadata for generic clients to offer them in a picker
+pub enum ListCommands {}
+
+impl lsp_types::request::Request for ListCommands {
+    type Params = ();
+    type Result = Vec<CommandInfo>;
+    const METHOD: &'static str = "hl7.listCommands";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandInfo {
+    pub id: String,
+    pub title: String,
+    pub category: String,
+    pub arguments: Vec<CommandArgument>,
+    /// Whether the command operates on a document, passed as a `uri` argument
+    pub requires_uri: bool,
+    /// Whether the command operates on a selection, passed as a `range`
+    /// argument
+    pub requires_selection: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandArgument {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the argument's value
+    pub schema: serde_json::Value,
+    pub optional: bool,
+}
+
+impl CommandArgument {
+    fn new(name: &'static str, description: &'static str, schema: serde_json::Value) -> Self {
+        CommandArgument {
+            name: name.to_string(),
+            description: description.to_string(),
+            schema,
+            optional: false,
+        }
+    }
+
+    fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    fn uri(description: &'static str) -> Self {
+        CommandArgument::new(
+            "uri",
+            description,
+            json!({ "type": "string", "format": "uri" }),
+        )
+    }
+
+    fn position(description: &'static str) -> Self {
+        CommandArgument::new(
+            "position",
+            description,
+            json!({
+                "type": "object",
+                "properties": {
+                    "line": { "type": "integer", "minimum": 0 },
+                    "character": { "type": "integer", "minimum": 0 },
+                },
+                "required": ["line", "character"],
+            }),
+        )
+    }
+
+    fn range(description: &'static str) -> Self {
+        let position = json!({
+            "type": "object",
+            "properties": {
+                "line": { "type": "integer", "minimum": 0 },
+                "character": { "type": "integer", "minimum": 0 },
+            },
+            "required": ["line", "character"],
+        });
+        CommandArgument::new(
+            "range",
+            description,
+            json!({
+                "type": "object",
+                "properties": { "start": position, "end": position },
+                "required": ["start", "end"],
+            }),
+        )
+    }
+}
+
+/// All of the commands supported by the server, in the order they should be
+/// presented to users
+pub fn list_commands() -> Vec<CommandInfo> {
+    vec![
+        CommandInfo {
+            id: CMD_SET_TO_NOW.to_string(),
+            title: "Set Timestamp to Now".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::range("The range of the timestamp to update"),
+            ],
+            requires_uri: true,
+            requires_selection: true,
+        },
+        CommandInfo {
+            id: CMD_SHIFT_DATES.to_string(),
+            title: "Shift Dates".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::new(
+                    "offset",
+                    "How far to move the dates, e.g. `+365d`, `-2w`, `1y`, `6mo`, `12h`, or `30min`",
+                    json!({ "type": ["string", "integer"] }),
+                ),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        #[cfg(feature = "mllp")]
+        CommandInfo {
+            id: CMD_SEND_MESSAGE.to_string(),
+            title: "Send Message".to_string(),
+            category: "MLLP".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to send"),
+                CommandArgument::new(
+                    "hostname",
+                    "The hostname of the destination, or the name of a connection",
+                    json!({ "type": "string" }),
+                ),
+                CommandArgument::new(
+                    "port",
+                    "The port of the destination, as a number or a string, or null for a connection",
+                    json!({
+                        "oneOf": [
+                            { "type": "integer", "minimum": 0, "maximum": 65535 },
+                            { "type": "string" },
+                            { "type": "null" },
+                        ],
+                    }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "timeout",
+                    "The timeout in seconds to wait for a response",
+                    json!({ "type": "number", "minimum": 0, "default": 5.0 }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "transform",
+                    "Values to set in the sent message only, by path (e.g. `{ \"MSH.11\": \"T\" }`)",
+                    json!({
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                    }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "incrementSequence",
+                    "Whether to increment the sequence number in MSH.13 of the document after a successful send",
+                    json!({ "type": "boolean", "default": false }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "frame",
+                    "The characters to wrap the message and its response in, if not the connection's or the standard MLLP framing",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "string", "default": "\u{0b}" },
+                            "end": { "type": "string", "default": "\u{1c}\r" },
+                        },
+                    }),
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        #[cfg(feature = "mllp")]
+        CommandInfo {
+            id: CMD_LIST_CONNECTIONS.to_string(),
+            title: "List Connections".to_string(),
+            category: "MLLP".to_string(),
+            arguments: vec![CommandArgument::uri(
+                "The URI of a document, to only list the connections that apply to it",
+            )
+            .optional()],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        #[cfg(feature = "mllp")]
+        CommandInfo {
+            id: CMD_START_LISTENER.to_string(),
+            title: "Start Listener".to_string(),
+            category: "MLLP".to_string(),
+            arguments: vec![
+                CommandArgument::new(
+                    "port",
+                    "The port to listen on, as a number or a string, or 0 for any free port",
+                    json!({
+                        "oneOf": [
+                            { "type": "integer", "minimum": 0, "maximum": 65535 },
+                            { "type": "string" },
+                        ],
+                    }),
+                ),
+                CommandArgument::new(
+                    "acknowledge",
+                    "Whether to acknowledge each message as accepted",
+                    json!({ "type": "boolean", "default": true }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "address",
+                    "The address to listen on",
+                    json!({ "type": "string", "default": "127.0.0.1" }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "frame",
+                    "The characters that wrap each message and acknowledgement, if not the standard MLLP framing",
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "start": { "type": "string", "default": "\u{0b}" },
+                            "end": { "type": "string", "default": "\u{1c}\r" },
+                        },
+                    }),
+                )
+                .optional(),
+                CommandArgument::new(
+                    "sinks",
+                    "Where to pass each message on to once it's been acknowledged",
+                    json!({
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "type": { "enum": ["editor", "file", "forward", "discard"] },
+                            },
+                            "required": ["type"],
+                        },
+                        "default": [{ "type": "editor" }],
+                    }),
+                )
+                .optional(),
+            ],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        #[cfg(feature = "mllp")]
+        CommandInfo {
+            id: CMD_STOP_LISTENER.to_string(),
+            title: "Stop Listener".to_string(),
+            category: "MLLP".to_string(),
+            arguments: vec![CommandArgument::new(
+                "port",
+                "The port of the listener to stop, or null to stop every listener",
+                json!({ "type": ["integer", "null"], "minimum": 0, "maximum": 65535 }),
+            )
+            .optional()],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_GENERATE_CONTROL_ID.to_string(),
+            title: "Generate Control ID".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::position(
+                    "A position in the message to update, for documents with several messages",
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_GENERATE_SAMPLE.to_string(),
+            title: "Generate Sample Message".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::new(
+                    "version",
+                    "The HL7 version of the message, e.g. `2.5.1`",
+                    json!({ "type": "string" }),
+                ),
+                CommandArgument::new(
+                    "messageType",
+                    "The message code and trigger event, e.g. `ADT^A04`",
+                    json!({ "type": "string" }),
+                ),
+            ],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_RENUMBER_SET_IDS.to_string(),
+            title: "Renumber Set IDs".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::position(
+                    "A position in the message to update, for documents with several messages",
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_SET_VALUE.to_string(),
+            title: "Set Value".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::new(
+                    "path",
+                    "The query path of the value, e.g. `PID.5.1` or `OBX[2].5`",
+                    json!({ "type": "string" }),
+                ),
+                CommandArgument::new(
+                    "value",
+                    "The value to set, which is encoded",
+                    json!({ "type": "string" }),
+                ),
+                CommandArgument::position(
+                    "A position in the message to update, for documents with several messages",
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_ANONYMIZE.to_string(),
+            title: "Anonymize".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![CommandArgument::uri("The URI of the document")],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_CLONE_MESSAGE.to_string(),
+            title: "Clone Message".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to clone"),
+                CommandArgument::new(
+                    "regenerateIdentifiers",
+                    "Whether to also regenerate the identifiers given by `--clone-identifier`",
+                    json!({ "type": "boolean", "default": false }),
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_EXTRACT_MESSAGE.to_string(),
+            title: "Extract Message to New File".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to extract the message from"),
+                CommandArgument::position("A position in the message to extract"),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_FRESHEN_MESSAGE.to_string(),
+            title: "Freshen Message".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::position(
+                    "A position in the message to update, for documents with several messages",
+                )
+                .optional(),
+                CommandArgument::new(
+                    "bumpSequenceNumber",
+                    "Whether to also increment the sequence number in MSH.13",
+                    json!({ "type": "boolean", "default": false }),
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_NORMALIZE_DELIMITERS.to_string(),
+            title: "Normalize Delimiters".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![CommandArgument::uri("The URI of the document to update")],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_CHANGE_VERSION.to_string(),
+            title: "Change Message Version".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::new(
+                    "targetVersion",
+                    "The HL7 version to change the messages to, e.g. `2.5.1`",
+                    json!({ "type": "string" }),
+                ),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_INCREMENT_SEQUENCE.to_string(),
+            title: "Increment Sequence Number".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to update"),
+                CommandArgument::position(
+                    "A position in the message to update, for documents with several messages",
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_WRAP_IN_BATCH.to_string(),
+            title: "Wrap in Batch".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![CommandArgument::uri("The URI of the document to wrap")],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_SPLIT_BATCH.to_string(),
+            title: "Split Batch into Files".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![CommandArgument::uri("The URI of the batch to split")],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_INSERT_SNIPPET.to_string(),
+            title: "Insert Snippet".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to insert the snippet into"),
+                CommandArgument::new(
+                    "name",
+                    "The name of the workspace snippet to insert",
+                    json!({ "type": "string" }),
+                ),
+                CommandArgument::position(
+                    "The position of the segment to insert the snippet after, defaulting to the last segment",
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_FIX_ALL_IN_WORKSPACE.to_string(),
+            title: "Fix All in Workspace".to_string(),
+            category: "Edit".to_string(),
+            arguments: Vec::new(),
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_ENCODE_TEXT.to_string(),
+            title: "Encode Text".to_string(),
+            category: "Encoding".to_string(),
+            arguments: vec![
+                CommandArgument::new("text", "The text to encode", json!({ "type": "string" })),
+                CommandArgument::uri("The URI of the document used to encode with").optional(),
+            ],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_DECODE_TEXT.to_string(),
+            title: "Decode Text".to_string(),
+            category: "Encoding".to_string(),
+            arguments: vec![
+                CommandArgument::new("text", "The text to decode", json!({ "type": "string" })),
+                CommandArgument::uri("The URI of the document used to decode with").optional(),
+            ],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_ENCODE_SELECTION.to_string(),
+            title: "Encode Selection".to_string(),
+            category: "Encoding".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document"),
+                CommandArgument::range("The range of the text to encode"),
+            ],
+            requires_uri: true,
+            requires_selection: true,
+        },
+        CommandInfo {
+            id: CMD_DECODE_SELECTION.to_string(),
+            title: "Decode Selection".to_string(),
+            category: "Encoding".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document"),
+                CommandArgument::range("The range of the text to decode"),
+            ],
+            requires_uri: true,
+            requires_selection: true,
+        },
+        CommandInfo {
+            id: CMD_EXPLAIN_SELECTION.to_string(),
+            title: "Explain Selection".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document"),
+                CommandArgument::range("The range of the message to explain"),
+            ],
+            requires_uri: true,
+            requires_selection: true,
+        },
+        CommandInfo {
+            id: CMD_DESCRIBE_MESSAGE.to_string(),
+            title: "Describe Message".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document"),
+                CommandArgument::position("A position in the message to describe").optional(),
+                CommandArgument::new(
+                    "format",
+                    "`markdown` (the default) or `json`",
+                    json!({ "type": "string", "enum": ["markdown", "json"] }),
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_COPY_PATH.to_string(),
+            title: "Copy Path".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document"),
+                CommandArgument::position("The position of the element to get the path of"),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_QUERY_VALUE.to_string(),
+            title: "Query Value".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to query"),
+                CommandArgument::new(
+                    "path",
+                    "The query path of the value, e.g. `PID.5.1` or `OBX[2].5`",
+                    json!({ "type": "string" }),
+                ),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_TO_JSON.to_string(),
+            title: "Convert to JSON".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![CommandArgument::uri("The URI of the document")],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_FROM_JSON.to_string(),
+            title: "Build Message from JSON".to_string(),
+            category: "Edit".to_string(),
+            arguments: vec![CommandArgument::new(
+                "json",
+                "The message(s) as given by `hl7.toJson`, or a map of paths to values",
+                json!({ "type": ["object", "array"] }),
+            )],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_SET_VALIDATION_PROFILE.to_string(),
+            title: "Set Validation Profile".to_string(),
+            category: "Validation".to_string(),
+            arguments: vec![
+                CommandArgument::uri("The URI of the document to validate with the profile"),
+                CommandArgument::new(
+                    "profile",
+                    "The name of the workspace spec to validate with, or null to use the specs in the document's folder",
+                    json!({ "type": ["string", "null"] }),
+                )
+                .optional(),
+            ],
+            requires_uri: true,
+            requires_selection: false,
+        },
+        CommandInfo {
+            id: CMD_EDIT_HISTORY.to_string(),
+            title: "Edit History".to_string(),
+            category: "Inspect".to_string(),
+            arguments: vec![CommandArgument::uri(
+                "Only list the edits made to this document",
+            )
+            .optional()],
+            requires_uri: false,
+            requires_selection: false,
+        },
+        goto_field_command(CMD_GOTO_NEXT_FIELD, "Go to Next Field"),
+        goto_field_command(CMD_GOTO_PREV_FIELD, "Go to Previous Field"),
+        goto_field_command(CMD_GOTO_NEXT_POPULATED_FIELD, "Go to Next Populated Field"),
+        goto_field_command(
+            CMD_GOTO_PREV_POPULATED_FIELD,
+            "Go to Previous Populated Field",
+        ),
+    ]
+}
+
+fn goto_field_command(id: &str, title: &str) -> CommandInfo {
+    CommandInfo {
+        id: id.to_string(),
+        title: title.to_string(),
+        category: "Navigate".to_string(),
+        arguments: vec![
+            CommandArgument::uri("The URI of the document"),
+            CommandArgument::position("The position to move from"),
+        ],
+        requires_uri: true,
+        requires_selection: false,
+    }
+}
 
 pub enum CommandResult {
     WorkspaceEdit {
@@ -25,30 +745,182 @@ pub enum CommandResult {
     ValueResponse {
         value: serde_json::Value,
     },
+    /// A value to respond with, along with an edit to apply afterwards
+    ValueResponseWithEdit {
+        value: serde_json::Value,
+        label: &'static str,
+        edit: WorkspaceEdit,
+    },
 }
 
-#[instrument(level = "debug", skip(params, documents))]
+/// Commands that start and stop MLLP listeners, which outlive the requests
+/// that start them and so are kept by the server, and which pass the messages
+/// they receive on to the client
+#[cfg(feature = "mllp")]
+#[instrument(level = "debug", skip(params, listeners, sender, opts))]
+pub fn handle_listener_command(
+    params: ExecuteCommandParams,
+    listeners: &Listeners,
+    sender: &Sender<Message>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    match params.command.as_str() {
+        CMD_START_LISTENER => {
+            listener::handle_start_listener_command(params, listeners, sender, opts)
+        }
+        CMD_STOP_LISTENER => listener::handle_stop_listener_command(params, listeners),
+        _ => Ok(None),
+    }
+}
+
+#[instrument(level = "debug", skip(params, documents, workspace, opts))]
 pub fn handle_execute_command_request(
     params: ExecuteCommandParams,
     documents: &TextDocuments,
+    workspace: Option<&Workspace>,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
     match params.command.as_str() {
-        CMD_SET_TO_NOW => set_to_now::handle_set_to_now_command(params, documents),
-        CMD_SEND_MESSAGE => send_message::handle_send_message_command(params, documents),
+        CMD_SHIFT_DATES => shift_dates::handle_shift_dates_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_SET_TO_NOW => set_to_now::handle_set_to_now_command(params, documents, opts),
+        #[cfg(feature = "mllp")]
+        CMD_SEND_MESSAGE => send_message::handle_send_message_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        #[cfg(feature = "mllp")]
+        CMD_LIST_CONNECTIONS => list_connections::handle_list_connections_command(
+            params,
+            workspace.map(|workspace| &*workspace.specs),
+        ),
         CMD_GENERATE_CONTROL_ID => {
-            generate_control_id::handle_generate_control_id_command(params, documents)
+            generate_control_id::handle_generate_control_id_command(params, documents, opts)
+        }
+        CMD_GENERATE_SAMPLE => generate_sample::handle_generate_sample_command(params, opts),
+        CMD_RENUMBER_SET_IDS => {
+            renumber_set_ids::handle_renumber_set_ids_command(params, documents, opts)
+        }
+        CMD_SET_VALUE => set_value::handle_set_value_command(params, documents, opts),
+        CMD_ANONYMIZE => anonymize::handle_anonymize_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_CLONE_MESSAGE => clone_message::handle_clone_message_command(params, documents, opts),
+        CMD_EXTRACT_MESSAGE => {
+            extract_message::handle_extract_message_command(params, documents, opts)
+        }
+        CMD_FRESHEN_MESSAGE => {
+            freshen_message::handle_freshen_message_command(params, documents, opts)
+        }
+        CMD_NORMALIZE_DELIMITERS => {
+            normalize_delimiters::handle_normalize_delimiters_command(params, documents, opts)
+        }
+        CMD_CHANGE_VERSION => change_version::handle_change_version_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_INCREMENT_SEQUENCE => {
+            increment_sequence::handle_increment_sequence_command(params, documents, opts)
+        }
+        CMD_SPLIT_BATCH => split_batch::handle_split_batch_command(params, documents, opts),
+        CMD_WRAP_IN_BATCH => wrap_in_batch::handle_wrap_in_batch_command(params, documents, opts),
+        CMD_INSERT_SNIPPET => {
+            insert_snippet::handle_insert_snippet_command(params, documents, workspace, opts)
+        }
+        CMD_FIX_ALL_IN_WORKSPACE => {
+            fix_all::handle_fix_all_in_workspace_command(params, documents, workspace, opts)
         }
         CMD_ENCODE_TEXT => encode_decode_text::handle_encode_text_command(params, documents),
         CMD_DECODE_TEXT => encode_decode_text::handle_decode_text_command(params, documents),
         CMD_ENCODE_SELECTION => {
-            encode_decode_selection::handle_encode_selection_command(params, documents)
+            encode_decode_selection::handle_encode_selection_command(params, documents, opts)
         }
         CMD_DECODE_SELECTION => {
-            encode_decode_selection::handle_decode_selection_command(params, documents)
+            encode_decode_selection::handle_decode_selection_command(params, documents, opts)
+        }
+        CMD_DESCRIBE_MESSAGE => describe_message::handle_describe_message_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_COPY_PATH => copy_path::handle_copy_path_command(params, documents, opts),
+        CMD_QUERY_VALUE => query_value::handle_query_value_command(params, documents, opts),
+        CMD_TO_JSON => to_json::handle_to_json_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_FROM_JSON => from_json::handle_from_json_command(params, opts),
+        CMD_EXPLAIN_SELECTION => explain_selection::handle_explain_selection_command(
+            params,
+            documents,
+            workspace.map(|workspace| &*workspace.specs),
+            opts,
+        ),
+        CMD_SET_VALIDATION_PROFILE => {
+            set_validation_profile::handle_set_validation_profile_command(params, workspace)
+        }
+        CMD_EDIT_HISTORY => edit_history::handle_edit_history_command(params, workspace),
+        CMD_GOTO_NEXT_FIELD => {
+            goto_field::handle_goto_field_command(params, documents, Direction::Next, false, opts)
         }
+        CMD_GOTO_PREV_FIELD => goto_field::handle_goto_field_command(
+            params,
+            documents,
+            Direction::Previous,
+            false,
+            opts,
+        ),
+        CMD_GOTO_NEXT_POPULATED_FIELD => {
+            goto_field::handle_goto_field_command(params, documents, Direction::Next, true, opts)
+        }
+        CMD_GOTO_PREV_POPULATED_FIELD => goto_field::handle_goto_field_command(
+            params,
+            documents,
+            Direction::Previous,
+            true,
+            opts,
+        ),
         _ => {
             tracing::warn!(command = ?params.command, args = ?params.arguments, "Unknown command");
             Ok(None)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_requirements_match_their_arguments() {
+        for command in list_commands() {
+            let required = |name| {
+                command
+                    .arguments
+                    .iter()
+                    .any(|arg| arg.name == name && !arg.optional)
+            };
+            assert_eq!(command.requires_uri, required("uri"), "{}", command.id);
+            assert_eq!(
+                command.requires_selection,
+                required("range"),
+                "{}",
+                command.id
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::truncation_character, messages::split_messages, utils::std_range_to_lsp_range, Opts,
+};
+use hl7_parser::{message::Separators, parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+/// The segments that declare the separators after their name
+const HEADER_SEGMENTS: &[&str] = &["MSH", "FHS", "BHS"];
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_normalize_delimiters_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!(
+            "Expected 1 argument for normalize delimiters command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let mut edits = Vec::new();
+    for document_message in split_messages(text, opts.position_encoding) {
+        let message = parse_message_with_lenient_newlines(document_message.text)
+            .wrap_err_with(|| "Failed to parse HL7 message")?;
+        edits.extend(
+            normalize_delimiters(&message)
+                .into_iter()
+                .map(|(range, new_text)| {
+                    let range = range.start + document_message.range.start
+                        ..range.end + document_message.range.start;
+                    TextEdit {
+                        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+                        new_text,
+                    }
+                }),
+        );
+    }
+    if edits.is_empty() {
+        return Err(eyre!("The document already uses the standard separators"));
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, edits);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Normalize delimiters",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// The edits that rewrite each segment of a message with the standard
+/// separators (`|^~\&`), keeping any truncation character
+///
+/// Escape sequences are kept with the standard escape character, and
+/// characters that were plain text but are standard separators are escaped.
+fn normalize_delimiters(message: &Message) -> Vec<(Range<usize>, String)> {
+    let separators = &message.separators;
+    let standard = Separators::default();
+    let truncation = truncation_character(message)
+        .map(String::from)
+        .unwrap_or_default();
+
+    let mut edits = Vec::new();
+    for segment in message.segments() {
+        let raw = segment.raw_value();
+        let mut normalized = String::with_capacity(raw.len());
+        let mut rest = raw;
+        if HEADER_SEGMENTS.contains(&segment.name) {
+            // the name, field separator, and encoding characters (with any
+            // truncation character)
+            let header_length = raw
+                .char_indices()
+                .skip(4)
+                .find(|&(_, c)| c == separators.field)
+                .map_or(raw.len(), |(i, _)| i);
+            normalized.push_str(&format!(
+                "{name}{field}{component}{repetition}{escape}{subcomponent}{truncation}",
+                name = segment.name,
+                field = standard.field,
+                component = standard.component,
+                repetition = standard.repetition,
+                escape = standard.escape,
+                subcomponent = standard.subcomponent,
+            ));
+            rest = &raw[header_length..];
+        }
+
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            if c == separators.escape {
+                // escape sequences (e.g. `\F\` or `\.br\`) keep what they
+                // stand for, and a lone escape character is a plain one
+                let content = &rest[i + c.len_utf8()..];
+                match content.find(separators.escape) {
+                    Some(end) => {
+                        let sequence = &content[..end];
+                        normalized.push(standard.escape);
+                        normalized.push_str(sequence);
+                        normalized.push(standard.escape);
+                        chars.nth(sequence.chars().count());
+                    }
+                    None => normalized.push_str(&escaped('E', &standard)),
+                }
+            } else if c == separators.field {
+                normalized.push(standard.field);
+            } else if c == separators.component {
+                normalized.push(standard.component);
+            } else if c == separators.repetition {
+                normalized.push(standard.repetition);
+            } else if c == separators.subcomponent {
+                normalized.push(standard.subcomponent);
+            } else if c == standard.field {
+                normalized.push_str(&escaped('F', &standard));
+            } else if c == standard.component {
+                normalized.push_str(&escaped('S', &standard));
+            } else if c == standard.repetition {
+                normalized.push_str(&escaped('R', &standard));
+            } else if c == standard.escape {
+                normalized.push_str(&escaped('E', &standard));
+            } else if c == standard.subcomponent {
+                normalized.push_str(&escaped('T', &standard));
+            } else {
+                normalized.push(c);
+            }
+        }
+
+        if normalized != raw {
+            edits.push((segment.range.clone(), normalized));
+        }
+    }
+    edits
+}
+
+fn escaped(code: char, separators: &Separators) -> String {
+    format!("{escape}{code}{escape}", escape = separators.escape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delimiters_are_normalized_and_values_reencoded() {
+        let text = "MSH*!%$@*App*Fac*****ADT!A01*1*P*2.5.1\rPID*1**123!!!Hosp@1.2.3**O$T$Brien!Mary%Smith!Jo|Ann^2*$.br$*100&~ \\";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let mut normalized = text.to_string();
+        for (range, new_text) in normalize_delimiters(&message).into_iter().rev() {
+            normalized.replace_range(range, &new_text);
+        }
+        assert_eq!(
+            normalized,
+            "MSH|^~\\&|App|Fac|||||ADT^A01|1|P|2.5.1\rPID|1||123^^^Hosp&1.2.3||O\\T\\Brien^Mary~Smith^Jo\\F\\Ann\\S\\2|\\.br\\|100\\T\\\\R\\ \\E\\"
+        );
+
+        let standard =
+            parse_message_with_lenient_newlines("MSH|^~\\&|App\rPID|1||O\\T\\Brien").unwrap();
+        assert!(normalize_delimiters(&standard).is_empty());
+    }
+}
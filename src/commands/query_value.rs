@@ -0,0 +1,87 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    messages::split_messages,
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Uri};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_query_value_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for query value command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+    let path = params.arguments[1]
+        .as_str()
+        .wrap_err("Expected path as second argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let values = split_messages(text, opts.position_encoding)
+        .into_iter()
+        .map(|message| {
+            let message = parse_message_with_lenient_newlines(message.text)
+                .wrap_err_with(|| "Failed to parse HL7 message")?;
+            Ok(query_value(&message, path)
+                .map(serde_json::Value::String)
+                .unwrap_or_default())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: serde_json::Value::Array(values),
+    }))
+}
+
+/// The decoded value at a query path in the message (e.g. `PID.5.1`), if the
+/// message has anything there
+///
+/// The separators of MSH.1 and MSH.2 are given as they are, rather than being
+/// decoded.
+fn query_value(message: &Message, path: &str) -> Option<String> {
+    let value = message.query(path)?.raw_value();
+    let is_encoding_field = matches!(path, "MSH.1" | "MSH.2");
+    Some(match is_encoding_field {
+        true => value.to_string(),
+        false => escapes::decode(value, &message.separators, truncation_character(message)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_decoded() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac\rPID|1||123||O\\T\\Brien^Mary~Smith^Mary",
+        )
+        .unwrap();
+
+        assert_eq!(query_value(&message, "PID.5.1").as_deref(), Some("O&Brien"));
+        assert_eq!(
+            query_value(&message, "PID.5[2]").as_deref(),
+            Some("Smith^Mary")
+        );
+        assert_eq!(query_value(&message, "MSH.2").as_deref(), Some("^~\\&"));
+        assert_eq!(query_value(&message, "OBR.7"), None);
+    }
+}
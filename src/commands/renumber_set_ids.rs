@@ -0,0 +1,183 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::message_at,
+    utils::{position_to_offset, std_range_to_lsp_range},
+    validation::renumber_set_ids,
+    Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
+use std::collections::HashMap;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_renumber_set_ids_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 2 {
+        return Err(color_eyre::eyre::eyre!(
+            "Expected 1 or 2 arguments for renumber set ids command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    // the message to renumber, if the document has several
+    let position: Option<Position> = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as second argument")?,
+        ),
+    };
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+
+    let parse_span = tracing::trace_span!("parse message");
+    let _parse_span_guard = parse_span.enter();
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    drop(_parse_span_guard);
+
+    let edits = renumber_set_ids(&message)
+        .into_iter()
+        .map(|(range, new_text)| {
+            let range = range.start + document_message.range.start
+                ..range.end + document_message.range.start;
+            TextEdit {
+                range: std_range_to_lsp_range(text, range, opts.position_encoding),
+                new_text,
+            }
+        })
+        .collect::<Vec<_>>();
+    if edits.is_empty() {
+        return Ok(None);
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, edits);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Renumber Set IDs",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hl7_ls::utils::PositionEncoding;
+    use lsp_types::notification::Notification;
+
+    const TEXT: &str = "MSH|^~\\&|App\nOBX|1\nOBX|2\n\nMSH|^~\\&|App\nPID|1||𝄞𝄞\nOBX|2\nOBX|3";
+
+    /// The lines and new text of the edits renumbering the message at the
+    /// position
+    fn renumber(
+        text: &str,
+        position: Option<Position>,
+        encoding: PositionEncoding,
+    ) -> Result<Vec<(u32, u32, String)>> {
+        let mut documents = TextDocuments::new();
+        documents.listen(
+            lsp_types::notification::DidOpenTextDocument::METHOD,
+            &serde_json::json!({
+                "textDocument": {
+                    "uri": "file:///message.hl7",
+                    "languageId": "hl7",
+                    "version": 1,
+                    "text": text,
+                },
+            }),
+        );
+        let opts = Opts {
+            position_encoding: encoding,
+            ..Default::default()
+        };
+        let params = ExecuteCommandParams {
+            command: super::super::CMD_RENUMBER_SET_IDS.to_string(),
+            arguments: vec![
+                serde_json::json!("file:///message.hl7"),
+                serde_json::json!(position),
+            ],
+            work_done_progress_params: Default::default(),
+        };
+        Ok(
+            match handle_renumber_set_ids_command(params, &documents, &opts)? {
+                Some(CommandResult::WorkspaceEdit { edit, .. }) => edit
+                    .changes
+                    .unwrap()
+                    .into_values()
+                    .flatten()
+                    .map(|edit| {
+                        (
+                            edit.range.start.line,
+                            edit.range.start.character,
+                            edit.new_text,
+                        )
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn only_the_message_at_the_position_is_renumbered() {
+        // without a position, the first message, which is already in order
+        assert!(renumber(TEXT, None, PositionEncoding::Utf16)
+            .unwrap()
+            .is_empty());
+
+        let second = vec![(6, 4, "1".to_string()), (7, 4, "2".to_string())];
+        // after the non-BMP characters, which are 2 UTF-16 code units and 4
+        // UTF-8 bytes each
+        assert_eq!(
+            renumber(TEXT, Some(Position::new(5, 11)), PositionEncoding::Utf16).unwrap(),
+            second
+        );
+        assert_eq!(
+            renumber(TEXT, Some(Position::new(5, 15)), PositionEncoding::Utf8).unwrap(),
+            second
+        );
+        // a blank line belongs to the message before it
+        assert!(
+            renumber(TEXT, Some(Position::new(3, 0)), PositionEncoding::Utf16)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn documents_without_a_message_header_cant_be_renumbered() {
+        assert!(renumber("PID|1\nOBX|2", None, PositionEncoding::Utf16).is_err());
+        assert!(renumber("", None, PositionEncoding::Utf16).is_err());
+    }
+}
@@ -1,8 +1,20 @@
+use crate::acknowledgements::{
+    acknowledgement_mode, read_acknowledgement, Acknowledgement, AcknowledgementCondition,
+    AcknowledgementStatus,
+};
 use color_eyre::{
-    eyre::{Context, ContextCompat},
+    eyre::{eyre, Context, ContextCompat},
     Result,
 };
-use hl7_parser::parse_message_with_lenient_newlines;
+use hl7_ls::{
+    utils::interpolate_env,
+    workspace::{
+        connections::{validate_timeout, Framing, Port},
+        specs::WorkspaceSpecs,
+    },
+    Opts, TimeZone,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
 use lsp_textdocument::TextDocuments;
 use lsp_types::{ExecuteCommandParams, Uri};
 use std::{
@@ -12,16 +24,21 @@ use std::{
 };
 use tracing::instrument;
 
-use super::CommandResult;
+use super::{increment_sequence, listener, CommandResult};
 
-#[instrument(level = "debug", skip(documents))]
+/// How long to wait for a response, in seconds, unless told otherwise
+pub(super) const DEFAULT_TIMEOUT: f64 = 5.0;
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
 pub fn handle_send_message_command(
     params: ExecuteCommandParams,
     documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
-    if params.arguments.len() < 3 || params.arguments.len() > 4 {
+    if params.arguments.len() < 2 || params.arguments.len() > 7 {
         return Err(color_eyre::eyre::eyre!(
-            "Expected 3 or 4 arguments for send message command"
+            "Expected 2 to 7 arguments for send message command"
         ));
     }
 
@@ -30,19 +47,95 @@ pub fn handle_send_message_command(
         .and_then(|s| s.parse().ok())
         .wrap_err("Expected uri as first argument")?;
 
-    let hostname = params.arguments[1]
+    let destination = params.arguments[1]
         .as_str()
-        .wrap_err("Expected hostname as second argument")?;
+        .wrap_err("Expected hostname or connection name as second argument")?;
+
+    // without a port, the destination is one of the workspace's connections
+    let (hostname, port, tls, frame, default_timeout) = match params.arguments.get(2) {
+        None | Some(serde_json::Value::Null) => {
+            let connection = workspace_specs
+                .map(|specs| specs.connections(Some(&uri)))
+                .unwrap_or_default()
+                .into_iter()
+                .find(|(name, _)| name == destination)
+                .map(|(_, connection)| connection)
+                .wrap_err_with(|| {
+                    format!("No connection named `{destination}` applies to this document")
+                })?;
+            let port = match &connection.port {
+                Port::Number(port) => *port,
+                Port::Text(port) => parse_port(port)
+                    .wrap_err_with(|| format!("Invalid port for connection `{destination}`"))?,
+            };
+            (
+                interpolate_env(&connection.host)?,
+                port,
+                connection.tls,
+                connection.frame,
+                connection.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            )
+        }
+        // the port may be given as a string so that it can be interpolated
+        Some(serde_json::Value::String(port)) => (
+            interpolate_env(destination)?,
+            parse_port(port).wrap_err("Expected port as third argument")?,
+            false,
+            Framing::default(),
+            DEFAULT_TIMEOUT,
+        ),
+        Some(port) => (
+            interpolate_env(destination)?,
+            port.as_u64()
+                .and_then(|port| u16::try_from(port).ok())
+                .wrap_err("Expected port as third argument")?,
+            false,
+            Framing::default(),
+            DEFAULT_TIMEOUT,
+        ),
+    };
+
+    let timeout = match params.arguments.get(3) {
+        None | Some(serde_json::Value::Null) => default_timeout,
+        Some(timeout) => {
+            let timeout = timeout
+                .as_f64()
+                .wrap_err("Expected timeout as fourth argument")?;
+            validate_timeout(timeout).wrap_err("Invalid timeout")?;
+            timeout
+        }
+    };
 
-    let port = params.arguments[2]
-        .as_u64()
-        .wrap_err("Expected port as third argument")?;
+    let transform = match params.arguments.get(4) {
+        None | Some(serde_json::Value::Null) => Vec::new(),
+        Some(serde_json::Value::Object(transform)) => transform
+            .iter()
+            .map(|(path, value)| {
+                let value = value
+                    .as_str()
+                    .wrap_err_with(|| format!("Expected a string value for {path} in transform"))
+                    .and_then(interpolate_env)?;
+                Ok((path.clone(), value))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Some(_) => return Err(eyre!("Expected transform object as fifth argument")),
+    };
 
-    let timeout = params
-        .arguments
-        .get(3)
-        .and_then(|v| v.as_f64())
-        .unwrap_or(5.0);
+    // for interfaces using the sequence number protocol, which expect each
+    // message to have the next one
+    let increment_sequence = match params.arguments.get(5) {
+        None | Some(serde_json::Value::Null) => false,
+        Some(arg) => arg
+            .as_bool()
+            .wrap_err("Expected boolean as sixth argument")?,
+    };
+
+    // for endpoints that wrap messages in something other than the standard
+    // framing
+    let frame = match params.arguments.get(6) {
+        None | Some(serde_json::Value::Null) => frame,
+        Some(frame) => parse_frame(frame).wrap_err("Expected frame object as seventh argument")?,
+    };
 
     let text = documents
         .get_document_content(&uri, None)
@@ -50,75 +143,269 @@ pub fn handle_send_message_command(
 
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
-    let _message = parse_message_with_lenient_newlines(text)
+    let message = parse_message_with_lenient_newlines(text)
         .wrap_err_with(|| "Failed to parse HL7 message")?;
     drop(_parse_span_guard);
 
-    tracing::trace!(?uri, ?hostname, ?port, "Sending message");
-    let response =
-        send_message(hostname, port as u16, text, timeout).wrap_err("Failed to send message")?;
-    tracing::trace!(?response, "Received response");
+    let payload =
+        transform_message(&message, &transform).wrap_err("Failed to transform message")?;
+    // worked out before sending so that a message without a valid sequence
+    // number isn't sent
+    let next_sequence = increment_sequence
+        .then(|| increment_sequence::increment_sequence(&message))
+        .transpose()?;
 
-    Ok(Some(CommandResult::ValueResponse {
-        value: serde_json::Value::String(response),
+    let destination = Destination {
+        host: hostname,
+        port,
+        tls,
+        frame,
+        timeout,
+    };
+    tracing::trace!(?uri, ?destination, "Sending message");
+    let acknowledgement = send_message(&destination, &payload, opts.output_timezone)
+        .wrap_err("Failed to send message")?;
+    tracing::debug!(status = ?acknowledgement.status, "Read acknowledgement");
+    // in enhanced mode, the message has only been processed once there's an
+    // application acknowledgement
+    let accepted = acknowledgement
+        .application
+        .as_ref()
+        .map_or(acknowledgement.status, |application| application.status)
+        == AcknowledgementStatus::Accepted;
+    let value = serde_json::to_value(acknowledgement).wrap_err("Failed to serialize response")?;
+    // the receiver only moves on to the next sequence number once it has
+    // accepted the message
+    Ok(Some(match next_sequence.filter(|_| accepted) {
+        Some((range, new_text)) => CommandResult::ValueResponseWithEdit {
+            value,
+            label: "Increment sequence number",
+            edit: increment_sequence::sequence_edit(text, uri, range, new_text, opts),
+        },
+        None => CommandResult::ValueResponse { value },
     }))
 }
 
-#[instrument(level = "info", skip(host, port))]
-fn send_message(host: &str, port: u16, message: &str, timeout: f64) -> Result<String> {
+/// Set the values at the given paths (e.g. `MSH.11`) in a copy of the
+/// message, leaving the document itself as it was
+///
+/// Values are written as they are, so may contain separators (e.g. `App^Fac`).
+fn transform_message(message: &Message, transform: &[(String, String)]) -> Result<String> {
+    let mut replacements = transform
+        .iter()
+        .map(|(path, value)| {
+            let range = message
+                .query(path)
+                .wrap_err_with(|| format!("{path} isn't in the message, so can't be set"))?
+                .range();
+            Ok((path, range, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // apply from the back so that earlier ranges stay valid
+    replacements.sort_by_key(|(_, range, _)| std::cmp::Reverse(range.start));
+    let mut payload = message.raw_value().to_string();
+    let mut replaced_from: Option<(&String, usize)> = None;
+    for (path, range, value) in replacements {
+        if let Some((other, start)) = replaced_from {
+            if range.end > start {
+                return Err(eyre!("Can't set both {path} and {other} as they overlap"));
+            }
+        }
+        replaced_from = Some((path, range.start));
+        payload.replace_range(range, value);
+    }
+    Ok(payload)
+}
+
+/// Parse a port given as a string, which may reference environment variables
+pub(super) fn parse_port(port: &str) -> Result<u16> {
+    interpolate_env(port)?
+        .trim()
+        .parse::<u16>()
+        .wrap_err_with(|| format!("`{port}` isn't a port"))
+}
+
+/// Parse the characters to wrap messages in, given as
+/// `{ "start": "\u000b", "end": "\u001c\r" }` (either of which defaults to the
+/// standard framing)
+pub(super) fn parse_frame(frame: &serde_json::Value) -> Result<Framing> {
+    let frame: Framing = serde_json::from_value(frame.clone()).wrap_err("Failed to parse frame")?;
+    frame.validate()?;
+    Ok(frame)
+}
+
+/// Where and how a message is sent
+#[derive(Debug, Clone)]
+pub(super) struct Destination {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub frame: Framing,
+    /// How long to wait for a response, in seconds
+    pub timeout: f64,
+}
+
+/// A connection that a message can be sent over and its response read from,
+/// whether or not it's secured with TLS
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Send a message and read the response to it as an acknowledgement
+///
+/// In enhanced mode, a commit acknowledgement may be followed by an
+/// application acknowledgement on the same connection once the message has
+/// been processed, which is waited for if the message asks for one, and
+/// committed to if it asks to be.
+#[instrument(level = "info", skip(destination, timezone))]
+pub(super) fn send_message(
+    destination: &Destination,
+    message: &str,
+    timezone: TimeZone,
+) -> Result<Acknowledgement> {
+    let Destination {
+        host,
+        port,
+        tls,
+        frame,
+        timeout,
+    } = destination;
     let addr = format!("{}:{}", host, port)
         .to_socket_addrs()
         .wrap_err_with(|| format!("Failed to resolve address for {}:{}", host, port))?
         .next()
         .wrap_err_with(|| "No address found")?;
 
-    let framed = format!(
-        "\x0B{message}\x1C\r",
-        message = message.replace("\r\n", "\r").replace("\n", "\r")
-    );
-    let frame_bytes = framed.as_bytes();
-
     let connection_span = tracing::info_span!("TCP connection", host = host, port = port);
     let send_span = tracing::info_span!(parent: &connection_span, "Send message");
     let receive_span = tracing::info_span!(parent: &connection_span, "Receive message");
 
     let _connection_guard = connection_span.enter();
-    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs_f64(timeout))
+    let tcp_stream = TcpStream::connect_timeout(&addr, Duration::from_secs_f64(*timeout))
         .wrap_err_with(|| format!("Failed to connect to {}:{}", host, port))?;
     tracing::info!("Connected");
-    stream
-        .set_read_timeout(Some(Duration::from_secs_f64(timeout)))
+    tcp_stream
+        .set_read_timeout(Some(Duration::from_secs_f64(*timeout)))
         .wrap_err_with(|| format!("Failed to set read timeout for {}:{}", host, port))?;
+    let mut stream: Box<dyn Stream> = match tls {
+        true => secure(host, tcp_stream)?,
+        false => Box::new(tcp_stream),
+    };
 
     let _send_guard = send_span.enter();
     stream
-        .write_all(frame_bytes)
+        .write_all(framed(message, frame).as_bytes())
         .wrap_err_with(|| format!("Failed to write message to {}:{}", host, port))?;
     drop(_send_guard);
 
     let _receive_guard = receive_span.enter();
-    let mut buf: Vec<u8> = Vec::with_capacity(1024);
-    read_till_started(&mut stream, timeout).wrap_err_with(|| "Failed to read start of message")?;
-    read_till_ended(&mut stream, &mut buf, timeout).wrap_err_with(|| "Failed to read message")?;
+    let response = read_framed(&mut stream, frame, *timeout)?;
     drop(_receive_guard);
 
-    let message = String::from_utf8(buf).wrap_err_with(|| "Failed to parse message as utf8")?;
-    Ok(message.replace("\r", "\n"))
+    let sent = parse_message_with_lenient_newlines(message).ok();
+    let sent_control_id = sent
+        .as_ref()
+        .and_then(|sent| sent.query("MSH.10").map(|id| id.raw_value().to_string()));
+    let mut acknowledgement = read_acknowledgement(&response, sent_control_id.as_deref());
+
+    let is_commit = acknowledgement
+        .code
+        .as_deref()
+        .is_some_and(|code| code.starts_with('C'));
+    let awaits_application = sent
+        .as_ref()
+        .and_then(acknowledgement_mode)
+        .is_some_and(|mode| mode.application != AcknowledgementCondition::Never);
+    if is_commit && acknowledgement.status == AcknowledgementStatus::Accepted && awaits_application
+    {
+        let _receive_guard = receive_span.enter();
+        match read_framed(&mut stream, frame, *timeout) {
+            Ok(response) => {
+                for reply in listener::replies(&response, timezone) {
+                    stream
+                        .write_all(framed(&reply, frame).as_bytes())
+                        .wrap_err("Failed to commit to the application acknowledgement")?;
+                }
+                acknowledgement.application = Some(Box::new(read_acknowledgement(
+                    &response,
+                    sent_control_id.as_deref(),
+                )));
+            }
+            // the application acknowledgement may be sent later, over
+            // another connection
+            Err(e) => tracing::debug!("No application acknowledgement: {e:#}"),
+        }
+    }
+    Ok(acknowledgement)
+}
+
+/// Wrap a message in the frame, with its segments terminated by `\r`
+pub(super) fn framed(message: &str, frame: &Framing) -> String {
+    format!(
+        "{start}{message}{end}",
+        start = frame.start,
+        message = message.replace("\r\n", "\r").replace("\n", "\r"),
+        end = frame.end,
+    )
+}
+
+/// Read the next message wrapped in the frame
+pub(super) fn read_framed(stream: &mut impl Read, frame: &Framing, timeout: f64) -> Result<String> {
+    let mut buf: Vec<u8> = Vec::with_capacity(1024);
+    read_till_started(stream, frame.start.as_bytes(), timeout)
+        .wrap_err_with(|| "Failed to read start of message")?;
+    read_till_ended(stream, &mut buf, frame.end.as_bytes(), timeout)
+        .wrap_err_with(|| "Failed to read message")?;
+    String::from_utf8(buf).wrap_err_with(|| "Failed to parse message as utf8")
+}
+
+/// Secure the connection with TLS, checking the server's certificate against
+/// the Mozilla root certificates
+#[cfg(feature = "tls")]
+fn secure(host: &str, stream: TcpStream) -> Result<Box<dyn Stream>> {
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+    use std::sync::Arc;
+
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .wrap_err("Failed to configure TLS")?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())
+        .wrap_err_with(|| format!("`{host}` isn't a valid server name for TLS"))?;
+    let connection = ClientConnection::new(Arc::new(config), server_name)
+        .wrap_err("Failed to start TLS connection")?;
+    Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn secure(_host: &str, _stream: TcpStream) -> Result<Box<dyn Stream>> {
+    Err(eyre!(
+        "Can't connect with TLS as hl7-ls was built without the `tls` feature"
+    ))
 }
 
 #[instrument(level = "trace", skip(stream))]
-fn read_till_started(stream: &mut TcpStream, timeout: f64) -> Result<()> {
+fn read_till_started(stream: &mut impl Read, frame_start: &[u8], timeout: f64) -> Result<()> {
     let start = Instant::now();
     let timeout = Duration::from_secs_f64(timeout);
 
-    loop {
+    let mut read: Vec<u8> = Vec::with_capacity(frame_start.len());
+    while !read.ends_with(frame_start) {
         let mut byte = [0u8; 1];
         stream
             .read_exact(&mut byte)
             .wrap_err_with(|| "Failed to read byte")?;
-        if byte[0] == 0x0B {
-            break;
+        // only as much as could be the start of the frame is kept
+        if read.len() == frame_start.len() {
+            read.remove(0);
         }
+        read.push(byte[0]);
 
         if start.elapsed() > timeout {
             return Err(color_eyre::eyre::eyre!(
@@ -130,7 +417,12 @@ fn read_till_started(stream: &mut TcpStream, timeout: f64) -> Result<()> {
 }
 
 #[instrument(level = "trace", skip(stream, buffer))]
-fn read_till_ended(stream: &mut TcpStream, buffer: &mut Vec<u8>, timeout: f64) -> Result<()> {
+fn read_till_ended(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    frame_end: &[u8],
+    timeout: f64,
+) -> Result<()> {
     let start = Instant::now();
     let timeout = Duration::from_secs_f64(timeout);
 
@@ -146,14 +438,14 @@ fn read_till_ended(stream: &mut TcpStream, buffer: &mut Vec<u8>, timeout: f64) -
             ));
         }
 
-        // search for the [0x1C, 0x0D] sequence
+        // search for the end of the frame (e.g. [0x1C, 0x0D])
         // if found, return the buffer
         // if not found, append the buffer and continue
         for c in buf.iter().take(count) {
             buffer.push(*c);
-            if buffer.len() >= 2 && buffer[buffer.len() - 2..] == [0x1C, 0x0D] {
-                // trim the 2 footer bytes off the message
-                buffer.truncate(buffer.len() - 2);
+            if buffer.ends_with(frame_end) {
+                // trim the footer bytes off the message
+                buffer.truncate(buffer.len() - frame_end.len());
                 return Ok(());
             }
             if buffer.len() > 65535 {
@@ -168,3 +460,51 @@ fn read_till_ended(stream: &mut TcpStream, buffer: &mut Vec<u8>, timeout: f64) -
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_only_change_the_payload() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|1|P|2.5.1\rPID|1",
+        )
+        .unwrap();
+
+        let payload = transform_message(
+            &message,
+            &[
+                ("MSH.11".to_string(), "T".to_string()),
+                ("MSH.5".to_string(), "TestRcv^Sub".to_string()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            payload,
+            "MSH|^~\\&|App|Fac|TestRcv^Sub|RcvFac|20240102030405||ADT^A01|1|T|2.5.1\rPID|1"
+        );
+
+        let overlapping = [
+            ("MSH.9".to_string(), "ADT^A04".to_string()),
+            ("MSH.9.2".to_string(), "A04".to_string()),
+        ];
+        assert!(transform_message(&message, &overlapping).is_err());
+        assert!(transform_message(&message, &[("ZZZ.1".to_string(), "x".to_string())]).is_err());
+    }
+
+    #[test]
+    fn responses_are_read_from_within_the_frame() {
+        let mut standard = std::io::Cursor::new(b"noise\x0BMSH|^~\\&\rMSA|AA|1\x1C\rmore".to_vec());
+        let mut buffer = Vec::new();
+        read_till_started(&mut standard, b"\x0B", 1.0).unwrap();
+        read_till_ended(&mut standard, &mut buffer, b"\x1C\r", 1.0).unwrap();
+        assert_eq!(buffer, b"MSH|^~\\&\rMSA|AA|1");
+
+        let mut custom = std::io::Cursor::new(b"<<MSH|^~\\&>>".to_vec());
+        let mut buffer = Vec::new();
+        read_till_started(&mut custom, b"<<", 1.0).unwrap();
+        read_till_ended(&mut custom, &mut buffer, b">>", 1.0).unwrap();
+        assert_eq!(buffer, b"MSH|^~\\&");
+    }
+}
@@ -1,16 +1,21 @@
 use super::CommandResult;
-use chrono::{DateTime, Utc};
 use color_eyre::{eyre::ContextCompat, Result};
-use hl7_parser::datetime::TimeStamp;
+use hl7_ls::{
+    messages::message_at,
+    utils::{lsp_range_to_std_range, trim_edited_segment},
+    Opts,
+};
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines};
 use lsp_textdocument::TextDocuments;
 use lsp_types::{ExecuteCommandParams, Range, TextEdit, Uri, WorkspaceEdit};
 use std::collections::HashMap;
 use tracing::instrument;
 
-#[instrument(level = "trace", skip(_documents))]
+#[instrument(level = "trace", skip(documents, opts))]
 pub fn handle_set_to_now_command(
     params: ExecuteCommandParams,
-    _documents: &TextDocuments,
+    documents: &TextDocuments,
+    opts: &Opts,
 ) -> Result<Option<CommandResult>> {
     assert_eq!(
         params.arguments.len(),
@@ -28,20 +33,31 @@ pub fn handle_set_to_now_command(
         .and_then(|obj| serde_json::from_value(serde_json::Value::Object(obj.clone())).ok())
         .wrap_err("Expected range as second argument")?;
 
-    let now: DateTime<Utc> = Utc::now();
-    let now: TimeStamp = now.into();
+    let now: TimeStamp = opts.output_timezone.now().into();
     let now = now.to_string();
 
     tracing::debug!(?uri, ?range, ?now, "Setting timestamp to now");
+    let mut edit = TextEdit {
+        range,
+        new_text: now,
+    };
+    if opts.trim_trailing_separators {
+        if let Some(text) = documents.get_document_content(&uri, None) {
+            let offset = lsp_range_to_std_range(text, range, opts.position_encoding)
+                .map(|range| range.start)
+                .unwrap_or_default();
+            let separators = parse_message_with_lenient_newlines(
+                message_at(text, offset, opts.position_encoding).text,
+            )
+            .map(|message| message.separators)
+            .unwrap_or_default();
+            edit = trim_edited_segment(text, edit, &separators, opts.position_encoding);
+        }
+    }
+
     #[allow(clippy::mutable_key_type)]
     let mut changes = HashMap::new();
-    changes.insert(
-        uri,
-        vec![TextEdit {
-            range,
-            new_text: now,
-        }],
-    );
+    changes.insert(uri, vec![edit]);
 
     Ok(Some(CommandResult::WorkspaceEdit {
         label: "Set timestamp to now",
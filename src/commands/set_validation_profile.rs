@@ -0,0 +1,47 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use hl7_ls::workspace::Workspace;
+use lsp_types::{ExecuteCommandParams, Uri};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(workspace))]
+pub fn handle_set_validation_profile_command(
+    params: ExecuteCommandParams,
+    workspace: Option<&Workspace>,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.is_empty() || params.arguments.len() > 2 {
+        return Err(eyre!(
+            "Expected 1 or 2 arguments for set validation profile command"
+        ));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let profile = match params.arguments.get(1) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(profile) => Some(
+            profile
+                .as_str()
+                .wrap_err("Expected profile name as second argument")?
+                .to_string(),
+        ),
+    };
+
+    let workspace = workspace.wrap_err("No workspace folders are open")?;
+    workspace.assign_profile(&uri, profile)?;
+
+    // globs may still give the document a profile when one is removed
+    Ok(Some(CommandResult::ValueResponse {
+        value: workspace
+            .specs
+            .profile(&uri)
+            .map(serde_json::Value::String)
+            .unwrap_or_default(),
+    }))
+}
@@ -0,0 +1,254 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    messages::message_at,
+    utils::{position_to_offset, std_range_to_lsp_range, trim_edited_segment},
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Position, TextEdit, Uri, WorkspaceEdit};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_set_value_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() < 3 || params.arguments.len() > 4 {
+        return Err(eyre!("Expected 3 or 4 arguments for set value command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+    let path = params.arguments[1]
+        .as_str()
+        .wrap_err("Expected path as second argument")?;
+    let value = params.arguments[2]
+        .as_str()
+        .wrap_err("Expected value as third argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    // the message to update, if the document has several
+    let position: Option<Position> = match params.arguments.get(3) {
+        None | Some(serde_json::Value::Null) => None,
+        Some(position) => Some(
+            serde_json::from_value(position.clone())
+                .wrap_err("Expected position as fourth argument")?,
+        ),
+    };
+    let offset = position
+        .and_then(|position| {
+            position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )
+        })
+        .unwrap_or_default();
+    let document_message = message_at(text, offset, opts.position_encoding);
+
+    let message = parse_message_with_lenient_newlines(document_message.text)
+        .wrap_err_with(|| "Failed to parse HL7 message")?;
+    let encoded = escapes::encode(value, &message.separators, truncation_character(&message));
+    let (range, new_text) = set_value(&message, &ValuePath::parse(path)?, &encoded)?;
+
+    let range =
+        range.start + document_message.range.start..range.end + document_message.range.start;
+    let mut edit = TextEdit {
+        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+        new_text,
+    };
+    if opts.trim_trailing_separators {
+        edit = trim_edited_segment(text, edit, &message.separators, opts.position_encoding);
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, vec![edit]);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Set value",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// Where to set a value, e.g. `OBX[2].5`, `PID.3[2].4.1`, or `PID.5.1`
+///
+/// The first occurrence of the segment and the first repeat of the field are
+/// used unless others are given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct ValuePath {
+    pub segment: String,
+    pub occurrence: usize,
+    pub field: usize,
+    pub repeat: usize,
+    pub component: Option<usize>,
+    pub sub_component: Option<usize>,
+}
+
+impl ValuePath {
+    pub(super) fn parse(path: &str) -> Result<Self> {
+        let invalid = || eyre!("Invalid path `{path}`, expected e.g. `PID.5.1` or `OBX[2].5`");
+        // a part and its optional index, e.g. `OBX[2]`
+        let indexed = |part: &str| -> Result<(String, usize)> {
+            match part.strip_suffix(']').and_then(|part| part.split_once('[')) {
+                Some((name, index)) => Ok((
+                    name.to_string(),
+                    index.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?,
+                )),
+                None => Ok((part.to_string(), 1)),
+            }
+        };
+        let number = |part: &str| part.parse::<usize>().ok().filter(|&n| n > 0);
+
+        let mut parts = path.trim().split('.');
+        let (segment, occurrence) = indexed(parts.next().unwrap_or_default())?;
+        if segment.len() != 3 {
+            return Err(invalid());
+        }
+        let (field, repeat) = indexed(parts.next().ok_or_else(invalid)?)?;
+        let field = number(&field).ok_or_else(invalid)?;
+        let component = parts
+            .next()
+            .map(|part| number(part).ok_or_else(invalid))
+            .transpose()?;
+        let sub_component = parts
+            .next()
+            .map(|part| number(part).ok_or_else(invalid))
+            .transpose()?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        if segment == "MSH" && field <= 2 {
+            return Err(eyre!("The separators in MSH.1 and MSH.2 can't be set"));
+        }
+        Ok(ValuePath {
+            segment,
+            occurrence,
+            field,
+            repeat,
+            component,
+            sub_component,
+        })
+    }
+}
+
+/// The edit that sets the (already encoded) value at the path: replacing
+/// what's there, or adding it along with the separators needed to reach it
+pub(super) fn set_value(
+    message: &Message,
+    path: &ValuePath,
+    value: &str,
+) -> Result<(Range<usize>, String)> {
+    let separators = &message.separators;
+    let segment = message
+        .segment_n(&path.segment, path.occurrence)
+        .wrap_err_with(|| format!("The message has no {} segment", path.segment))?;
+
+    // the separators that start the missing parts below a new field, repeat,
+    // or component
+    let nested = |from_component: bool| {
+        let mut leading = String::new();
+        if let (false, Some(component)) = (from_component, path.component) {
+            leading.extend(std::iter::repeat_n(separators.component, component - 1));
+        }
+        if let Some(sub_component) = path.sub_component {
+            leading.extend(std::iter::repeat_n(
+                separators.subcomponent,
+                sub_component - 1,
+            ));
+        }
+        format!("{leading}{value}")
+    };
+    let insert = |at: usize, separator: char, missing: usize, rest: String| {
+        let mut new_text = separator.to_string().repeat(missing);
+        new_text.push_str(&rest);
+        (at..at, new_text)
+    };
+
+    let Some(field) = segment.field(path.field) else {
+        let missing = path.field - segment.fields().count();
+        let repeats = separators.repetition.to_string().repeat(path.repeat - 1);
+        let rest = format!("{repeats}{}", nested(false));
+        return Ok(insert(segment.range.end, separators.field, missing, rest));
+    };
+    let Some(repeat) = field.repeat(path.repeat) else {
+        let missing = path.repeat - field.repeats().count();
+        return Ok(insert(
+            field.range.end,
+            separators.repetition,
+            missing,
+            nested(false),
+        ));
+    };
+    let Some(component_index) = path.component else {
+        return Ok((repeat.range.clone(), value.to_string()));
+    };
+    let Some(component) = repeat.component(component_index) else {
+        let missing = component_index - repeat.components().count();
+        return Ok(insert(
+            repeat.range.end,
+            separators.component,
+            missing,
+            nested(true),
+        ));
+    };
+    let Some(sub_component_index) = path.sub_component else {
+        return Ok((component.range.clone(), value.to_string()));
+    };
+    match component.subcomponent(sub_component_index) {
+        Some(sub_component) => Ok((sub_component.range.clone(), value.to_string())),
+        None => {
+            let missing = sub_component_index - component.subcomponents().count();
+            Ok(insert(
+                component.range.end,
+                separators.subcomponent,
+                missing,
+                value.to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_set_with_the_separators_needed_to_reach_them() {
+        let text = "MSH|^~\\&|App\rPID|1||123^^^Hosp||Doe~Roe";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let set = |path: &str| {
+            let (range, value) =
+                set_value(&message, &ValuePath::parse(path).unwrap(), "X").unwrap();
+            let mut text = text.to_string();
+            text.replace_range(range, &value);
+            text.split('\r').nth(1).unwrap().to_string()
+        };
+
+        assert_eq!(set("PID.3.4"), "PID|1||123^^^X||Doe~Roe");
+        assert_eq!(set("PID.5[2]"), "PID|1||123^^^Hosp||Doe~X");
+        assert_eq!(set("PID.5[2].2"), "PID|1||123^^^Hosp||Doe~Roe^X");
+        assert_eq!(set("PID.3.4.2"), "PID|1||123^^^Hosp&X||Doe~Roe");
+        assert_eq!(set("PID.8.2.3"), "PID|1||123^^^Hosp||Doe~Roe|||^&&X");
+        assert_eq!(set("PID.5[3]"), "PID|1||123^^^Hosp||Doe~Roe~X");
+        assert!(ValuePath::parse("PID.x").is_err());
+        assert!(ValuePath::parse("MSH.2").is_err());
+    }
+}
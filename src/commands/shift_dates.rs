@@ -0,0 +1,253 @@
+use super::CommandResult;
+use chrono::{Months, NaiveDate, NaiveDateTime, TimeDelta};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::split_messages, utils::std_range_to_lsp_range, workspace::specs::WorkspaceSpecs, Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+/// The datatypes whose values are shifted
+const DATE_DATATYPES: &[&str] = &["TS", "DTM", "DT"];
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_shift_dates_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 2 {
+        return Err(eyre!("Expected 2 arguments for shift dates command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+    let shift = match &params.arguments[1] {
+        serde_json::Value::Number(days) => days
+            .as_i64()
+            .map(|days| Shift::Duration(TimeDelta::days(days)))
+            .wrap_err("Expected a whole number of days as second argument")?,
+        offset => Shift::parse(
+            offset
+                .as_str()
+                .wrap_err("Expected offset as second argument")?,
+        )?,
+    };
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let mut edits = Vec::new();
+    for document_message in split_messages(text, opts.position_encoding) {
+        let message = parse_message_with_lenient_newlines(document_message.text)
+            .wrap_err_with(|| "Failed to parse HL7 message")?;
+        let version = opts
+            .message_version(&uri, &message, workspace_specs)
+            .version;
+        edits.extend(
+            shift_dates(&message, version, shift)
+                .into_iter()
+                .map(|(range, new_text)| {
+                    let range = range.start + document_message.range.start
+                        ..range.end + document_message.range.start;
+                    TextEdit {
+                        range: std_range_to_lsp_range(text, range, opts.position_encoding),
+                        new_text,
+                    }
+                }),
+        );
+    }
+    if edits.is_empty() {
+        return Ok(None);
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, edits);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Shift dates",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// How far to move dates and times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shift {
+    /// Calendar months (and years), which keep the day of the month where
+    /// they can
+    Months(i32),
+    Duration(TimeDelta),
+}
+
+impl Shift {
+    /// Parse an offset such as `+365d`, `-2w`, `1y`, `+6mo`, `-12h`, or
+    /// `30min`, where a number on its own is a number of days
+    fn parse(offset: &str) -> Result<Self> {
+        let invalid = || {
+            eyre!("Invalid offset `{offset}`, expected e.g. `+365d`, `-2w`, `1y`, `6mo`, `12h`, or `30min`")
+        };
+        let offset = offset.trim();
+        let (sign, rest) = match offset.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, offset.strip_prefix('+').unwrap_or(offset)),
+        };
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount = rest[..digits].parse::<i32>().map_err(|_| invalid())? * sign;
+        let amount_64 = i64::from(amount);
+        Ok(match rest[digits..].trim() {
+            "y" => Shift::Months(amount * 12),
+            "mo" => Shift::Months(amount),
+            "w" => Shift::Duration(TimeDelta::weeks(amount_64)),
+            "" | "d" => Shift::Duration(TimeDelta::days(amount_64)),
+            "h" => Shift::Duration(TimeDelta::hours(amount_64)),
+            "min" => Shift::Duration(TimeDelta::minutes(amount_64)),
+            _ => return Err(invalid()),
+        })
+    }
+
+    fn apply(self, datetime: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Shift::Months(months) if months < 0 => {
+                datetime.checked_sub_months(Months::new(months.unsigned_abs()))
+            }
+            Shift::Months(months) => datetime.checked_add_months(Months::new(months as u32)),
+            Shift::Duration(duration) => datetime.checked_add_signed(duration),
+        }
+    }
+}
+
+/// The edits that shift each date and time in a message: the values of the
+/// fields and components whose datatype is a timestamp (TS or DTM) or date
+/// (DT), and of observations with one of those value types
+fn shift_dates(message: &Message, version: &str, shift: Shift) -> Vec<(Range<usize>, String)> {
+    let mut values = Vec::new();
+    for segment in message.segments() {
+        let definition = hl7_definitions::get_segment(version, segment.name);
+        for (fi, field) in segment.fields().enumerate() {
+            let datatype = match (segment.name, fi + 1) {
+                // the encoding characters
+                ("MSH", 1 | 2) => continue,
+                ("OBX", 5) => segment
+                    .field(2)
+                    .map(|value_type| value_type.raw_value())
+                    .filter(|value_type| DATE_DATATYPES.contains(value_type)),
+                _ => definition
+                    .and_then(|definition| definition.fields.get(fi))
+                    .map(|field| field.datatype),
+            };
+            let Some(datatype) = datatype else {
+                continue;
+            };
+            for repeat in field.repeats() {
+                if DATE_DATATYPES.contains(&datatype) {
+                    values.extend(
+                        repeat
+                            .component(1)
+                            .map(|c| (c.range.clone(), c.raw_value())),
+                    );
+                    continue;
+                }
+                // components that are dates, e.g. the effective date of a name
+                let Some(components) = hl7_definitions::get_field(version, datatype) else {
+                    continue;
+                };
+                for (ci, component) in repeat.components().enumerate() {
+                    let is_date = components
+                        .subfields
+                        .get(ci)
+                        .is_some_and(|c| DATE_DATATYPES.contains(&c.datatype));
+                    if is_date {
+                        values.extend(
+                            component
+                                .subcomponent(1)
+                                .map(|s| (s.range.clone(), s.raw_value())),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    values
+        .into_iter()
+        .filter_map(|(range, value)| Some((range, shift_value(value, shift)?)))
+        .collect()
+}
+
+/// Shift a date or timestamp (e.g. `20240102`, `202401020304.5-0500`),
+/// keeping its precision, fractional seconds, and time zone
+///
+/// Returns `None` if the value isn't one, or can't be shifted.
+fn shift_value(value: &str, shift: Shift) -> Option<String> {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    if !matches!(digits, 4 | 6 | 8 | 10 | 12 | 14) {
+        return None;
+    }
+    let part = |start: usize, default: u32| {
+        value
+            .get(start..start + 2)
+            .filter(|_| start + 2 <= digits)
+            .map_or(Some(default), |part| part.parse().ok())
+    };
+    let datetime = NaiveDate::from_ymd_opt(value[..4].parse().ok()?, part(4, 1)?, part(6, 1)?)?
+        .and_hms_opt(part(8, 0)?, part(10, 0)?, part(12, 0)?)?;
+    let shifted = shift.apply(datetime)?.format("%Y%m%d%H%M%S").to_string();
+    Some(format!("{}{}", &shifted[..digits], &value[digits..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_shifted_keeping_their_precision_and_time_zone() {
+        let text = "MSH|^~\\&|App|Fac|||20240102030405-0500||ADT^A01|1|P|2.5.1\rPID|1||123||Doe||19800229\rOBX|1|DT|||20231231\rOBX|2|ST|||20231231";
+        let message = parse_message_with_lenient_newlines(text).unwrap();
+        let shifted = |shift: &str| {
+            let mut text = text.to_string();
+            for (range, value) in shift_dates(&message, "2.5.1", Shift::parse(shift).unwrap())
+                .into_iter()
+                .rev()
+            {
+                text.replace_range(range, &value);
+            }
+            text
+        };
+
+        assert_eq!(
+            shifted("+1d"),
+            "MSH|^~\\&|App|Fac|||20240103030405-0500||ADT^A01|1|P|2.5.1\rPID|1||123||Doe||19800301\rOBX|1|DT|||20240101\rOBX|2|ST|||20231231"
+        );
+        assert_eq!(
+            shifted("-1y"),
+            "MSH|^~\\&|App|Fac|||20230102030405-0500||ADT^A01|1|P|2.5.1\rPID|1||123||Doe||19790228\rOBX|1|DT|||20221231\rOBX|2|ST|||20231231"
+        );
+        assert_eq!(
+            shift_value("2024", Shift::parse("400").unwrap()),
+            Some("2025".to_string())
+        );
+        assert_eq!(
+            shift_value("202401020304.5", Shift::parse("-4h").unwrap()),
+            Some("202401012304.5".to_string())
+        );
+        assert!(Shift::parse("3 fortnights").is_err());
+    }
+}
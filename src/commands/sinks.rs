@@ -0,0 +1,266 @@
+use super::{
+    listener::{MessageReceived, ReceivedMessage, POLL_INTERVAL},
+    send_message::{parse_port, send_message, Destination, DEFAULT_TIMEOUT},
+};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use hl7_ls::{
+    utils::interpolate_env,
+    workspace::connections::{validate_timeout, Framing, Port},
+    TimeZone,
+};
+use lsp_server::{Message, Notification};
+use lsp_types::notification::Notification as _;
+use serde::Deserialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How big a file sink's file can get before it's rolled over, in bytes
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How long to wait before trying to forward a message again, after failing
+/// to reach the destination
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Somewhere a listener passes the messages it receives on to, once they've
+/// been acknowledged
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Sink {
+    /// Pass the message on to the client, to be opened in the editor
+    Editor,
+    /// Append the message to a file, which is moved aside to `<path>.1` once
+    /// it would grow past `max_size` bytes
+    File {
+        /// May reference environment variables as `${NAME}`
+        path: String,
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+    },
+    /// Send the message on to another MLLP destination, queueing it until
+    /// the destination can be reached
+    Forward {
+        /// May reference environment variables as `${NAME}`
+        host: String,
+        port: Port,
+        #[serde(default)]
+        tls: bool,
+        #[serde(default)]
+        frame: Framing,
+        /// How long to wait for a response, in seconds
+        timeout: Option<f64>,
+    },
+    /// Drop the message once it's been acknowledged
+    Discard,
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+/// Parse the sinks to pass received messages on to, given as an array of
+/// objects tagged with their `type`
+pub(super) fn parse_sinks(sinks: &serde_json::Value) -> Result<Vec<Sink>> {
+    let sinks: Vec<Sink> =
+        serde_json::from_value(sinks.clone()).wrap_err("Failed to parse sinks")?;
+    for sink in sinks.iter() {
+        match sink {
+            Sink::File { max_size: 0, .. } => {
+                return Err(eyre!("The maximum size of a file sink can't be 0"));
+            }
+            Sink::Forward { frame, timeout, .. } => {
+                frame.validate()?;
+                if let Some(timeout) = timeout {
+                    validate_timeout(*timeout)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sinks)
+}
+
+/// A listener's sinks, ready to pass messages on to
+#[derive(Debug)]
+pub struct Outputs {
+    outputs: Vec<Output>,
+}
+
+#[derive(Debug)]
+enum Output {
+    Editor(Sender<Message>),
+    File(Mutex<RollingFile>),
+    /// The queue of messages waiting to be sent by the forwarding thread
+    Forward(Sender<String>),
+    Discard,
+}
+
+/// A file that messages are appended to, one segment per line
+#[derive(Debug)]
+struct RollingFile {
+    path: PathBuf,
+    max_size: u64,
+}
+
+impl Outputs {
+    /// Resolve the sinks' environment variables and start a thread for each
+    /// destination messages are forwarded to, which runs until `stopping` is
+    /// set
+    pub fn start(
+        sinks: Vec<Sink>,
+        sender: &Sender<Message>,
+        stopping: &Arc<AtomicBool>,
+        timezone: TimeZone,
+    ) -> Result<(Outputs, Vec<JoinHandle<()>>)> {
+        let mut outputs = Vec::with_capacity(sinks.len());
+        let mut forwarders = Vec::new();
+        for sink in sinks {
+            outputs.push(match sink {
+                Sink::Editor => Output::Editor(sender.clone()),
+                Sink::File { path, max_size } => Output::File(Mutex::new(RollingFile {
+                    path: PathBuf::from(interpolate_env(&path)?),
+                    max_size,
+                })),
+                Sink::Forward {
+                    host,
+                    port,
+                    tls,
+                    frame,
+                    timeout,
+                } => {
+                    let port = match &port {
+                        Port::Number(port) => *port,
+                        Port::Text(port) => {
+                            parse_port(port).wrap_err("Invalid port to forward messages to")?
+                        }
+                    };
+                    let destination = Destination {
+                        host: interpolate_env(&host)?,
+                        port,
+                        tls,
+                        frame,
+                        timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+                    };
+                    let (queue, queued) = crossbeam_channel::unbounded();
+                    let stopping = stopping.clone();
+                    forwarders.push(thread::spawn(move || {
+                        forward(queued, destination, stopping, timezone)
+                    }));
+                    Output::Forward(queue)
+                }
+                Sink::Discard => Output::Discard,
+            });
+        }
+        Ok((Outputs { outputs }, forwarders))
+    }
+
+    /// Pass a message on to each of the sinks, carrying on past any that fail
+    pub fn deliver(&self, raw: &str, received: &ReceivedMessage) {
+        for output in self.outputs.iter() {
+            let delivered = match output {
+                Output::Editor(sender) => sender
+                    .send(Message::Notification(Notification::new(
+                        MessageReceived::METHOD.to_string(),
+                        received,
+                    )))
+                    .wrap_err("Failed to pass message on to the client"),
+                Output::File(file) => file
+                    .lock()
+                    .expect("file sink lock isn't poisoned")
+                    .append(&received.message),
+                Output::Forward(queue) => queue
+                    .send(raw.to_string())
+                    .wrap_err("Failed to queue message to be forwarded"),
+                Output::Discard => Ok(()),
+            };
+            if let Err(e) = delivered {
+                tracing::warn!(peer = received.peer, "{e:#}");
+            }
+        }
+    }
+}
+
+impl RollingFile {
+    fn append(&mut self, message: &str) -> Result<()> {
+        let entry = format!("{message}\n");
+        let size = fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+        if size > 0 && size + entry.len() as u64 > self.max_size {
+            let mut rolled = self.path.clone().into_os_string();
+            rolled.push(".1");
+            // renaming over an existing file fails on Windows
+            let _ = fs::remove_file(&rolled);
+            fs::rename(&self.path, &rolled)
+                .wrap_err_with(|| format!("Failed to roll over {}", self.path.display()))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err_with(|| format!("Failed to open {}", self.path.display()))?;
+        file.write_all(entry.as_bytes())
+            .wrap_err_with(|| format!("Failed to write to {}", self.path.display()))
+    }
+}
+
+/// Send queued messages on to the destination in the order they were
+/// received, retrying each until the destination can be reached or the
+/// listener is stopped
+fn forward(
+    queued: Receiver<String>,
+    destination: Destination,
+    stopping: Arc<AtomicBool>,
+    timezone: TimeZone,
+) {
+    while !stopping.load(Ordering::Relaxed) {
+        let message = match queued.recv_timeout(POLL_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        loop {
+            match send_message(&destination, &message, timezone) {
+                Ok(acknowledgement) => {
+                    // the destination has the message, so retrying one it
+                    // rejected won't help
+                    tracing::debug!(status = ?acknowledgement.status, "Forwarded message");
+                    break;
+                }
+                Err(e) => tracing::warn!(
+                    host = destination.host,
+                    port = destination.port,
+                    "Failed to forward message, retrying: {e:#}"
+                ),
+            }
+
+            let retry_at = Instant::now() + RETRY_INTERVAL;
+            while Instant::now() < retry_at {
+                if stopping.load(Ordering::Relaxed) {
+                    tracing::warn!(
+                        dropped = queued.len() + 1,
+                        "Stopped before forwarding every message"
+                    );
+                    return;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
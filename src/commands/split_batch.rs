@@ -0,0 +1,164 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::split_messages, utils::file_path, validation::is_batch_file,
+    workspace::snippets::line_ending, Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_textdocument::TextDocuments;
+use lsp_types::{
+    CreateFile, CreateFileOptions, DocumentChangeOperation, DocumentChanges, ExecuteCommandParams,
+    OneOf, OptionalVersionedTextDocumentIdentifier, Range, ResourceOp, TextDocumentEdit, TextEdit,
+    Uri, WorkspaceEdit,
+};
+use std::collections::HashSet;
+use tracing::instrument;
+
+/// How many files with the same name to try before giving up
+const MAX_COPIES: usize = 100;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_split_batch_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!("Expected 1 argument for split batch command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    if uri
+        .scheme()
+        .is_some_and(|scheme| scheme.as_str() == "untitled")
+    {
+        return Err(eyre!(
+            "Can't split an untitled document as there's nowhere to put the messages, save it first"
+        ));
+    }
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    let messages = split_messages(text, opts.position_encoding);
+    if messages.len() < 2 && !is_batch_file(text) {
+        return Err(eyre!("The document has no other messages to split it into"));
+    }
+
+    let original = uri.as_str();
+    let folder = &original[..original.rfind('/').map(|i| i + 1).unwrap_or(0)];
+    let extension = original[folder.len()..]
+        .rfind('.')
+        .filter(|&i| i > 0)
+        .map(|i| &original[folder.len() + i..])
+        .unwrap_or(".hl7");
+    let newline = line_ending(text);
+
+    let mut taken = HashSet::new();
+    let mut operations = Vec::new();
+    for (mi, message) in messages.iter().enumerate() {
+        let name = message_file_name(message.text, mi + 1);
+        let message_uri = (1..=MAX_COPIES)
+            .map(|copy| match copy {
+                1 => format!("{folder}{name}{extension}"),
+                _ => format!("{folder}{name}-{copy}{extension}"),
+            })
+            .filter(|candidate| !taken.contains(candidate))
+            .filter_map(|candidate| candidate.parse::<Uri>().ok())
+            .find(|candidate| {
+                let is_open = documents.get_document(candidate).is_some();
+                let is_on_disk = file_path(candidate).is_some_and(|path| path.exists());
+                !is_open && !is_on_disk
+            })
+            .wrap_err_with(|| format!("Too many files named {name} already exist"))?;
+        taken.insert(message_uri.as_str().to_string());
+
+        operations.push(DocumentChangeOperation::Op(ResourceOp::Create(
+            CreateFile {
+                uri: message_uri.clone(),
+                options: Some(CreateFileOptions {
+                    overwrite: Some(false),
+                    ignore_if_exists: Some(false),
+                }),
+                annotation_id: None,
+            },
+        )));
+        operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: message_uri,
+                version: None,
+            },
+            edits: vec![OneOf::Left(TextEdit {
+                range: Range::default(),
+                new_text: format!("{}{newline}", message.text.trim_end()),
+            })],
+        }));
+    }
+    tracing::debug!(files = messages.len(), "splitting batch");
+
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Split batch into files",
+        edit: WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            change_annotations: None,
+        },
+    }))
+}
+
+/// The name of the file to put a message in, from its type and control ID
+/// (e.g. `ADT_A01-MSG00001`), or its number in the batch if it has neither
+fn message_file_name(message: &str, number: usize) -> String {
+    let Ok(message) = parse_message_with_lenient_newlines(message) else {
+        return format!("message-{number}");
+    };
+    let value = |path: &str| {
+        message
+            .query(path)
+            .map(|value| value.raw_value())
+            .filter(|value| !value.is_empty())
+    };
+    let message_type = [value("MSH.9.1"), value("MSH.9.2")]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("_");
+    let name = match (message_type.is_empty(), value("MSH.10")) {
+        (false, Some(control_id)) => format!("{message_type}-{control_id}"),
+        (false, None) => format!("{message_type}-{number}"),
+        (true, Some(control_id)) => control_id.to_string(),
+        (true, None) => format!("message-{number}"),
+    };
+    // only characters that are safe in file names (and URIs) are kept
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_named_from_the_message_type_and_control_id() {
+        assert_eq!(
+            message_file_name("MSH|^~\\&|App|Fac|||||ADT^A01|MSG/001|P|2.5.1", 1),
+            "ADT_A01-MSG_001"
+        );
+        assert_eq!(
+            message_file_name("MSH|^~\\&|App|Fac|||||ORU^R01", 2),
+            "ORU_R01-2"
+        );
+        assert_eq!(message_file_name("PID|1", 3), "message-3");
+    }
+}
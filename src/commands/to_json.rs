@@ -0,0 +1,229 @@
+use super::CommandResult;
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    escapes::{self, truncation_character},
+    messages::split_messages,
+    workspace::specs::WorkspaceSpecs,
+    Opts,
+};
+use hl7_parser::{
+    message::{Component, Field, Segment},
+    parse_message_with_lenient_newlines, Message,
+};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, Uri};
+use serde_json::{json, Map, Value};
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, workspace_specs, opts))]
+pub fn handle_to_json_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!("Expected 1 argument for to JSON command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let messages = split_messages(text, opts.position_encoding)
+        .into_iter()
+        .map(|message| {
+            let message = parse_message_with_lenient_newlines(message.text)
+                .wrap_err_with(|| "Failed to parse HL7 message")?;
+            let version = opts
+                .message_version(&uri, &message, workspace_specs)
+                .version;
+            Ok(message_json(&message, version))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(CommandResult::ValueResponse {
+        value: Value::Array(messages),
+    }))
+}
+
+/// The message as JSON: its segments, their fields, the repeats of those, and
+/// their components and sub-components, named from the spec and with their
+/// values decoded
+///
+/// Empty fields, components, and sub-components are left out, and the parts
+/// of a repeat are only listed if it has more than one.
+fn message_json(message: &Message, version: &str) -> Value {
+    let segments = message
+        .segments()
+        .map(|segment| segment_json(message, segment, version))
+        .collect::<Vec<_>>();
+    json!({ "version": version, "segments": segments })
+}
+
+fn segment_json(message: &Message, segment: &Segment, version: &str) -> Value {
+    let definition = hl7_definitions::get_segment(version, segment.name);
+    let fields = segment
+        .fields()
+        .enumerate()
+        .filter(|(_, field)| !field.is_empty())
+        .map(|(fi, field)| {
+            let definition = definition.and_then(|definition| definition.fields.get(fi));
+            let mut json = Map::new();
+            json.insert("field".to_string(), json!(fi + 1));
+            if let Some(definition) = definition {
+                json.insert("name".to_string(), json!(definition.description));
+            }
+            // the separators in MSH.1 and MSH.2 aren't values to decode or split
+            if segment.name == "MSH" && fi < 2 {
+                json.insert("value".to_string(), json!(field.raw_value()));
+            } else {
+                let datatype = definition.map(|definition| definition.datatype);
+                json.insert(
+                    "repeats".to_string(),
+                    Value::Array(field_repeats(message, field, datatype, version)),
+                );
+            }
+            Value::Object(json)
+        })
+        .collect::<Vec<_>>();
+
+    let mut json = Map::new();
+    json.insert("segment".to_string(), json!(segment.name));
+    if let Some(definition) = definition {
+        json.insert("name".to_string(), json!(definition.description));
+    }
+    json.insert("fields".to_string(), Value::Array(fields));
+    Value::Object(json)
+}
+
+fn field_repeats(
+    message: &Message,
+    field: &Field,
+    datatype: Option<&str>,
+    version: &str,
+) -> Vec<Value> {
+    let decode =
+        |value: &str| escapes::decode(value, &message.separators, truncation_character(message));
+    let subfields = datatype
+        .and_then(|datatype| hl7_definitions::get_field(version, datatype))
+        .map(|datatype| &datatype.subfields[..])
+        .unwrap_or_default();
+
+    field
+        .repeats()
+        .map(|repeat| {
+            let is_simple = repeat.components().count() <= 1
+                && repeat
+                    .components()
+                    .all(|component| component.subcomponents().count() <= 1);
+            if is_simple {
+                return json!({ "value": decode(repeat.raw_value()) });
+            }
+            let components = repeat
+                .components()
+                .enumerate()
+                .filter(|(_, component)| !component.is_empty())
+                .map(|(ci, component)| {
+                    let definition = subfields.get(ci);
+                    let mut json = Map::new();
+                    json.insert("component".to_string(), json!(ci + 1));
+                    if let Some(definition) = definition {
+                        json.insert("name".to_string(), json!(definition.description));
+                    }
+                    let datatype = definition.map(|definition| definition.datatype);
+                    match sub_components(component, datatype, version, &decode) {
+                        Some(sub_components) => {
+                            json.insert("subcomponents".to_string(), sub_components)
+                        }
+                        None => {
+                            json.insert("value".to_string(), json!(decode(component.raw_value())))
+                        }
+                    };
+                    Value::Object(json)
+                })
+                .collect::<Vec<_>>();
+            json!({ "components": components })
+        })
+        .collect()
+}
+
+/// The sub-components of a component, if it has more than one
+fn sub_components(
+    component: &Component,
+    datatype: Option<&str>,
+    version: &str,
+    decode: &impl Fn(&str) -> String,
+) -> Option<Value> {
+    if component.subcomponents().count() <= 1 {
+        return None;
+    }
+    let subfields = datatype
+        .and_then(|datatype| hl7_definitions::get_field(version, datatype))
+        .map(|datatype| &datatype.subfields[..])
+        .unwrap_or_default();
+    let sub_components = component
+        .subcomponents()
+        .enumerate()
+        .filter(|(_, sub_component)| !sub_component.is_empty())
+        .map(|(si, sub_component)| {
+            let mut json = Map::new();
+            json.insert("subcomponent".to_string(), json!(si + 1));
+            if let Some(definition) = subfields.get(si) {
+                json.insert("name".to_string(), json!(definition.description));
+            }
+            json.insert(
+                "value".to_string(),
+                json!(decode(sub_component.raw_value())),
+            );
+            Value::Object(json)
+        })
+        .collect();
+    Some(Value::Array(sub_components))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_converted_with_names_and_decoded_values() {
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App\rPID|1||123^^^Hosp&1.2.3&ISO||O\\T\\Brien^Mary~Smith",
+        )
+        .unwrap();
+        let json = message_json(&message, "2.5.1");
+
+        assert_eq!(json["version"], "2.5.1");
+        let msh = &json["segments"][0];
+        assert_eq!(msh["segment"], "MSH");
+        assert_eq!(msh["fields"][1]["value"], "^~\\&");
+        assert_eq!(msh["fields"][2]["repeats"][0]["value"], "App");
+
+        let pid = &json["segments"][1];
+        assert_eq!(pid["name"], "Patient Identification");
+        let fields = pid["fields"].as_array().unwrap();
+        assert_eq!(
+            fields
+                .iter()
+                .map(|field| field["field"].as_u64().unwrap())
+                .collect::<Vec<_>>(),
+            vec![1, 3, 5]
+        );
+        assert_eq!(fields[1]["name"], "Patient Identifier List");
+        let authority = &fields[1]["repeats"][0]["components"][1];
+        assert_eq!(authority["component"], 4);
+        assert_eq!(authority["subcomponents"][1]["value"], "1.2.3");
+        let names = fields[2]["repeats"].as_array().unwrap();
+        assert_eq!(names[0]["components"][0]["value"], "O&Brien");
+        assert_eq!(names[1]["value"], "Smith");
+    }
+}
@@ -0,0 +1,142 @@
+use super::{generate_control_id::new_control_id, CommandResult};
+use color_eyre::{
+    eyre::{eyre, Context, ContextCompat},
+    Result,
+};
+use hl7_ls::{
+    messages::split_messages,
+    utils::{range_from_offsets, PositionEncoding},
+    validation::is_batch_file,
+    workspace::snippets::line_ending,
+    Opts, TimeZone,
+};
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, TextEdit, Uri, WorkspaceEdit};
+use std::collections::HashMap;
+use tracing::instrument;
+
+#[instrument(level = "debug", skip(documents, opts))]
+pub fn handle_wrap_in_batch_command(
+    params: ExecuteCommandParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<CommandResult>> {
+    if params.arguments.len() != 1 {
+        return Err(eyre!("Expected 1 argument for wrap in batch command"));
+    }
+
+    let uri: Uri = params.arguments[0]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .wrap_err("Expected uri as first argument")?;
+
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+    if is_batch_file(text) {
+        return Err(eyre!("The document is already a batch file"));
+    }
+
+    let edits = wrap_in_batch(text, opts.output_timezone, opts.position_encoding)?;
+
+    #[allow(clippy::mutable_key_type)]
+    let mut changes: HashMap<Uri, Vec<TextEdit>> = HashMap::new();
+    changes.insert(uri, edits);
+    Ok(Some(CommandResult::WorkspaceEdit {
+        label: "Wrap in batch",
+        edit: WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        },
+    }))
+}
+
+/// Edits that put a file and batch header (FHS and BHS) before the messages
+/// of the document, and their trailers (BTS and FTS) after them
+///
+/// The headers take their separators and sending and receiving applications
+/// and facilities from the first message, and are given the current time (in
+/// the given timezone) and new control IDs.
+fn wrap_in_batch(
+    text: &str,
+    timezone: TimeZone,
+    encoding: PositionEncoding,
+) -> Result<Vec<TextEdit>> {
+    let messages = split_messages(text, encoding);
+    let first = messages.first().wrap_err("The document has no messages")?;
+    let first = parse_message_with_lenient_newlines(first.text)
+        .wrap_err_with(|| "Failed to parse the first message")?;
+
+    let field = first.separators.field;
+    let header_fields = (2..=6)
+        .map(|i| {
+            first
+                .query(&format!("MSH.{i}"))
+                .map(|value| value.raw_value())
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join(&field.to_string());
+    let now: TimeStamp = timezone.now().into();
+    // the date/time is the 7th field and the control ID the 11th
+    let header = |name: &str| {
+        format!(
+            "{name}{field}{header_fields}{field}{now}{field}{field}{field}{field}{control_id}",
+            control_id = new_control_id()
+        )
+    };
+
+    let line_ending = line_ending(text);
+    let headers = format!(
+        "{file}{line_ending}{batch}{line_ending}",
+        file = header("FHS"),
+        batch = header("BHS")
+    );
+    let ends_with_line_break = text.ends_with(['\r', '\n']);
+    let trailers = format!(
+        "{break_before}BTS{field}{count}{line_ending}FTS{field}1{break_after}",
+        break_before = if ends_with_line_break {
+            ""
+        } else {
+            line_ending
+        },
+        count = messages.len(),
+        break_after = if ends_with_line_break {
+            line_ending
+        } else {
+            ""
+        },
+    );
+
+    Ok(vec![
+        TextEdit {
+            range: range_from_offsets(text, 0, 0, encoding),
+            new_text: headers,
+        },
+        TextEdit {
+            range: range_from_offsets(text, text.len(), text.len(), encoding),
+            new_text: trailers,
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_wrapped_in_a_batch_with_their_count() {
+        let text = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102||ADT^A01|1|P|2.5.1\n\nMSH|^~\\&|App|Fac|Rcv|RcvFac|20240102||ADT^A01|2|P|2.5.1\n";
+        let edits = wrap_in_batch(text, TimeZone::Utc, PositionEncoding::Utf16).unwrap();
+
+        let headers = edits[0].new_text.lines().collect::<Vec<_>>();
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].starts_with("FHS|^~\\&|App|Fac|Rcv|RcvFac|"));
+        assert!(headers[1].starts_with("BHS|^~\\&|App|Fac|Rcv|RcvFac|"));
+        assert_eq!(headers[1].split('|').nth(10).unwrap().len(), 20);
+        assert_eq!(edits[1].new_text, "BTS|2\nFTS|1\n");
+        assert_eq!(edits[1].range.start.line, 3);
+    }
+}
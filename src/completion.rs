@@ -1,99 +1,468 @@
 use color_eyre::{eyre::ContextCompat, Result};
-use hl7_parser::parse_message_with_lenient_newlines;
+use hl7_parser::{locate::LocatedCursor, message::Separators, parse_message_with_lenient_newlines};
 use lsp_textdocument::TextDocuments;
-use lsp_types::{CompletionItem, CompletionItemKind, CompletionParams, CompletionResponse};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionParams, CompletionResponse, CompletionTextEdit,
+    Documentation, MarkupContent, MarkupKind, TextEdit, Uri,
+};
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{spec, utils::position_to_offset};
+use hl7_ls::{
+    spec,
+    utils::{line_ranges, position_to_offset, range_from_offsets},
+    workspace::{
+        snippets::{line_ending, Snippet},
+        specs::WorkspaceSpecs,
+    },
+    Opts,
+};
 
-#[instrument(level = "debug", skip(params, documents))]
+/// Data attached to table value completion items so that their documentation
+/// can be looked up when the item is resolved, rather than up front
+#[derive(Debug, Serialize, Deserialize)]
+struct TableValueData {
+    uri: Uri,
+    version: String,
+    segment: String,
+    field: usize,
+    component: Option<usize>,
+    #[serde(default)]
+    sub_component: Option<usize>,
+}
+
+/// The part of a message that completions are offered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompletionLevel {
+    Field,
+    Component,
+    SubComponent,
+}
+
+impl CompletionLevel {
+    /// The level of the position that was just created by typing a separator,
+    /// if the trigger character is one of the message's separators
+    fn from_trigger(separators: &Separators, trigger: char) -> Option<Self> {
+        if trigger == separators.field || trigger == separators.repetition {
+            Some(CompletionLevel::Field)
+        } else if trigger == separators.component {
+            Some(CompletionLevel::Component)
+        } else if trigger == separators.subcomponent {
+            Some(CompletionLevel::SubComponent)
+        } else {
+            None
+        }
+    }
+
+    /// The most specific level at the cursor when completion was invoked
+    /// manually
+    fn from_location(location: &LocatedCursor) -> Self {
+        let has_sub_components = location
+            .component
+            .map(|c| c.1.has_subcomponents())
+            .unwrap_or(false);
+        let has_components = location
+            .repeat
+            .map(|r| r.1.has_components())
+            .unwrap_or(false);
+        if has_sub_components {
+            CompletionLevel::SubComponent
+        } else if has_components {
+            CompletionLevel::Component
+        } else {
+            CompletionLevel::Field
+        }
+    }
+}
+
+#[instrument(level = "debug", skip(params, documents, workspace_specs, opts))]
 pub fn handle_completion_request(
     params: CompletionParams,
     documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
 ) -> Result<CompletionResponse> {
     let uri = params.text_document_position.text_document.uri;
     let text = documents
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
     let position = params.text_document_position.position;
-    let offset = position_to_offset(text, position.line, position.character)
-        .wrap_err_with(|| "Failed to convert position to offset")?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err_with(|| "Failed to convert position to offset")?;
+    let trigger = params
+        .context
+        .and_then(|context| context.trigger_character)
+        .and_then(|trigger| trigger.chars().next());
 
     let mut completions = vec![];
+    let mut message_version = None;
 
     if let Ok(message) = {
         let parse_span = tracing::trace_span!("parse message");
         let _parse_span_guard = parse_span.enter();
         parse_message_with_lenient_newlines(text)
     } {
-        let version = message
-            .query("MSH.12")
-            .map(|v| v.raw_value())
-            .unwrap_or("2.7.1");
+        let version = opts
+            .message_version(&uri, &message, workspace_specs)
+            .version;
+        message_version = Some(version);
+
+        let level = match trigger {
+            Some(trigger) => match CompletionLevel::from_trigger(&message.separators, trigger) {
+                Some(level) => Some(level),
+                None => {
+                    tracing::trace!(?trigger, "trigger character isn't a separator");
+                    return Ok(CompletionResponse::Array(completions));
+                }
+            },
+            None => None,
+        };
 
         if let Some(location) = message.locate_cursor(offset) {
             if let Some((segment_name, _si, _segment)) = location.segment {
                 if let Some((fi, _field)) = location.field {
-                    let has_components = location
-                        .repeat
-                        .map(|r| r.1.has_components())
-                        .unwrap_or(false);
-                    if has_components {
-                        if let Some(table_values) = spec::component_table_values(
-                            version,
-                            segment_name,
-                            fi - 1,
-                            location.component.unwrap().0 - 1,
-                        ) {
-                            tracing::trace!(?table_values, "found component table values");
-                            completions.extend(table_values.into_iter().map(|v| {
-                                let (label, detail) = v;
-                                lsp_types::CompletionItem {
-                                    label,
-                                    label_details: Some(lsp_types::CompletionItemLabelDetails {
-                                        detail,
-                                        description: None,
-                                    }),
-                                    kind: Some(CompletionItemKind::VALUE),
-                                    ..Default::default()
-                                }
-                            }));
-                        } else {
-                            tracing::trace!("no component table values found");
+                    // typing the separators into MSH.1 and MSH.2 doesn't
+                    // create anything to complete
+                    if level.is_some() && segment_name == "MSH" && fi <= 2 {
+                        return Ok(CompletionResponse::Array(completions));
+                    }
+
+                    let level = level.unwrap_or_else(|| CompletionLevel::from_location(&location));
+                    tracing::trace!(?level, "completing");
+                    let component = location.component.map(|c| c.0).unwrap_or(1);
+                    let sub_component = location.sub_component.map(|s| s.0).unwrap_or(1);
+                    let data = TableValueData {
+                        uri: uri.clone(),
+                        version: version.to_string(),
+                        segment: segment_name.to_string(),
+                        field: fi,
+                        component: None,
+                        sub_component: None,
+                    };
+
+                    match level {
+                        CompletionLevel::SubComponent => {
+                            let table = spec::sub_component_table(
+                                version,
+                                segment_name,
+                                fi,
+                                component,
+                                sub_component,
+                            );
+                            if let Some(table_values) =
+                                workspace_table(workspace_specs, &uri, table).or_else(|| {
+                                    spec::sub_component_table_values(
+                                        version,
+                                        segment_name,
+                                        fi - 1,
+                                        component - 1,
+                                        sub_component - 1,
+                                    )
+                                })
+                            {
+                                tracing::trace!(?table_values, "found sub-component table values");
+                                let data = TableValueData {
+                                    component: Some(component),
+                                    sub_component: Some(sub_component),
+                                    ..data
+                                };
+                                completions.extend(table_value_completions(table_values, &data));
+                            } else {
+                                tracing::trace!("no sub-component table values found");
+                            }
                         }
-                    } else if let Some(table_values) =
-                        spec::field_table_values(version, segment_name, fi)
-                    {
-                        tracing::trace!(?table_values, "found field table values");
-                        completions.extend(table_values.into_iter().map(|v| {
-                            let (label, detail) = v;
-
-                            lsp_types::CompletionItem {
-                                label,
-                                label_details: Some(lsp_types::CompletionItemLabelDetails {
-                                    detail,
-                                    description: None,
-                                }),
-                                kind: Some(CompletionItemKind::VALUE),
-                                ..Default::default()
+                        CompletionLevel::Component => {
+                            let table = spec::component_table(version, segment_name, fi, component);
+                            if let Some(table_values) =
+                                workspace_table(workspace_specs, &uri, table).or_else(|| {
+                                    spec::component_table_values(
+                                        version,
+                                        segment_name,
+                                        fi - 1,
+                                        component - 1,
+                                    )
+                                })
+                            {
+                                tracing::trace!(?table_values, "found component table values");
+                                let data = TableValueData {
+                                    component: Some(component),
+                                    ..data
+                                };
+                                completions.extend(table_value_completions(table_values, &data));
+                            } else {
+                                tracing::trace!("no component table values found");
                             }
-                        }));
-                    } else {
-                        tracing::trace!("no field table values found");
+                        }
+                        CompletionLevel::Field => {
+                            let workspace_table_values = workspace_specs
+                                .map(|specs| specs.table_values(&uri, segment_name, fi))
+                                .unwrap_or_default();
+                            if !workspace_table_values.is_empty() {
+                                tracing::trace!(
+                                    ?workspace_table_values,
+                                    "found workspace table values"
+                                );
+                                completions.extend(table_value_completions(
+                                    workspace_table_values
+                                        .into_iter()
+                                        .map(|(code, description)| (code, Some(description)))
+                                        .collect(),
+                                    &data,
+                                ));
+                            } else if let Some(table_values) = workspace_table(
+                                workspace_specs,
+                                &uri,
+                                spec::field_table(version, segment_name, fi),
+                            )
+                            .or_else(|| spec::field_table_values(version, segment_name, fi))
+                            {
+                                tracing::trace!(?table_values, "found field table values");
+                                completions.extend(table_value_completions(table_values, &data));
+                            } else {
+                                tracing::trace!("no field table values found");
+                            }
+                        }
                     }
                 }
             }
         }
     }
 
-    if completions.is_empty() && position.character < 3 {
-        completions.extend(segment_completions("2.7.1"));
+    // segment names are only offered when completion is invoked manually
+    // where a segment name is being typed
+    if trigger.is_none() {
+        if let Some(first_segment) = segment_name_position(text, offset) {
+            let version = message_version.unwrap_or_else(|| {
+                spec::resolve_version(None, opts.fallback_version.as_deref()).version
+            });
+            let segments = segment_completions(version);
+            if first_segment {
+                completions.extend(segments.into_iter().filter(|item| item.label == "MSH"));
+            } else {
+                completions.extend(segments);
+                if let Some(workspace_specs) = workspace_specs {
+                    completions.extend(snippet_completions(
+                        text,
+                        offset,
+                        &workspace_specs.snippets(&uri),
+                        opts,
+                    ));
+                }
+            }
+        }
     }
 
     Ok(CompletionResponse::Array(completions))
 }
 
+/// The values of a table that the workspace defines, which are offered in
+/// place of the standard's
+fn workspace_table(
+    workspace_specs: Option<&WorkspaceSpecs>,
+    uri: &Uri,
+    table: Option<u16>,
+) -> Option<Vec<(String, Option<String>)>> {
+    let table = workspace_specs?.table(uri, table?)?;
+    Some(
+        table
+            .values
+            .into_iter()
+            .map(|(code, description)| (code, Some(description).filter(|d| !d.is_empty())))
+            .collect(),
+    )
+    .filter(|values: &Vec<_>| !values.is_empty())
+}
+
+fn table_value_completions(
+    table_values: Vec<(String, Option<String>)>,
+    data: &TableValueData,
+) -> impl Iterator<Item = CompletionItem> {
+    let data = serde_json::to_value(data).expect("can serialize completion data");
+    table_values
+        .into_iter()
+        .map(move |(label, detail)| CompletionItem {
+            label,
+            label_details: Some(lsp_types::CompletionItemLabelDetails {
+                detail,
+                description: None,
+            }),
+            kind: Some(CompletionItemKind::VALUE),
+            data: Some(data.clone()),
+            ..Default::default()
+        })
+}
+
+/// Fill in the documentation for a table value completion item
+#[instrument(level = "debug", skip(item, workspace_specs))]
+pub fn handle_completion_resolve_request(
+    mut item: CompletionItem,
+    workspace_specs: Option<&WorkspaceSpecs>,
+) -> Result<CompletionItem> {
+    let Some(data) = item.data.as_ref() else {
+        return Ok(item);
+    };
+    let Ok(data) = serde_json::from_value::<TableValueData>(data.clone()) else {
+        return Ok(item);
+    };
+    let TableValueData {
+        uri,
+        version,
+        segment,
+        field,
+        component,
+        sub_component,
+    } = data;
+    let code = item.label.as_str();
+
+    let (path, table) = match (component, sub_component) {
+        (Some(component), Some(sub_component)) => (
+            format!("{segment}.{field}.{component}.{sub_component}"),
+            spec::sub_component_table(&version, &segment, field, component, sub_component),
+        ),
+        (Some(component), None) => (
+            format!("{segment}.{field}.{component}"),
+            spec::component_table(&version, &segment, field, component),
+        ),
+        (None, _) => (
+            format!("{segment}.{field}"),
+            spec::field_table(&version, &segment, field),
+        ),
+    };
+
+    let workspace_description = workspace_specs
+        .filter(|_| component.is_none())
+        .and_then(|specs| {
+            specs
+                .table_values(&uri, &segment, field)
+                .into_iter()
+                .find(|(value, _)| value == code)
+        })
+        .map(|(_, description)| description);
+    let workspace_table = table.and_then(|table| {
+        workspace_specs
+            .and_then(|specs| specs.table(&uri, table))
+            .map(|values| (table, values))
+    });
+    let table_description = match &workspace_table {
+        Some((_, values)) => values.describe(code).map(str::to_string),
+        None => table
+            .and_then(|table| spec::table_value_description(table, code))
+            .map(str::to_string),
+    };
+
+    let mut documentation = format!("**`{code}`**");
+    if let Some(description) = workspace_description
+        .as_deref()
+        .or(table_description.as_deref())
+        .filter(|description| !description.is_empty())
+    {
+        documentation.push_str(format!(": {description}").as_str());
+    }
+    if workspace_description.is_some() {
+        documentation.push_str(format!("\n\nAllowed by the workspace spec for `{path}`").as_str());
+    }
+    if let Some((table, values)) = &workspace_table {
+        documentation.push_str(format!("\n\nWorkspace table {table:04}").as_str());
+        if let Some(description) = &values.description {
+            documentation.push_str(format!(" ({description})").as_str());
+        }
+        documentation.push_str(format!(", used by `{path}`").as_str());
+    } else if let Some(table) = table {
+        documentation.push_str(
+            format!(
+                "\n\nHL7 v{version} table {table:04}, used by `{path}`\n\n[{url}]({url})",
+                url = spec::table_url(&version, table)
+            )
+            .as_str(),
+        );
+    }
+    if let Some(notes) = workspace_specs
+        .filter(|_| component.is_none())
+        .map(|specs| specs.describe_field(&uri, &segment, field))
+        .filter(|notes| !notes.is_empty())
+    {
+        documentation.push_str(format!("\n\n**Workspace notes**:{notes}").as_str());
+    }
+
+    item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value: documentation,
+    }));
+    Ok(item)
+}
+
 #[instrument(level = "trace")]
+/// Whether the offset is where a segment name goes: at the start of a line
+/// (ignoring leading whitespace) with nothing but part of a segment name
+/// before it
+///
+/// Returns whether the segment would be the first in the document, which can
+/// only be the message header.
+fn segment_name_position(text: &str, offset: usize) -> Option<bool> {
+    let line = line_ranges(text).find(|line| line.start <= offset && offset <= line.end)?;
+    let typed = text[line.start..offset].trim_start();
+    if typed.len() > 3 || !typed.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(text[..line.start].trim().is_empty())
+}
+
+/// The workspace's snippets, inserted in place of the segment name being
+/// typed with their Set IDs and timestamps adjusted to fit where they go
+///
+/// Snippets are only offered at the end of a line, so that they don't run into
+/// the rest of it.
+fn snippet_completions(
+    text: &str,
+    offset: usize,
+    snippets: &[(String, Snippet)],
+    opts: &Opts,
+) -> Vec<CompletionItem> {
+    let Some(line) = line_ranges(text).find(|line| line.start <= offset && offset <= line.end)
+    else {
+        return Vec::new();
+    };
+    if !text[offset..line.end].trim().is_empty() {
+        return Vec::new();
+    }
+    let range = range_from_offsets(text, line.start, line.end, opts.position_encoding);
+    snippets
+        .iter()
+        .map(|(name, snippet)| {
+            let segments = snippet.adjust(
+                &text[..line.start],
+                &text[line.end..],
+                line_ending(text),
+                opts.fallback_version.as_deref(),
+                opts.output_timezone,
+            );
+            CompletionItem {
+                label: name.clone(),
+                label_details: Some(lsp_types::CompletionItemLabelDetails {
+                    detail: None,
+                    description: snippet.description.clone(),
+                }),
+                kind: Some(CompletionItemKind::SNIPPET),
+                documentation: Some(Documentation::MarkupContent(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("```hl7\n{}\n```", segments.replace('\r', "\n")),
+                })),
+                filter_text: Some(name.clone()),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                    range,
+                    new_text: segments,
+                })),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
 fn segment_completions(version: &str) -> Vec<CompletionItem> {
     hl7_definitions::get_definition(version)
         .map(|def| {
@@ -108,3 +477,50 @@ fn segment_completions(version: &str) -> Vec<CompletionItem> {
         })
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trigger_levels_follow_the_message_separators() {
+        let message =
+            parse_message_with_lenient_newlines("MSH#$~\\&#sender").expect("can parse message");
+        let separators = message.separators;
+        assert_eq!(
+            CompletionLevel::from_trigger(&separators, '#'),
+            Some(CompletionLevel::Field)
+        );
+        assert_eq!(
+            CompletionLevel::from_trigger(&separators, '~'),
+            Some(CompletionLevel::Field)
+        );
+        assert_eq!(
+            CompletionLevel::from_trigger(&separators, '$'),
+            Some(CompletionLevel::Component)
+        );
+        assert_eq!(
+            CompletionLevel::from_trigger(&separators, '&'),
+            Some(CompletionLevel::SubComponent)
+        );
+        assert_eq!(CompletionLevel::from_trigger(&separators, '|'), None);
+        assert_eq!(CompletionLevel::from_trigger(&separators, '^'), None);
+    }
+
+    #[test]
+    fn segment_names_are_completed_at_the_start_of_segments() {
+        let text = "MSH|^~\\&|App\r  PI\rPV1|1|I\r\r";
+        let at = |needle: &str| text.find(needle).unwrap();
+
+        assert_eq!(segment_name_position(text, 0), Some(true));
+        assert_eq!(segment_name_position(text, 2), Some(true));
+        assert_eq!(segment_name_position(text, at("PI") + 2), Some(false));
+        assert_eq!(segment_name_position(text, at("PV1") + 3), Some(false));
+        assert_eq!(segment_name_position(text, text.len()), Some(false));
+
+        // in the middle of a short segment's content
+        assert_eq!(segment_name_position(text, at("|1|") + 2), None);
+        assert_eq!(segment_name_position(text, at("|I") + 2), None);
+        assert_eq!(segment_name_position(text, at("App") + 1), None);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::cli::MessageSource;
+use color_eyre::{eyre::Context, Result};
+use hl7_ls::{
+    messages::split_messages,
+    spec,
+    workspace::{load_specs, specs::WorkspaceSpecs},
+    Opts,
+};
+use hl7_parser::{parse_message_with_lenient_newlines, Message};
+use lsp_types::Uri;
+use std::fmt::Write;
+
+/// Print each segment of every message and the fields they hold, along with
+/// what each one is
+///
+/// Fields are named by the specs in the current directory where those
+/// describe them, as they would be in a workspace opened there, and by the
+/// HL7 standard otherwise.
+pub fn describe_message(source: &MessageSource, opts: &Opts) -> Result<()> {
+    let (name, uri, text) = source.read()?;
+    let specs = load_specs(&[std::env::current_dir()?], opts)?;
+
+    let mut descriptions = Vec::new();
+    for message in split_messages(&text, opts.position_encoding) {
+        let line = message.line + 1;
+        let message = parse_message_with_lenient_newlines(message.text)
+            .wrap_err_with(|| format!("Failed to parse the message at {name}:{line}"))?;
+        descriptions.push(describe(&uri, &message, &specs, opts));
+    }
+    print!("{}", descriptions.join("\n"));
+    Ok(())
+}
+
+fn describe(uri: &Uri, message: &Message, specs: &WorkspaceSpecs, opts: &Opts) -> String {
+    let version = opts.message_version(uri, message, Some(specs)).version;
+    let mut description = String::new();
+    for segment in message.segments() {
+        let _ = writeln!(
+            description,
+            "{name} ({segment})",
+            name = segment.name,
+            segment = spec::segment_description(version, segment.name),
+        );
+        for (i, field) in segment.fields().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+            let field_name = specs
+                .field_specs(uri, segment.name, i + 1)
+                .into_iter()
+                .find_map(|(_, field_spec)| field_spec.description)
+                .or_else(|| {
+                    hl7_definitions::get_segment(version, segment.name)
+                        .and_then(|s| s.fields.get(i))
+                        .map(|f| f.description.to_string())
+                })
+                .unwrap_or_else(|| "Unknown field".to_string());
+            let _ = writeln!(
+                description,
+                "  {segment}.{field}  {field_name}: {value}",
+                segment = segment.name,
+                field = i + 1,
+                value = field.raw_value(),
+            );
+        }
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn populated_fields_are_named() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let message = parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App||||20240102||ADT^A01|1|P|2.5.1\rPID|1||||Doe^John|",
+        )
+        .unwrap();
+        let specs = WorkspaceSpecs::new(std::iter::empty::<&str>()).unwrap();
+
+        assert_eq!(
+            describe(&uri, &message, &specs, &Opts::default()),
+            "MSH (Message Header)\n\
+             \x20 MSH.1  Field Separator: |\n\
+             \x20 MSH.2  Encoding Characters: ^~\\&\n\
+             \x20 MSH.3  Sending Application: App\n\
+             \x20 MSH.7  Date/Time of Message: 20240102\n\
+             \x20 MSH.9  Message Type: ADT^A01\n\
+             \x20 MSH.10  Message Control ID: 1\n\
+             \x20 MSH.11  Processing ID: P\n\
+             \x20 MSH.12  Version ID: 2.5.1\n\
+             PID (Patient Identification)\n\
+             \x20 PID.1  Set ID - PID: 1\n\
+             \x20 PID.5  Patient Name: Doe^John\n"
+        );
+    }
+
+    #[test]
+    fn messages_from_standard_input_are_described_by_the_current_specs() {
+        let folder = std::env::temp_dir().join(format!("hl7-ls-describe-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(
+            folder.join("spec.hl7v.toml"),
+            "name = \"Local\"\n\n[[segments]]\nname = \"PID\"\n\n\
+             [segments.fields.3]\ndescription = \"Medical Record Number (MRN)\"\n",
+        )
+        .unwrap();
+        let specs = load_specs(std::slice::from_ref(&folder), &Opts::default()).unwrap();
+
+        let uri: Uri = "untitled:stdin".parse().unwrap();
+        let message = parse_message_with_lenient_newlines("MSH|^~\\&|App\rPID|1||123").unwrap();
+        let description = describe(&uri, &message, &specs, &Opts::default());
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert!(description.contains("  PID.3  Medical Record Number (MRN): 123\n"));
+    }
+}
@@ -1,10 +1,9 @@
-use hl7_parser::parser::ParseError;
+use hl7_parser::{parse_message_with_lenient_newlines, parser::ParseError};
 use lsp_server::{Connection, Message, Notification};
-use lsp_types::{
-    notification::Notification as _, Diagnostic, DiagnosticSeverity, Position, Range, Uri,
-};
+use lsp_types::{notification::Notification as _, Diagnostic, Uri};
 
-use crate::utils::position_from_offset;
+use hl7_ls::{utils::range_from_offsets, Opts};
+use std::ops::Range;
 
 pub fn clear_diagnostics(connection: &Connection, uri: Uri) {
     let publish_diagnostics = lsp_types::PublishDiagnosticsParams {
@@ -21,29 +20,105 @@ pub fn clear_diagnostics(connection: &Connection, uri: Uri) {
         .expect("can send diagnostics");
 }
 
-pub fn parse_error_to_diagnostic(text: &str, error: ParseError) -> Diagnostic {
+/// The header standalone segments are parsed behind when the message's own
+/// header can't be used
+const FALLBACK_HEADER: &str = "MSH|^~\\&";
+
+/// Diagnose the parse error, along with every later line that would fail to
+/// parse on its own so that a broken document can be fixed in one go rather
+/// than one error at a time
+pub fn parse_error_diagnostics(text: &str, error: ParseError, opts: &Opts) -> Vec<Diagnostic> {
+    let failed_at = error_offset(text, &error);
+    let mut diagnostics = vec![parse_error_to_diagnostic(text, error, opts)];
+    diagnostics.extend(
+        recover_parse_errors(text, failed_at)
+            .into_iter()
+            .map(|error| parse_error_to_diagnostic(text, error, opts)),
+    );
+    diagnostics
+}
+
+pub fn parse_error_to_diagnostic(text: &str, error: ParseError, opts: &Opts) -> Diagnostic {
     let message = error.to_string();
-    let pos = match error {
-        ParseError::FailedToParse {
-            position: offset, ..
-        } => position_from_offset(text, offset),
-        ParseError::IncompleteInput(_) => position_from_offset(text, text.len()),
-    };
+    let offset = error_offset(text, &error);
+    // highlight the character the error is at, however wide it is
+    let end = offset
+        + text
+            .get(offset..)
+            .and_then(|rest| rest.chars().next())
+            .map(char::len_utf8)
+            .unwrap_or(1);
 
     Diagnostic {
-        range: Range {
-            start: pos,
-            end: Position {
-                line: pos.line,
-                character: pos.character + 1,
-            },
-        },
-        severity: Some(DiagnosticSeverity::ERROR),
+        range: range_from_offsets(text, offset, end, opts.position_encoding),
+        severity: Some(opts.parse_error_severity.into()),
         message,
         ..Default::default()
     }
 }
 
+fn error_offset(text: &str, error: &ParseError) -> usize {
+    match error {
+        ParseError::FailedToParse {
+            position: offset, ..
+        } => *offset,
+        ParseError::IncompleteInput(_) => text.len(),
+    }
+}
+
+/// Parse every line after the one the parser gave up on as a standalone
+/// segment, returning the errors with their positions in the whole document
+///
+/// Incomplete lines are reported as failing at their end rather than at the
+/// end of the document.
+fn recover_parse_errors(text: &str, failed_at: usize) -> Vec<ParseError> {
+    let lines = segment_lines(text);
+    let header = lines
+        .first()
+        .map(|line| &text[line.clone()])
+        .filter(|line| line.starts_with("MSH") && parse_message_with_lenient_newlines(line).is_ok())
+        .unwrap_or(FALLBACK_HEADER);
+
+    lines
+        .into_iter()
+        .filter(|line| line.start > failed_at && !line.is_empty())
+        .filter_map(|line| {
+            let segment = &text[line.clone()];
+            let (standalone, prefix) = if segment.starts_with("MSH") {
+                (segment.to_string(), 0)
+            } else {
+                (format!("{header}\r{segment}"), header.len() + 1)
+            };
+            let error = parse_message_with_lenient_newlines(&standalone).err()?;
+            tracing::trace!(?line, "recovered parse error: {error}");
+            Some(match error {
+                ParseError::FailedToParse { context, position } => ParseError::FailedToParse {
+                    context,
+                    position: line.start + position.saturating_sub(prefix).min(segment.len()),
+                },
+                ParseError::IncompleteInput(_) => ParseError::FailedToParse {
+                    context: "incomplete segment".to_string(),
+                    position: line.end,
+                },
+            })
+        })
+        .collect()
+}
+
+/// The byte ranges of every segment in the text, not including terminators
+fn segment_lines(text: &str) -> Vec<Range<usize>> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '\r' || c == '\n' {
+            lines.push(start..i);
+            start = i + 1;
+        }
+    }
+    lines.push(start..text.len());
+    lines
+}
+
 pub fn publish_parse_error_diagnostics(
     connection: &Connection,
     uri: Uri,
@@ -63,3 +138,25 @@ pub fn publish_parse_error_diagnostics(
         )))
         .expect("can send diagnostics");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_after_the_first_are_recovered() {
+        let text = "MSH|^~\\&|App\rPID|1\rX\rPV1|1\r\nY\rOBX|1";
+        let Err(error) = parse_message_with_lenient_newlines(text) else {
+            panic!("message should fail to parse");
+        };
+        let failed_at = error_offset(text, &error);
+        assert_eq!(&text[failed_at..failed_at + 1], "X");
+
+        let recovered = recover_parse_errors(text, failed_at)
+            .iter()
+            .map(|error| error_offset(text, error))
+            .collect::<Vec<_>>();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(&text[recovered[0]..recovered[0] + 1], "Y");
+    }
+}
@@ -1,5 +1,13 @@
-use crate::{spec, utils::std_range_to_lsp_range};
+use crate::cancellation::CancellationToken;
 use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    messages::{split_messages, DocumentMessage},
+    spec,
+    utils::{std_range_to_lsp_range, PositionEncoding},
+    validation::{observations, Observation},
+    workspace::specs::WorkspaceSpecs,
+    Opts,
+};
 use hl7_parser::{
     message::{Field, Repeat, Segment},
     Message,
@@ -8,72 +16,210 @@ use lsp_textdocument::TextDocuments;
 use lsp_types::{DocumentSymbol, DocumentSymbolParams, SymbolKind};
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(params, documents))]
+#[instrument(
+    level = "debug",
+    skip(params, documents, workspace_specs, opts, cancel)
+)]
 pub fn handle_document_symbols_request(
     params: DocumentSymbolParams,
     documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+    cancel: &CancellationToken,
 ) -> Result<Vec<DocumentSymbol>> {
     let uri = params.text_document.uri;
     let text = documents
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {uri:?}"))?;
 
-    let parse_span = tracing::trace_span!("parse message");
-    let _parse_span_guard = parse_span.enter();
-    let message = match hl7_parser::parse_message_with_lenient_newlines(text) {
-        Ok(message) => message,
-        Err(e) => {
-            tracing::debug!(error = %e, "Failed to parse message");
-            return Ok(Vec::new());
+    let messages = split_messages(text, opts.position_encoding);
+    let several = messages.len() > 1;
+    let mut symbols = Vec::new();
+    for (mi, document_message) in messages.iter().enumerate() {
+        let parse_span = tracing::trace_span!("parse message");
+        let _parse_span_guard = parse_span.enter();
+        let message = match hl7_parser::parse_message_with_lenient_newlines(document_message.text) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::debug!(error = %e, "Failed to parse message");
+                continue;
+            }
+        };
+        drop(_parse_span_guard);
+
+        let version = opts
+            .message_version(&uri, &message, workspace_specs)
+            .version;
+        let segments = segment_symbols(
+            version,
+            &message,
+            document_message.text,
+            opts.position_encoding,
+            cancel,
+        )?
+        .into_iter()
+        .map(|symbol| move_symbol(symbol, document_message))
+        .collect();
+
+        // documents with several messages list each message's segments under
+        // it, so that the messages can be told apart
+        if several {
+            symbols.push(message_symbol(
+                mi,
+                &message,
+                document_message,
+                segments,
+                text,
+                opts.position_encoding,
+            ));
+        } else {
+            symbols.extend(segments);
         }
-    };
-    drop(_parse_span_guard);
-
-    let mut version = message
-        .query("MSH.12")
-        .map(|v| v.raw_value())
-        .unwrap_or("2.7.1");
-    if !spec::is_valid_version(version) {
-        version = "2.7.1";
     }
+    Ok(symbols)
+}
 
-    Ok(segment_symbols(version, &message, text))
+/// A symbol for one of several messages in a document, named by its position
+/// in the document and described by its type and control ID
+fn message_symbol(
+    index: usize,
+    message: &Message,
+    document_message: &DocumentMessage,
+    children: Vec<DocumentSymbol>,
+    text: &str,
+    encoding: PositionEncoding,
+) -> DocumentSymbol {
+    let range = std_range_to_lsp_range(text, document_message.range.clone(), encoding);
+    let detail = ["MSH.9", "MSH.10"]
+        .into_iter()
+        .filter_map(|path| message.query(path).map(|value| value.raw_value()))
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name: format!("Message {number}", number = index + 1),
+        detail: (!detail.is_empty()).then(|| detail.join(" ")),
+        kind: SymbolKind::MODULE,
+        tags: None,
+        range,
+        selection_range: range,
+        children: Some(children),
+        deprecated: None,
+    }
+}
+
+/// Move a symbol (and its children) from the message it was found in to
+/// where the message is in the document
+fn move_symbol(symbol: DocumentSymbol, document_message: &DocumentMessage) -> DocumentSymbol {
+    DocumentSymbol {
+        range: document_message.range_in_document(symbol.range),
+        selection_range: document_message.range_in_document(symbol.selection_range),
+        children: symbol.children.map(|children| {
+            children
+                .into_iter()
+                .map(|child| move_symbol(child, document_message))
+                .collect()
+        }),
+        ..symbol
+    }
 }
 
-#[instrument(level = "trace", skip(msg, text))]
-fn segment_symbols(version: &str, msg: &Message, text: &str) -> Vec<DocumentSymbol> {
+#[instrument(level = "trace", skip(msg, text, cancel))]
+fn segment_symbols(
+    version: &str,
+    msg: &Message,
+    text: &str,
+    encoding: PositionEncoding,
+    cancel: &CancellationToken,
+) -> Result<Vec<DocumentSymbol>> {
+    let observations = observations(msg);
     let mut symbols = Vec::new();
     for segment in msg.segments() {
-        let name = segment.name.to_string();
-        let range = std_range_to_lsp_range(text, segment.range.clone());
+        cancel.check()?;
 
-        let detail = hl7_definitions::get_segment(version, name.as_str())
-            .map(|def| def.description.to_string());
+        // the parts of an observation split across OBX segments are listed
+        // together, under the first of them
+        if let Some(observation) = observations.iter().find(|o| o.part_of(segment).is_some()) {
+            if observation.part_of(segment) == Some(0) {
+                let children = observation
+                    .parts
+                    .iter()
+                    .map(|part| segment_symbol(version, part.segment, text, encoding))
+                    .collect();
+                symbols.push(observation_symbol(observation, children, text, encoding));
+            }
+            continue;
+        }
 
-        #[allow(deprecated)]
-        let symbol = DocumentSymbol {
-            name,
-            detail,
-            kind: SymbolKind::CLASS,
-            tags: None,
-            range,
-            selection_range: range,
-            children: Some(field_symbols(version, segment, text)),
-            deprecated: None,
-        };
-        symbols.push(symbol);
+        symbols.push(segment_symbol(version, segment, text, encoding));
     }
 
-    symbols
+    Ok(symbols)
+}
+
+fn segment_symbol(
+    version: &str,
+    segment: &Segment,
+    text: &str,
+    encoding: PositionEncoding,
+) -> DocumentSymbol {
+    let name = segment.name.to_string();
+    let range = std_range_to_lsp_range(text, segment.range.clone(), encoding);
+
+    let detail =
+        hl7_definitions::get_segment(version, name.as_str()).map(|def| def.description.to_string());
+
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name,
+        detail,
+        kind: SymbolKind::CLASS,
+        tags: None,
+        range,
+        selection_range: range,
+        children: Some(field_symbols(version, segment, text, encoding)),
+        deprecated: None,
+    }
+}
+
+fn observation_symbol(
+    observation: &Observation,
+    children: Vec<DocumentSymbol>,
+    text: &str,
+    encoding: PositionEncoding,
+) -> DocumentSymbol {
+    let range = std_range_to_lsp_range(text, observation.range(), encoding);
+    let parts = format!("{} parts", observation.parts.len());
+
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name: format!("OBX {identifier}", identifier = observation.identifier),
+        detail: Some(match observation.text {
+            Some(text) => format!("{text} ({parts})"),
+            None => parts,
+        }),
+        kind: SymbolKind::ARRAY,
+        tags: None,
+        range,
+        selection_range: range,
+        children: Some(children),
+        deprecated: None,
+    }
 }
 
 #[instrument(level = "trace", skip(version, segment, text))]
-fn field_symbols(version: &str, segment: &Segment, text: &str) -> Vec<DocumentSymbol> {
+fn field_symbols(
+    version: &str,
+    segment: &Segment,
+    text: &str,
+    encoding: PositionEncoding,
+) -> Vec<DocumentSymbol> {
     let mut symbols = Vec::new();
 
     for (i, field) in segment.fields().enumerate() {
         let name = format!("{segment}.{field}", segment = segment.name, field = i + 1);
-        let range = std_range_to_lsp_range(text, field.range.clone());
+        let range = std_range_to_lsp_range(text, field.range.clone(), encoding);
 
         let detail = hl7_definitions::get_segment(version, segment.name)
             .and_then(|seg| seg.fields.get(i))
@@ -87,7 +233,7 @@ fn field_symbols(version: &str, segment: &Segment, text: &str) -> Vec<DocumentSy
             tags: None,
             range,
             selection_range: range,
-            children: repeat_symbols(version, segment, (i, field), text),
+            children: repeat_symbols(version, segment, (i, field), text, encoding),
             deprecated: None,
         };
         symbols.push(symbol);
@@ -102,12 +248,19 @@ fn repeat_symbols(
     segment: &Segment,
     field: (usize, &Field),
     text: &str,
+    encoding: PositionEncoding,
 ) -> Option<Vec<DocumentSymbol>> {
     match field.1.repeats.len() {
         0 => None,
         1 => {
-            let c_symbols =
-                component_symbols(version, segment, field, (None, &field.1.repeats[0]), text);
+            let c_symbols = component_symbols(
+                version,
+                segment,
+                field,
+                (None, &field.1.repeats[0]),
+                text,
+                encoding,
+            );
             if c_symbols.is_empty() {
                 None
             } else {
@@ -120,16 +273,27 @@ fn repeat_symbols(
                 .repeats()
                 .enumerate()
                 .map(|(ri, repeat)| {
-                    let name = format!(
+                    let mut name = format!(
                         "{segment}.{field}[{repeat}]",
                         segment = segment.name,
                         field = field.0 + 1,
                         repeat = ri + 1
                     );
-                    let range = std_range_to_lsp_range(text, repeat.range.clone());
+                    if let Some(label) =
+                        spec::repeat_label(version, segment.name, field.0 + 1, repeat)
+                    {
+                        name.push_str(&format!(" ({label})"));
+                    }
+                    let range = std_range_to_lsp_range(text, repeat.range.clone(), encoding);
 
-                    let c_symbols =
-                        component_symbols(version, segment, field, (Some(ri), repeat), text);
+                    let c_symbols = component_symbols(
+                        version,
+                        segment,
+                        field,
+                        (Some(ri), repeat),
+                        text,
+                        encoding,
+                    );
 
                     #[allow(deprecated)]
                     DocumentSymbol {
@@ -159,6 +323,7 @@ fn component_symbols(
     field: (usize, &Field),
     repeat: (Option<usize>, &Repeat),
     text: &str,
+    encoding: PositionEncoding,
 ) -> Vec<DocumentSymbol> {
     repeat
         .1
@@ -176,7 +341,7 @@ fn component_symbols(
                 repeat = repeat_name,
                 component = ci + 1
             );
-            let range = std_range_to_lsp_range(text, component.range.clone());
+            let range = std_range_to_lsp_range(text, component.range.clone(), encoding);
 
             let detail = hl7_definitions::get_segment(version, segment.name)
                 .and_then(|seg| seg.fields.get(field.0))
@@ -0,0 +1,74 @@
+use crate::acknowledgements::document_name;
+use hl7_ls::{
+    utils::{file_path, file_uri},
+    validation::ValidationCode,
+    workspace::{control_ids::ControlIds, specs::WorkspaceSpecs},
+    Opts, Severity,
+};
+use lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location, Uri};
+use tracing::instrument;
+
+/// Index a document's control IDs as it is now, and report those that are
+/// used by other messages in it or elsewhere in the workspace, pointing at
+/// each of the other messages
+#[instrument(level = "debug", skip(text, control_ids, workspace_specs, opts))]
+pub fn duplicate_control_id_diagnostics(
+    uri: &Uri,
+    text: &str,
+    control_ids: &ControlIds,
+    workspace_specs: &WorkspaceSpecs,
+    opts: &Opts,
+) -> Vec<Diagnostic> {
+    let Some(path) = file_path(uri) else {
+        return Vec::new();
+    };
+    control_ids.update(&path, text);
+
+    let code = ValidationCode::ControlId;
+    let mut overrides = opts.severity_overrides.clone();
+    overrides.extend(workspace_specs.severity_overrides(uri));
+    let severity = match overrides.get(code.key()) {
+        Some(severity) => severity.severity(),
+        None => Some(Severity::Warning),
+    };
+    let Some(severity) = severity else {
+        return Vec::new();
+    };
+
+    control_ids
+        .duplicates(&path)
+        .into_iter()
+        .map(|(control_id, others)| {
+            let related_information = others
+                .iter()
+                .filter_map(|(other, range)| {
+                    Some(DiagnosticRelatedInformation {
+                        location: Location {
+                            uri: file_uri(other).ok()?,
+                            range: *range,
+                        },
+                        message: "Message with the same control ID".to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut files = others
+                .iter()
+                .filter_map(|(other, _)| file_uri(other).ok())
+                .map(|other| document_name(&other))
+                .collect::<Vec<_>>();
+            files.dedup();
+            Diagnostic {
+                range: control_id.range,
+                severity: Some(severity.into()),
+                code: Some(lsp_types::NumberOrString::String(code.to_string())),
+                message: format!(
+                    "Control ID `{id}` is also used in {files}, so the message may be dropped as a duplicate",
+                    id = control_id.id,
+                    files = files.join(", ")
+                ),
+                related_information: Some(related_information),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,479 @@
+//! Escape sequences that encode characters (`\Xhh..\`) or switch character
+//! sets (`\Cxxyy\` and `\Mxxyyzz\`), which the parser leaves as they are, and
+//! the truncation character (`\P\`), which the parser doesn't know about
+//!
+//! Also finds observation values that were pasted in with line breaks, which
+//! need escaping to be part of the message.
+
+use crate::utils::line_ranges;
+use hl7_parser::{message::Separators, Message};
+use std::{fmt, ops::Range};
+
+/// A character set a message can declare in MSH-18
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Charset {
+    /// 7-bit ASCII, the default when MSH-18 is empty
+    Ascii,
+    /// ISO 8859-1
+    Latin1,
+    Utf8,
+    /// UTF-16, big-endian
+    Utf16,
+    /// A character set that escapes can't be checked against
+    Unsupported(String),
+}
+
+impl Charset {
+    /// The default character set declared in the first repeat of MSH-18
+    pub fn declared(message: &Message) -> Self {
+        let declared = message
+            .query("MSH.18[1]")
+            .map(|charset| charset.raw_value())
+            .unwrap_or_default();
+        match declared {
+            "" | "ASCII" => Charset::Ascii,
+            "8859/1" => Charset::Latin1,
+            "UNICODE UTF-8" => Charset::Utf8,
+            "UNICODE" | "UNICODE UTF-16" => Charset::Utf16,
+            other => Charset::Unsupported(other.to_string()),
+        }
+    }
+
+    /// Decode bytes in this character set, or return why they can't be
+    ///
+    /// Returns `Ok(None)` if the character set isn't supported.
+    pub fn decode(&self, bytes: &[u8]) -> Result<Option<String>, String> {
+        match self {
+            Charset::Ascii => {
+                if let Some(byte) = bytes.iter().find(|b| !b.is_ascii()) {
+                    return Err(format!("byte {byte:02X} isn't {self}"));
+                }
+                Ok(Some(bytes.iter().map(|&b| char::from(b)).collect()))
+            }
+            Charset::Latin1 => Ok(Some(bytes.iter().map(|&b| char::from(b)).collect())),
+            Charset::Utf8 => String::from_utf8(bytes.to_vec())
+                .map(Some)
+                .map_err(|_| format!("bytes aren't valid {self}")),
+            Charset::Utf16 => {
+                if !bytes.len().is_multiple_of(2) {
+                    return Err(format!("an odd number of bytes can't be {self}"));
+                }
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                    .collect::<Vec<_>>();
+                String::from_utf16(&units)
+                    .map(Some)
+                    .map_err(|_| format!("bytes aren't valid {self}"))
+            }
+            Charset::Unsupported(_) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Charset::Ascii => write!(f, "ASCII"),
+            Charset::Latin1 => write!(f, "8859/1"),
+            Charset::Utf8 => write!(f, "UNICODE UTF-8"),
+            Charset::Utf16 => write!(f, "UNICODE UTF-16"),
+            Charset::Unsupported(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Single-byte character sets, by the ISO 2022 escape that `\Cxxyy\`
+/// switches to them with
+const SINGLE_BYTE_CHARSETS: &[(&str, &str)] = &[
+    ("2842", "ASCII (ISO-IR6)"),
+    ("2D41", "ISO 8859-1 (ISO-IR100)"),
+    ("2D42", "ISO 8859-2 (ISO-IR101)"),
+    ("2D43", "ISO 8859-3 (ISO-IR109)"),
+    ("2D44", "ISO 8859-4 (ISO-IR110)"),
+    ("2D4C", "ISO 8859-5 (ISO-IR144)"),
+    ("2D47", "ISO 8859-6 (ISO-IR127)"),
+    ("2D46", "ISO 8859-7 (ISO-IR126)"),
+    ("2D48", "ISO 8859-8 (ISO-IR138)"),
+    ("2D4D", "ISO 8859-9 (ISO-IR148)"),
+    ("284A", "JIS X 0201 Romaji (ISO-IR14)"),
+    ("2949", "JIS X 0201 Katakana (ISO-IR13)"),
+];
+
+/// Multi-byte character sets, by the ISO 2022 escape that `\Mxxyyzz\` (or
+/// `\Mxxyy\`) switches to them with
+const MULTI_BYTE_CHARSETS: &[(&str, &str)] = &[
+    ("2442", "JIS X 0208 (ISO-IR87)"),
+    ("242842", "JIS X 0208 (ISO-IR87)"),
+    ("242844", "JIS X 0212 (ISO-IR159)"),
+    ("242841", "GB 2312 (ISO-IR58)"),
+    ("242943", "KS X 1001 (ISO-IR149)"),
+];
+
+/// What an escape sequence stands for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    /// The characters encoded by a `\X..\` sequence
+    Characters(String),
+    /// The characters can't be checked as the charset isn't supported
+    Undecodable,
+    /// The character set switched to by a `\C..\` or `\M..\` sequence
+    Charset(&'static str),
+    /// Why the sequence doesn't decode
+    Invalid(String),
+}
+
+/// A hex or character set escape sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escape<'s> {
+    /// The sequence, including its escape characters
+    pub sequence: &'s str,
+    /// Where the sequence is in the text it was found in
+    pub range: Range<usize>,
+    pub decoded: Decoded,
+}
+
+/// Find the hex and character set escape sequences in the text, decoding hex
+/// data with the given character set
+///
+/// Escape sequences can't span separators, so the text may be a whole
+/// segment; MSH-1 and MSH-2 are skipped as they hold the escape character
+/// itself.
+pub fn find_escapes<'s>(
+    text: &'s str,
+    separators: &Separators,
+    charset: &Charset,
+) -> Vec<Escape<'s>> {
+    escape_sequences(text, separators)
+        .into_iter()
+        .filter_map(|range| {
+            let content = &text[range.start + separators.escape.len_utf8()
+                ..range.end - separators.escape.len_utf8()];
+            let decoded = match content.chars().next() {
+                Some('X') => decode_hex(&content[1..], charset),
+                Some('C') => named_charset(&content[1..], SINGLE_BYTE_CHARSETS),
+                Some('M') => named_charset(&content[1..], MULTI_BYTE_CHARSETS),
+                _ => return None,
+            };
+            Some(Escape {
+                sequence: &text[range.clone()],
+                range,
+                decoded,
+            })
+        })
+        .collect()
+}
+
+/// Where the escape sequences of any kind (e.g. `\F\` or `\X0D\`) are in the
+/// text, including their escape characters
+///
+/// As for [find_escapes], the text may be a whole segment.
+pub fn escape_sequences(text: &str, separators: &Separators) -> Vec<Range<usize>> {
+    let is_separator = |c: char| {
+        c == separators.field
+            || c == separators.repetition
+            || c == separators.component
+            || c == separators.subcomponent
+    };
+    let mut offset = if text.starts_with("MSH") {
+        text.match_indices(separators.field)
+            .nth(1)
+            .map(|(i, _)| i)
+            .unwrap_or(text.len())
+    } else {
+        0
+    };
+
+    let mut sequences = Vec::new();
+    while let Some(start) = text[offset..].find(separators.escape).map(|i| offset + i) {
+        let content_start = start + separators.escape.len_utf8();
+        let Some(end) = text[content_start..]
+            .find(separators.escape)
+            .map(|i| content_start + i)
+        else {
+            break;
+        };
+        let content = &text[content_start..end];
+        if content.contains(is_separator) {
+            // not a sequence, just a stray escape character
+            offset = content_start;
+            continue;
+        }
+        offset = end + separators.escape.len_utf8();
+        sequences.push(start..offset);
+    }
+    sequences
+}
+
+fn decode_hex(hex: &str, charset: &Charset) -> Decoded {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Decoded::Invalid("expected an even number of hex digits".to_string());
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("is hex"))
+        .collect::<Vec<_>>();
+    match charset.decode(&bytes) {
+        Ok(Some(characters)) => Decoded::Characters(characters),
+        Ok(None) => Decoded::Undecodable,
+        Err(problem) => Decoded::Invalid(problem),
+    }
+}
+
+/// The truncation character declared after the encoding characters in MSH-2
+/// (e.g. the `#` of `^~\&#`), which v2.7 added to mark values that were cut
+/// short to their conformance length
+pub fn truncation_character(message: &Message) -> Option<char> {
+    let msh = message.segment("MSH")?;
+    let field_separator = msh.raw_value().get(3..)?.chars().next()?;
+    msh.raw_value()[3 + field_separator.len_utf8()..]
+        .chars()
+        .take_while(|&c| c != field_separator && c != '\r' && c != '\n')
+        .nth(4)
+}
+
+/// Escape the separators in the text (and the truncation character, as
+/// `\P\`, if the message declares one)
+pub fn encode(text: &str, separators: &Separators, truncation: Option<char>) -> String {
+    let encoded = separators.encode(text).to_string();
+    match truncation {
+        Some(truncation) => {
+            let escape = separators.escape;
+            encoded.replace(truncation, &format!("{escape}P{escape}"))
+        }
+        None => encoded,
+    }
+}
+
+/// Unescape the separators in the text (and `\P\`, as the truncation
+/// character, if the message declares one)
+pub fn decode(text: &str, separators: &Separators, truncation: Option<char>) -> String {
+    let Some(truncation) = truncation else {
+        return separators.decode(text).to_string();
+    };
+    let escape = separators.escape;
+    let mut decoded = String::new();
+    // the text between `\P\` sequences is decoded as usual, which can't split
+    // any other sequence as the sequences are found in order
+    let (mut offset, mut undecoded) = (0, 0);
+    while let Some(start) = text[offset..].find(escape).map(|i| offset + i) {
+        let content_start = start + escape.len_utf8();
+        let Some(end) = text[content_start..]
+            .find(escape)
+            .map(|i| content_start + i)
+        else {
+            break;
+        };
+        offset = end + escape.len_utf8();
+        if &text[content_start..end] == "P" {
+            decoded.push_str(&separators.decode(&text[undecoded..start]).to_string());
+            decoded.push(truncation);
+            undecoded = offset;
+        }
+    }
+    decoded.push_str(&separators.decode(&text[undecoded..]).to_string());
+    decoded
+}
+
+/// An observation value (OBX-5) that runs over several lines, as happens when
+/// report text is pasted in, with the two ways of keeping it in one segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenObservation {
+    /// The range of the value in the message, up to the fields that follow
+    /// it on its last line
+    pub range: Range<usize>,
+    /// The value with its line breaks and separators escaped (as `\.br\` for
+    /// formatted text, and as their hex codes otherwise)
+    pub escaped: String,
+    /// The value with each line as a repeat, and its separators escaped
+    pub repeats: String,
+}
+
+/// Find the observation values in the text of a message that are followed by
+/// lines that aren't segments, which will be the rest of the value
+///
+/// This works on the text rather than the parsed message, as the lines that
+/// were pasted in usually stop the message from being parsed at all.
+pub fn broken_observations(text: &str) -> Vec<BrokenObservation> {
+    let separators = header_separators(text);
+    let lines = line_ranges(text).collect::<Vec<_>>();
+    let mut broken = Vec::new();
+    let mut li = 0;
+    while li < lines.len() {
+        let line = &text[lines[li].clone()];
+        let continued = lines[li + 1..]
+            .iter()
+            .take_while(|next| !is_segment(&text[(*next).clone()], separators.field))
+            .count();
+        let value_start = line
+            .starts_with("OBX")
+            .then(|| line.match_indices(separators.field).nth(4))
+            .flatten()
+            .map(|(i, _)| lines[li].start + i + separators.field.len_utf8());
+        let (Some(value_start), true) = (value_start, continued > 0) else {
+            li += 1;
+            continue;
+        };
+
+        // the value stops where the fields after it pick up on its last line
+        let last = &lines[li + continued];
+        let value_end = text[last.clone()]
+            .find(separators.field)
+            .map_or(last.end, |i| last.start + i);
+        let is_formatted = line.split(separators.field).nth(2) == Some("FT");
+        let value = &text[value_start..value_end];
+
+        let mut parts = Vec::new();
+        let mut breaks = Vec::new();
+        let mut part_start = 0;
+        let mut rest = value;
+        while let Some(i) = rest.find(['\r', '\n']) {
+            let len = if rest[i..].starts_with("\r\n") { 2 } else { 1 };
+            let at = value.len() - rest.len() + i;
+            parts.push(&value[part_start..at]);
+            breaks.push(&value[at..at + len]);
+            part_start = at + len;
+            rest = &value[part_start..];
+        }
+        parts.push(&value[part_start..]);
+
+        let escape = separators.escape;
+        let mut escaped = encode(parts[0], &separators, None);
+        for (line_break, part) in breaks.iter().zip(&parts[1..]) {
+            match is_formatted {
+                true => escaped.push_str(&format!("{escape}.br{escape}")),
+                false => {
+                    let hex = line_break
+                        .bytes()
+                        .map(|b| format!("{b:02X}"))
+                        .collect::<String>();
+                    escaped.push_str(&format!("{escape}X{hex}{escape}"));
+                }
+            }
+            escaped.push_str(&encode(part, &separators, None));
+        }
+        let repeats = parts
+            .iter()
+            .map(|part| encode(part, &separators, None))
+            .collect::<Vec<_>>()
+            .join(&separators.repetition.to_string());
+
+        broken.push(BrokenObservation {
+            range: value_start..value_end,
+            escaped,
+            repeats,
+        });
+        li += continued + 1;
+    }
+    broken
+}
+
+/// The separators declared by the message header at the start of the text,
+/// or the defaults if it doesn't have one
+fn header_separators(text: &str) -> Separators {
+    let mut separators = Separators::default();
+    let mut declared = text.strip_prefix("MSH").unwrap_or_default().chars();
+    if let Some(field) = declared.next() {
+        separators.field = field;
+    }
+    let mut encoding = declared.take_while(|&c| c != separators.field && c != '\r' && c != '\n');
+    let defaults = separators;
+    separators.component = encoding.next().unwrap_or(defaults.component);
+    separators.repetition = encoding.next().unwrap_or(defaults.repetition);
+    separators.escape = encoding.next().unwrap_or(defaults.escape);
+    separators.subcomponent = encoding.next().unwrap_or(defaults.subcomponent);
+    separators
+}
+
+/// Whether a line starts with a segment name known to any version of the
+/// standard (or a Z-segment's), followed by a field separator
+fn is_segment(line: &str, field_separator: char) -> bool {
+    let Some(name) = line.get(..3) else {
+        return false;
+    };
+    let is_name = name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    let is_known = name.starts_with('Z')
+        || hl7_definitions::VERSIONS
+            .iter()
+            .any(|version| hl7_definitions::get_segment(version, name).is_some());
+    let rest = &line[3..];
+    is_name && is_known && (rest.is_empty() || rest.starts_with(field_separator))
+}
+
+fn named_charset(code: &str, charsets: &[(&str, &'static str)]) -> Decoded {
+    charsets
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(code))
+        .map(|(_, name)| Decoded::Charset(name))
+        .unwrap_or_else(|| Decoded::Invalid(format!("unknown character set `{code}`")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_are_decoded_with_the_declared_charset() {
+        let separators = Separators::default();
+        let text = "OBX|1|TX|||caf\\XC3A9\\^\\C2D41\\x\\Mzz\\|\\F\\|a\\b|c\\";
+
+        let escapes = find_escapes(text, &separators, &Charset::Utf8);
+        let decoded = escapes
+            .iter()
+            .map(|e| (e.sequence, e.decoded.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decoded,
+            vec![
+                ("\\XC3A9\\", Decoded::Characters("é".to_string())),
+                ("\\C2D41\\", Decoded::Charset("ISO 8859-1 (ISO-IR100)")),
+                (
+                    "\\Mzz\\",
+                    Decoded::Invalid("unknown character set `zz`".to_string())
+                ),
+            ]
+        );
+        assert_eq!(&text[escapes[0].range.clone()], "\\XC3A9\\");
+
+        let escapes = find_escapes("NTE|1||\\XC3A9\\\\X4\\", &separators, &Charset::Ascii);
+        assert_eq!(
+            escapes[0].decoded,
+            Decoded::Invalid("byte C3 isn't ASCII".to_string())
+        );
+        assert_eq!(
+            escapes[1].decoded,
+            Decoded::Invalid("expected an even number of hex digits".to_string())
+        );
+
+        // the escape character in MSH-2 doesn't start a sequence
+        assert_eq!(
+            find_escapes("MSH|^~\\&|A\\X41\\", &separators, &Charset::Ascii).len(),
+            1
+        );
+
+        let message = hl7_parser::parse_message_with_lenient_newlines("MSH|^~\\&#|A").unwrap();
+        let truncation = truncation_character(&message);
+        assert_eq!(truncation, Some('#'));
+        let encoded = encode("Apt #4^B", &separators, truncation);
+        assert_eq!(encoded, "Apt \\P\\4\\S\\B");
+        assert_eq!(decode(&encoded, &separators, truncation), "Apt #4^B");
+        assert_eq!(decode("\\E\\P\\E\\", &separators, truncation), "\\P\\");
+
+        let text = "MSH|^~\\&|A\rOBX|1|TX|||Lungs A^B clear.\r\nNo effusion.|||F\rNTE|1";
+        let broken = broken_observations(text);
+        assert_eq!(broken.len(), 1);
+        assert_eq!(
+            &text[broken[0].range.clone()],
+            "Lungs A^B clear.\r\nNo effusion."
+        );
+        assert_eq!(
+            broken[0].escaped,
+            "Lungs A\\S\\B clear.\\X0D0A\\No effusion."
+        );
+        assert_eq!(broken[0].repeats, "Lungs A\\S\\B clear.~No effusion.");
+    }
+}
@@ -0,0 +1,150 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    utils::{line_ranges, position_from_offset, PositionEncoding},
+    Opts,
+};
+use lsp_textdocument::TextDocuments;
+use lsp_types::TextDocumentIdentifier;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+/// Custom request for where each field on each line of a document starts and
+/// ends, so that client extensions can draw rulers or show which field the
+/// cursor is in without parsing messages themselves
+pub enum FieldBoundaries {}
+
+impl lsp_types::request::Request for FieldBoundaries {
+    type Params = FieldBoundariesParams;
+    type Result = Vec<LineFields>;
+    const METHOD: &'static str = "hl7/fieldBoundaries";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldBoundariesParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+/// The fields on a single (non-empty) line of the document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineFields {
+    pub line: u32,
+    pub segment: String,
+    pub fields: Vec<FieldBoundary>,
+}
+
+/// Where a field is on its line, in characters of the negotiated position
+/// encoding; the end is exclusive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldBoundary {
+    pub field: usize,
+    pub start: u32,
+    pub end: u32,
+}
+
+#[instrument(level = "debug", skip(params, documents, opts))]
+pub fn handle_field_boundaries_request(
+    params: FieldBoundariesParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Vec<LineFields>> {
+    let uri = params.text_document.uri;
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    Ok(field_boundaries(text, opts.position_encoding))
+}
+
+/// Split each line on the field separator declared in MSH, rather than parsing
+/// the message, so that boundaries are available even while it is invalid
+fn field_boundaries(text: &str, encoding: PositionEncoding) -> Vec<LineFields> {
+    let separator = text
+        .strip_prefix("MSH")
+        .and_then(|rest| rest.chars().next())
+        .unwrap_or('|');
+    let column = |offset: usize| position_from_offset(text, offset, encoding).character;
+
+    let mut lines = Vec::new();
+    for (line, range) in line_ranges(text).enumerate() {
+        let start = range.start;
+        let segment = &text[range];
+        if segment.is_empty() {
+            continue;
+        }
+
+        let mut fields = Vec::new();
+        let name_end = segment.find(separator).unwrap_or(segment.len());
+        let is_msh = &segment[..name_end] == "MSH";
+        if is_msh && name_end < segment.len() {
+            // MSH.1 is the field separator itself
+            let separator_start = start + name_end;
+            fields.push(FieldBoundary {
+                field: 1,
+                start: column(separator_start),
+                end: column(separator_start + separator.len_utf8()),
+            });
+        }
+
+        let mut field_start = name_end + separator.len_utf8();
+        let mut index = if is_msh { 2 } else { 1 };
+        while field_start <= segment.len() {
+            let field_end = segment[field_start..]
+                .find(separator)
+                .map(|i| field_start + i)
+                .unwrap_or(segment.len());
+            fields.push(FieldBoundary {
+                field: index,
+                start: column(start + field_start),
+                end: column(start + field_end),
+            });
+            field_start = field_end + separator.len_utf8();
+            index += 1;
+        }
+
+        lines.push(LineFields {
+            line: line as u32,
+            segment: segment[..name_end].to_string(),
+            fields,
+        });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boundaries(line: &LineFields) -> Vec<(usize, u32, u32)> {
+        line.fields
+            .iter()
+            .map(|f| (f.field, f.start, f.end))
+            .collect()
+    }
+
+    #[test]
+    fn fields_are_found_on_each_line() {
+        let text = "MSH|^~\\&|App\r\nPID|1||Zoë😀|\r\nNTE";
+        let lines = field_boundaries(text, PositionEncoding::Utf16);
+        assert_eq!(lines.len(), 3);
+
+        assert_eq!(lines[0].line, 0);
+        assert_eq!(lines[0].segment, "MSH");
+        assert_eq!(
+            boundaries(&lines[0]),
+            vec![(1, 3, 4), (2, 4, 8), (3, 9, 12)]
+        );
+
+        assert_eq!(lines[1].line, 1);
+        assert_eq!(lines[1].segment, "PID");
+        assert_eq!(
+            boundaries(&lines[1]),
+            vec![(1, 4, 5), (2, 6, 6), (3, 7, 12), (4, 13, 13)]
+        );
+
+        assert_eq!(lines[2].segment, "NTE");
+        assert!(lines[2].fields.is_empty());
+    }
+}
@@ -1,30 +1,170 @@
-use crate::{
+use crate::cancellation::CancellationToken;
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    escapes::{find_escapes, Charset, Decoded},
+    messages::message_at,
     spec,
     utils::{position_to_offset, range_from_offsets},
+    validation::observations,
     workspace::specs::WorkspaceSpecs,
-    Opts,
+    Opts, TimeZone,
 };
-use chrono::{DateTime, Local, Utc};
-use color_eyre::{eyre::ContextCompat, Result};
-use hl7_parser::parse_message_with_lenient_newlines;
+use hl7_parser::{message::Segment, parse_message_with_lenient_newlines, Message};
 use lsp_textdocument::TextDocuments;
-use lsp_types::{Hover, HoverContents, HoverParams, MarkedString};
+use lsp_types::{Hover, HoverContents, HoverParams, MarkupContent, MarkupKind};
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(params, documents, workspace_specs, opts))]
+/// A run of hover text with a style that is rendered according to the output
+/// format
+enum Span {
+    Text(String),
+    Code(String),
+    Bold(String),
+    Italic(String),
+    Link(String),
+}
+
+/// A group of related lines in a hover, optionally with a title and
+/// optionally rendered as a (nested) list
+#[derive(Default)]
+struct Section {
+    title: Option<&'static str>,
+    list: bool,
+    lines: Vec<(usize, Vec<Span>)>,
+}
+
+impl Section {
+    fn titled(title: &'static str) -> Self {
+        Section {
+            title: Some(title),
+            ..Default::default()
+        }
+    }
+
+    fn list(mut self) -> Self {
+        self.list = true;
+        self
+    }
+
+    fn line(mut self, spans: Vec<Span>) -> Self {
+        self.lines.push((0, spans));
+        self
+    }
+
+    fn indented_line(mut self, depth: usize, spans: Vec<Span>) -> Self {
+        self.lines.push((depth, spans));
+        self
+    }
+}
+
+enum Block {
+    Section(Section),
+    Rule,
+}
+
+/// Hover text built up from sections so that it can be rendered as either
+/// Markdown or plain text, depending on what the client supports
+#[derive(Default)]
+struct HoverText {
+    blocks: Vec<Block>,
+}
+
+impl HoverText {
+    fn push(&mut self, section: Section) {
+        if !section.lines.is_empty() {
+            self.blocks.push(Block::Section(section));
+        }
+    }
+
+    fn rule(&mut self) {
+        self.blocks.push(Block::Rule);
+    }
+
+    /// Render the hover text; `line_break` separates lines that aren't in a
+    /// list when rendering Markdown
+    fn render(&self, kind: &MarkupKind, line_break: &str) -> String {
+        let markdown = *kind == MarkupKind::Markdown;
+        let render_span = |span: &Span| match (span, markdown) {
+            (Span::Text(text), _) => text.clone(),
+            (Span::Code(code), true) => format!("`{code}`"),
+            (Span::Bold(text), true) => format!("**{text}**"),
+            (Span::Italic(text), true) => format!("_{text}_"),
+            (Span::Link(url), true) => format!("[{url}]({url})"),
+            (
+                Span::Code(text) | Span::Bold(text) | Span::Italic(text) | Span::Link(text),
+                false,
+            ) => text.clone(),
+        };
+
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for block in self.blocks.iter() {
+            let section = match block {
+                Block::Rule if markdown => {
+                    blocks.push("---".to_string());
+                    continue;
+                }
+                Block::Rule => continue,
+                Block::Section(section) => section,
+            };
+
+            let mut lines = Vec::with_capacity(section.lines.len() + 1);
+            match (section.title, markdown) {
+                (Some(title), true) => lines.push(format!("**{title}**:")),
+                (Some(title), false) => lines.push(format!("{title}:")),
+                (None, _) => {}
+            }
+            for (depth, spans) in section.lines.iter() {
+                let text = spans.iter().map(render_span).collect::<String>();
+                let line = match (section.list, markdown) {
+                    (true, true) => format!("{indent}- {text}", indent = "  ".repeat(*depth)),
+                    (true, false) => format!("{indent}{text}", indent = "  ".repeat(*depth + 1)),
+                    (false, _) => text,
+                };
+                lines.push(line);
+            }
+
+            let separator = if markdown && !section.list {
+                line_break
+            } else {
+                "\n"
+            };
+            blocks.push(lines.join(separator));
+        }
+
+        blocks.join("\n\n")
+    }
+}
+
+#[instrument(
+    level = "debug",
+    skip(params, documents, workspace_specs, opts, cancel)
+)]
 pub fn handle_hover_request(
     params: HoverParams,
     documents: &TextDocuments,
     workspace_specs: Option<&WorkspaceSpecs>,
     opts: &Opts,
+    markup_kind: MarkupKind,
+    cancel: &CancellationToken,
 ) -> Result<Hover> {
     let uri = params.text_document_position_params.text_document.uri;
     let text = documents
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
     let position = params.text_document_position_params.position;
-    let offset = position_to_offset(text, position.line, position.character)
-        .wrap_err_with(|| "Failed to convert position to offset")?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err_with(|| "Failed to convert position to offset")?;
+
+    // only the message the cursor is in is needed, if there are several
+    let document_message = message_at(text, offset, opts.position_encoding);
+    let text = document_message.text;
+    let offset = offset.saturating_sub(document_message.range.start);
 
     let parse_span = tracing::trace_span!("parse message");
     let _parse_span_guard = parse_span.enter();
@@ -33,14 +173,16 @@ pub fn handle_hover_request(
         Err(e) => {
             tracing::debug!(error = %e, "Failed to parse message");
             return Ok(Hover {
-                contents: HoverContents::Scalar(MarkedString::from_markdown(
-                    "Failed to parse HL7 message".to_string(),
-                )),
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: markup_kind,
+                    value: "Failed to parse HL7 message".to_string(),
+                }),
                 range: None,
             });
         }
     };
     drop(_parse_span_guard);
+    cancel.check()?;
 
     let locate_span = tracing::trace_span!("locate cursor");
     let _locate_span_guard = locate_span.enter();
@@ -48,28 +190,50 @@ pub fn handle_hover_request(
         .locate_cursor(offset)
         .wrap_err_with(|| format!("Failed to locate cursor (at offset {offset}) in HL7 message"))?;
     drop(_locate_span_guard);
+    cancel.check()?;
 
-    // format the hover text
+    // build the hover text
     let format_span = tracing::trace_span!("format hover text");
     let _format_span_guard = format_span.enter();
     let located_value = location
         .value(&message.separators)
         .map(|v| v.to_string())
         .unwrap_or_default();
-    let mut hover_text = format!("`{location}`: `{located_value}`");
+    let mut hover_text = HoverText::default();
+    hover_text.push(Section::default().line(vec![
+        Span::Code(location.to_string()),
+        Span::Text(": ".to_string()),
+        Span::Code(located_value),
+    ]));
+
     let mut url = None;
     let mut timestamp = None;
     if let Some(seg) = location.segment {
-        let message_version = message
-            .query("MSH.12")
-            .map(|v| v.raw_value())
-            .unwrap_or("2.7.1");
-        if !spec::is_valid_version(message_version) {
-            hover_text.push_str(format!("\n\nUnknown HL7 version `{}`", message_version).as_str());
+        let version = opts.message_version(&uri, &message, workspace_specs);
+        let message_version = version.version;
+        if let (true, Some(declared)) = (version.is_fallback(), version.declared) {
+            // a pinned version is used even when the declared one is known
+            let note = match opts.pinned_version(&uri, workspace_specs) {
+                Some(_) => "HL7 version ",
+                None => "Unknown HL7 version ",
+            };
+            hover_text.push(Section::default().line(vec![
+                Span::Text(note.to_string()),
+                Span::Code(declared.to_string()),
+                Span::Text(format!(
+                    ", using HL7 v{message_version} definitions instead"
+                )),
+            ]));
         }
 
+        let mut definitions = Section::default().list();
+        let mut workspace_notes = Section::titled("Workspace notes").list();
+
         let description = spec::segment_description(message_version, seg.0);
-        hover_text.push_str(format!("\n  {segment}: {description}", segment = seg.0).as_str());
+        definitions = definitions.line(vec![
+            Span::Code(seg.0.to_string()),
+            Span::Text(format!(": {description}")),
+        ]);
 
         if let Some(field) = location.field {
             let field_description = spec::describe_field(message_version, seg.0, field.0);
@@ -88,160 +252,375 @@ pub fn handle_hover_request(
                 .unwrap_or(false);
 
             if let Some(workspace_specs) = workspace_specs {
-                let workspace_description = workspace_specs.describe_field(&uri, seg.0, field.0);
-                if !workspace_description.is_empty() {
-                    hover_text.push_str(
-                        format!(
-                            "\n  **{segment}.{field}{repeat}** _workspace description(s)_:{workspace_description}",
+                for (spec_name, field_spec) in workspace_specs.field_specs(&uri, seg.0, field.0) {
+                    let mut note = vec![
+                        Span::Bold(spec_name),
+                        Span::Text(format!(
+                            " ({segment}.{field}{repeat})",
                             segment = seg.0,
-                            field = field.0,
-                            repeat = repeat,
-                            workspace_description = workspace_description,
-                        )
-                        .as_str(),
-                    );
+                            field = field.0
+                        )),
+                    ];
+                    if let Some(description) = field_spec.description {
+                        note.push(Span::Text(format!(": {description}")));
+                    }
+                    if let Some(datatype) = field_spec.datatype {
+                        note.push(Span::Text(" ".to_string()));
+                        note.push(Span::Code(datatype));
+                    }
+                    match field_spec.required {
+                        Some(true) => note.push(Span::Italic(" required".to_string())),
+                        Some(false) => note.push(Span::Italic(" optional".to_string())),
+                        None => {}
+                    }
+                    workspace_notes = workspace_notes.line(note);
+                    for (value, description) in field_spec.allowed_values.unwrap_or_default() {
+                        workspace_notes = workspace_notes.indented_line(
+                            1,
+                            vec![Span::Code(value), Span::Text(format!(": {description}"))],
+                        );
+                    }
+                    if let Some(pattern) = field_spec.pattern {
+                        let mut line = vec![Span::Text("Matches ".to_string())];
+                        if let Some(description) = field_spec.pattern_description {
+                            line.push(Span::Text(format!("{description} ")));
+                        }
+                        line.push(Span::Code(pattern));
+                        workspace_notes = workspace_notes.indented_line(1, line);
+                    }
                 }
             }
 
-            hover_text.push_str(
-                format!(
-                    "\n  {segment}.{field}{repeat}: {field_description}",
+            // values drawn from tables the workspace defines are described
+            // by the workspace
+            let (table, value) = match (location.component, location.sub_component) {
+                (Some(component), Some(sub_component))
+                    if has_components && component.1.subcomponents().count() > 1 =>
+                {
+                    (
+                        spec::sub_component_table(
+                            message_version,
+                            seg.0,
+                            field.0,
+                            component.0,
+                            sub_component.0,
+                        ),
+                        sub_component.1.raw_value(),
+                    )
+                }
+                (Some(component), _) if has_components => (
+                    spec::component_table(message_version, seg.0, field.0, component.0),
+                    component.1.raw_value(),
+                ),
+                _ => (
+                    spec::field_table(message_version, seg.0, field.0),
+                    location
+                        .repeat
+                        .map(|r| r.1.raw_value())
+                        .unwrap_or(field.1.raw_value()),
+                ),
+            };
+            if let Some((table, values)) = table
+                .filter(|_| !value.is_empty())
+                .and_then(|table| Some((table, workspace_specs?.table(&uri, table)?)))
+            {
+                let mut note = vec![Span::Bold(format!("Table {table:04}"))];
+                if let Some(description) = values.description.as_ref() {
+                    note.push(Span::Text(format!(" ({description})")));
+                }
+                note.push(Span::Text(": ".to_string()));
+                note.push(Span::Code(value.to_string()));
+                match values.describe(value) {
+                    Some(description) if !description.is_empty() => {
+                        note.push(Span::Text(format!(": {description}")));
+                    }
+                    Some(_) => {}
+                    None => note.push(Span::Italic(" not in the table".to_string())),
+                }
+                workspace_notes = workspace_notes.line(note);
+            }
+
+            let repeat_label = location
+                .repeat
+                .filter(|_| has_repeats)
+                .and_then(|r| spec::repeat_label(message_version, seg.0, field.0, r.1))
+                .map(|label| format!(" ({label})"))
+                .unwrap_or_default();
+            definitions = definitions.line(vec![
+                Span::Code(format!(
+                    "{segment}.{field}{repeat}",
                     segment = seg.0,
-                    field = field.0,
-                )
-                .as_str(),
-            );
+                    field = field.0
+                )),
+                Span::Text(format!("{repeat_label}: {field_description}")),
+            ]);
 
             if let (true, Some(component)) = (has_components, location.component) {
                 let component_description =
                     spec::describe_component(message_version, seg.0, field.0, component.0);
-                hover_text.push_str(
-                    format!(
-                        "\n  {segment}.{field}.{component}: {component_description}",
+                definitions = definitions.line(vec![
+                    Span::Code(format!(
+                        "{segment}.{field}.{component}",
                         segment = seg.0,
                         field = field.0,
                         component = component.0,
-                    )
-                    .as_str(),
-                );
+                    )),
+                    Span::Text(format!(": {component_description}")),
+                ]);
 
-                url = Some(format!(
-                        "https://hl7-definition.caristix.com/v2/HL7v{message_version}/Fields/{segment}.{field}.{component}",
-                        segment = seg.0,
-                        field = field.0,
-                        component = component.0
-                    ));
+                url = Some(spec::component_url(
+                    message_version,
+                    seg.0,
+                    field.0,
+                    component.0,
+                ));
 
                 if spec::is_component_a_timestamp(message_version, seg.0, field.0, component.0) {
-                    timestamp = Some(
-                        match hl7_parser::datetime::parse_timestamp(component.1.raw_value(), false)
-                        {
-                            Ok(ts) => {
-                                let ts_utc = ts
-                                    .try_into()
-                                    .map(|ts: DateTime<Utc>| ts.to_rfc2822())
-                                    .unwrap_or_else(|e| {
-                                        format!("Failed to parse timestamp as UTC: {e:#}")
-                                    });
-                                let ts_local = ts
-                                    .try_into()
-                                    .map(|ts: DateTime<Local>| ts.to_rfc2822())
-                                    .unwrap_or_else(|e| {
-                                        format!("Failed to parse timestamp as local: {e:#}")
-                                    });
-                                format!("  UTC: `{ts_utc}`\n  Local: `{ts_local}`")
-                            }
-                            Err(e) => format!("Invalid timestamp: {e:#}"),
-                        },
-                    );
+                    timestamp = Some(describe_timestamp(
+                        component.1.raw_value(),
+                        opts.display_timezone,
+                    ));
                 }
             } else {
-                url = Some(format!(
-                        "https://hl7-definition.caristix.com/v2/HL7v{message_version}/Fields/{segment}.{field}",
-                        segment = seg.0,
-                        field = field.0
-                    ));
+                url = Some(spec::field_url(message_version, seg.0, field.0));
 
                 if spec::is_field_a_timestamp(message_version, seg.0, field.0) {
-                    timestamp = Some(
-                        match hl7_parser::datetime::parse_timestamp(field.1.raw_value(), false) {
-                            Ok(ts) => {
-                                let ts_utc = ts
-                                    .try_into()
-                                    .map(|ts: DateTime<Utc>| ts.to_rfc2822())
-                                    .unwrap_or_else(|e| {
-                                        format!("Failed to parse timestamp as UTC: {e:#}")
-                                    });
-                                let ts_local = ts
-                                    .try_into()
-                                    .map(|ts: DateTime<Local>| ts.to_rfc2822())
-                                    .unwrap_or_else(|e| {
-                                        format!("Failed to parse timestamp as local: {e:#}")
-                                    });
-                                format!("  UTC: `{ts_utc}`\n  Local: `{ts_local}`")
-                            }
-                            Err(e) => format!("Invalid timestamp: {e:#}"),
-                        },
-                    );
+                    timestamp = Some(describe_timestamp(
+                        field.1.raw_value(),
+                        opts.display_timezone,
+                    ));
                 }
             }
         } else {
-            url = Some(format!(
-                "https://hl7-definition.caristix.com/v2/HL7v{message_version}/Segments/{segment}",
-                segment = seg.0
-            ));
+            url = Some(spec::segment_url(message_version, seg.0));
+        }
+
+        hover_text.push(definitions);
+        if let Some(observation) = describe_observation(&message, seg.2) {
+            hover_text.push(observation);
         }
+        hover_text.push(workspace_notes);
     }
 
-    if url.is_some() || timestamp.is_some() {
-        hover_text.push_str("\n\n---");
+    let raw_value = location
+        .sub_component
+        .map(|s| s.1.raw_value())
+        .or(location.component.map(|c| c.1.raw_value()))
+        .or(location.repeat.map(|r| r.1.raw_value()))
+        .or(location.field.map(|f| f.1.raw_value()));
+    let escapes = raw_value.and_then(|value| describe_escapes(value, &message));
+
+    if url.is_some() || timestamp.is_some() || escapes.is_some() {
+        hover_text.rule();
     }
 
     if let Some(timestamp) = timestamp {
-        hover_text.push_str(format!("\n\n**Timestamp**:\n{timestamp}").as_str());
+        hover_text.push(timestamp);
+    }
+    if let Some(escapes) = escapes {
+        hover_text.push(escapes);
     }
     if let Some(url) = url {
-        hover_text.push_str(format!("\n\n**More info**: [{url}]({url})").as_str());
+        hover_text.push(Section::titled("More info").line(vec![Span::Link(url)]));
     }
 
     // figure out the most relevant hover range
     let range = if let Some(sub_component) = location.sub_component {
         let start = sub_component.1.range.start;
         let end = sub_component.1.range.end;
-        Some(range_from_offsets(text, start, end))
+        Some(range_from_offsets(text, start, end, opts.position_encoding))
     } else if let Some(component) = location.component {
         let start = component.1.range.start;
         let end = component.1.range.end;
-        Some(range_from_offsets(text, start, end))
+        Some(range_from_offsets(text, start, end, opts.position_encoding))
     } else if let Some(repeat) = location.repeat {
         let start = repeat.1.range.start;
         let end = repeat.1.range.end;
-        Some(range_from_offsets(text, start, end))
+        Some(range_from_offsets(text, start, end, opts.position_encoding))
     } else if let Some(field) = location.field {
         let start = field.1.range.start;
         let end = field.1.range.end;
-        Some(range_from_offsets(text, start, end))
+        Some(range_from_offsets(text, start, end, opts.position_encoding))
     } else if let Some(segment) = location.segment {
         let start = segment.2.range.start;
         let end = segment.2.range.end;
-        Some(range_from_offsets(text, start, end))
+        Some(range_from_offsets(text, start, end, opts.position_encoding))
     } else {
         None
-    };
+    }
+    .map(|range| document_message.range_in_document(range));
 
+    // some clients (i.e. vscode) don't render Markdown's trailing-space line
+    // breaks
+    let line_break = if opts.vscode { "<br/>\n" } else { "  \n" };
+    let hover_text = hover_text.render(&markup_kind, line_break);
     drop(_format_span_guard);
     tracing::trace!(hover_text = %hover_text, range = ?range, "generated hover text");
 
-    let hover_text = if opts.vscode {
-        hover_text.replace("\n", "<br/>\n")
-    } else {
-        hover_text
-    };
-
     let hover = Hover {
-        contents: HoverContents::Scalar(MarkedString::from_markdown(hover_text)),
+        contents: HoverContents::Markup(MarkupContent {
+            kind: markup_kind,
+            value: hover_text,
+        }),
         range,
     };
 
     Ok(hover)
 }
+
+/// Show which part of an observation split across OBX segments a segment is
+fn describe_observation(message: &Message, segment: &Segment) -> Option<Section> {
+    let observations = observations(message);
+    let (observation, part) = observations
+        .iter()
+        .find_map(|o| o.part_of(segment).map(|part| (o, part)))?;
+
+    let mut line = vec![
+        Span::Text(format!(
+            "Part {part} of {parts} of observation ",
+            part = part + 1,
+            parts = observation.parts.len()
+        )),
+        Span::Code(observation.identifier.to_string()),
+    ];
+    if let Some(text) = observation.text {
+        line.push(Span::Text(format!(" ({text})")));
+    }
+    if let Some(sub_id) = observation.parts[part].sub_id {
+        line.push(Span::Text(", sub-ID ".to_string()));
+        line.push(Span::Code(sub_id.to_string()));
+    }
+    Some(Section::default().line(line))
+}
+
+/// Show the timestamp in UTC and in the configured display timezone
+fn describe_timestamp(value: &str, timezone: TimeZone) -> Section {
+    let section = Section::titled("Timestamp").list();
+    let ts = match hl7_parser::datetime::parse_timestamp(value, false) {
+        Ok(ts) => ts,
+        Err(e) => return section.line(vec![Span::Text(format!("Invalid timestamp: {e:#}"))]),
+    };
+    let ts: DateTime<Utc> = match ts.try_into() {
+        Ok(ts) => ts,
+        Err(e) => {
+            return section.line(vec![Span::Text(format!(
+                "Failed to parse timestamp as UTC: {e:#}"
+            ))])
+        }
+    };
+
+    let section = section.line(vec![
+        Span::Text("UTC: ".to_string()),
+        Span::Code(ts.to_rfc2822()),
+    ]);
+    if timezone == TimeZone::Utc {
+        return section;
+    }
+    section.line(vec![
+        Span::Text(format!("{}: ", timezone.label())),
+        Span::Code(timezone.convert(ts).to_rfc2822()),
+    ])
+}
+
+/// Show what the hex and character set escape sequences in the value stand
+/// for, as the parser leaves them encoded
+fn describe_escapes(value: &str, message: &Message) -> Option<Section> {
+    let charset = Charset::declared(message);
+    let escapes = find_escapes(value, &message.separators, &charset);
+    if escapes.is_empty() {
+        return None;
+    }
+
+    let mut section = Section::titled("Escape sequences").list();
+    for escape in escapes {
+        let mut line = vec![
+            Span::Code(escape.sequence.to_string()),
+            Span::Text(": ".to_string()),
+        ];
+        match escape.decoded {
+            Decoded::Characters(characters) => {
+                line.push(Span::Code(characters.escape_debug().to_string()));
+                line.push(Span::Text(format!(" ({charset})")));
+            }
+            Decoded::Undecodable => line.push(Span::Text(format!(
+                "hex data in {charset}, which can't be shown"
+            ))),
+            Decoded::Charset(name) => line.push(Span::Text(format!("switch to {name}"))),
+            Decoded::Invalid(problem) => line.push(Span::Text(format!("invalid, {problem}"))),
+        }
+        section = section.line(line);
+    }
+    Some(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> HoverText {
+        let mut hover_text = HoverText::default();
+        hover_text.push(Section::default().line(vec![
+            Span::Code("PID.5".to_string()),
+            Span::Text(": ".to_string()),
+            Span::Code("Doe^John".to_string()),
+        ]));
+        hover_text.push(
+            Section::titled("Workspace notes")
+                .list()
+                .line(vec![
+                    Span::Bold("Example".to_string()),
+                    Span::Italic(" required".to_string()),
+                ])
+                .indented_line(1, vec![Span::Code("I".to_string())]),
+        );
+        hover_text.push(Section::titled("Empty").list());
+        hover_text.rule();
+        hover_text.push(
+            Section::titled("More info").line(vec![Span::Link("https://example.com".to_string())]),
+        );
+        hover_text
+    }
+
+    #[test]
+    fn hover_text_renders_as_markdown() {
+        assert_eq!(
+            sample().render(&MarkupKind::Markdown, "  \n"),
+            "`PID.5`: `Doe^John`\n\n\
+             **Workspace notes**:\n- **Example**_ required_\n  - `I`\n\n\
+             ---\n\n\
+             **More info**:  \n[https://example.com](https://example.com)"
+        );
+    }
+
+    #[test]
+    fn hover_text_renders_as_plain_text() {
+        assert_eq!(
+            sample().render(&MarkupKind::PlainText, "  \n"),
+            "PID.5: Doe^John\n\n\
+             Workspace notes:\n  Example required\n    I\n\n\
+             More info:\nhttps://example.com"
+        );
+    }
+
+    #[test]
+    fn timestamps_are_shown_in_the_display_timezone() {
+        let render = |timezone: TimeZone| {
+            let mut hover_text = HoverText::default();
+            hover_text.push(describe_timestamp("20240102030405+0000", timezone));
+            hover_text.render(&MarkupKind::PlainText, "\n")
+        };
+
+        let offset: TimeZone = "+05:30".parse().unwrap();
+        let text = render(offset);
+        assert!(text.contains("UTC: Tue, 2 Jan 2024 03:04:05 +0000"));
+        assert!(text.contains("UTC+05:30: Tue, 2 Jan 2024 08:34:05 +0530"));
+
+        let text = render(TimeZone::Utc);
+        assert!(text.contains("UTC: Tue, 2 Jan 2024 03:04:05 +0000"));
+        assert!(!text.contains("+0530"));
+
+        assert_eq!("-0700".parse(), Ok(TimeZone::Offset(-7 * 3600)));
+        assert_eq!("UTC".parse(), Ok(TimeZone::Utc));
+        assert!("+25:00".parse::<TimeZone>().is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//! The validation engine behind the `hl7-ls` language server
+//!
+//! This library exposes the same parsing, validation, and description logic
+//! that the language server uses, so that other tools (interface engine
+//! plugins, CI utilities, etc.) can validate HL7 messages without going
+//! through LSP.
+//!
+//! ```no_run
+//! let uri = "file:///tmp/message.hl7".parse().unwrap();
+//! let text = std::fs::read_to_string("/tmp/message.hl7").unwrap();
+//! let opts = hl7_ls::Opts::default();
+//! match hl7_ls::validate_text(&uri, &text, None, &opts) {
+//!     Ok(errors) => {
+//!         for error in errors {
+//!             println!("{}: {}", error.code, error.message);
+//!         }
+//!     }
+//!     Err(e) => eprintln!("failed to parse message: {e}"),
+//! }
+//! ```
+
+use hl7_parser::{parser::ParseError, Message};
+use lsp_types::{DiagnosticSeverity, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utils::PositionEncoding;
+use validation::ValidationError;
+use workspace::specs::WorkspaceSpecs;
+
+pub mod escapes;
+pub mod messages;
+pub mod spec;
+pub mod utils;
+pub mod validation;
+pub mod workspace;
+
+/// Options that control validation and how results are presented
+#[derive(Debug, Clone, Default)]
+pub struct Opts {
+    /// Format output for Visual Studio Code
+    pub vscode: bool,
+    /// Skip table value checks for tables that come from the HL7 standard
+    pub disable_std_table_validations: bool,
+    /// Log a structured summary of every validation pass
+    pub log_validation_stats: bool,
+    /// How long a validation pass may take before it stops, reporting the
+    /// segments it didn't get to
+    pub validation_budget: Option<std::time::Duration>,
+    /// HL7 version to use when a message's version is unknown or missing
+    pub fallback_version: Option<String>,
+    /// How positions in the ranges of results are encoded
+    pub position_encoding: PositionEncoding,
+    /// The line ending that segments are converted to when a message is saved
+    pub segment_terminator: SegmentTerminator,
+    /// The severity to report messages that can't be parsed with
+    pub parse_error_severity: Severity,
+    /// Globs matching documents that parse errors aren't reported for, see
+    /// [utils::glob_matches]
+    pub suppress_parse_errors: Vec<String>,
+    /// Fields (e.g. `PID.3`) holding identifiers to regenerate when a message
+    /// is cloned
+    pub clone_identifiers: Vec<String>,
+    /// Trim separators left redundant at the end of a segment by generated
+    /// edits, see [utils::trim_edited_segment]
+    pub trim_trailing_separators: bool,
+    /// The timezone timestamps are shown in, e.g. when hovering over them
+    pub display_timezone: TimeZone,
+    /// The timezone generated timestamps (e.g. from `hl7.setTimestampToNow`)
+    /// are written in
+    pub output_timezone: TimeZone,
+    /// Globs matching documents to the validation profile (workspace spec
+    /// name) they're validated with, see
+    /// [workspace::specs::WorkspaceSpecs::profile]
+    pub validation_profiles: Vec<(String, String)>,
+    /// Which workspace specs apply to documents that aren't files
+    pub non_file_specs: NonFileSpecs,
+    /// A file to append the edits made by commands to, relative to the first
+    /// workspace folder
+    pub edit_history: Option<std::path::PathBuf>,
+    /// Whether to point out separators and whitespace that don't mean
+    /// anything, such as empty fields at the end of segments
+    pub lint_style: bool,
+    /// Whether the coding systems of coded elements must be in table 0396
+    pub check_coding_systems: bool,
+    /// Whether to report messages whose control ID is used by another message
+    /// in the workspace
+    pub unique_control_ids: bool,
+    /// The HL7 version documents must declare, from the client's settings;
+    /// workspace specs can pin another for the documents they apply to
+    pub pinned_version: Option<String>,
+    /// The severities to report validation codes (by
+    /// [validation::ValidationCode::key]) at, as configured by the client;
+    /// workspace specs can override these for the documents they apply to
+    pub severity_overrides: HashMap<String, SeverityOverride>,
+}
+
+impl Opts {
+    /// The HL7 version pinned for a document, by the workspace specs that
+    /// apply to it or else by the client's settings
+    pub fn pinned_version(
+        &self,
+        uri: &Uri,
+        workspace_specs: Option<&WorkspaceSpecs>,
+    ) -> Option<String> {
+        workspace_specs
+            .and_then(|specs| specs.pinned_version(uri))
+            .or_else(|| self.pinned_version.clone())
+    }
+
+    /// The version of the standard a document's message is read with: its
+    /// pinned version (see [Opts::pinned_version]), or else the one it
+    /// declares
+    pub fn message_version<'m>(
+        &self,
+        uri: &Uri,
+        message: &'m Message,
+        workspace_specs: Option<&WorkspaceSpecs>,
+    ) -> spec::ResolvedVersion<'m> {
+        spec::pinned_message_version(
+            message,
+            self.pinned_version(uri, workspace_specs).as_deref(),
+            self.fallback_version.as_deref(),
+        )
+    }
+
+    /// Whether parse errors in the given document should go unreported
+    pub fn suppresses_parse_errors(&self, uri: &Uri) -> bool {
+        let path = uri.path().as_str();
+        self.suppress_parse_errors
+            .iter()
+            .any(|glob| utils::glob_matches(glob, path))
+    }
+}
+
+/// How severe a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+pub enum Severity {
+    #[default]
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl From<Severity> for DiagnosticSeverity {
+    fn from(severity: Severity) -> Self {
+        match severity {
+            Severity::Error => DiagnosticSeverity::ERROR,
+            Severity::Warning => DiagnosticSeverity::WARNING,
+            Severity::Information => DiagnosticSeverity::INFORMATION,
+            Severity::Hint => DiagnosticSeverity::HINT,
+        }
+    }
+}
+
+/// The severity that diagnostics with a validation code are reported at in
+/// place of the one the rule gives them, or `Off` to not report them at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeverityOverride {
+    Error,
+    Warning,
+    #[serde(alias = "info")]
+    Information,
+    Hint,
+    Off,
+}
+
+impl SeverityOverride {
+    /// The severity to report diagnostics at, or `None` if they aren't
+    /// reported
+    pub fn severity(self) -> Option<Severity> {
+        match self {
+            SeverityOverride::Error => Some(Severity::Error),
+            SeverityOverride::Warning => Some(Severity::Warning),
+            SeverityOverride::Information => Some(Severity::Information),
+            SeverityOverride::Hint => Some(Severity::Hint),
+            SeverityOverride::Off => None,
+        }
+    }
+}
+
+/// Which workspace specs apply to documents that aren't files on disk, such
+/// as unsaved `untitled:` documents, as they aren't in any workspace folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+pub enum NonFileSpecs {
+    /// The specs directly in the workspace folders
+    #[default]
+    WorkspaceRoot,
+    /// No specs, only the HL7 standard
+    None,
+}
+
+/// The line ending used to separate segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "server", derive(clap::ValueEnum))]
+pub enum SegmentTerminator {
+    /// Leave line endings as they are
+    #[default]
+    Preserve,
+    /// `\r`, as required by the standard
+    Cr,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    #[cfg_attr(feature = "server", value(name = "crlf"))]
+    CrLf,
+}
+
+impl SegmentTerminator {
+    /// The terminator's text, or `None` if line endings are preserved
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            SegmentTerminator::Preserve => None,
+            SegmentTerminator::Cr => Some("\r"),
+            SegmentTerminator::Lf => Some("\n"),
+            SegmentTerminator::CrLf => Some("\r\n"),
+        }
+    }
+}
+
+/// A timezone that timestamps are shown or written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeZone {
+    /// The machine's timezone
+    #[default]
+    Local,
+    Utc,
+    /// A fixed offset east of UTC, in seconds
+    Offset(i32),
+}
+
+impl std::str::FromStr for TimeZone {
+    type Err = String;
+
+    /// Parse `local`, `utc`, or an offset such as `+05:30`, `-0700`, or `+01`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => return Ok(TimeZone::Local),
+            "utc" | "z" => return Ok(TimeZone::Utc),
+            _ => {}
+        }
+
+        let invalid = || {
+            format!(
+                "Invalid timezone `{s}`, expected `local`, `utc`, or an offset such as `+05:30`"
+            )
+        };
+        let (sign, offset) = if let Some(offset) = s.strip_prefix('+') {
+            (1, offset)
+        } else if let Some(offset) = s.strip_prefix('-') {
+            (-1, offset)
+        } else {
+            return Err(invalid());
+        };
+        let digits = offset.replace(':', "");
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let (hours, minutes) = match digits.len() {
+            2 => (&digits[..], "0"),
+            4 => digits.split_at(2),
+            _ => return Err(invalid()),
+        };
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+}
+
+impl std::fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeZone::Local => write!(f, "local"),
+            TimeZone::Utc => write!(f, "utc"),
+            TimeZone::Offset(seconds) => {
+                let sign = if *seconds < 0 { '-' } else { '+' };
+                let minutes = seconds.abs() / 60;
+                write!(f, "{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl TimeZone {
+    /// The offset from UTC the timezone has at the given instant
+    pub fn offset_at(&self, instant: chrono::DateTime<chrono::Utc>) -> chrono::FixedOffset {
+        use chrono::{FixedOffset, Offset, TimeZone as _};
+        match self {
+            TimeZone::Local => chrono::Local
+                .offset_from_utc_datetime(&instant.naive_utc())
+                .fix(),
+            TimeZone::Utc => chrono::Utc.fix(),
+            TimeZone::Offset(seconds) => {
+                FixedOffset::east_opt(*seconds).unwrap_or_else(|| chrono::Utc.fix())
+            }
+        }
+    }
+
+    /// The given instant, in this timezone
+    pub fn convert<Tz: chrono::TimeZone>(
+        &self,
+        instant: chrono::DateTime<Tz>,
+    ) -> chrono::DateTime<chrono::FixedOffset> {
+        let instant = instant.with_timezone(&chrono::Utc);
+        instant.with_timezone(&self.offset_at(instant))
+    }
+
+    /// The current time, in this timezone
+    pub fn now(&self) -> chrono::DateTime<chrono::FixedOffset> {
+        self.convert(chrono::Utc::now())
+    }
+
+    /// A name for the timezone to label times shown in it with
+    pub fn label(&self) -> String {
+        match self {
+            TimeZone::Local => "Local".to_string(),
+            TimeZone::Utc => "UTC".to_string(),
+            TimeZone::Offset(_) => format!("UTC{self}"),
+        }
+    }
+}
+
+/// Parse and validate a message, returning all validation errors found
+///
+/// Text holding several messages (or a batch file) has each message validated
+/// on its own. The `uri` identifies the message's location, which is used to
+/// determine which workspace specs apply to it.
+pub fn validate_text(
+    uri: &Uri,
+    text: &str,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Result<Vec<ValidationError>, ParseError> {
+    if validation::is_batch(text, opts) {
+        let batch = validation::validate_batch(uri, text, &workspace_specs, opts, &|| false)
+            .expect("validation is never cancelled");
+        return match batch.parse_errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(batch.errors),
+        };
+    }
+    let message = hl7_parser::parse_message_with_lenient_newlines(text)?;
+    Ok(validation::validate_message(
+        uri,
+        &message,
+        &workspace_specs,
+        opts,
+    ))
+}
@@ -0,0 +1,163 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    escapes::escape_sequences,
+    utils::{position_to_offset, std_range_to_lsp_range},
+    Opts,
+};
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_textdocument::TextDocuments;
+use lsp_types::{LinkedEditingRangeParams, LinkedEditingRanges};
+use tracing::instrument;
+
+/// Link every occurrence of a separator character to its declaration in
+/// MSH-1 / MSH-2, so that changing a separator in the header updates it
+/// throughout the message instead of breaking every segment after it
+#[instrument(level = "debug", skip(params, documents, opts))]
+pub fn handle_linked_editing_range_request(
+    params: LinkedEditingRangeParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<LinkedEditingRanges>> {
+    let uri = params.text_document_position_params.text_document.uri;
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let parse_span = tracing::trace_span!("parse message");
+    let _parse_span_guard = parse_span.enter();
+    let Ok(message) = parse_message_with_lenient_newlines(text) else {
+        return Ok(None);
+    };
+    drop(_parse_span_guard);
+
+    let position = params.text_document_position_params.position;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err_with(|| "Failed to convert position to offset")?;
+
+    let Some(msh) = message.segments().find(|segment| segment.name == "MSH") else {
+        return Ok(None);
+    };
+
+    // the separators are declared by the field separator at MSH-1 and the
+    // encoding characters in MSH-2, which run up to the next field separator
+    let field_separator = message.separators.field;
+    let declaration_start = msh.range.start + 3;
+    let declaration_end = text
+        .get(declaration_start..msh.range.end)
+        .and_then(|declaration| {
+            declaration
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == field_separator)
+        })
+        .map(|(i, _)| declaration_start + i)
+        .unwrap_or(msh.range.end);
+    let declaration = declaration_start..declaration_end;
+
+    // the cursor may be on either side of the separator being edited
+    let Some(separator_offset) = [Some(offset), offset.checked_sub(1)]
+        .into_iter()
+        .flatten()
+        .find(|offset| declaration.contains(offset))
+    else {
+        return Ok(None);
+    };
+    let Some(separator) = text
+        .get(separator_offset..)
+        .and_then(|rest| rest.chars().next())
+    else {
+        return Ok(None);
+    };
+    tracing::trace!(?separator, "linking separator occurrences");
+
+    // characters inside escape sequences (e.g. the truncation character in a
+    // `\Z..\` sequence) aren't separators, though the escape characters around
+    // them are
+    let escaped = message
+        .segments()
+        .flat_map(|segment| {
+            escape_sequences(&text[segment.range.clone()], &message.separators)
+                .into_iter()
+                .map(move |sequence| {
+                    let escape = message.separators.escape.len_utf8();
+                    segment.range.start + sequence.start + escape
+                        ..segment.range.start + sequence.end - escape
+                })
+        })
+        .collect::<Vec<_>>();
+    let ranges = text
+        .char_indices()
+        .filter(|(i, c)| *c == separator && !escaped.iter().any(|range| range.contains(i)))
+        .map(|(i, c)| std_range_to_lsp_range(text, i..i + c.len_utf8(), opts.position_encoding))
+        .collect();
+
+    Ok(Some(LinkedEditingRanges {
+        ranges,
+        word_pattern: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{
+        notification::Notification, Position, Range, TextDocumentIdentifier,
+        TextDocumentPositionParams,
+    };
+
+    /// The ranges linked to the separator at the given character of the first
+    /// line
+    fn linked(text: &str, character: u32) -> Vec<Range> {
+        let mut documents = TextDocuments::new();
+        documents.listen(
+            lsp_types::notification::DidOpenTextDocument::METHOD,
+            &serde_json::json!({
+                "textDocument": {
+                    "uri": "file:///message.hl7",
+                    "languageId": "hl7",
+                    "version": 1,
+                    "text": text,
+                },
+            }),
+        );
+        let params = LinkedEditingRangeParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: "file:///message.hl7".parse().unwrap(),
+                },
+                position: Position { line: 0, character },
+            },
+            work_done_progress_params: Default::default(),
+        };
+        handle_linked_editing_range_request(params, &documents, &Opts::default())
+            .unwrap()
+            .map(|ranges| ranges.ranges)
+            .unwrap_or_default()
+    }
+
+    fn at(line: u32, character: u32) -> Range {
+        Range {
+            start: Position { line, character },
+            end: Position {
+                line,
+                character: character + 1,
+            },
+        }
+    }
+
+    #[test]
+    fn separators_inside_escape_sequences_arent_linked() {
+        let text = "MSH|^~\\&#|App\rNTE|1||Cut#\\Z#1\\";
+
+        // the truncation character, which is also in the custom escape
+        assert_eq!(linked(text, 8), vec![at(0, 8), at(1, 10)]);
+        // the escape character, which is also around the custom escape
+        assert_eq!(linked(text, 6), vec![at(0, 6), at(1, 11), at(1, 15)]);
+        assert!(linked(text, 12).is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// The MLLP listeners started by `hl7.startListener`, by the port they're
+/// listening on
+///
+/// Listeners run in the background until they're stopped or the server shuts
+/// down, so outlive the requests that start them.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    running: Mutex<HashMap<u16, Listener>>,
+}
+
+/// A listener running on its own thread, which checks whether it's been asked
+/// to stop while it waits for connections and messages
+#[derive(Debug)]
+pub struct Listener {
+    pub address: SocketAddr,
+    pub stopping: Arc<AtomicBool>,
+    pub thread: JoinHandle<()>,
+}
+
+impl Listeners {
+    pub fn insert(&self, listener: Listener) {
+        self.running
+            .lock()
+            .expect("listeners lock isn't poisoned")
+            .insert(listener.address.port(), listener);
+    }
+
+    /// Stop the listener on the given port, or every listener, returning the
+    /// ports that were being listened on
+    pub fn stop(&self, port: Option<u16>) -> Vec<u16> {
+        let stopped: Vec<Listener> = {
+            let mut running = self.running.lock().expect("listeners lock isn't poisoned");
+            match port {
+                Some(port) => running.remove(&port).into_iter().collect(),
+                None => running.drain().map(|(_, listener)| listener).collect(),
+            }
+        };
+
+        let mut ports = Vec::with_capacity(stopped.len());
+        for listener in stopped {
+            listener.stopping.store(true, Ordering::Relaxed);
+            if listener.thread.join().is_err() {
+                tracing::warn!(address = %listener.address, "Listener panicked");
+            }
+            ports.push(listener.address.port());
+        }
+        ports.sort_unstable();
+        ports
+    }
+}
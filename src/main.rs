@@ -1,53 +1,89 @@
+use cancellation::Inbox;
 use cli::Cli;
 use color_eyre::eyre::Context;
 use color_eyre::Result;
 use crossbeam_channel::select;
-use lsp_server::{Connection, ExtractError, Message, Request, RequestId, Response, ResponseError};
+use hl7_ls::utils::{
+    build_response, convert_position, file_path, PositionEncoding, RequestCancelled,
+};
+use hl7_ls::validation::{self, ValidationCache, ValidationCode};
+use hl7_ls::workspace::{history::EditRecord, Workspace};
+use hl7_ls::{spec, Opts, SeverityOverride};
+use listeners::Listeners;
+use lsp_server::{Connection, Message, Request, Response, ResponseError};
 use lsp_textdocument::TextDocuments;
 use lsp_types::notification::{
-    self, DidChangeTextDocument, DidOpenTextDocument, LogMessage, Notification,
+    self, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
+    DidChangeWorkspaceFolders, DidCloseTextDocument, DidOpenTextDocument, LogMessage, Notification,
 };
 use lsp_types::request::{
-    ApplyWorkspaceEdit, CodeActionRequest, Completion, DocumentSymbolRequest, ExecuteCommand,
-    HoverRequest, Request as LspRequest, SelectionRangeRequest, SignatureHelpRequest,
+    ApplyWorkspaceEdit, CodeActionRequest, CodeActionResolveRequest, CodeLensRequest, Completion,
+    DocumentSymbolRequest, ExecuteCommand, HoverRequest, LinkedEditingRange, RegisterCapability,
+    Request as LspRequest, ResolveCompletionItem, SelectionRangeRequest, SignatureHelpRequest,
+    WillSaveWaitUntil,
 };
 use lsp_types::{
     ApplyWorkspaceEditParams, ClientCapabilities, CodeActionOptions, CodeActionProviderCapability,
-    CompletionOptions, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
-    ExecuteCommandOptions, HoverProviderCapability, LogMessageParams, MessageType, OneOf,
-    PositionEncodingKind, TextDocumentSyncCapability, TextDocumentSyncKind, Uri, WorkspaceFolder,
+    CompletionOptions, DidChangeConfigurationParams, DidChangeTextDocumentParams,
+    DidChangeWatchedFilesParams, DidChangeWatchedFilesRegistrationOptions,
+    DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DocumentSymbolResponse, ExecuteCommandOptions, ExecuteCommandParams, FileSystemWatcher,
+    GlobPattern, HoverProviderCapability, LogMessageParams, MarkupKind, MessageType, OneOf,
+    Registration, RegistrationParams, TextDocumentItem, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextDocumentSyncOptions, Uri, WorkspaceFolder,
 };
 use lsp_types::{InitializeParams, ServerCapabilities};
+use prompts::Prompts;
+use router::{RequestContext, Router};
+use std::collections::HashMap;
 use std::fs::{self};
 use std::io::IsTerminal;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::instrument;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{filter, prelude::*, Registry};
-use utils::build_response;
-use workspace::Workspace;
+use workers::WorkerPool;
 
+mod acknowledgements;
+mod cancellation;
+mod check;
 mod cli;
 mod code_actions;
+mod code_lens;
 mod commands;
 mod completion;
+mod describe;
 mod diagnostics;
 mod document_symbols;
+mod duplicate_control_ids;
+mod field_boundaries;
 mod hover;
+mod linked_editing_range;
+#[cfg_attr(not(feature = "mllp"), allow(dead_code))]
+mod listeners;
+mod prompts;
+mod router;
 mod selection_range;
 mod signature_help;
-pub mod spec;
-pub mod utils;
-mod validation;
-mod workspace;
+mod will_save;
+mod workers;
 
 fn setup_logging(cli: Cli) -> Result<()> {
     let use_colours = match (cli.colour, &cli.command) {
         (clap::ColorChoice::Never, _) => false,
         (clap::ColorChoice::Always, _) => true,
         (_, Some(cli::Commands::LogToFile { .. })) => false,
-        (_, Some(cli::Commands::LogToStderr)) => std::io::stderr().is_terminal(),
+        (
+            _,
+            Some(
+                cli::Commands::LogToStderr
+                | cli::Commands::Check { .. }
+                | cli::Commands::Describe { .. },
+            ),
+        ) => std::io::stderr().is_terminal(),
         (_, None) => std::io::stderr().is_terminal(),
     };
 
@@ -115,25 +151,51 @@ fn setup_logging(cli: Cli) -> Result<()> {
     Ok(())
 }
 
-struct Opts {
-    vscode: bool,
-    disable_std_table_validations: bool,
-}
-
 impl From<&Cli> for Opts {
     fn from(value: &Cli) -> Self {
         Self {
             vscode: value.vscode,
             disable_std_table_validations: value.disable_std_table_validations,
+            log_validation_stats: value.log_validation_stats,
+            validation_budget: value.validation_budget.map(Duration::from_millis),
+            fallback_version: value.fallback_version.clone(),
+            position_encoding: Default::default(),
+            segment_terminator: value.segment_terminator,
+            parse_error_severity: value.parse_error_severity,
+            suppress_parse_errors: value.suppress_parse_errors.clone(),
+            clone_identifiers: value.clone_identifier.clone(),
+            trim_trailing_separators: value.trim_trailing_separators,
+            display_timezone: value.display_timezone,
+            output_timezone: value.output_timezone,
+            validation_profiles: value.validation_profile.clone(),
+            non_file_specs: value.non_file_specs,
+            edit_history: value.edit_history.clone(),
+            lint_style: value.lint_style,
+            check_coding_systems: value.check_coding_systems,
+            unique_control_ids: value.unique_control_ids,
+            pinned_version: None,
+            severity_overrides: Default::default(),
         }
     }
 }
 
 fn main() -> Result<()> {
     let cli = cli::cli();
-    let opts = (&cli).into();
+    let mut opts: Opts = (&cli).into();
+    let command = cli.command.clone();
     setup_logging(cli).wrap_err_with(|| "Failed to setup logging")?;
 
+    match command {
+        Some(cli::Commands::Check { message }) => {
+            let found_errors = check::check_message(&message, &opts)?;
+            std::process::exit(if found_errors { 1 } else { 0 });
+        }
+        Some(cli::Commands::Describe { from_message }) => {
+            return describe::describe_message(&from_message, &opts);
+        }
+        _ => {}
+    }
+
     let initial_span = tracing::info_span!("initialise");
     let _initial_span_guard = initial_span.enter();
     tracing::info!("Starting HL7 Language Server");
@@ -146,25 +208,23 @@ fn main() -> Result<()> {
     let client_capabilities = init_params.capabilities;
     let workspace_folders = init_params.workspace_folders;
 
-    let client_supports_utf8_positions = client_capabilities
-        .general
-        .as_ref()
-        .and_then(|g| g.position_encodings.as_ref())
-        .map(|p| p.contains(&PositionEncodingKind::UTF8))
-        .unwrap_or(false);
-    let encoding = if client_supports_utf8_positions {
-        PositionEncodingKind::UTF8
-    } else {
-        tracing::warn!(
-            "Client does not support UTF-8 position encoding, unicode stuff will probably be broken"
-        );
-        PositionEncodingKind::UTF16
-    };
+    opts.position_encoding = PositionEncoding::negotiate(
+        client_capabilities
+            .general
+            .as_ref()
+            .and_then(|g| g.position_encodings.as_deref()),
+    );
+    tracing::debug!(encoding = ?opts.position_encoding, "negotiated position encoding");
 
     let server_capabilities = serde_json::to_value(&ServerCapabilities {
-        position_encoding: Some(encoding),
-        text_document_sync: Some(TextDocumentSyncCapability::Kind(
-            TextDocumentSyncKind::INCREMENTAL,
+        position_encoding: Some(opts.position_encoding.kind()),
+        text_document_sync: Some(TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::INCREMENTAL),
+                will_save_wait_until: Some(opts.segment_terminator.as_str().is_some()),
+                ..Default::default()
+            },
         )),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
         document_symbol_provider: Some(OneOf::Right(lsp_types::DocumentSymbolOptions {
@@ -172,22 +232,28 @@ fn main() -> Result<()> {
             work_done_progress_options: Default::default(),
         })),
         completion_provider: Some(CompletionOptions {
+            resolve_provider: Some(true),
+            trigger_characters: Some(
+                ["|", "^", "~", "&"]
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+            ),
             ..Default::default()
         }),
         code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
             code_action_kinds: Some(vec![lsp_types::CodeActionKind::QUICKFIX]),
+            resolve_provider: Some(true),
             ..Default::default()
         })),
+        code_lens_provider: Some(lsp_types::CodeLensOptions {
+            resolve_provider: Some(false),
+        }),
         execute_command_provider: Some(ExecuteCommandOptions {
-            commands: vec![
-                commands::CMD_SET_TO_NOW.to_string(),
-                commands::CMD_SEND_MESSAGE.to_string(),
-                commands::CMD_GENERATE_CONTROL_ID.to_string(),
-                commands::CMD_DECODE_TEXT.to_string(),
-                commands::CMD_ENCODE_TEXT.to_string(),
-                commands::CMD_DECODE_SELECTION.to_string(),
-                commands::CMD_ENCODE_SELECTION.to_string(),
-            ],
+            commands: commands::list_commands()
+                .into_iter()
+                .map(|command| command.id)
+                .collect(),
             ..Default::default()
         }),
         selection_range_provider: Some(lsp_types::SelectionRangeProviderCapability::Simple(true)),
@@ -196,6 +262,16 @@ fn main() -> Result<()> {
             retrigger_characters: Some(vec!["|".to_string(), "^".to_string()]),
             ..Default::default()
         }),
+        workspace: Some(lsp_types::WorkspaceServerCapabilities {
+            workspace_folders: Some(lsp_types::WorkspaceFoldersServerCapabilities {
+                supported: Some(true),
+                change_notifications: Some(OneOf::Left(true)),
+            }),
+            file_operations: None,
+        }),
+        linked_editing_range_provider: Some(
+            lsp_types::LinkedEditingRangeServerCapabilities::Simple(true),
+        ),
         ..Default::default()
     })
     .expect("can to serialize server capabilities");
@@ -221,6 +297,59 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Whether the client can watch files for us, once asked to with
+/// [register_spec_file_watchers]
+fn client_watches_files(client_capabilities: &ClientCapabilities) -> bool {
+    client_capabilities
+        .workspace
+        .as_ref()
+        .and_then(|w| w.did_change_watched_files.as_ref())
+        .and_then(|d| d.dynamic_registration)
+        .unwrap_or(false)
+}
+
+/// Ask the client to watch spec files for us, which is more reliable than
+/// watching them ourselves on remote / WSL filesystems
+fn register_spec_file_watchers(connection: &Connection) {
+    let register_options = DidChangeWatchedFilesRegistrationOptions {
+        // conformance profiles can be named anything
+        watchers: [
+            "**/*.hl7v.toml",
+            "**/*.hl7snippets.toml",
+            "**/*.hl7connections.toml",
+            "**/*.tbl.csv",
+            "**/*.xml",
+            // for the index of control IDs
+            "**/*.hl7",
+        ]
+        .into_iter()
+        .map(|glob| FileSystemWatcher {
+            glob_pattern: GlobPattern::String(glob.to_string()),
+            kind: None,
+        })
+        .collect(),
+    };
+    let params = RegistrationParams {
+        registrations: vec![Registration {
+            id: "hl7-ls-spec-files".to_string(),
+            method: DidChangeWatchedFiles::METHOD.to_string(),
+            register_options: Some(
+                serde_json::to_value(register_options).expect("can serialize register options"),
+            ),
+        }],
+    };
+    let request_id: i32 = rand::random();
+    tracing::trace!(?params, ?request_id, "registering spec file watchers");
+    connection
+        .sender
+        .send(Message::Request(Request {
+            id: request_id.into(),
+            method: RegisterCapability::METHOD.to_string(),
+            params: serde_json::to_value(params).expect("can serialize registration params"),
+        }))
+        .expect("can send request");
+}
+
 fn send_log_message<S: ToString>(
     connection: &Connection,
     message_type: MessageType,
@@ -246,9 +375,11 @@ fn main_loop(
     connection: Connection,
     client_capabilities: ClientCapabilities,
     workspace_folders: Option<Vec<WorkspaceFolder>>,
-    opts: Opts,
+    mut opts: Opts,
 ) -> Result<()> {
     let mut documents = TextDocuments::new();
+    let mut validation_caches = ValidationCaches::new();
+    let inbox = Inbox::new(connection.receiver.clone());
 
     let diagnostics_enabled = client_capabilities
         .text_document
@@ -257,93 +388,252 @@ fn main_loop(
         .unwrap_or(false);
     tracing::debug!("diagnostics enabled: {diagnostics_enabled}");
 
+    let code_action_resolve_edits = client_capabilities
+        .text_document
+        .as_ref()
+        .and_then(|tdc| tdc.code_action.as_ref())
+        .and_then(|ca| ca.resolve_support.as_ref())
+        .map(|rs| rs.properties.iter().any(|p| p == "edit"))
+        .unwrap_or(false);
+    tracing::debug!("code action edit resolution enabled: {code_action_resolve_edits}");
+
+    // clients list the formats they support in order of preference
+    let hover_markdown = client_capabilities
+        .text_document
+        .as_ref()
+        .and_then(|tdc| tdc.hover.as_ref())
+        .and_then(|hover| hover.content_format.as_ref())
+        .and_then(|formats| formats.first())
+        .map(|format| *format == MarkupKind::Markdown)
+        .unwrap_or(false);
+    tracing::debug!("markdown hovers enabled: {hover_markdown}");
+
+    // clients that can't offer actions on messages can still show them
+    let message_actions = client_capabilities
+        .window
+        .as_ref()
+        .is_some_and(|window| window.show_message.is_some());
+    tracing::debug!("message actions enabled: {message_actions}");
+
+    let client_support = ClientSupport {
+        diagnostics: diagnostics_enabled,
+        code_action_resolve_edits,
+        hover_markdown,
+        message_actions,
+    };
+    let prompts = Arc::new(Prompts::default());
+    let listeners = Arc::new(Listeners::default());
+
     let load_custom_validators_span = tracing::debug_span!("load_custom_validators");
     let _load_custom_validators_span_guard = load_custom_validators_span.enter();
-    let workspace = workspace_folders
-        .map(Workspace::new)
-        .transpose()
-        .wrap_err_with(|| "Failed to load custom validators")?;
-    if workspace.is_some() {
+    // always set up the workspace, even without any folders, as the client may
+    // add folders later
+    let client_watches_files = client_watches_files(&client_capabilities);
+    tracing::debug!("client file watching enabled: {client_watches_files}");
+    let workspace = Workspace::new(
+        workspace_folders.unwrap_or_default(),
+        client_watches_files,
+        &opts,
+    )
+    .wrap_err_with(|| "Failed to load custom validators")?;
+    let workspace = Arc::new(workspace);
+    if client_watches_files {
+        register_spec_file_watchers(&connection);
+    }
+    if !workspace.specs.specs.is_empty() {
         tracing::info!("Custom validators loaded");
         send_log_message(&connection, MessageType::INFO, "Custom validators loaded")
             .wrap_err_with(|| "Failed to send log message")?;
     } else {
         tracing::info!("No custom validators found");
     }
+    show_spec_failures(&connection, &workspace);
     drop(_load_custom_validators_span_guard);
 
+    let router = router();
+
     tracing::debug!("starting main loop");
-    if let Some(workspace) = workspace {
-        loop {
-            select! {
-                recv(&connection.receiver) -> msg => {
-                    let msg = msg.wrap_err_with(|| "Failed to receive message")?;
-                    handle_msg(msg, &connection, &mut documents, &opts, Some(&workspace), diagnostics_enabled)
-                        .wrap_err_with(|| "Failed to handle message")?;
-                }
-                recv(workspace._custom_spec_changes) -> _ => {
-                    for (document_uri, document) in documents.documents() {
-                        if let Err(e) = handle_diagnostics(&connection, document_uri, Some(document.version()), &documents, Some(&workspace), &opts) {
-                            tracing::error!("Failed to handle diagnostics: {e:?}");
-                        }
-                    }
-                }
-            }
-        }
-    } else {
-        for msg in &connection.receiver {
+    loop {
+        while let Some(msg) = inbox.pop() {
             handle_msg(
                 msg,
                 &connection,
+                &inbox,
                 &mut documents,
-                &opts,
-                workspace.as_ref(),
-                diagnostics_enabled,
+                &mut validation_caches,
+                &mut opts,
+                Some(&workspace),
+                client_support,
+                &prompts,
+                &listeners,
+                &router,
             )
             .wrap_err_with(|| "Failed to handle message")?;
         }
+
+        select! {
+            recv(&connection.receiver) -> msg => {
+                // the client hung up after shutting down
+                let Ok(msg) = msg else {
+                    break;
+                };
+                inbox.push(msg);
+            }
+            recv(workspace._custom_spec_changes) -> _ => {
+                show_spec_failures(&connection, &workspace);
+                // previous results may have come from the old specs
+                validation_caches.clear();
+                for (document_uri, document) in documents.documents() {
+                    if let Err(e) = handle_diagnostics(&connection, &inbox, document_uri, Some(document.version()), &documents, &mut validation_caches, Some(&*workspace), &opts) {
+                        tracing::error!("Failed to handle diagnostics: {e:?}");
+                    }
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Let the user know about specs that couldn't be loaded, as documents would
+/// otherwise be validated without them and nobody the wiser
+fn show_spec_failures(connection: &Connection, workspace: &Workspace) {
+    for (path, error) in workspace.specs.take_failures() {
+        prompts::show_message(
+            &connection.sender,
+            MessageType::ERROR,
+            format!("Failed to load spec {}: {error}", path.display()),
+        );
+    }
+}
+
+/// What the connected client supports, as negotiated during initialisation
+#[derive(Debug, Clone, Copy, Default)]
+struct ClientSupport {
+    diagnostics: bool,
+    code_action_resolve_edits: bool,
+    hover_markdown: bool,
+    /// Whether errors can be shown with actions (e.g. to retry), using
+    /// `window/showMessageRequest`
+    message_actions: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handle_msg(
     msg: Message,
     connection: &Connection,
+    inbox: &Inbox,
     documents: &mut TextDocuments,
-    opts: &Opts,
-    workspace: Option<&Workspace>,
-    diagnostics_enabled: bool,
+    validation_caches: &mut ValidationCaches,
+    opts: &mut Opts,
+    workspace: Option<&Arc<Workspace>>,
+    client_support: ClientSupport,
+    prompts: &Arc<Prompts>,
+    listeners: &Arc<Listeners>,
+    router: &Router,
 ) -> Result<()> {
+    let request_context =
+        |id: lsp_server::RequestId, documents: &TextDocuments, opts: &Opts| RequestContext {
+            id: id.clone(),
+            sender: connection.sender.clone(),
+            token: inbox.token(id),
+            documents: snapshot(documents),
+            workspace: workspace.cloned(),
+            opts: opts.clone(),
+            client_support,
+            prompts: prompts.clone(),
+            listeners: listeners.clone(),
+        };
+
     match msg {
         Message::Request(req) => {
             let request_span = tracing::debug_span!("request", method = ?req.method, id = ?req.id);
             let _request_span_guard = request_span.enter();
 
             if connection.handle_shutdown(&req)? {
+                listeners.stop(None);
+                return Ok(());
+            }
+
+            let id = req.id.clone();
+            if inbox.is_cancelled(&id) {
+                tracing::debug!("request was cancelled before it was handled");
+                let resp = build_response::<()>(id.clone(), Err(RequestCancelled.into()));
+                connection
+                    .sender
+                    .send(Message::Response(resp))
+                    .expect("can send response");
+                inbox.finish(&id);
                 return Ok(());
             }
 
-            if let Some(req) = handle_hover_req(req, documents, workspace, opts, connection)
-                .and_then(|req| handle_document_symbols_req(req, documents, connection))
-                .and_then(|req| handle_completion_request(req, documents, connection))
-                .and_then(|req| handle_code_action_request(req, documents, connection))
-                .and_then(|req| handle_command_request(req, documents, connection))
-                .and_then(|req| handle_selection_range_req(req, documents, connection))
-                .and_then(|req| handle_signature_help_request(req, documents, connection))
-            {
+            if !router.handles(&req.method) {
                 tracing::warn!("unhandled request: {req:?}");
+                inbox.finish(&id);
+                return Ok(());
             }
+
+            router.dispatch(req, request_context(id, documents, opts));
         }
+
         Message::Response(resp) => {
-            tracing::warn!(response = ?resp, "got response from server??");
+            if let Some(params) = prompts.answered(&resp) {
+                tracing::debug!(command = params.command, "retrying command");
+                let ctx = request_context(resp.id, documents, opts);
+                router.execute(ctx, move |ctx| retry_command(params, ctx));
+            } else {
+                tracing::warn!(response = ?resp, "got response from server??");
+            }
         }
         Message::Notification(not) => {
             let notification_span = tracing::debug_span!("notification", method = ?not.method);
             let _notification_span_guard = notification_span.enter();
 
-            if documents.listen(not.method.as_str(), &not.params) {
-                if !diagnostics_enabled {
+            if !has_valid_params(&not) {
+                tracing::warn!("malformed notification params: {not:?}");
+                return Ok(());
+            }
+
+            if not.method == DidChangeWatchedFiles::METHOD {
+                let params: DidChangeWatchedFilesParams = serde_json::from_value(not.params)
+                    .expect("Expect receive DidChangeWatchedFilesParams");
+                if let Some(workspace) = workspace {
+                    workspace.files_changed(params.changes);
+                }
+            } else if not.method == DidChangeConfiguration::METHOD {
+                let params: DidChangeConfigurationParams = serde_json::from_value(not.params)
+                    .expect("Expect receive DidChangeConfigurationParams");
+                opts.severity_overrides = severity_overrides(&params.settings);
+                opts.pinned_version = pinned_version(&params.settings);
+                if !client_support.diagnostics {
+                    return Ok(());
+                }
+                for (uri, document) in documents.documents() {
+                    if let Err(e) = handle_diagnostics(
+                        connection,
+                        inbox,
+                        uri,
+                        Some(document.version()),
+                        documents,
+                        validation_caches,
+                        workspace.map(|w| &**w),
+                        opts,
+                    ) {
+                        tracing::error!("Failed to handle diagnostics: {e:?}");
+                    }
+                }
+            } else if not.method == DidChangeWorkspaceFolders::METHOD {
+                let params: DidChangeWorkspaceFoldersParams = serde_json::from_value(not.params)
+                    .expect("Expect receive DidChangeWorkspaceFoldersParams");
+                if let Some(workspace) = workspace {
+                    // diagnostics are refreshed through the workspace's spec
+                    // change notifications
+                    if let Err(e) = workspace.change_folders(params.event) {
+                        tracing::error!("Failed to change workspace folders: {e:?}");
+                    }
+                }
+            } else if listen(documents, &not, opts.position_encoding) {
+                if !client_support.diagnostics {
                     return Ok(());
                 }
 
@@ -367,16 +657,60 @@ fn handle_msg(
                         let text_document = params.text_document;
                         (Some(text_document.uri), Some(text_document.version))
                     }
+                    <DidCloseTextDocument as notification::Notification>::METHOD => {
+                        let params: DidCloseTextDocumentParams =
+                            serde_json::from_value(not.params.clone())
+                                .expect("Expect receive DidCloseTextDocumentParams");
+                        validation_caches.remove(params.text_document.uri.as_str());
+                        (None, None)
+                    }
                     _ => (None, None),
                 };
 
-                if let Some(uri) = uri {
-                    if let Err(e) =
-                        handle_diagnostics(connection, &uri, version, documents, workspace, opts)
-                    {
+                if let Some(uri) = &uri {
+                    if let Err(e) = handle_diagnostics(
+                        connection,
+                        inbox,
+                        uri,
+                        version,
+                        documents,
+                        validation_caches,
+                        workspace.map(|w| &**w),
+                        opts,
+                    ) {
                         tracing::error!("Failed to handle diagnostics: {e:?}");
                     }
                 }
+
+                // acknowledgements are reported on the messages they
+                // acknowledge, which need diagnosing again when one is
+                // changed or closed
+                let changed_acknowledgement = match &uri {
+                    Some(uri) => documents
+                        .get_document_content(uri, None)
+                        .and_then(|text| hl7_parser::parse_message_with_lenient_newlines(text).ok())
+                        .is_some_and(|message| acknowledgements::is_acknowledgement(&message)),
+                    None => not.method == DidCloseTextDocument::METHOD,
+                };
+                if changed_acknowledgement {
+                    for (other_uri, document) in documents.documents() {
+                        if Some(other_uri) == uri.as_ref() {
+                            continue;
+                        }
+                        if let Err(e) = handle_diagnostics(
+                            connection,
+                            inbox,
+                            other_uri,
+                            Some(document.version()),
+                            documents,
+                            validation_caches,
+                            workspace.map(|w| &**w),
+                            opts,
+                        ) {
+                            tracing::error!("Failed to handle diagnostics: {e:?}");
+                        }
+                    }
+                }
             } else {
                 tracing::warn!("unhandled notification: {not:?}");
             }
@@ -385,12 +719,58 @@ fn handle_msg(
     Ok(())
 }
 
-#[instrument(level = "debug", skip(connection, documents, workspace, opts))]
+/// Keep the open documents up to date with the client's notifications
+///
+/// [TextDocuments] always treats the ranges of changes as UTF-16, so changes
+/// are applied one at a time with their ranges re-encoded into UTF-16 against
+/// the text they apply to.
+fn listen(
+    documents: &mut TextDocuments,
+    not: &lsp_server::Notification,
+    encoding: PositionEncoding,
+) -> bool {
+    if not.method != DidChangeTextDocument::METHOD || encoding == PositionEncoding::Utf16 {
+        return documents.listen(not.method.as_str(), &not.params);
+    }
+
+    let params: DidChangeTextDocumentParams = serde_json::from_value(not.params.clone())
+        .expect("Expect receive DidChangeTextDocumentParams");
+    for mut change in params.content_changes {
+        let text = documents.get_document_content(&params.text_document.uri, None);
+        if let (Some(range), Some(text)) = (change.range, text) {
+            let convert = |position| {
+                convert_position(text, position, encoding, PositionEncoding::Utf16)
+                    .unwrap_or(position)
+            };
+            change.range = Some(lsp_types::Range {
+                start: convert(range.start),
+                end: convert(range.end),
+            });
+        }
+        let params = DidChangeTextDocumentParams {
+            text_document: params.text_document.clone(),
+            content_changes: vec![change],
+        };
+        documents.listen(
+            not.method.as_str(),
+            &serde_json::to_value(params).expect("can serialize DidChangeTextDocumentParams"),
+        );
+    }
+    true
+}
+
+#[instrument(
+    level = "debug",
+    skip(connection, inbox, documents, validation_caches, workspace, opts)
+)]
+#[allow(clippy::too_many_arguments)]
 fn handle_diagnostics(
     connection: &Connection,
+    inbox: &Inbox,
     uri: &Uri,
     version: Option<i32>,
     documents: &TextDocuments,
+    validation_caches: &mut ValidationCaches,
     workspace: Option<&Workspace>,
     opts: &Opts,
 ) -> Result<()> {
@@ -398,17 +778,78 @@ fn handle_diagnostics(
     if let Some(text) = text {
         let parse_and_validate_span = tracing::debug_span!("parse and validate");
         let _parse_and_validate_span_guard = parse_and_validate_span.enter();
-        let errors = match hl7_parser::parse_message_with_lenient_newlines(text) {
-            Ok(message) => validation::validate_message(
+        let errors = if validation::is_batch(text, opts) {
+            let Some(batch) = validation::validate_batch(
                 uri,
-                &message,
+                text,
                 &workspace.as_ref().map(|w| w.specs.deref()),
                 opts,
-            )
-            .into_iter()
-            .map(|e| e.into_diagnostic(text))
-            .collect(),
-            Err(err) => vec![diagnostics::parse_error_to_diagnostic(text, err)],
+                &|| inbox.has_pending_change(uri),
+            ) else {
+                return Ok(());
+            };
+            let parse_errors = if opts.suppresses_parse_errors(uri) {
+                Vec::new()
+            } else {
+                batch.parse_errors
+            };
+            batch
+                .errors
+                .into_iter()
+                .map(|e| e.into_diagnostic(uri, text, opts.position_encoding))
+                .chain(
+                    parse_errors
+                        .into_iter()
+                        .map(|e| diagnostics::parse_error_to_diagnostic(text, e, opts)),
+                )
+                .collect()
+        } else {
+            match hl7_parser::parse_message_with_lenient_newlines(text) {
+                Ok(message) => {
+                    // no point finishing if the document has already changed again
+                    let Some(errors) = validation::validate_message_incrementally(
+                        uri,
+                        &message,
+                        &workspace.as_ref().map(|w| w.specs.deref()),
+                        opts,
+                        validation_caches.entry(uri.to_string()).or_default(),
+                        &|| inbox.has_pending_change(uri),
+                    ) else {
+                        return Ok(());
+                    };
+                    errors
+                        .into_iter()
+                        .map(|e| e.into_diagnostic(uri, text, opts.position_encoding))
+                        .chain(acknowledgements::acknowledgement_diagnostics(
+                            uri,
+                            text,
+                            &message,
+                            documents,
+                            opts.position_encoding,
+                        ))
+                        .collect()
+                }
+                Err(_) if opts.suppresses_parse_errors(uri) => {
+                    tracing::debug!("parse errors are suppressed for this document");
+                    Vec::new()
+                }
+                Err(err) => diagnostics::parse_error_diagnostics(text, err, opts),
+            }
+        };
+        let errors = match workspace
+            .and_then(|workspace| Some((workspace, workspace.control_ids.as_ref()?)))
+        {
+            Some((workspace, control_ids)) => errors
+                .into_iter()
+                .chain(duplicate_control_ids::duplicate_control_id_diagnostics(
+                    uri,
+                    text,
+                    control_ids,
+                    &workspace.specs,
+                    opts,
+                ))
+                .collect(),
+            None => errors,
         };
         drop(_parse_and_validate_span_guard);
         let publish_diagnostics_span = tracing::debug_span!("publish diagnostics");
@@ -424,254 +865,729 @@ fn handle_diagnostics(
     Ok(())
 }
 
-fn cast_request<R>(req: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
-where
-    R: lsp_types::request::Request,
-    R::Params: serde::de::DeserializeOwned,
-{
-    req.extract(R::METHOD)
+/// The validation results of each open document's segments, by the
+/// document's URI
+type ValidationCaches = HashMap<String, ValidationCache>;
+
+/// Copy the open documents so that a request can be answered on a worker
+/// while the main thread carries on applying changes to them
+fn snapshot(documents: &TextDocuments) -> TextDocuments {
+    let mut snapshot = TextDocuments::new();
+    for (uri, document) in documents.documents() {
+        let params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: document.language_id().to_string(),
+                version: document.version(),
+                text: document.get_content(None).to_string(),
+            },
+        };
+        snapshot.listen(
+            DidOpenTextDocument::METHOD,
+            &serde_json::to_value(params).expect("can serialize document"),
+        );
+    }
+    snapshot
 }
 
-fn handle_hover_req(
-    req: Request,
-    documents: &TextDocuments,
-    workspace: Option<&Workspace>,
-    opts: &Opts,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<HoverRequest>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got Hover request");
-            let resp = hover::handle_hover_request(
+/// The severities to report validation codes at from the client's settings,
+/// given as `{ "hl7": { "severities": { "<code>": "<severity>" } } }` (or
+/// without the `hl7` section), where the severity is one of `error`,
+/// `warning`, `info`, `hint`, or `off`
+///
+/// Unknown codes and severities are ignored.
+fn severity_overrides(settings: &serde_json::Value) -> HashMap<String, SeverityOverride> {
+    let severities = settings
+        .get("hl7")
+        .unwrap_or(settings)
+        .get("severities")
+        .and_then(|severities| severities.as_object());
+    let Some(severities) = severities else {
+        return HashMap::new();
+    };
+
+    let mut overrides = HashMap::new();
+    for (code, severity) in severities {
+        if !ValidationCode::KEYS.contains(&code.as_str()) {
+            tracing::warn!(code, "Unknown validation code in severity settings");
+            continue;
+        }
+        match serde_json::from_value::<SeverityOverride>(severity.clone()) {
+            Ok(severity) => {
+                overrides.insert(code.clone(), severity);
+            }
+            Err(e) => tracing::warn!(code, "Invalid severity in settings: {e}"),
+        }
+    }
+    overrides
+}
+
+/// The HL7 version documents must declare from the client's settings, given
+/// as `{ "hl7": { "version": "<version>" } }` (or without the `hl7` section)
+///
+/// Unknown versions are ignored.
+fn pinned_version(settings: &serde_json::Value) -> Option<String> {
+    let version = settings
+        .get("hl7")
+        .unwrap_or(settings)
+        .get("version")?
+        .as_str()?;
+    if !spec::is_valid_version(version) {
+        tracing::warn!(version, "Unknown HL7 version in settings");
+        return None;
+    }
+    Some(version.to_string())
+}
+
+/// Check that the params of a notification we handle are well-formed, as
+/// neither we nor [TextDocuments] can do anything sensible with malformed ones
+fn has_valid_params(not: &lsp_server::Notification) -> bool {
+    fn parses<N>(params: &serde_json::Value) -> bool
+    where
+        N: Notification,
+        N::Params: serde::de::DeserializeOwned,
+    {
+        serde_json::from_value::<N::Params>(params.clone()).is_ok()
+    }
+
+    match not.method.as_str() {
+        DidOpenTextDocument::METHOD => parses::<DidOpenTextDocument>(&not.params),
+        DidChangeTextDocument::METHOD => parses::<DidChangeTextDocument>(&not.params),
+        DidCloseTextDocument::METHOD => parses::<DidCloseTextDocument>(&not.params),
+        DidChangeWorkspaceFolders::METHOD => parses::<DidChangeWorkspaceFolders>(&not.params),
+        DidChangeWatchedFiles::METHOD => parses::<DidChangeWatchedFiles>(&not.params),
+        DidChangeConfiguration::METHOD => parses::<DidChangeConfiguration>(&not.params),
+        _ => true,
+    }
+}
+
+/// How many requests can be answered at once
+const WORKER_THREADS: usize = 4;
+
+/// The handlers for every request the server answers
+fn router() -> Router {
+    Router::new(WorkerPool::new(WORKER_THREADS))
+        .on::<HoverRequest, _>(|params, ctx| {
+            hover::handle_hover_request(
                 params,
-                documents,
-                workspace.as_ref().map(|w| &*w.specs),
-                opts,
+                &ctx.documents,
+                ctx.specs(),
+                &ctx.opts,
+                ctx.markup_kind(),
+                &ctx.token,
+            )
+            .map(Some)
+        })
+        .on::<DocumentSymbolRequest, _>(|params, ctx| {
+            document_symbols::handle_document_symbols_request(
+                params,
+                &ctx.documents,
+                ctx.specs(),
+                &ctx.opts,
+                &ctx.token,
+            )
+            .map(|symbols| Some(DocumentSymbolResponse::Nested(symbols)))
+        })
+        .on::<Completion, _>(|params, ctx| {
+            completion::handle_completion_request(params, &ctx.documents, ctx.specs(), &ctx.opts)
+                .map(Some)
+        })
+        .on::<ResolveCompletionItem, _>(|params, ctx| {
+            completion::handle_completion_resolve_request(params, ctx.specs())
+        })
+        .on::<CodeActionRequest, _>(|params, ctx| {
+            code_actions::handle_code_actions_request(
+                params,
+                &ctx.documents,
+                ctx.specs(),
+                &ctx.opts,
+                ctx.client_support.code_action_resolve_edits,
+            )
+        })
+        .on::<CodeLensRequest, _>(|params, ctx| {
+            code_lens::handle_code_lens_request(params, &ctx.documents, ctx.specs(), &ctx.opts)
+        })
+        .on::<CodeActionResolveRequest, _>(|params, ctx| {
+            code_actions::handle_code_action_resolve_request(params, &ctx.documents, &ctx.opts)
+        })
+        .on_raw::<ExecuteCommand, _>(handle_command_request)
+        .on::<commands::ListCommands, _>(|(), _| Ok(commands::list_commands()))
+        .on::<field_boundaries::FieldBoundaries, _>(|params, ctx| {
+            field_boundaries::handle_field_boundaries_request(params, &ctx.documents, &ctx.opts)
+        })
+        .on::<SelectionRangeRequest, _>(|params, ctx| {
+            selection_range::handle_selection_range_request(params, &ctx.documents, &ctx.opts)
+                .map(Some)
+        })
+        .on::<SignatureHelpRequest, _>(|params, ctx| {
+            signature_help::handle_signature_help_request(
+                params,
+                &ctx.documents,
+                ctx.specs(),
+                &ctx.opts,
+            )
+        })
+        .on::<LinkedEditingRange, _>(|params, ctx| {
+            linked_editing_range::handle_linked_editing_range_request(
+                params,
+                &ctx.documents,
+                &ctx.opts,
+            )
+        })
+        .on::<WillSaveWaitUntil, _>(|params, ctx| {
+            will_save::handle_will_save_wait_until_request(params, &ctx.documents, &ctx.opts)
+        })
+}
+
+/// Commands respond with either a value or `true`, after which any edit the
+/// command made is sent to the client to apply
+fn handle_command_request(params: ExecuteCommandParams, ctx: &RequestContext) {
+    let id = ctx.id.clone();
+    let command = params.command.clone();
+    let retry = prompts::RETRYABLE_COMMANDS
+        .contains(&command.as_str())
+        .then(|| params.clone());
+
+    let (edit, resp) = match execute_command(params, ctx) {
+        Ok(Some(command_result)) => match command_result {
+            commands::CommandResult::WorkspaceEdit { label, edit } => (
+                Some((label, edit)),
+                Response {
+                    id,
+                    result: Some(serde_json::Value::Bool(true)),
+                    error: None,
+                },
+            ),
+            commands::CommandResult::ValueResponse { value } => (
+                None,
+                Response {
+                    id,
+                    result: Some(value),
+                    error: None,
+                },
+            ),
+            commands::CommandResult::ValueResponseWithEdit { value, label, edit } => (
+                Some((label, edit)),
+                Response {
+                    id,
+                    result: Some(value),
+                    error: None,
+                },
+            ),
+        },
+        Ok(None) => (
+            None,
+            Response {
+                id,
+                result: Some(serde_json::Value::Null),
+                error: Some(ResponseError {
+                    code: lsp_server::ErrorCode::RequestFailed as i32,
+                    message: "Unknown command".to_string(),
+                    data: None,
+                }),
+            },
+        ),
+        Err(error) => {
+            // the client may not show failed commands to the user, who can
+            // do something about these
+            if retry.is_some() {
+                ctx.show_error(format!("{error:#}"), retry);
+            }
+            (
+                None,
+                Response {
+                    id,
+                    result: None,
+                    error: Some(ResponseError {
+                        code: lsp_server::ErrorCode::InternalError as i32,
+                        message: format!("{error:#}"),
+                        data: None,
+                    }),
+                },
             )
-            .map_err(|e| {
-                tracing::warn!("Failed to handle hover request: {e:?}");
-                e
-            });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
+    };
+    ctx.send(Message::Response(resp));
+
+    if let Some((label, edit)) = edit {
+        apply_command_edit(&command, label, edit, ctx);
     }
 }
 
-fn handle_document_symbols_req(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<DocumentSymbolRequest>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got DocumentSymbol request");
-            let resp = document_symbols::handle_document_symbols_request(params, documents)
-                .map_err(|e| {
-                    tracing::warn!("Failed to handle document symbols request: {e:?}");
-                    e
-                });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
+/// Run a command again after the user chose to retry it, showing them how it
+/// went as there's no request to respond to
+fn retry_command(params: ExecuteCommandParams, ctx: &RequestContext) {
+    let command = params.command.clone();
+    match execute_command(params.clone(), ctx) {
+        Ok(Some(commands::CommandResult::WorkspaceEdit { label, edit })) => {
+            apply_command_edit(&command, label, edit, ctx);
+        }
+        Ok(Some(commands::CommandResult::ValueResponse { .. })) => {
+            prompts::show_message(
+                &ctx.sender,
+                MessageType::INFO,
+                format!("Retried `{command}` successfully"),
+            );
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
+        Ok(Some(commands::CommandResult::ValueResponseWithEdit { label, edit, .. })) => {
+            prompts::show_message(
+                &ctx.sender,
+                MessageType::INFO,
+                format!("Retried `{command}` successfully"),
+            );
+            apply_command_edit(&command, label, edit, ctx);
+        }
+        Ok(None) => {}
+        Err(error) => ctx.show_error(format!("{error:#}"), Some(params)),
     }
 }
 
-fn handle_completion_request(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<Completion>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got Completion request");
-            let resp = completion::handle_completion_request(params, documents).map_err(|e| {
-                tracing::warn!("Failed to handle completion request: {e:?}");
-                e
-            });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
+fn execute_command(
+    params: ExecuteCommandParams,
+    ctx: &RequestContext,
+) -> Result<Option<commands::CommandResult>> {
+    match params.command.as_str() {
+        #[cfg(feature = "mllp")]
+        commands::CMD_START_LISTENER | commands::CMD_STOP_LISTENER => {
+            commands::handle_listener_command(params, &ctx.listeners, &ctx.sender, &ctx.opts)
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
+        _ => commands::handle_execute_command_request(
+            params,
+            &ctx.documents,
+            ctx.workspace.as_deref(),
+            &ctx.opts,
+        ),
     }
+    .map_err(|e| {
+        tracing::warn!("Failed to handle execute command request: {e:?}");
+        e
+    })
 }
 
-fn handle_code_action_request(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<CodeActionRequest>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got CodeAction request");
-            let resp = code_actions::handle_code_actions_request(params, documents).map_err(|e| {
-                tracing::warn!("Failed to handle code action request: {e:?}");
-                e
-            });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
-        }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
+/// Record an edit made by a command and send it to the client to apply
+fn apply_command_edit(
+    command: &str,
+    label: &str,
+    edit: lsp_types::WorkspaceEdit,
+    ctx: &RequestContext,
+) {
+    let apply_edit_span = tracing::debug_span!("apply edit");
+    let _apply_edit_span_guard = apply_edit_span.enter();
+    if let Some(workspace) = ctx.workspace.as_deref() {
+        let text_of = |uri: &Uri| {
+            ctx.documents
+                .get_document_content(uri, None)
+                .map(str::to_string)
+                .or_else(|| fs::read_to_string(file_path(uri)?).ok())
+        };
+        workspace.history.record(EditRecord::new(
+            command,
+            label,
+            &edit,
+            text_of,
+            ctx.opts.position_encoding,
+        ));
     }
+    let apply_edit_params = ApplyWorkspaceEditParams {
+        label: Some(label.to_string()),
+        edit,
+    };
+    let request_id: i32 = rand::random();
+    tracing::trace!(?apply_edit_params, ?request_id, "sending apply edit");
+    let apply_edit_req = Request {
+        id: request_id.into(),
+        method: ApplyWorkspaceEdit::METHOD.to_string(),
+        params: serde_json::to_value(apply_edit_params).unwrap(),
+    };
+    ctx.send(Message::Request(apply_edit_req));
 }
 
-fn handle_command_request(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<ExecuteCommand>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got ExecuteCommand request");
-            let result = commands::handle_execute_command_request(params, documents).map_err(|e| {
-                tracing::warn!("Failed to handle execute command request: {e:?}");
-                e
-            });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{notification::Cancel, CancelParams, NumberOrString};
 
-            let (edit, resp) = match result {
-                Ok(Some(command_result)) => match command_result {
-                    commands::CommandResult::WorkspaceEdit { label, edit } => (
-                        Some((label, edit)),
-                        Response {
-                            id,
-                            result: Some(serde_json::Value::Bool(true)),
-                            error: None,
-                        },
-                    ),
-                    commands::CommandResult::ValueResponse { value } => (
-                        None,
-                        Response {
-                            id,
-                            result: Some(value),
-                            error: None,
-                        },
-                    ),
-                },
-                Ok(None) => (
-                    None,
-                    Response {
-                        id,
-                        result: Some(serde_json::Value::Null),
-                        error: Some(ResponseError {
-                            code: lsp_server::ErrorCode::RequestFailed as i32,
-                            message: "Unknown command".to_string(),
-                            data: None,
-                        }),
-                    },
-                ),
-                Err(error) => (
-                    None,
-                    Response {
-                        id,
-                        result: None,
-                        error: Some(ResponseError {
-                            code: lsp_server::ErrorCode::InternalError as i32,
-                            message: format!("{error:#}"),
-                            data: None,
-                        }),
-                    },
-                ),
-            };
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-
-            if let Some((label, edit)) = edit {
-                let apply_edit_span = tracing::debug_span!("apply edit");
-                let _apply_edit_span_guard = apply_edit_span.enter();
-                let apply_edit_params = ApplyWorkspaceEditParams {
-                    label: Some(label.to_string()),
-                    edit,
-                };
-                let request_id: i32 = rand::random();
-                tracing::trace!(?apply_edit_params, ?request_id, "sending apply edit");
-                let apply_edit_req = Request {
-                    id: request_id.into(),
-                    method: ApplyWorkspaceEdit::METHOD.to_string(),
-                    params: serde_json::to_value(apply_edit_params).unwrap(),
+    const REQUEST_METHODS: &[&str] = &[
+        HoverRequest::METHOD,
+        DocumentSymbolRequest::METHOD,
+        Completion::METHOD,
+        ResolveCompletionItem::METHOD,
+        CodeActionRequest::METHOD,
+        CodeActionResolveRequest::METHOD,
+        ExecuteCommand::METHOD,
+        SelectionRangeRequest::METHOD,
+        LinkedEditingRange::METHOD,
+        SignatureHelpRequest::METHOD,
+        WillSaveWaitUntil::METHOD,
+        field_boundaries::FieldBoundaries::METHOD,
+    ];
+
+    const NOTIFICATION_METHODS: &[&str] = &[
+        DidOpenTextDocument::METHOD,
+        DidChangeTextDocument::METHOD,
+        DidCloseTextDocument::METHOD,
+        DidChangeWorkspaceFolders::METHOD,
+        DidChangeWatchedFiles::METHOD,
+        DidChangeConfiguration::METHOD,
+    ];
+
+    fn malformed_params() -> Vec<serde_json::Value> {
+        vec![
+            serde_json::Value::Null,
+            serde_json::json!(42),
+            serde_json::json!("params"),
+            serde_json::json!([1, 2, 3]),
+            serde_json::json!({ "garbage": true }),
+            serde_json::json!({ "textDocument": { "uri": 42 }, "position": "here" }),
+        ]
+    }
+
+    /// Handle a message, waiting for any request it makes to be answered, as
+    /// dropping the router waits for its workers to finish
+    fn send(msg: Message, server: &Connection) {
+        let mut documents = TextDocuments::new();
+        let inbox = Inbox::new(server.receiver.clone());
+        handle_msg(
+            msg,
+            server,
+            &inbox,
+            &mut documents,
+            &mut ValidationCaches::new(),
+            &mut Opts::default(),
+            None,
+            ClientSupport {
+                diagnostics: true,
+                code_action_resolve_edits: true,
+                hover_markdown: true,
+                message_actions: true,
+            },
+            &Arc::default(),
+            &Arc::default(),
+            &router(),
+        )
+        .expect("can handle message");
+    }
+
+    #[test]
+    fn every_request_is_routed() {
+        let router = router();
+        for method in REQUEST_METHODS {
+            assert!(router.handles(method), "{method} is not routed");
+        }
+        assert!(router.handles(commands::ListCommands::METHOD));
+    }
+
+    #[test]
+    fn malformed_request_params_get_invalid_params_responses() {
+        let (server, client) = Connection::memory();
+        let mut id = 0;
+        for method in REQUEST_METHODS {
+            for params in malformed_params() {
+                id += 1;
+                send(
+                    Message::Request(Request::new(id.into(), method.to_string(), params)),
+                    &server,
+                );
+
+                let Ok(Message::Response(resp)) = client.receiver.try_recv() else {
+                    panic!("expected a response to {method}");
                 };
-                connection
-                    .sender
-                    .send(Message::Request(apply_edit_req))
-                    .expect("can send request");
+                assert_eq!(resp.id, id.into());
+                assert!(resp.result.is_none());
+                let error = resp.error.expect("response is an error");
+                assert_eq!(error.code, lsp_server::ErrorCode::InvalidParams as i32);
+                assert!(error.message.contains(method));
             }
-
-            None
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
     }
-}
 
-fn handle_selection_range_req(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<SelectionRangeRequest>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got SelectionRange request");
-            let resp =
-                selection_range::handle_selection_range_request(params, documents).map_err(|e| {
-                    tracing::warn!("Failed to handle selection range request: {e:?}");
-                    e
-                });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
+    #[test]
+    fn commands_can_be_listed() {
+        let (server, client) = Connection::memory();
+        send(
+            Message::Request(Request::new(
+                1.into(),
+                commands::ListCommands::METHOD.to_string(),
+                serde_json::Value::Null,
+            )),
+            &server,
+        );
+
+        let Ok(Message::Response(resp)) = client.receiver.try_recv() else {
+            panic!("expected a response");
+        };
+        let listed = resp.result.expect("commands are listed");
+        let listed = listed.as_array().expect("commands are listed in an array");
+        assert_eq!(listed.len(), commands::list_commands().len());
+        assert_eq!(listed[0]["id"], commands::CMD_SET_TO_NOW);
+        assert_eq!(listed[0]["title"], "Set Timestamp to Now");
+        assert_eq!(listed[0]["requiresSelection"], true);
+    }
+
+    #[test]
+    fn clients_that_can_watch_files_are_asked_to_watch_specs() {
+        let capabilities = |capabilities: serde_json::Value| {
+            client_watches_files(&serde_json::from_value(capabilities).unwrap())
+        };
+        assert!(!capabilities(serde_json::json!({})));
+        assert!(!capabilities(serde_json::json!({
+            "workspace": { "didChangeWatchedFiles": {} },
+        })));
+        assert!(!capabilities(serde_json::json!({
+            "workspace": { "didChangeWatchedFiles": { "dynamicRegistration": false } },
+        })));
+        assert!(capabilities(serde_json::json!({
+            "workspace": { "didChangeWatchedFiles": { "dynamicRegistration": true } },
+        })));
+
+        let (server, client) = Connection::memory();
+        register_spec_file_watchers(&server);
+        let Ok(Message::Request(req)) = client.receiver.try_recv() else {
+            panic!("expected a registration request");
+        };
+        assert_eq!(req.method, RegisterCapability::METHOD);
+        let params: RegistrationParams = serde_json::from_value(req.params).unwrap();
+        assert_eq!(params.registrations.len(), 1);
+        assert_eq!(
+            params.registrations[0].method,
+            DidChangeWatchedFiles::METHOD
+        );
+        let options: DidChangeWatchedFilesRegistrationOptions =
+            serde_json::from_value(params.registrations[0].register_options.clone().unwrap())
+                .unwrap();
+        let globs = options
+            .watchers
+            .into_iter()
+            .map(|watcher| match watcher.glob_pattern {
+                GlobPattern::String(glob) => glob,
+                GlobPattern::Relative(_) => panic!("expected plain globs"),
+            })
+            .collect::<Vec<_>>();
+        for glob in ["**/*.hl7v.toml", "**/*.tbl.csv", "**/*.xml", "**/*.hl7"] {
+            assert!(globs.contains(&glob.to_string()), "{glob}");
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
     }
-}
 
-fn handle_signature_help_request(
-    req: Request,
-    documents: &TextDocuments,
-    connection: &Connection,
-) -> Option<Request> {
-    match cast_request::<SignatureHelpRequest>(req) {
-        Ok((id, params)) => {
-            tracing::debug!("got SignatureHelp request");
-            let resp =
-                signature_help::handle_signature_help_request(params, documents).map_err(|e| {
-                    tracing::warn!("Failed to handle signature help request: {e:?}");
-                    e
-                });
-            let resp = build_response(id, resp);
-            connection
-                .sender
-                .send(Message::Response(resp))
-                .expect("can send response");
-            None
+    #[test]
+    fn workspace_commands_see_unsaved_changes() {
+        let folder = std::env::temp_dir().join(format!("hl7-ls-fix-all-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("message.hl7");
+        fs::write(&path, "MSH|^~\\&|App\rOBX|1").unwrap();
+        let uri = hl7_ls::utils::file_uri(&path).unwrap();
+        let opts = Opts::default();
+        let workspace = Arc::new(
+            Workspace::new(
+                vec![WorkspaceFolder {
+                    uri: hl7_ls::utils::file_uri(&folder).unwrap(),
+                    name: "fixtures".to_string(),
+                }],
+                true,
+                &opts,
+            )
+            .unwrap(),
+        );
+
+        // the file on disk is already tidy, but the open buffer isn't
+        let mut documents = TextDocuments::new();
+        documents.listen(
+            DidOpenTextDocument::METHOD,
+            &serde_json::json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": "hl7",
+                    "version": 3,
+                    "text": "MSH|^~\\&|App\rOBX|2",
+                },
+            }),
+        );
+        let (server, client) = Connection::memory();
+        handle_msg(
+            Message::Request(Request::new(
+                1.into(),
+                ExecuteCommand::METHOD.to_string(),
+                serde_json::json!({ "command": commands::CMD_FIX_ALL_IN_WORKSPACE }),
+            )),
+            &server,
+            &Inbox::new(server.receiver.clone()),
+            &mut documents,
+            &mut ValidationCaches::new(),
+            &mut opts.clone(),
+            Some(&workspace),
+            ClientSupport::default(),
+            &Arc::default(),
+            &Arc::default(),
+            &router(),
+        )
+        .expect("can handle message");
+        fs::remove_dir_all(&folder).unwrap();
+
+        let Ok(Message::Response(resp)) = client.receiver.try_recv() else {
+            panic!("expected a response");
+        };
+        assert_eq!(resp.result, Some(serde_json::Value::Bool(true)));
+        let Ok(Message::Request(req)) = client.receiver.try_recv() else {
+            panic!("expected the edit to be applied");
+        };
+        let params: ApplyWorkspaceEditParams = serde_json::from_value(req.params).unwrap();
+        let Some(lsp_types::DocumentChanges::Edits(edits)) = params.edit.document_changes else {
+            panic!("expected document edits");
+        };
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].text_document.uri, uri);
+        assert_eq!(edits[0].text_document.version, Some(3));
+        // `OneOf` is untagged, so annotated edits read back as plain ones
+        let edit = match &edits[0].edits[0] {
+            OneOf::Left(edit) => edit,
+            OneOf::Right(edit) => &edit.text_edit,
+        };
+        assert_eq!(edit.new_text, "MSH|^~\\&|App\rOBX|1");
+    }
+
+    #[test]
+    fn changes_from_utf8_clients_are_applied_at_the_right_place() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let mut documents = TextDocuments::new();
+        let opts = Opts {
+            position_encoding: PositionEncoding::Utf8,
+            ..Default::default()
+        };
+        let open = lsp_server::Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            serde_json::json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": "hl7",
+                    "version": 1,
+                    "text": "PID|1||José😀^Zoë|X",
+                },
+            }),
+        );
+        assert!(listen(&mut documents, &open, opts.position_encoding));
+
+        // replace the "X", which is 22 bytes into the line but 18 UTF-16 code
+        // units, then the "Z", relying on the first change having been applied
+        let change = lsp_server::Notification::new(
+            DidChangeTextDocument::METHOD.to_string(),
+            serde_json::json!({
+                "textDocument": { "uri": uri, "version": 2 },
+                "contentChanges": [
+                    {
+                        "range": {
+                            "start": { "line": 0, "character": 22 },
+                            "end": { "line": 0, "character": 23 },
+                        },
+                        "text": "Ý",
+                    },
+                    {
+                        "range": {
+                            "start": { "line": 0, "character": 17 },
+                            "end": { "line": 0, "character": 18 },
+                        },
+                        "text": "Ż",
+                    },
+                ],
+            }),
+        );
+        assert!(listen(&mut documents, &change, opts.position_encoding));
+        assert_eq!(
+            documents.get_document_content(&uri, None),
+            Some("PID|1||José😀^Żoë|Ý")
+        );
+    }
+
+    #[test]
+    fn requests_cancelled_before_they_are_handled_are_not_handled() {
+        let (server, client) = Connection::memory();
+        let inbox = Inbox::new(server.receiver.clone());
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                7.into(),
+                HoverRequest::METHOD.to_string(),
+                serde_json::json!({
+                    "textDocument": { "uri": "file:///message.hl7" },
+                    "position": { "line": 0, "character": 0 },
+                }),
+            )))
+            .unwrap();
+        client
+            .sender
+            .send(Message::Notification(lsp_server::Notification::new(
+                Cancel::METHOD.to_string(),
+                CancelParams {
+                    id: NumberOrString::Number(7),
+                },
+            )))
+            .unwrap();
+
+        let msg = inbox.pop().expect("request is pending");
+        let mut documents = TextDocuments::new();
+        handle_msg(
+            msg,
+            &server,
+            &inbox,
+            &mut documents,
+            &mut ValidationCaches::new(),
+            &mut Opts::default(),
+            None,
+            ClientSupport::default(),
+            &Arc::default(),
+            &Arc::default(),
+            &router(),
+        )
+        .expect("can handle message");
+
+        let Ok(Message::Response(resp)) = client.receiver.try_recv() else {
+            panic!("expected a response");
+        };
+        assert_eq!(resp.id, 7.into());
+        let error = resp.error.expect("response is an error");
+        assert_eq!(error.code, lsp_server::ErrorCode::RequestCanceled as i32);
+        assert!(inbox.pop().is_none());
+    }
+
+    #[test]
+    fn malformed_notification_params_are_ignored() {
+        let (server, client) = Connection::memory();
+        for method in NOTIFICATION_METHODS {
+            for params in malformed_params() {
+                send(
+                    Message::Notification(lsp_server::Notification::new(
+                        method.to_string(),
+                        params,
+                    )),
+                    &server,
+                );
+                assert!(client.receiver.try_recv().is_err());
+            }
         }
-        Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-        Err(ExtractError::MethodMismatch(req)) => Some(req),
+    }
+
+    #[test]
+    fn severity_overrides_are_read_from_settings() {
+        let settings = serde_json::json!({
+            "hl7": {
+                "severities": {
+                    "length": "off",
+                    "table-value": "info",
+                    "optionality": "loud",
+                    "not-a-code": "error",
+                }
+            }
+        });
+        assert_eq!(
+            severity_overrides(&settings),
+            HashMap::from([
+                ("length".to_string(), SeverityOverride::Off),
+                ("table-value".to_string(), SeverityOverride::Information),
+            ])
+        );
+        assert!(severity_overrides(&serde_json::Value::Null).is_empty());
+        assert_eq!(
+            pinned_version(&serde_json::json!({ "hl7": { "version": "2.5.1" } })),
+            Some("2.5.1".to_string())
+        );
+        assert_eq!(pinned_version(&serde_json::json!({ "version": "9" })), None);
     }
 }
@@ -0,0 +1,144 @@
+//! Documents holding more than one message
+//!
+//! Many `.hl7` files hold several messages, one after another or separated by
+//! blank lines, which can't be parsed as a single message. Each message
+//! starts at the beginning of a line, so positions within a message only need
+//! their line moving to be positions in the document.
+
+use crate::utils::{line_ranges, position_from_offset, PositionEncoding};
+use lsp_types::{Position, Range};
+
+/// The segments that wrap the messages of batch files, rather than being
+/// part of any message
+const BATCH_SEGMENTS: &[&str] = &["FHS", "BHS", "BTS", "FTS"];
+
+/// A message within a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentMessage<'t> {
+    /// The text of the message
+    pub text: &'t str,
+    /// Where the message is in the document
+    pub range: std::ops::Range<usize>,
+    /// The line of the document that the message starts on
+    pub line: u32,
+}
+
+impl DocumentMessage<'_> {
+    /// A position in the document as a position in the message, if it's at
+    /// or after the start of the message
+    pub fn position_in_message(&self, position: Position) -> Option<Position> {
+        Some(Position {
+            line: position.line.checked_sub(self.line)?,
+            character: position.character,
+        })
+    }
+
+    /// A range in the document as a range in the message, if it starts at or
+    /// after the start of the message
+    pub fn range_in_message(&self, range: Range) -> Option<Range> {
+        Some(Range {
+            start: self.position_in_message(range.start)?,
+            end: self.position_in_message(range.end)?,
+        })
+    }
+
+    /// A position in the message as a position in the document
+    pub fn position_in_document(&self, position: Position) -> Position {
+        Position {
+            line: position.line + self.line,
+            character: position.character,
+        }
+    }
+
+    /// A range in the message as a range in the document
+    pub fn range_in_document(&self, range: Range) -> Range {
+        Range {
+            start: self.position_in_document(range.start),
+            end: self.position_in_document(range.end),
+        }
+    }
+}
+
+/// Split a document into its messages, each starting at an MSH segment or
+/// after a blank line, and leaving out the headers and trailers of batch
+/// files
+///
+/// Lines that don't follow an MSH segment start a message of their own, so
+/// that they're reported when the message fails to parse.
+pub fn split_messages(text: &str, encoding: PositionEncoding) -> Vec<DocumentMessage<'_>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    let mut current: Option<std::ops::Range<usize>> = None;
+    for line in line_ranges(text) {
+        let segment = &text[line.clone()];
+        if segment.trim().is_empty() || BATCH_SEGMENTS.iter().any(|s| segment.starts_with(s)) {
+            ranges.extend(current.take());
+            continue;
+        }
+        match current.as_mut() {
+            Some(message) if !segment.starts_with("MSH") => message.end = line.end,
+            _ => ranges.extend(current.replace(line)),
+        }
+    }
+    ranges.extend(current);
+
+    ranges
+        .into_iter()
+        .map(|range| DocumentMessage {
+            text: &text[range.clone()],
+            line: position_from_offset(text, range.start, encoding).line,
+            range,
+        })
+        .collect()
+}
+
+/// The message of a document that an offset is in, or else the last message
+/// before it (e.g. when the offset is on a blank line after a message)
+///
+/// The whole document is given if it has no messages, so that it can still
+/// be parsed to report why.
+pub fn message_at(text: &str, offset: usize, encoding: PositionEncoding) -> DocumentMessage<'_> {
+    let mut messages = split_messages(text, encoding);
+    let at = messages
+        .iter()
+        .rposition(|message| message.range.start <= offset)
+        .unwrap_or(0);
+    if at < messages.len() {
+        messages.swap_remove(at)
+    } else {
+        DocumentMessage {
+            text,
+            range: 0..text.len(),
+            line: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_are_split_into_messages() {
+        let text = "MSH|^~\\&|A\r\nPID|1\r\n\r\nMSH|^~\\&|B\nMSH|^~\\&|C\rPV1|1\n\nBTS|3";
+        let messages = split_messages(text, PositionEncoding::Utf16);
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| (message.text, message.line))
+                .collect::<Vec<_>>(),
+            vec![
+                ("MSH|^~\\&|A\r\nPID|1", 0),
+                ("MSH|^~\\&|B", 3),
+                ("MSH|^~\\&|C\rPV1|1", 4),
+            ]
+        );
+
+        let offset = text.find("PV1").unwrap();
+        let message = message_at(text, offset, PositionEncoding::Utf16);
+        assert_eq!(message.line, 4);
+        assert_eq!(
+            message.position_in_document(Position::new(1, 2)),
+            Position::new(5, 2)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+#[cfg(feature = "mllp")]
+use crate::commands::CMD_SEND_MESSAGE;
+use crossbeam_channel::Sender;
+use lsp_server::{Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{Notification as _, ShowMessage};
+use lsp_types::request::{Request as _, ShowMessageRequest};
+use lsp_types::{
+    ExecuteCommandParams, MessageActionItem, MessageType, ShowMessageParams,
+    ShowMessageRequestParams,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+/// The action offered to run a failed command again
+pub const RETRY: &str = "Retry";
+
+/// Commands that fail because of something outside the editor (e.g. an
+/// interface engine that isn't listening), and so are worth retrying
+#[cfg(feature = "mllp")]
+pub const RETRYABLE_COMMANDS: &[&str] = &[CMD_SEND_MESSAGE];
+#[cfg(not(feature = "mllp"))]
+pub const RETRYABLE_COMMANDS: &[&str] = &[];
+
+/// Failures the user can act on, shown in the editor rather than only being
+/// logged where they'd never be seen
+///
+/// Clients that can offer actions on messages are offered to retry failed
+/// commands, which are remembered until the user answers.
+#[derive(Debug, Default)]
+pub struct Prompts {
+    pending: Mutex<HashMap<RequestId, ExecuteCommandParams>>,
+}
+
+impl Prompts {
+    /// Show an error, offering to retry `retry` if the client can show actions
+    pub fn show_error(
+        &self,
+        sender: &Sender<Message>,
+        actions_supported: bool,
+        message: String,
+        retry: Option<ExecuteCommandParams>,
+    ) {
+        let Some(retry) = retry.filter(|_| actions_supported) else {
+            show_message(sender, MessageType::ERROR, message);
+            return;
+        };
+
+        let request_id: RequestId = rand::random::<i32>().into();
+        let params = ShowMessageRequestParams {
+            typ: MessageType::ERROR,
+            message,
+            actions: Some(vec![MessageActionItem {
+                title: RETRY.to_string(),
+                properties: HashMap::new(),
+            }]),
+        };
+        self.pending
+            .lock()
+            .expect("prompts lock isn't poisoned")
+            .insert(request_id.clone(), retry);
+        sender
+            .send(Message::Request(Request {
+                id: request_id,
+                method: ShowMessageRequest::METHOD.to_string(),
+                params: serde_json::to_value(params).expect("can serialize show message params"),
+            }))
+            .expect("can send request");
+    }
+
+    /// The command to run again if `resp` answers a prompt to retry it, and
+    /// the user chose to
+    pub fn answered(&self, resp: &Response) -> Option<ExecuteCommandParams> {
+        let retry = self
+            .pending
+            .lock()
+            .expect("prompts lock isn't poisoned")
+            .remove(&resp.id)?;
+        let action: Option<MessageActionItem> = resp
+            .result
+            .clone()
+            .and_then(|result| serde_json::from_value(result).ok());
+        action.filter(|action| action.title == RETRY).map(|_| retry)
+    }
+}
+
+/// Show a message to the user, which every client supports
+pub fn show_message<S: ToString>(sender: &Sender<Message>, typ: MessageType, message: S) {
+    sender
+        .send(Message::Notification(Notification::new(
+            ShowMessage::METHOD.to_string(),
+            ShowMessageParams {
+                typ,
+                message: message.to_string(),
+            },
+        )))
+        .expect("can send notification");
+}
+
+#[cfg(all(test, feature = "mllp"))]
+mod tests {
+    use super::*;
+
+    fn send_params() -> ExecuteCommandParams {
+        ExecuteCommandParams {
+            command: CMD_SEND_MESSAGE.to_string(),
+            arguments: vec![serde_json::json!("file:///message.hl7")],
+            work_done_progress_params: Default::default(),
+        }
+    }
+
+    #[test]
+    fn failed_commands_are_retried_when_the_user_chooses_to() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let prompts = Prompts::default();
+
+        prompts.show_error(&sender, true, "Failed".to_string(), Some(send_params()));
+        let Ok(Message::Request(req)) = receiver.try_recv() else {
+            panic!("expected a show message request");
+        };
+        assert_eq!(req.method, ShowMessageRequest::METHOD);
+
+        let dismissed = Response::new_ok(req.id.clone(), serde_json::Value::Null);
+        assert!(prompts.answered(&dismissed).is_none());
+
+        prompts.show_error(&sender, true, "Failed".to_string(), Some(send_params()));
+        let Ok(Message::Request(req)) = receiver.try_recv() else {
+            panic!("expected a show message request");
+        };
+        let retried = Response::new_ok(
+            req.id.clone(),
+            MessageActionItem {
+                title: RETRY.to_string(),
+                properties: HashMap::new(),
+            },
+        );
+        assert_eq!(
+            prompts.answered(&retried).map(|params| params.command),
+            Some(CMD_SEND_MESSAGE.to_string())
+        );
+        assert!(prompts.answered(&retried).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    cancellation::CancellationToken, listeners::Listeners, prompts::Prompts, workers::WorkerPool,
+    ClientSupport,
+};
+use color_eyre::Result;
+use crossbeam_channel::Sender;
+use hl7_ls::{
+    utils::build_response,
+    workspace::{specs::WorkspaceSpecs, Workspace},
+    Opts,
+};
+use lsp_server::{ExtractError, Message, Request, RequestId, Response};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{ExecuteCommandParams, MarkupKind};
+use std::{collections::HashMap, sync::Arc};
+
+/// Everything a request handler may need to answer a request
+///
+/// Requests are answered on worker threads, so the context owns a snapshot of
+/// the documents as they were when the request was received.
+pub struct RequestContext {
+    pub id: RequestId,
+    pub sender: Sender<Message>,
+    pub token: CancellationToken,
+    pub documents: TextDocuments,
+    pub workspace: Option<Arc<Workspace>>,
+    pub opts: Opts,
+    pub client_support: ClientSupport,
+    pub prompts: Arc<Prompts>,
+    #[cfg_attr(not(feature = "mllp"), allow(dead_code))]
+    pub listeners: Arc<Listeners>,
+}
+
+impl RequestContext {
+    pub fn specs(&self) -> Option<&WorkspaceSpecs> {
+        self.workspace.as_ref().map(|w| &*w.specs)
+    }
+
+    /// The markup the client would prefer rich text to be sent in
+    pub fn markup_kind(&self) -> MarkupKind {
+        if self.client_support.hover_markdown {
+            MarkupKind::Markdown
+        } else {
+            MarkupKind::PlainText
+        }
+    }
+
+    pub fn send(&self, msg: Message) {
+        self.sender.send(msg).expect("can send message");
+    }
+
+    /// Show an error to the user, offering to retry the command that caused
+    /// it where that might help
+    pub fn show_error(&self, message: String, retry: Option<ExecuteCommandParams>) {
+        self.prompts.show_error(
+            &self.sender,
+            self.client_support.message_actions,
+            message,
+            retry,
+        );
+    }
+}
+
+type Handler = Arc<dyn Fn(Request, &RequestContext) + Send + Sync>;
+
+/// Maps request methods to the handlers that answer them, which are run on a
+/// pool of workers
+///
+/// New LSP features only need to register their handler with [Router::on]
+/// (and advertise their capability) to be wired into the main loop.
+pub struct Router {
+    handlers: HashMap<&'static str, Handler>,
+    workers: WorkerPool,
+}
+
+impl Router {
+    pub fn new(workers: WorkerPool) -> Self {
+        Router {
+            handlers: HashMap::new(),
+            workers,
+        }
+    }
+
+    /// Answer requests for `R` with the result of `handler`
+    pub fn on<R, F>(self, handler: F) -> Self
+    where
+        R: lsp_types::request::Request,
+        R::Params: serde::de::DeserializeOwned,
+        F: Fn(R::Params, &RequestContext) -> Result<R::Result> + Send + Sync + 'static,
+    {
+        self.on_raw::<R, _>(move |params, ctx| {
+            let resp = handler(params, ctx).map_err(|e| {
+                tracing::warn!("Failed to handle {} request: {e:?}", R::METHOD);
+                e
+            });
+            ctx.send(Message::Response(build_response(ctx.id.clone(), resp)));
+        })
+    }
+
+    /// Hand requests for `R` to `handler`, which is responsible for responding
+    /// to them itself
+    pub fn on_raw<R, F>(mut self, handler: F) -> Self
+    where
+        R: lsp_types::request::Request,
+        R::Params: serde::de::DeserializeOwned,
+        F: Fn(R::Params, &RequestContext) + Send + Sync + 'static,
+    {
+        let previous = self.handlers.insert(
+            R::METHOD,
+            Arc::new(move |req, ctx| {
+                if let Ok((_, params)) = cast_request::<R>(req, &ctx.sender) {
+                    tracing::debug!("got {} request", R::METHOD);
+                    handler(params, ctx);
+                }
+            }),
+        );
+        debug_assert!(previous.is_none(), "{} is routed twice", R::METHOD);
+        self
+    }
+
+    pub fn handles(&self, method: &str) -> bool {
+        self.handlers.contains_key(method)
+    }
+
+    /// Answer a request on the next free worker, finishing it once it has
+    /// been answered
+    ///
+    /// If the handler panics, the client is sent an `InternalError` response
+    /// instead so that it isn't left waiting for one.
+    pub fn dispatch(&self, req: Request, ctx: RequestContext) {
+        let Some(handler) = self.handlers.get(req.method.as_str()).cloned() else {
+            tracing::warn!("unhandled request: {req:?}");
+            ctx.token.finish();
+            return;
+        };
+        let recover = {
+            let (id, sender, token) = (ctx.id.clone(), ctx.sender.clone(), ctx.token.clone());
+            let message = format!("Failed to handle {} request", req.method);
+            move || {
+                let resp =
+                    Response::new_err(id, lsp_server::ErrorCode::InternalError as i32, message);
+                sender
+                    .send(Message::Response(resp))
+                    .expect("can send response");
+                token.finish();
+            }
+        };
+        self.workers.execute(
+            move || {
+                let request_span =
+                    tracing::debug_span!("request", method = ?req.method, id = ?req.id);
+                let _request_span_guard = request_span.enter();
+                handler(req, &ctx);
+                ctx.token.finish();
+            },
+            recover,
+        );
+    }
+
+    /// Run work that isn't answering a request (e.g. retrying a command the
+    /// user asked to) on the next free worker
+    pub fn execute(&self, ctx: RequestContext, job: impl FnOnce(&RequestContext) + Send + 'static) {
+        let token = ctx.token.clone();
+        self.workers.execute(
+            move || {
+                job(&ctx);
+                ctx.token.finish();
+            },
+            move || token.finish(),
+        );
+    }
+}
+
+/// Extract the params of a request for `R`
+///
+/// If the request is for `R` but its params are malformed, the client is sent
+/// an `InvalidParams` error response so that callers only need to ignore the
+/// [ExtractError::JsonError].
+fn cast_request<R>(
+    req: Request,
+    sender: &Sender<Message>,
+) -> Result<(RequestId, R::Params), ExtractError<Request>>
+where
+    R: lsp_types::request::Request,
+    R::Params: serde::de::DeserializeOwned,
+{
+    let id = req.id.clone();
+    req.extract(R::METHOD).inspect_err(|err| {
+        if let ExtractError::JsonError { method, error } = err {
+            tracing::warn!(?id, method, "Received malformed request params: {error}");
+            let resp = Response::new_err(
+                id.clone(),
+                lsp_server::ErrorCode::InvalidParams as i32,
+                format!("Invalid params for {method}: {error}"),
+            );
+            sender
+                .send(Message::Response(resp))
+                .expect("can send response");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::Inbox;
+    use lsp_server::Connection;
+    use lsp_types::{
+        notification::{Cancel, Notification},
+        request::{HoverRequest, Request as _},
+        CancelParams, NumberOrString,
+    };
+
+    #[test]
+    fn requests_whose_handlers_panic_fail() {
+        let (server, client) = Connection::memory();
+        let inbox = Inbox::new(server.receiver.clone());
+        client
+            .sender
+            .send(Message::Request(Request::new(
+                1.into(),
+                HoverRequest::METHOD.to_string(),
+                serde_json::json!({
+                    "textDocument": { "uri": "file:///message.hl7" },
+                    "position": { "line": 0, "character": 0 },
+                }),
+            )))
+            .unwrap();
+        let Some(Message::Request(req)) = inbox.pop() else {
+            panic!("expected the request");
+        };
+        let ctx = RequestContext {
+            id: req.id.clone(),
+            sender: server.sender.clone(),
+            token: inbox.token(req.id.clone()),
+            documents: TextDocuments::new(),
+            workspace: None,
+            opts: Opts::default(),
+            client_support: ClientSupport::default(),
+            prompts: Arc::default(),
+            listeners: Arc::default(),
+        };
+
+        let router = Router::new(WorkerPool::new(1))
+            .on::<HoverRequest, _>(|_, _| panic!("bug in a handler"));
+        router.dispatch(req, ctx);
+        drop(router);
+
+        let Ok(Message::Response(resp)) = client.receiver.try_recv() else {
+            panic!("expected a response");
+        };
+        assert_eq!(resp.id, 1.into());
+        let error = resp.error.expect("response is an error");
+        assert_eq!(error.code, lsp_server::ErrorCode::InternalError as i32);
+
+        // the request was finished, so a late cancellation of it is forgotten
+        client
+            .sender
+            .send(Message::Notification(lsp_server::Notification::new(
+                Cancel::METHOD.to_string(),
+                CancelParams {
+                    id: NumberOrString::Number(1),
+                },
+            )))
+            .unwrap();
+        assert!(!inbox.is_cancelled(&1.into()));
+    }
+}
@@ -1,41 +1,45 @@
-use crate::utils::{position_to_offset, std_range_to_lsp_range};
 use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{
+    messages::{message_at, DocumentMessage},
+    utils::{clamp_offset, position_to_offset, std_range_to_lsp_range},
+    Opts,
+};
 use hl7_parser::{locate::LocatedCursor, parse_message_with_lenient_newlines};
 use lsp_textdocument::TextDocuments;
 use lsp_types::{SelectionRange, SelectionRangeParams};
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(params, documents))]
+#[instrument(level = "debug", skip(params, documents, opts))]
 pub fn handle_selection_range_request(
     params: SelectionRangeParams,
     documents: &TextDocuments,
+    opts: &Opts,
 ) -> Result<Vec<SelectionRange>> {
     let uri = params.text_document.uri;
     let text = documents
         .get_document_content(&uri, None)
         .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
 
-    let parse_span = tracing::trace_span!("parse message");
-    let _parse_span_guard = parse_span.enter();
-    let Ok(message) = parse_message_with_lenient_newlines(text) else {
-        let mut ranges = Vec::with_capacity(params.positions.len());
-        for _ in 0..params.positions.len() {
-            ranges.push(SelectionRange {
-                range: lsp_types::Range::default(),
-                parent: None,
-            });
-        }
-        return Ok(ranges);
-    };
-    drop(_parse_span_guard);
-
     Ok(params
         .positions
         .into_iter()
         .map(|position| {
-            let location =
-                position_to_offset(message.raw_value(), position.line, position.character)
-                    .and_then(|offset| message.locate_cursor(offset))?;
+            let offset = position_to_offset(
+                text,
+                position.line,
+                position.character,
+                opts.position_encoding,
+            )?;
+            // only the message the position is in is needed, if there are
+            // several
+            let document_message = message_at(text, offset, opts.position_encoding);
+            let parse_span = tracing::trace_span!("parse message");
+            let _parse_span_guard = parse_span.enter();
+            let message = parse_message_with_lenient_newlines(document_message.text).ok()?;
+            drop(_parse_span_guard);
+
+            let offset = offset.saturating_sub(document_message.range.start);
+            let location = message.locate_cursor(clamp_offset(message.raw_value(), offset))?;
 
             let LocatedCursor {
                 segment,
@@ -48,13 +52,21 @@ pub fn handle_selection_range_request(
             let segment = segment?.2;
 
             let range = SelectionRange {
-                range: std_range_to_lsp_range(message.raw_value(), segment.range.clone()),
+                range: std_range_to_lsp_range(
+                    message.raw_value(),
+                    segment.range.clone(),
+                    opts.position_encoding,
+                ),
                 parent: None,
             };
 
             let range = match field.map(|f| f.1) {
                 Some(field) => SelectionRange {
-                    range: std_range_to_lsp_range(message.raw_value(), field.range.clone()),
+                    range: std_range_to_lsp_range(
+                        message.raw_value(),
+                        field.range.clone(),
+                        opts.position_encoding,
+                    ),
                     parent: Some(Box::new(range)),
                 },
                 None => range,
@@ -62,7 +74,11 @@ pub fn handle_selection_range_request(
 
             let range = match repeat.map(|r| r.1) {
                 Some(repeat) => SelectionRange {
-                    range: std_range_to_lsp_range(message.raw_value(), repeat.range.clone()),
+                    range: std_range_to_lsp_range(
+                        message.raw_value(),
+                        repeat.range.clone(),
+                        opts.position_encoding,
+                    ),
                     parent: Some(Box::new(range)),
                 },
                 None => range,
@@ -70,7 +86,11 @@ pub fn handle_selection_range_request(
 
             let range = match component.map(|c| c.1) {
                 Some(component) => SelectionRange {
-                    range: std_range_to_lsp_range(message.raw_value(), component.range.clone()),
+                    range: std_range_to_lsp_range(
+                        message.raw_value(),
+                        component.range.clone(),
+                        opts.position_encoding,
+                    ),
                     parent: Some(Box::new(range)),
                 },
                 None => range,
@@ -78,13 +98,17 @@ pub fn handle_selection_range_request(
 
             let range = match sub_component.map(|s| s.1) {
                 Some(sub_component) => SelectionRange {
-                    range: std_range_to_lsp_range(message.raw_value(), sub_component.range.clone()),
+                    range: std_range_to_lsp_range(
+                        message.raw_value(),
+                        sub_component.range.clone(),
+                        opts.position_encoding,
+                    ),
                     parent: Some(Box::new(range)),
                 },
                 None => range,
             };
 
-            Some(range)
+            Some(move_range(range, &document_message))
         })
         .map(|range| {
             range.unwrap_or_else(|| SelectionRange {
@@ -94,3 +118,90 @@ pub fn handle_selection_range_request(
         })
         .collect())
 }
+
+/// Move a selection range (and its parents) from the message it was found in
+/// to where the message is in the document
+fn move_range(range: SelectionRange, document_message: &DocumentMessage) -> SelectionRange {
+    SelectionRange {
+        range: document_message.range_in_document(range.range),
+        parent: range
+            .parent
+            .map(|parent| Box::new(move_range(*parent, document_message))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hl7_ls::utils::PositionEncoding;
+    use lsp_types::{notification::Notification, Position, Range, TextDocumentIdentifier};
+
+    /// The ranges selected around the position, from innermost to outermost
+    fn select(encoding: PositionEncoding, position: Position) -> Vec<Range> {
+        let mut documents = TextDocuments::new();
+        documents.listen(
+            lsp_types::notification::DidOpenTextDocument::METHOD,
+            &serde_json::json!({
+                "textDocument": {
+                    "uri": "file:///message.hl7",
+                    "languageId": "hl7",
+                    "version": 1,
+                    "text": "MSH|^~\\&|𝄞App\rPID|1||Zoë😀^José|F",
+                },
+            }),
+        );
+        let opts = Opts {
+            position_encoding: encoding,
+            ..Default::default()
+        };
+        let params = SelectionRangeParams {
+            text_document: TextDocumentIdentifier {
+                uri: "file:///message.hl7".parse().unwrap(),
+            },
+            positions: vec![position],
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        let ranges = handle_selection_range_request(params, &documents, &opts).unwrap();
+        std::iter::successors(ranges.first(), |range| range.parent.as_deref())
+            .map(|range| range.range)
+            .collect()
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range {
+            start: Position {
+                line,
+                character: start,
+            },
+            end: Position {
+                line,
+                character: end,
+            },
+        }
+    }
+
+    #[test]
+    fn ranges_are_encoded_for_the_client() {
+        // the cursor is on the "J", after a non-BMP emoji and an accented name
+        let utf16 = select(
+            PositionEncoding::Utf16,
+            Position {
+                line: 1,
+                character: 13,
+            },
+        );
+        assert!(utf16.contains(&range(1, 13, 17)), "component: {utf16:?}");
+        assert!(utf16.contains(&range(1, 7, 17)), "field: {utf16:?}");
+
+        let utf8 = select(
+            PositionEncoding::Utf8,
+            Position {
+                line: 1,
+                character: 16,
+            },
+        );
+        assert!(utf8.contains(&range(1, 16, 21)), "component: {utf8:?}");
+        assert!(utf8.contains(&range(1, 7, 21)), "field: {utf8:?}");
+    }
+}
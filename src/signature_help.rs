@@ -1,5 +1,5 @@
-use crate::utils::position_to_offset;
 use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{utils::position_to_offset, workspace::specs::WorkspaceSpecs, Opts};
 use hl7_parser::{locate::LocatedCursor, message::Segment, parse_message_with_lenient_newlines};
 use lsp_textdocument::TextDocuments;
 use lsp_types::{
@@ -7,10 +7,12 @@ use lsp_types::{
 };
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(params, documents))]
+#[instrument(level = "debug", skip(params, documents, workspace_specs, opts))]
 pub fn handle_signature_help_request(
     params: SignatureHelpParams,
     documents: &TextDocuments,
+    workspace_specs: Option<&WorkspaceSpecs>,
+    opts: &Opts,
 ) -> Result<Option<SignatureHelp>> {
     let uri = params.text_document_position_params.text_document.uri;
     let text = documents
@@ -25,16 +27,20 @@ pub fn handle_signature_help_request(
     drop(_parse_span_guard);
 
     let position = params.text_document_position_params.position;
-    let offset = position_to_offset(text, position.line, position.character)
-        .wrap_err_with(|| "Failed to convert position to offset")?;
+    let offset = position_to_offset(
+        text,
+        position.line,
+        position.character,
+        opts.position_encoding,
+    )
+    .wrap_err_with(|| "Failed to convert position to offset")?;
     let Some(location) = message.locate_cursor(offset) else {
         return Ok(None);
     };
 
-    let version = message
-        .query("MSH.12")
-        .map(|v| v.raw_value())
-        .unwrap_or("2.7.1");
+    let version = opts
+        .message_version(&uri, &message, workspace_specs)
+        .version;
 
     let LocatedCursor {
         segment,
@@ -88,7 +94,7 @@ fn build_segment_signature(
         "{segment_name}{field_separator}",
         segment_name = segment.name
     );
-    let field_list = crate::spec::segment_parameters(version, segment.name)?;
+    let field_list = hl7_ls::spec::segment_parameters(version, segment.name)?;
     let mut field_parameters: Vec<[u32; 2]> = vec![];
     let mut parameter_start = signature_label.len();
     for parameter in field_list.into_iter() {
@@ -127,7 +133,7 @@ fn build_field_signature(
         segment_name = segment.name,
         field = field
     );
-    let component_list = crate::spec::field_parameters(version, segment.name, field)?;
+    let component_list = hl7_ls::spec::field_parameters(version, segment.name, field)?;
     let mut component_parameters: Vec<[u32; 2]> = vec![];
     let mut parameter_start = signature_label.len();
     for parameter in component_list.into_iter() {
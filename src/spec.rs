@@ -1,7 +1,154 @@
+use hl7_parser::{message::Repeat, Message};
+
+/// The version used when a message doesn't declare one in MSH-12
+pub const DEFAULT_VERSION: &str = "2.7.1";
+
 pub fn is_valid_version(version: &str) -> bool {
     hl7_definitions::VERSIONS.contains(&version)
 }
 
+/// The HL7 version that definitions are looked up against for a message, along
+/// with the version the message actually declared (if any)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedVersion<'m> {
+    pub declared: Option<&'m str>,
+    pub version: &'static str,
+}
+
+impl ResolvedVersion<'_> {
+    /// Whether the declared version was unknown and another one was substituted
+    pub fn is_fallback(&self) -> bool {
+        self.declared
+            .map(|declared| declared != self.version)
+            .unwrap_or(false)
+    }
+}
+
+/// Splits a version string such as `2.5.1` into its numeric parts, ignoring any
+/// non-numeric noise (e.g. `v2.9beta` becomes `[2, 9]`)
+fn version_key(version: &str) -> Vec<u32> {
+    let mut key = version
+        .split('.')
+        .filter_map(|part| {
+            let digits: String = part
+                .chars()
+                .skip_while(|c| !c.is_ascii_digit())
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse::<u32>().ok()
+        })
+        .collect::<Vec<u32>>();
+    if key.len() == 2 {
+        key.push(0);
+    }
+    key
+}
+
+/// Whether the version is the same as or newer than `minimum`
+pub fn is_at_least(version: &str, minimum: &str) -> bool {
+    version_key(version) >= version_key(minimum)
+}
+
+/// Finds the known version closest to (but not newer than) the given version,
+/// or the oldest known version if the given version predates all of them
+fn nearest_known_version(version: &str) -> Option<&'static str> {
+    let key = version_key(version);
+    if key.is_empty() {
+        return None;
+    }
+
+    let mut versions = hl7_definitions::VERSIONS.to_vec();
+    versions.sort_by_key(|v| version_key(v));
+    versions
+        .iter()
+        .rev()
+        .find(|v| version_key(v) <= key)
+        .or_else(|| versions.first())
+        .copied()
+}
+
+/// Resolve which version of the standard to use for a declared version
+///
+/// Known versions are used as-is. If a fallback version is configured it is
+/// used for unknown or missing versions, otherwise unknown versions degrade to
+/// the nearest known version and missing versions use [DEFAULT_VERSION].
+pub fn resolve_version<'m>(
+    declared: Option<&'m str>,
+    fallback: Option<&str>,
+) -> ResolvedVersion<'m> {
+    let fallback = fallback.and_then(|fallback| {
+        hl7_definitions::VERSIONS
+            .iter()
+            .find(|v| **v == fallback)
+            .copied()
+    });
+
+    let version = match declared {
+        Some(declared) => hl7_definitions::VERSIONS
+            .iter()
+            .find(|v| **v == declared)
+            .copied()
+            .or(fallback)
+            .or_else(|| nearest_known_version(declared)),
+        None => fallback,
+    }
+    .unwrap_or(DEFAULT_VERSION);
+
+    ResolvedVersion { declared, version }
+}
+
+/// Resolve the version to use for a message based on its MSH-12 value
+pub fn message_version<'m>(message: &'m Message, fallback: Option<&str>) -> ResolvedVersion<'m> {
+    let declared = message
+        .query("MSH.12")
+        .map(|v| v.raw_value())
+        .filter(|v| !v.is_empty());
+    resolve_version(declared, fallback)
+}
+
+/// Resolve the version to use for a message whose version is pinned (if it
+/// is), whatever its MSH-12 value is
+///
+/// Pinned versions that aren't known are ignored.
+pub fn pinned_message_version<'m>(
+    message: &'m Message,
+    pinned: Option<&str>,
+    fallback: Option<&str>,
+) -> ResolvedVersion<'m> {
+    let resolved = message_version(message, fallback);
+    match pinned.and_then(|pinned| hl7_definitions::VERSIONS.iter().find(|v| **v == pinned)) {
+        Some(pinned) => ResolvedVersion {
+            version: pinned,
+            ..resolved
+        },
+        None => resolved,
+    }
+}
+
+pub fn segment_url(version: &str, segment: &str) -> String {
+    format!("https://hl7-definition.caristix.com/v2/HL7v{version}/Segments/{segment}")
+}
+
+pub fn field_url(version: &str, segment: &str, field: usize) -> String {
+    format!("https://hl7-definition.caristix.com/v2/HL7v{version}/Fields/{segment}.{field}")
+}
+
+pub fn component_url(version: &str, segment: &str, field: usize, component: usize) -> String {
+    format!(
+        "https://hl7-definition.caristix.com/v2/HL7v{version}/Fields/{segment}.{field}.{component}"
+    )
+}
+
+pub fn trigger_event_url(version: &str, message_code: &str, trigger_event: &str) -> String {
+    format!(
+        "https://hl7-definition.caristix.com/v2/HL7v{version}/TriggerEvents/{message_code}_{trigger_event}"
+    )
+}
+
+pub fn table_url(version: &str, table: u16) -> String {
+    format!("https://hl7-definition.caristix.com/v2/HL7v{version}/Tables/{table:04}")
+}
+
 pub fn segment_description(version: &str, segment: &str) -> String {
     hl7_definitions::get_segment(version, segment)
         .map(|s| s.description.to_string())
@@ -148,6 +295,86 @@ pub fn describe_component(version: &str, segment: &str, field: usize, component:
         .unwrap_or_else(|| "Unknown segment".to_string())
 }
 
+/// The table number that a field's values are drawn from, if any
+pub fn field_table(version: &str, segment: &str, field: usize) -> Option<u16> {
+    hl7_definitions::get_segment(version, segment)
+        .and_then(|s| s.fields.get(field.checked_sub(1)?))
+        .and_then(|f| f.table)
+        .map(|t| t as u16)
+}
+
+/// The table number that a component's values are drawn from, if any
+pub fn component_table(
+    version: &str,
+    segment: &str,
+    field: usize,
+    component: usize,
+) -> Option<u16> {
+    hl7_definitions::get_segment(version, segment)
+        .and_then(|s| s.fields.get(field.checked_sub(1)?))
+        .and_then(|f| hl7_definitions::get_field(version, f.datatype))
+        .and_then(|f| f.subfields.get(component.checked_sub(1)?))
+        .and_then(|c| c.table)
+        .map(|t| t as u16)
+}
+
+/// The table number that a sub-component's values are drawn from, if any
+pub fn sub_component_table(
+    version: &str,
+    segment: &str,
+    field: usize,
+    component: usize,
+    sub_component: usize,
+) -> Option<u16> {
+    hl7_definitions::get_segment(version, segment)
+        .and_then(|s| s.fields.get(field.checked_sub(1)?))
+        .and_then(|f| hl7_definitions::get_field(version, f.datatype))
+        .and_then(|f| f.subfields.get(component.checked_sub(1)?))
+        .and_then(|c| hl7_definitions::get_field(version, c.datatype))
+        .and_then(|c| c.subfields.get(sub_component.checked_sub(1)?))
+        .and_then(|s| s.table)
+        .map(|t| t as u16)
+}
+
+/// Look up the description of a single value in a table
+pub fn table_value_description(table: u16, value: &str) -> Option<&'static str> {
+    hl7_definitions::table_values(table)
+        .and_then(|values| values.iter().find(|(code, _)| *code == value))
+        .map(|(_, description)| *description)
+}
+
+/// The component that tells the repeats of a field apart, by the field's
+/// datatype (e.g. the identifier type of a `CX`, or the use code of an `XTN`)
+const REPEAT_DISCRIMINATORS: &[(&str, usize)] = &[("CX", 5), ("XAD", 7), ("XPN", 7), ("XTN", 2)];
+
+/// The 1-based component whose value distinguishes one repeat of a field from
+/// another, for fields where the meaning of each repeat depends on it
+pub fn repeat_discriminator(version: &str, segment: &str, field: usize) -> Option<usize> {
+    let datatype = hl7_definitions::get_segment(version, segment)
+        .and_then(|s| s.fields.get(field.checked_sub(1)?))
+        .map(|f| f.datatype)?;
+    REPEAT_DISCRIMINATORS
+        .iter()
+        .find(|(d, _)| *d == datatype)
+        .map(|(_, component)| *component)
+}
+
+/// A short label for a repeat of a field, such as the `MRN` in `PID.3[2]
+/// (MRN)`, if the field's repeats have a discriminating component that is set
+pub fn repeat_label<'m>(
+    version: &str,
+    segment: &str,
+    field: usize,
+    repeat: &Repeat<'m>,
+) -> Option<&'m str> {
+    let component = repeat_discriminator(version, segment, field)?;
+    repeat
+        .components()
+        .nth(component - 1)
+        .map(|c| c.raw_value())
+        .filter(|value| !value.is_empty())
+}
+
 pub fn field_table_values(
     version: &str,
     segment: &str,
@@ -159,23 +386,7 @@ pub fn field_table_values(
             .iter()
             .map(|v| v.to_string())
             .collect();
-        versions.sort_by(|a, b| {
-            let mut a = a
-                .split('.')
-                .map(|s| s.parse::<u32>().unwrap_or(0))
-                .collect::<Vec<u32>>();
-            if a.len() == 2 {
-                a.push(0);
-            }
-            let mut b = b
-                .split('.')
-                .map(|s| s.parse::<u32>().unwrap_or(0))
-                .collect::<Vec<u32>>();
-            if b.len() == 2 {
-                b.push(0);
-            }
-            a.cmp(&b)
-        });
+        versions.sort_by_key(|v| version_key(v));
         return Some(versions.into_iter().map(|v| (v, None)).collect());
     }
 
@@ -219,6 +430,31 @@ pub fn component_table_values(
         })
 }
 
+pub fn sub_component_table_values(
+    version: &str,
+    segment: &str,
+    field: usize,
+    component: usize,
+    sub_component: usize,
+) -> Option<Vec<(String, Option<String>)>> {
+    hl7_definitions::get_segment(version, segment)
+        .and_then(|s| s.fields.get(field))
+        .and_then(|f| hl7_definitions::get_field(version, f.datatype))
+        .and_then(|f| f.subfields.get(component))
+        .and_then(|c| hl7_definitions::get_field(version, c.datatype))
+        .and_then(|c| c.subfields.get(sub_component))
+        .and_then(|s| s.table)
+        .and_then(|t| hl7_definitions::table_values(t as u16))
+        .map(|values| {
+            let mut values = values
+                .iter()
+                .map(|(code, description)| (code.to_string(), Some(description.to_string())))
+                .collect::<Vec<(String, Option<String>)>>();
+            values.sort();
+            values
+        })
+}
+
 pub fn segment_parameters(version: &str, segment: &str) -> Option<Vec<String>> {
     hl7_definitions::get_segment(version, segment).map(|s| {
         s.fields
@@ -270,3 +506,59 @@ pub fn field_parameters(version: &str, segment: &str, field: usize) -> Option<Ve
                 .unwrap_or_default()
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_labelled_by_their_discriminating_component() {
+        let message = hl7_parser::parse_message_with_lenient_newlines(
+            "MSH|^~\\&|||||||||||2.5.1\rPID|1||123^^^Hosp^MR~456^^^Gov^SS~789|X",
+        )
+        .unwrap();
+        let pid = message.segment("PID").unwrap();
+        let labels = pid
+            .field(3)
+            .unwrap()
+            .repeats()
+            .map(|repeat| repeat_label("2.5.1", "PID", 3, repeat))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec![Some("MR"), Some("SS"), None]);
+
+        let patient_id = pid.field(1).unwrap().repeats().next().unwrap();
+        assert_eq!(repeat_label("2.5.1", "PID", 1, patient_id), None);
+    }
+
+    #[test]
+    fn known_versions_resolve_to_themselves() {
+        let resolved = resolve_version(Some("2.5.1"), None);
+        assert_eq!(resolved.version, "2.5.1");
+        assert!(!resolved.is_fallback());
+    }
+
+    #[test]
+    fn missing_versions_use_the_default() {
+        assert_eq!(resolve_version(None, None).version, DEFAULT_VERSION);
+        assert_eq!(resolve_version(None, Some("2.3")).version, "2.3");
+    }
+
+    #[test]
+    fn unknown_versions_degrade_to_the_nearest_known_version() {
+        let resolved = resolve_version(Some("2.5.1.3"), None);
+        assert_eq!(resolved.version, "2.5.1");
+        assert!(resolved.is_fallback());
+
+        assert_eq!(resolve_version(Some("v2.5.1beta"), None).version, "2.5.1");
+        assert_eq!(
+            resolve_version(Some("garbage"), None).version,
+            DEFAULT_VERSION
+        );
+    }
+
+    #[test]
+    fn configured_fallback_overrides_nearest_version() {
+        assert_eq!(resolve_version(Some("2.5.1.3"), Some("2.3")).version, "2.3");
+        assert_eq!(resolve_version(Some("2.5.1"), Some("2.3")).version, "2.5.1");
+    }
+}
@@ -1,94 +1,407 @@
 use color_eyre::Result;
+use hl7_parser::message::Separators;
+#[cfg(feature = "server")]
 use lsp_server::{RequestId, Response, ResponseError};
-use lsp_types::{Position, Range};
+use lsp_types::{Position, PositionEncodingKind, Range, TextEdit, Uri};
+#[cfg(feature = "server")]
 use serde::Serialize;
+#[cfg(feature = "server")]
 use tracing::instrument;
 
-pub fn position_to_offset(text: &str, line: u32, column: u32) -> Option<usize> {
-    let mut offset = 0;
-    let mut current_line = 0;
-    let mut current_column = 0;
-    let mut chars = text.chars().peekable();
+/// How the characters in LSP positions are counted, as negotiated with the
+/// client; all conversions between LSP positions and byte offsets into the
+/// text go through this
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    /// Characters are counted in bytes
+    Utf8,
+    /// Characters are counted in UTF-16 code units, which every client supports
+    #[default]
+    Utf16,
+    /// Characters are counted in unicode code points
+    Utf32,
+}
 
-    while let Some(c) = chars.next() {
-        if current_line == line {
-            if current_column == column {
-                return Some(offset);
-            }
-            current_column += 1;
+impl PositionEncoding {
+    /// Pick the encoding to use from the ones the client supports, preferring
+    /// UTF-8 as it matches how the text is stored
+    pub fn negotiate(supported: Option<&[PositionEncodingKind]>) -> Self {
+        let supported = supported.unwrap_or_default();
+        if supported.contains(&PositionEncodingKind::UTF8) {
+            PositionEncoding::Utf8
+        } else if supported.contains(&PositionEncodingKind::UTF32) {
+            PositionEncoding::Utf32
+        } else {
+            PositionEncoding::Utf16
         }
+    }
 
-        if c == '\n' {
-            current_line += 1;
-            current_column = 0;
-            offset += 1;
-        } else if c == '\r' {
-            current_line += 1;
-            current_column = 0;
-            offset += 1;
-            if let Some(&'\n') = chars.peek() {
-                chars.next();
-                offset += 1;
-            }
-        } else {
-            offset += 1;
+    pub fn kind(&self) -> PositionEncodingKind {
+        match self {
+            PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+            PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+            PositionEncoding::Utf32 => PositionEncodingKind::UTF32,
         }
     }
 
-    None
+    /// The number of units the character takes up in this encoding
+    fn width(&self, c: char) -> u32 {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8() as u32,
+            PositionEncoding::Utf16 => c.len_utf16() as u32,
+            PositionEncoding::Utf32 => 1,
+        }
+    }
 }
 
-pub fn position_from_offset(text: &str, offset: usize) -> Position {
-    let mut line = 0;
-    let mut character = 0;
-    let mut chars = text.chars().peekable();
+/// The byte ranges of each line in the text, not including line terminators
+/// (`\r`, `\n`, or `\r\n`)
+pub fn line_ranges(text: &str) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+    let bytes = text.as_bytes();
+    let mut start = Some(0);
+    std::iter::from_fn(move || {
+        let line_start = start?;
+        match bytes[line_start..]
+            .iter()
+            .position(|&b| b == b'\r' || b == b'\n')
+        {
+            Some(i) => {
+                let line_end = line_start + i;
+                let terminator = if bytes[line_end..].starts_with(b"\r\n") {
+                    2
+                } else {
+                    1
+                };
+                start = Some(line_end + terminator);
+                Some(line_start..line_end)
+            }
+            None => {
+                start = None;
+                Some(line_start..text.len())
+            }
+        }
+    })
+}
 
-    let mut i = 0;
-    while let Some(c) = chars.next() {
-        if i == offset {
+/// Convert a position into a byte offset into the text
+///
+/// Columns past the end of the line are clamped to the end of the line, and
+/// columns in the middle of a character resolve to the start of it. Returns
+/// `None` if the line doesn't exist.
+pub fn position_to_offset(
+    text: &str,
+    line: u32,
+    column: u32,
+    encoding: PositionEncoding,
+) -> Option<usize> {
+    let line = line_ranges(text).nth(line as usize)?;
+    let mut units = 0;
+    for (i, c) in text[line.clone()].char_indices() {
+        let width = encoding.width(c);
+        if units + width > column {
+            return Some(line.start + i);
+        }
+        units += width;
+    }
+    Some(line.end)
+}
+
+/// Convert a byte offset into the text into a position
+///
+/// Offsets past the end of the text are clamped to the end of it, and offsets
+/// in the middle of a character or line terminator resolve to the start of it.
+pub fn position_from_offset(text: &str, offset: usize, encoding: PositionEncoding) -> Position {
+    let offset = clamp_offset(text, offset);
+    let mut position = Position::default();
+    for (line, range) in line_ranges(text).enumerate() {
+        if range.start > offset {
             break;
         }
+        let end = offset.min(range.end);
+        position = Position {
+            line: line as u32,
+            character: text[range.start..end]
+                .chars()
+                .map(|c| encoding.width(c))
+                .sum(),
+        };
+    }
+    position
+}
 
-        if c == '\n' {
-            line += 1;
-            character = 0;
-        } else if c == '\r' {
-            line += 1;
-            character = 0;
-            if let Some('\n') = chars.peek() {
-                chars.next();
-                i += 1;
-            }
+pub fn range_from_offsets(
+    text: &str,
+    start: usize,
+    end: usize,
+    encoding: PositionEncoding,
+) -> Range {
+    Range {
+        start: position_from_offset(text, start, encoding),
+        end: position_from_offset(text, end, encoding),
+    }
+}
+
+pub fn std_range_to_lsp_range(
+    text: &str,
+    range: std::ops::Range<usize>,
+    encoding: PositionEncoding,
+) -> Range {
+    range_from_offsets(text, range.start, range.end, encoding)
+}
+
+pub fn lsp_range_to_std_range(
+    text: &str,
+    range: Range,
+    encoding: PositionEncoding,
+) -> Option<std::ops::Range<usize>> {
+    let start = position_to_offset(text, range.start.line, range.start.character, encoding)?;
+    let end = position_to_offset(text, range.end.line, range.end.character, encoding)?;
+    Some(start..end)
+}
+
+/// Re-encode a position from one encoding to another
+pub fn convert_position(
+    text: &str,
+    position: Position,
+    from: PositionEncoding,
+    to: PositionEncoding,
+) -> Option<Position> {
+    if from == to {
+        return Some(position);
+    }
+    let offset = position_to_offset(text, position.line, position.character, from)?;
+    Some(position_from_offset(text, offset, to))
+}
+
+/// Clamp a byte offset so that it lies within the text and on a char boundary,
+/// moving it backwards if it lands inside a multibyte character
+pub fn clamp_offset(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+/// Clamp a byte range so that it lies within the text and on char boundaries,
+/// widening it to cover any multibyte characters it partially overlaps
+pub fn clamp_range(text: &str, range: std::ops::Range<usize>) -> std::ops::Range<usize> {
+    let start = clamp_offset(text, range.start);
+    let mut end = range.end.min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    start..end.max(start)
+}
+
+/// Slice the text without panicking on out-of-bounds or mid-character ranges
+///
+/// Ranges are clamped to the text and to char boundaries; inverted ranges
+/// (where the start is after the end) are an error.
+pub fn slice_text(text: &str, range: std::ops::Range<usize>) -> Result<&str> {
+    if range.start > range.end {
+        return Err(color_eyre::eyre::eyre!(
+            "Invalid range: start ({start}) is after end ({end})",
+            start = range.start,
+            end = range.end
+        ));
+    }
+    Ok(&text[clamp_range(text, range)])
+}
+
+/// A `file://` URI for an absolute path, percent-encoding anything that isn't
+/// allowed in a URI's path
+pub fn file_uri(path: &std::path::Path) -> Result<Uri> {
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
         } else {
-            character += 1;
+            uri.push_str(&format!("%{byte:02X}"));
         }
+    }
+    uri.parse()
+        .map_err(|e| color_eyre::eyre::eyre!("Invalid uri for {path:?}: {e}"))
+}
 
-        i += 1;
+/// The filesystem path of a `file://` URI, or `None` for any other scheme
+/// (e.g. unsaved `untitled:` documents, or documents on a remote filesystem)
+pub fn file_path(uri: &Uri) -> Option<std::path::PathBuf> {
+    if !uri
+        .scheme()
+        .is_some_and(|scheme| scheme.as_str().eq_ignore_ascii_case("file"))
+    {
+        return None;
     }
+    let path = uri.path().as_estr().decode().into_string_lossy();
+    Some(std::path::PathBuf::from(path.as_ref()))
+}
 
-    Position { line, character }
+/// Whether a `/`-separated path matches a glob
+///
+/// `*` matches any run of characters within a path component, `?` matches a
+/// single character, and `**` matches any number of whole components. Globs
+/// that don't start with `/` can match anywhere in the path, so `fragments/**`
+/// matches every file under any directory named `fragments`.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    fn component_matches(glob: &[char], name: &[char]) -> bool {
+        match glob.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|i| component_matches(rest, &name[i..])),
+            Some(('?', rest)) => !name.is_empty() && component_matches(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && component_matches(rest, &name[1..]),
+        }
+    }
+
+    fn components_match(glob: &[Vec<char>], path: &[Vec<char>]) -> bool {
+        match glob.split_first() {
+            None => path.is_empty(),
+            Some((first, rest)) if first.iter().collect::<String>() == "**" => {
+                (0..=path.len()).any(|i| components_match(rest, &path[i..]))
+            }
+            Some((first, rest)) => {
+                !path.is_empty()
+                    && component_matches(first, &path[0])
+                    && components_match(rest, &path[1..])
+            }
+        }
+    }
+
+    let split = |s: &str| {
+        s.split('/')
+            .filter(|c| !c.is_empty())
+            .map(|c| c.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+    let mut glob_components = split(glob);
+    if !glob.starts_with('/') {
+        glob_components.insert(0, vec!['*', '*']);
+    }
+    components_match(&glob_components, &split(path))
 }
 
-pub fn range_from_offsets(text: &str, start: usize, end: usize) -> Range {
-    Range {
-        start: position_from_offset(text, start),
-        end: position_from_offset(text, end),
+/// Replace `${NAME}` references in the text with the value of the environment
+/// variable `NAME`, so that secrets and per-developer settings don't need to be
+/// committed. `$${` produces a literal `${`.
+pub fn interpolate_env(text: &str) -> Result<String> {
+    interpolate_vars(text, |name| std::env::var(name).ok())
+}
+
+/// Replace `${NAME}` references in the text with the value `lookup` returns
+/// for `NAME`; it is an error to reference a variable that isn't defined
+pub fn interpolate_vars<F>(text: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference.find('}').ok_or_else(|| {
+                color_eyre::eyre::eyre!("Unterminated variable reference: `{rest}`")
+            })?;
+            let name = &reference[..end];
+            let value = lookup(name).ok_or_else(|| {
+                color_eyre::eyre::eyre!("Environment variable `{name}` is not set")
+            })?;
+            result.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
     }
+    result.push_str(rest);
+
+    Ok(result)
 }
 
-pub fn std_range_to_lsp_range(text: &str, range: std::ops::Range<usize>) -> Range {
-    Range {
-        start: position_from_offset(text, range.start),
-        end: position_from_offset(text, range.end),
+/// Remove the empty fields, repeats, components, and sub-components left at
+/// the end of a segment, e.g. `PID|1||123^^^|||` becomes `PID|1||123`
+///
+/// The encoding characters in MSH.2 are never trimmed, even though they are
+/// made up of separators.
+pub fn trim_trailing_separators<'s>(segment: &'s str, separators: &Separators) -> &'s str {
+    let is_separator = |c: char| {
+        c == separators.field
+            || c == separators.repetition
+            || c == separators.component
+            || c == separators.subcomponent
+    };
+    // MSH.1 and MSH.2 run up to the second field separator
+    let keep = if segment.starts_with("MSH") {
+        segment
+            .match_indices(separators.field)
+            .nth(1)
+            .map(|(i, _)| i)
+            .unwrap_or(segment.len())
+    } else {
+        0
+    };
+    let trimmed = segment[keep..].trim_end_matches(is_separator);
+    &segment[..keep + trimmed.len()]
+}
+
+/// Widen an edit to replace the whole segment it is made in, trimming the
+/// separators left redundant at the end of that segment once the edit is
+/// applied (see [trim_trailing_separators])
+///
+/// Edits that span multiple segments, or that leave nothing to trim, are
+/// returned as they are.
+pub fn trim_edited_segment(
+    text: &str,
+    edit: TextEdit,
+    separators: &Separators,
+    encoding: PositionEncoding,
+) -> TextEdit {
+    let Some(range) = lsp_range_to_std_range(text, edit.range, encoding) else {
+        return edit;
+    };
+    let Some(line) =
+        line_ranges(text).find(|line| line.start <= range.start && range.end <= line.end)
+    else {
+        return edit;
+    };
+
+    let mut segment = text[line.clone()].to_string();
+    segment.replace_range(
+        range.start - line.start..range.end - line.start,
+        &edit.new_text,
+    );
+    let trimmed = trim_trailing_separators(&segment, separators);
+    if trimmed.len() == segment.len() {
+        return edit;
+    }
+
+    TextEdit {
+        range: std_range_to_lsp_range(text, line, encoding),
+        new_text: trimmed.to_string(),
     }
 }
 
-pub fn lsp_range_to_std_range(text: &str, range: Range) -> Option<std::ops::Range<usize>> {
-    let start = position_to_offset(text, range.start.line, range.start.character)?;
-    let end = position_to_offset(text, range.end.line, range.end.character)?;
-    Some(start..end)
+/// Returned by request handlers that gave up because the client cancelled the
+/// request
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub struct RequestCancelled;
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for RequestCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request cancelled")
+    }
 }
 
+#[cfg(feature = "server")]
+impl std::error::Error for RequestCancelled {}
+
+#[cfg(feature = "server")]
 #[instrument(level = "debug", skip(result))]
 pub fn build_response<R: Serialize>(id: RequestId, result: Result<R>) -> Response {
     let (result, error) = match result {
@@ -96,14 +409,21 @@ pub fn build_response<R: Serialize>(id: RequestId, result: Result<R>) -> Respons
             Some(serde_json::to_value(result).expect("can serialize response")),
             None,
         ),
-        Err(error) => (
-            None,
-            Some(ResponseError {
-                code: lsp_server::ErrorCode::InternalError as i32,
-                message: error.to_string(),
-                data: None,
-            }),
-        ),
+        Err(error) => {
+            let code = if error.downcast_ref::<RequestCancelled>().is_some() {
+                lsp_server::ErrorCode::RequestCanceled
+            } else {
+                lsp_server::ErrorCode::InternalError
+            };
+            (
+                None,
+                Some(ResponseError {
+                    code: code as i32,
+                    message: error.to_string(),
+                    data: None,
+                }),
+            )
+        }
     };
 
     Response { id, result, error }
@@ -113,56 +433,237 @@ pub fn build_response<R: Serialize>(id: RequestId, result: Result<R>) -> Respons
 mod tests {
     use super::*;
 
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "MLLP_HOST" => Some("hl7.example.com".to_string()),
+            "MLLP_PORT" => Some("2575".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn can_interpolate_variables() {
+        assert_eq!(
+            interpolate_vars("${MLLP_HOST}:${MLLP_PORT}", lookup).unwrap(),
+            "hl7.example.com:2575"
+        );
+        assert_eq!(
+            interpolate_vars("host = \"${MLLP_HOST}\"", lookup).unwrap(),
+            "host = \"hl7.example.com\""
+        );
+        assert_eq!(
+            interpolate_vars("no variables", lookup).unwrap(),
+            "no variables"
+        );
+    }
+
+    #[test]
+    fn interpolation_leaves_other_dollar_signs_alone() {
+        assert_eq!(interpolate_vars("costs $5", lookup).unwrap(), "costs $5");
+        assert_eq!(
+            interpolate_vars("trailing $", lookup).unwrap(),
+            "trailing $"
+        );
+        assert_eq!(
+            interpolate_vars("$${MLLP_HOST}", lookup).unwrap(),
+            "${MLLP_HOST}"
+        );
+    }
+
+    #[test]
+    fn interpolation_fails_on_missing_or_unterminated_variables() {
+        assert!(interpolate_vars("${NOT_SET}", lookup).is_err());
+        assert!(interpolate_vars("${MLLP_HOST", lookup).is_err());
+    }
+
+    #[test]
+    fn can_match_globs() {
+        assert!(glob_matches(
+            "fragments/**",
+            "/home/me/msgs/fragments/adt.hl7"
+        ));
+        assert!(glob_matches("fragments/**", "/fragments/a/b/adt.hl7"));
+        assert!(!glob_matches("fragments/**", "/home/me/msgs/adt.hl7"));
+        assert!(glob_matches("*.frag.hl7", "/msgs/adt.frag.hl7"));
+        assert!(!glob_matches("*.frag.hl7", "/msgs/adt.hl7"));
+        assert!(glob_matches("/msgs/**/a?t.hl7", "/msgs/x/y/adt.hl7"));
+        assert!(!glob_matches("/msgs/**/a?t.hl7", "/other/msgs/adt.hl7"));
+    }
+
+    #[test]
+    fn only_file_uris_have_paths() {
+        let path = |uri: &str| file_path(&uri.parse().unwrap());
+        assert_eq!(
+            path("file:///tmp/my%20messages/a.hl7"),
+            Some(std::path::PathBuf::from("/tmp/my messages/a.hl7"))
+        );
+        assert_eq!(
+            file_uri(&path("file:///tmp/my%20messages/a.hl7").unwrap()).unwrap(),
+            "file:///tmp/my%20messages/a.hl7".parse().unwrap()
+        );
+        assert_eq!(path("untitled:Untitled-1"), None);
+        assert_eq!(path("vscode-vfs://github/owner/repo/a.hl7"), None);
+    }
+
     #[test]
     fn can_calculate_offset_newlines() {
         let text = "abc\ndef\nghi";
-        assert_eq!(position_to_offset(text, 0, 0), Some(0));
-        assert_eq!(position_to_offset(text, 0, 1), Some(1));
-        assert_eq!(position_to_offset(text, 0, 2), Some(2));
+        assert_eq!(
+            position_to_offset(text, 0, 0, PositionEncoding::Utf16),
+            Some(0)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 1, PositionEncoding::Utf16),
+            Some(1)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 2, PositionEncoding::Utf16),
+            Some(2)
+        );
 
-        assert_eq!(position_to_offset(text, 1, 0), Some(4));
-        assert_eq!(position_to_offset(text, 1, 1), Some(5));
-        assert_eq!(position_to_offset(text, 1, 2), Some(6));
+        assert_eq!(
+            position_to_offset(text, 1, 0, PositionEncoding::Utf16),
+            Some(4)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 1, PositionEncoding::Utf16),
+            Some(5)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 2, PositionEncoding::Utf16),
+            Some(6)
+        );
 
-        assert_eq!(position_to_offset(text, 2, 0), Some(8));
-        assert_eq!(position_to_offset(text, 2, 1), Some(9));
-        assert_eq!(position_to_offset(text, 2, 2), Some(10));
+        assert_eq!(
+            position_to_offset(text, 2, 0, PositionEncoding::Utf16),
+            Some(8)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 1, PositionEncoding::Utf16),
+            Some(9)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 2, PositionEncoding::Utf16),
+            Some(10)
+        );
     }
 
     #[test]
     fn can_calculate_offset_carriage_returns() {
         let text = "abc\rdef\rghi";
-        assert_eq!(position_to_offset(text, 0, 0), Some(0));
-        assert_eq!(position_to_offset(text, 0, 1), Some(1));
-        assert_eq!(position_to_offset(text, 0, 2), Some(2));
+        assert_eq!(
+            position_to_offset(text, 0, 0, PositionEncoding::Utf16),
+            Some(0)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 1, PositionEncoding::Utf16),
+            Some(1)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 2, PositionEncoding::Utf16),
+            Some(2)
+        );
 
-        assert_eq!(position_to_offset(text, 1, 0), Some(4));
-        assert_eq!(position_to_offset(text, 1, 1), Some(5));
-        assert_eq!(position_to_offset(text, 1, 2), Some(6));
+        assert_eq!(
+            position_to_offset(text, 1, 0, PositionEncoding::Utf16),
+            Some(4)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 1, PositionEncoding::Utf16),
+            Some(5)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 2, PositionEncoding::Utf16),
+            Some(6)
+        );
 
-        assert_eq!(position_to_offset(text, 2, 0), Some(8));
-        assert_eq!(position_to_offset(text, 2, 1), Some(9));
-        assert_eq!(position_to_offset(text, 2, 2), Some(10));
+        assert_eq!(
+            position_to_offset(text, 2, 0, PositionEncoding::Utf16),
+            Some(8)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 1, PositionEncoding::Utf16),
+            Some(9)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 2, PositionEncoding::Utf16),
+            Some(10)
+        );
 
-        assert_eq!(position_to_offset(text, 3, 0), None);
+        assert_eq!(
+            position_to_offset(text, 3, 0, PositionEncoding::Utf16),
+            None
+        );
     }
 
     #[test]
     fn can_calculate_offset_crlf() {
         let text = "abc\r\ndef\r\nghi";
-        assert_eq!(position_to_offset(text, 0, 0), Some(0));
-        assert_eq!(position_to_offset(text, 0, 1), Some(1));
-        assert_eq!(position_to_offset(text, 0, 2), Some(2));
+        assert_eq!(
+            position_to_offset(text, 0, 0, PositionEncoding::Utf16),
+            Some(0)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 1, PositionEncoding::Utf16),
+            Some(1)
+        );
+        assert_eq!(
+            position_to_offset(text, 0, 2, PositionEncoding::Utf16),
+            Some(2)
+        );
 
-        assert_eq!(position_to_offset(text, 1, 0), Some(5));
-        assert_eq!(position_to_offset(text, 1, 1), Some(6));
-        assert_eq!(position_to_offset(text, 1, 2), Some(7));
+        assert_eq!(
+            position_to_offset(text, 1, 0, PositionEncoding::Utf16),
+            Some(5)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 1, PositionEncoding::Utf16),
+            Some(6)
+        );
+        assert_eq!(
+            position_to_offset(text, 1, 2, PositionEncoding::Utf16),
+            Some(7)
+        );
 
-        assert_eq!(position_to_offset(text, 2, 0), Some(10));
-        assert_eq!(position_to_offset(text, 2, 1), Some(11));
-        assert_eq!(position_to_offset(text, 2, 2), Some(12));
+        assert_eq!(
+            position_to_offset(text, 2, 0, PositionEncoding::Utf16),
+            Some(10)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 1, PositionEncoding::Utf16),
+            Some(11)
+        );
+        assert_eq!(
+            position_to_offset(text, 2, 2, PositionEncoding::Utf16),
+            Some(12)
+        );
+
+        assert_eq!(
+            position_to_offset(text, 3, 0, PositionEncoding::Utf16),
+            None
+        );
+    }
 
-        assert_eq!(position_to_offset(text, 3, 0), None);
+    #[test]
+    fn can_slice_text_safely() {
+        let text = "PID|1||Zoë^Ünïcode";
+        assert_eq!(slice_text(text, 0..3).unwrap(), "PID");
+        // ranges inside a multibyte character are widened to cover it
+        assert_eq!(slice_text(text, 9..10).unwrap(), "ë");
+        // out of bounds ranges are clamped
+        assert_eq!(slice_text(text, 13..1000).unwrap(), "Ünïcode");
+        assert_eq!(slice_text(text, 1000..2000).unwrap(), "");
+        // inverted ranges are an error
+        assert!(slice_text(text, std::ops::Range { start: 5, end: 2 }).is_err());
+    }
+
+    #[test]
+    fn can_clamp_offsets() {
+        let text = "Zoë";
+        assert_eq!(clamp_offset(text, 0), 0);
+        assert_eq!(clamp_offset(text, 3), 2);
+        assert_eq!(clamp_offset(text, 4), 4);
+        assert_eq!(clamp_offset(text, 100), 4);
     }
 
     #[test]
@@ -170,21 +671,21 @@ mod tests {
         let text = "abc\r\ndef\r\nghi";
 
         assert_eq!(
-            position_from_offset(text, 0),
+            position_from_offset(text, 0, PositionEncoding::Utf16),
             Position {
                 line: 0,
                 character: 0
             }
         );
         assert_eq!(
-            position_from_offset(text, 1),
+            position_from_offset(text, 1, PositionEncoding::Utf16),
             Position {
                 line: 0,
                 character: 1
             }
         );
         assert_eq!(
-            position_from_offset(text, 2),
+            position_from_offset(text, 2, PositionEncoding::Utf16),
             Position {
                 line: 0,
                 character: 2
@@ -192,21 +693,21 @@ mod tests {
         );
 
         assert_eq!(
-            position_from_offset(text, 5),
+            position_from_offset(text, 5, PositionEncoding::Utf16),
             Position {
                 line: 1,
                 character: 0
             }
         );
         assert_eq!(
-            position_from_offset(text, 6),
+            position_from_offset(text, 6, PositionEncoding::Utf16),
             Position {
                 line: 1,
                 character: 1
             }
         );
         assert_eq!(
-            position_from_offset(text, 7),
+            position_from_offset(text, 7, PositionEncoding::Utf16),
             Position {
                 line: 1,
                 character: 2
@@ -214,25 +715,157 @@ mod tests {
         );
 
         assert_eq!(
-            position_from_offset(text, 10),
+            position_from_offset(text, 10, PositionEncoding::Utf16),
             Position {
                 line: 2,
                 character: 0
             }
         );
         assert_eq!(
-            position_from_offset(text, 11),
+            position_from_offset(text, 11, PositionEncoding::Utf16),
             Position {
                 line: 2,
                 character: 1
             }
         );
         assert_eq!(
-            position_from_offset(text, 12),
+            position_from_offset(text, 12, PositionEncoding::Utf16),
             Position {
                 line: 2,
                 character: 2
             }
         );
     }
+
+    #[test]
+    fn positions_are_counted_in_the_negotiated_encoding() {
+        // "é" is 2 bytes / 1 UTF-16 unit, "😀" is 4 bytes / 2 UTF-16 units
+        let text = "MSH|^~\\&\rPID|1||José😀^Zoë|X";
+        let x = text.find('X').unwrap();
+        let line_start = text.find("PID").unwrap();
+
+        for (encoding, column) in [
+            (PositionEncoding::Utf8, (x - line_start) as u32),
+            (PositionEncoding::Utf16, 18),
+            (PositionEncoding::Utf32, 17),
+        ] {
+            let position = Position {
+                line: 1,
+                character: column,
+            };
+            assert_eq!(position_from_offset(text, x, encoding), position);
+            assert_eq!(position_to_offset(text, 1, column, encoding), Some(x));
+        }
+    }
+
+    #[test]
+    fn positions_inside_characters_resolve_to_their_start() {
+        let text = "a😀b";
+        // the middle of the surrogate pair
+        assert_eq!(
+            position_to_offset(text, 0, 2, PositionEncoding::Utf16),
+            Some(1)
+        );
+        // past the end of the line
+        assert_eq!(
+            position_to_offset(text, 0, 100, PositionEncoding::Utf16),
+            Some(6)
+        );
+        // in the middle of the emoji's bytes
+        assert_eq!(
+            position_from_offset(text, 3, PositionEncoding::Utf16),
+            Position {
+                line: 0,
+                character: 1
+            }
+        );
+    }
+
+    #[test]
+    fn can_convert_positions_between_encodings() {
+        let text = "PID|1||Zoë😀|";
+        let utf8 = Position {
+            line: 0,
+            character: 15,
+        };
+        let utf16 = Position {
+            line: 0,
+            character: 12,
+        };
+        assert_eq!(
+            convert_position(text, utf8, PositionEncoding::Utf8, PositionEncoding::Utf16),
+            Some(utf16)
+        );
+        assert_eq!(
+            convert_position(text, utf16, PositionEncoding::Utf16, PositionEncoding::Utf8),
+            Some(utf8)
+        );
+    }
+
+    #[test]
+    fn prefers_utf8_when_negotiating() {
+        assert_eq!(PositionEncoding::negotiate(None), PositionEncoding::Utf16);
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&[
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8
+            ])),
+            PositionEncoding::Utf8
+        );
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&[PositionEncodingKind::UTF32])),
+            PositionEncoding::Utf32
+        );
+    }
+
+    #[test]
+    fn trailing_separators_are_trimmed_from_edited_segments() {
+        let separators = Separators::default();
+        assert_eq!(
+            trim_trailing_separators("PID|1||123^^^|||", &separators),
+            "PID|1||123"
+        );
+        assert_eq!(
+            trim_trailing_separators("MSH|^~\\&|||", &separators),
+            "MSH|^~\\&"
+        );
+        assert_eq!(
+            trim_trailing_separators("MSH|^~\\&", &separators),
+            "MSH|^~\\&"
+        );
+
+        let text = "MSH|^~\\&\rPV1|1|||||\rPID|1";
+        let pv1_2 = text.find("PV1").unwrap() + 6;
+        let edit = TextEdit {
+            range: range_from_offsets(text, pv1_2, pv1_2, PositionEncoding::Utf16),
+            new_text: "W^1".to_string(),
+        };
+        let trimmed = trim_edited_segment(text, edit, &separators, PositionEncoding::Utf16);
+        assert_eq!(trimmed.new_text, "PV1|1|W^1");
+        assert_eq!(
+            trimmed.range.start,
+            Position {
+                line: 1,
+                character: 0
+            }
+        );
+        assert_eq!(
+            trimmed.range.end,
+            Position {
+                line: 1,
+                character: 10
+            }
+        );
+
+        // nothing to trim, so the edit is left alone
+        let pid_1 = text.find("PID").unwrap() + 4;
+        let edit = TextEdit {
+            range: range_from_offsets(text, pid_1, pid_1 + 1, PositionEncoding::Utf16),
+            new_text: "2".to_string(),
+        };
+        assert_eq!(
+            trim_edited_segment(text, edit.clone(), &separators, PositionEncoding::Utf16),
+            edit
+        );
+    }
 }
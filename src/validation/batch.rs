@@ -0,0 +1,269 @@
+use super::{
+    override_severities, validate_message_unless_cancelled, ValidationCode, ValidationError,
+};
+use crate::{messages::split_messages, utils::line_ranges, workspace::specs::WorkspaceSpecs, Opts};
+use hl7_parser::{parse_message_with_lenient_newlines, parser::ParseError};
+use lsp_types::{DiagnosticSeverity, Uri};
+use std::ops::Range;
+use tracing::instrument;
+
+/// The problems found in a batch of messages, which can't be parsed as a
+/// single message
+#[derive(Debug, Default)]
+pub struct BatchValidation {
+    /// Problems with the batch's structure and with each of its messages
+    pub errors: Vec<ValidationError>,
+    /// The errors of the messages that failed to parse, positioned in the
+    /// whole file
+    pub parse_errors: Vec<ParseError>,
+}
+
+/// Whether the text is a batch of messages: either a batch file, starting
+/// with a file (FHS) or batch (BHS) header rather than a message header, or
+/// simply several messages one after another
+pub fn is_batch(text: &str, opts: &Opts) -> bool {
+    is_batch_file(text) || split_messages(text, opts.position_encoding).len() > 1
+}
+
+/// Whether the text starts with a file (FHS) or batch (BHS) header
+pub fn is_batch_file(text: &str) -> bool {
+    line_ranges(text)
+        .map(|line| text[line].trim_start())
+        .find(|line| !line.is_empty())
+        .is_some_and(|line| line.starts_with("FHS") || line.starts_with("BHS"))
+}
+
+/// A batch that has been started by a BHS, and the messages in it so far
+struct OpenBatch {
+    header: Range<usize>,
+    messages: usize,
+}
+
+/// Validate a batch of messages: that the file and batch headers and
+/// trailers (FHS/BHS/BTS/FTS) of a batch file are in order and that their
+/// counts are right, and each of the messages as if it was on its own
+///
+/// Gives up and returns `None` if `is_cancelled` returns true, which is
+/// checked between each message.
+#[instrument(level = "debug", skip(text, workspace_specs, opts, is_cancelled))]
+pub fn validate_batch(
+    uri: &Uri,
+    text: &str,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    opts: &Opts,
+    is_cancelled: &dyn Fn() -> bool,
+) -> Option<BatchValidation> {
+    let mut errors = Vec::new();
+    let structure_error = |message: &str, range: Range<usize>| {
+        ValidationError::new(
+            ValidationCode::MessageStructure,
+            message.to_string(),
+            range,
+            DiagnosticSeverity::WARNING,
+        )
+    };
+
+    let mut file_header: Option<Range<usize>> = None;
+    let mut file_trailer: Option<Range<usize>> = None;
+    let mut batch: Option<OpenBatch> = None;
+    let mut batches = 0;
+    let mut first = true;
+    for line in line_ranges(text) {
+        let segment = &text[line.clone()];
+        if segment.trim().is_empty() {
+            continue;
+        }
+        if file_trailer.is_some() {
+            errors.push(structure_error(
+                "Segments after the FTS (File Trailer) aren't part of the file",
+                line.clone(),
+            ));
+        }
+        let name = segment.get(..3).unwrap_or(segment);
+        match name {
+            "MSH" => {
+                if let Some(batch) = batch.as_mut() {
+                    batch.messages += 1;
+                }
+            }
+            "FHS" if !first || file_header.is_some() => errors.push(structure_error(
+                "FHS (File Header) must be the first segment of the file",
+                line.clone(),
+            )),
+            "FHS" => file_header = Some(line.clone()),
+            "BHS" => {
+                if let Some(unfinished) = batch.take() {
+                    errors.push(structure_error(
+                        "Batch has no BTS (Batch Trailer) before the next batch starts",
+                        unfinished.header,
+                    ));
+                }
+                batch = Some(OpenBatch {
+                    header: line.clone(),
+                    messages: 0,
+                });
+                batches += 1;
+            }
+            "BTS" => match batch.take() {
+                Some(finished) => {
+                    errors.extend(check_count(
+                        text,
+                        line.clone(),
+                        finished.messages,
+                        "BTS.1 (Batch Message Count)",
+                        (finished.header, "The batch starts here"),
+                    ));
+                }
+                None => errors.push(structure_error(
+                    "BTS (Batch Trailer) has no BHS (Batch Header) to end",
+                    line.clone(),
+                )),
+            },
+            "FTS" => {
+                match file_header.clone() {
+                    Some(header) => errors.extend(check_count(
+                        text,
+                        line.clone(),
+                        batches,
+                        "FTS.1 (File Batch Count)",
+                        (header, "The file starts here"),
+                    )),
+                    None => errors.push(structure_error(
+                        "FTS (File Trailer) has no FHS (File Header) to end",
+                        line.clone(),
+                    )),
+                }
+                file_trailer = Some(line.clone());
+            }
+            _ => {}
+        }
+        first = false;
+    }
+    if let Some(unfinished) = batch {
+        errors.push(structure_error(
+            "Batch has no BTS (Batch Trailer) to end it",
+            unfinished.header,
+        ));
+    }
+    if let (Some(header), None) = (file_header, file_trailer) {
+        errors.push(structure_error(
+            "File has no FTS (File Trailer) to end it",
+            header,
+        ));
+    }
+    let mut overrides = opts.severity_overrides.clone();
+    if let Some(specs) = workspace_specs {
+        overrides.extend(specs.severity_overrides(uri));
+    }
+    let mut validation = BatchValidation {
+        errors: override_severities(errors, &overrides),
+        parse_errors: Vec::new(),
+    };
+
+    for message in split_messages(text, opts.position_encoding) {
+        let (source, range) = (message.text, message.range);
+        match parse_message_with_lenient_newlines(source) {
+            Ok(message) => {
+                let errors = validate_message_unless_cancelled(
+                    uri,
+                    &message,
+                    workspace_specs,
+                    opts,
+                    is_cancelled,
+                )?;
+                validation.errors.extend(
+                    errors
+                        .iter()
+                        .map(|error| error.moved(0..source.len(), range.start)),
+                );
+            }
+            Err(error) => validation.parse_errors.push(match error {
+                ParseError::FailedToParse { context, position } => ParseError::FailedToParse {
+                    context,
+                    position: range.start + position,
+                },
+                ParseError::IncompleteInput(_) => ParseError::FailedToParse {
+                    context: "incomplete message".to_string(),
+                    position: range.end,
+                },
+            }),
+        }
+    }
+    Some(validation)
+}
+
+/// Check the count in the first field of a batch or file trailer, if it has
+/// one
+fn check_count(
+    text: &str,
+    trailer: Range<usize>,
+    count: usize,
+    field: &str,
+    related: (Range<usize>, &str),
+) -> Option<ValidationError> {
+    let segment = &text[trailer.clone()];
+    let separator = segment.chars().nth(3)?;
+    let start = trailer.start + segment.find(separator)? + 1;
+    let end = text[start..trailer.end]
+        .find(separator)
+        .map_or(trailer.end, |end| start + end);
+    let value = &text[start..end];
+    if value.is_empty() || value.parse() == Ok(count) {
+        return None;
+    }
+    Some(
+        ValidationError::new(
+            ValidationCode::MessageStructure,
+            format!("{field} is {value}, but there are {count}"),
+            start..end,
+            DiagnosticSeverity::WARNING,
+        )
+        .with_related_information(Some((related.0, related.1.to_string())))
+        .with_suggestions(vec![count.to_string()]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_are_checked_around_their_messages() {
+        let text = "FHS|^~\\&|App\r\
+            BHS|^~\\&|App\r\
+            MSH|^~\\&|App|Fac|||20240102||ADT^A01|1|P|2.5.1\r\
+            MSH|^~\\&|App|Fac|||20240102||ADT^A01|2|P|2.5.1\r\
+            BTS|3\r\
+            BTS|1\r\
+            FTS|1";
+        assert!(is_batch(text, &Opts::default()));
+        assert!(!is_batch("MSH|^~\\&|App\rPID|1", &Opts::default()));
+        assert!(is_batch(
+            "MSH|^~\\&|App\rPID|1\r\rMSH|^~\\&|App\rPID|2",
+            &Opts::default()
+        ));
+
+        let uri = "file:///tmp/batch.hl7".parse().unwrap();
+        let validation = validate_batch(&uri, text, &None, &Opts::default(), &|| false).unwrap();
+        assert!(validation.parse_errors.is_empty());
+        let errors = validation
+            .errors
+            .iter()
+            .map(|error| (&text[error.range.clone()], error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert!(errors.contains(&("3", "BTS.1 (Batch Message Count) is 3, but there are 2")));
+        assert!(errors.contains(&(
+            "BTS|1",
+            "BTS (Batch Trailer) has no BHS (Batch Header) to end"
+        )));
+        assert!(!errors.iter().any(|(_, message)| message.contains("FTS")));
+        // only the messages themselves are too short, not the headers
+        assert_eq!(
+            errors
+                .iter()
+                .filter(|(_, message)| *message == "Message must have at least 2 segments")
+                .count(),
+            2
+        );
+    }
+}
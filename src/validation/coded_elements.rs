@@ -0,0 +1,266 @@
+use super::{ValidationCode, ValidationError};
+use crate::workspace::specs::WorkspaceSpecs;
+use hl7_parser::message::Segment;
+use lsp_types::{DiagnosticSeverity, Uri};
+use std::ops::Range;
+use tracing::instrument;
+
+/// The datatypes made of an identifier, text, and coding system, followed by
+/// an alternate triplet of the same
+const CODED_DATATYPES: &[&str] = &["CE", "CNE", "CWE"];
+/// The table of coding systems
+const CODING_SYSTEM_TABLE: u16 = 396;
+
+/// The positions and names of the parts of a triplet
+struct Triplet {
+    identifier: (usize, &'static str),
+    text: (usize, &'static str),
+    coding_system: (usize, &'static str),
+}
+
+const PRIMARY: Triplet = Triplet {
+    identifier: (1, "Identifier"),
+    text: (2, "Text"),
+    coding_system: (3, "Name of Coding System"),
+};
+const ALTERNATE: Triplet = Triplet {
+    identifier: (4, "Alternate Identifier"),
+    text: (5, "Alternate Text"),
+    coding_system: (6, "Name of Alternate Coding System"),
+};
+
+/// Check that the triplets of coded element fields (and components) hang
+/// together: identifiers have a coding system to say what they mean, coding
+/// systems have an identifier, and text comes with the code it describes
+///
+/// If `check_coding_systems` is set, coding systems must also be in table
+/// 0396 (as defined by the workspace, or else the standard), or be one of its
+/// local forms (`L`, `99zzz`, or `HL7nnnn`).
+#[instrument(level = "trace", skip(uri, segment, workspace_specs), fields(segment = segment.name))]
+pub fn validate_segment(
+    uri: &Uri,
+    segment: &Segment,
+    version: &str,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    check_coding_systems: bool,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) else {
+        return errors;
+    };
+    let coding_systems = check_coding_systems
+        .then(|| coding_systems(uri, workspace_specs))
+        .flatten();
+    let mut check = |path: String, parts: Vec<Option<(&str, Range<usize>)>>| {
+        let part = |i: usize| parts.get(i - 1).cloned().flatten();
+        for triplet in [&PRIMARY, &ALTERNATE] {
+            errors.extend(check_triplet(&path, triplet, part));
+        }
+        if let Some(coding_systems) = &coding_systems {
+            for (i, _) in [PRIMARY.coding_system, ALTERNATE.coding_system] {
+                let Some((value, range)) = part(i) else {
+                    continue;
+                };
+                if !is_known_coding_system(coding_systems, value) {
+                    errors.push(
+                        ValidationError::new(
+                            ValidationCode::CodedElement,
+                            format!("{path}.{i} has unknown coding system `{value}` (table 0396)"),
+                            range,
+                            DiagnosticSeverity::WARNING,
+                        )
+                        .with_table(CODING_SYSTEM_TABLE),
+                    );
+                }
+            }
+        }
+    };
+
+    for (fi, field) in segment.fields().enumerate() {
+        if field.is_empty() {
+            continue;
+        }
+        let Some(field_definition) = segment_definition.fields.get(fi) else {
+            continue;
+        };
+        let path = format!("{}.{}", segment.name, fi + 1);
+        if CODED_DATATYPES.contains(&field_definition.datatype) {
+            for repeat in field.repeats().filter(|repeat| !repeat.is_empty()) {
+                let parts = repeat
+                    .components()
+                    .map(|component| {
+                        (!component.is_empty())
+                            .then(|| (component.raw_value(), component.range.clone()))
+                    })
+                    .collect();
+                check(path.clone(), parts);
+            }
+            continue;
+        }
+
+        // e.g. the name context of an XPN
+        let Some(datatype) = hl7_definitions::get_field(version, field_definition.datatype) else {
+            continue;
+        };
+        for repeat in field.repeats() {
+            for (ci, component) in repeat.components().enumerate() {
+                let is_coded = datatype
+                    .subfields
+                    .get(ci)
+                    .is_some_and(|definition| CODED_DATATYPES.contains(&definition.datatype));
+                if !is_coded || component.is_empty() {
+                    continue;
+                }
+                let parts = component
+                    .subcomponents()
+                    .map(|subcomponent| {
+                        (!subcomponent.is_empty())
+                            .then(|| (subcomponent.raw_value(), subcomponent.range.clone()))
+                    })
+                    .collect();
+                check(format!("{path}.{}", ci + 1), parts);
+            }
+        }
+    }
+    errors
+}
+
+/// Report the parts of a triplet that are populated without the ones that
+/// give them meaning
+fn check_triplet<'m>(
+    path: &str,
+    triplet: &Triplet,
+    part: impl Fn(usize) -> Option<(&'m str, Range<usize>)>,
+) -> Vec<ValidationError> {
+    let (identifier, text, coding_system) = (
+        part(triplet.identifier.0),
+        part(triplet.text.0),
+        part(triplet.coding_system.0),
+    );
+    let name = |(i, name): (usize, &str)| format!("{path}.{i} ({name})");
+    let problem = |populated: (usize, &str), missing: (usize, &str), range, severity| {
+        ValidationError::new(
+            ValidationCode::CodedElement,
+            format!(
+                "{populated} is populated without {missing}",
+                populated = name(populated),
+                missing = name(missing)
+            ),
+            range,
+            severity,
+        )
+    };
+
+    let mut errors = Vec::new();
+    match (identifier, coding_system) {
+        (Some((_, range)), None) => errors.push(problem(
+            triplet.identifier,
+            triplet.coding_system,
+            range,
+            DiagnosticSeverity::WARNING,
+        )),
+        (None, Some((_, range))) => errors.push(problem(
+            triplet.coding_system,
+            triplet.identifier,
+            range,
+            DiagnosticSeverity::WARNING,
+        )),
+        (None, None) => {
+            // text on its own is allowed, if not very useful to a receiver
+            if let Some((_, range)) = text {
+                errors.push(problem(
+                    triplet.text,
+                    triplet.identifier,
+                    range,
+                    DiagnosticSeverity::INFORMATION,
+                ));
+            }
+        }
+        (Some(_), Some(_)) => {}
+    }
+    errors
+}
+
+/// The codes of the coding system table, if it has any values to check
+/// against
+fn coding_systems(uri: &Uri, workspace_specs: &Option<&WorkspaceSpecs>) -> Option<Vec<String>> {
+    if let Some(table) = workspace_specs
+        .and_then(|specs| specs.table(uri, CODING_SYSTEM_TABLE))
+        .filter(|table| !table.values.is_empty())
+    {
+        return Some(table.values.into_iter().map(|(code, _)| code).collect());
+    }
+    hl7_definitions::table_values(CODING_SYSTEM_TABLE)
+        .filter(|values| !values.is_empty())
+        .map(|values| values.iter().map(|(code, _)| code.to_string()).collect())
+}
+
+/// Whether a coding system is in the table, or is a local coding system or an
+/// HL7 table, which table 0396 allows for by pattern
+fn is_known_coding_system(coding_systems: &[String], value: &str) -> bool {
+    let is_local =
+        value == "L" || (value.len() == 5 && value.starts_with("99") && value.is_ascii());
+    let is_hl7_table = value
+        .strip_prefix("HL7")
+        .is_some_and(|table| table.len() == 4 && table.chars().all(|c| c.is_ascii_digit()));
+    is_local || is_hl7_table || coding_systems.iter().any(|code| code == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coded_element_triplets_must_be_consistent() {
+        let uri: Uri = "file:///tmp/message.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App|Fac|||20240102||ORU^R01|1|P|2.5.1\r\
+            OBX|1|ST|1234^Glucose^LN^GLU|1|105~\r\
+            OBX|2|ST|^Sodium^^NA|1|140~\r\
+            OBX|3|ST|^^LN^^^XX|1|4.2";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+
+        let errors = message
+            .segments()
+            .flat_map(|segment| validate_segment(&uri, segment, "2.5.1", &None, false))
+            .map(|error| (&text[error.range], error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "GLU",
+                    "OBX.3.4 (Alternate Identifier) is populated without OBX.3.6 (Name of \
+                     Alternate Coding System)"
+                        .to_string()
+                ),
+                (
+                    "Sodium",
+                    "OBX.3.2 (Text) is populated without OBX.3.1 (Identifier)".to_string()
+                ),
+                (
+                    "NA",
+                    "OBX.3.4 (Alternate Identifier) is populated without OBX.3.6 (Name of \
+                     Alternate Coding System)"
+                        .to_string()
+                ),
+                (
+                    "LN",
+                    "OBX.3.3 (Name of Coding System) is populated without OBX.3.1 (Identifier)"
+                        .to_string()
+                ),
+                (
+                    "XX",
+                    "OBX.3.6 (Name of Alternate Coding System) is populated without OBX.3.4 \
+                     (Alternate Identifier)"
+                        .to_string()
+                ),
+            ]
+        );
+
+        let coding_systems = vec!["LN".to_string()];
+        assert!(is_known_coding_system(&coding_systems, "LN"));
+        assert!(is_known_coding_system(&coding_systems, "99LAB"));
+        assert!(is_known_coding_system(&coding_systems, "HL70136"));
+        assert!(!is_known_coding_system(&coding_systems, "XX"));
+    }
+}
@@ -0,0 +1,243 @@
+use super::{datatypes, ValidationCode, ValidationError};
+use crate::workspace::specs::{RuleSpec, WorkspaceSpecs};
+use color_eyre::eyre::{eyre, Result};
+use hl7_parser::{message::Segment, Message};
+use lsp_types::{DiagnosticSeverity, Uri};
+use std::{fmt, ops::Range};
+use tracing::instrument;
+
+/// Where a rule's value is, e.g. `PID.30` or `OBX.3.1`
+///
+/// Components and sub-components are taken from the first repeat of the
+/// field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RulePath {
+    pub segment: String,
+    pub field: usize,
+    pub component: Option<usize>,
+    pub sub_component: Option<usize>,
+}
+
+impl RulePath {
+    /// Parse a path such as `PID.30`, also accepting `PID-30`
+    pub fn parse(path: &str) -> Result<Self> {
+        let invalid = || eyre!("Invalid path `{path}`, expected e.g. `PID.30` or `OBX.3.1`");
+        let path = path.trim().replace('-', ".");
+        let mut parts = path.split('.');
+        let segment = parts
+            .next()
+            .filter(|segment| segment.len() == 3)
+            .ok_or_else(invalid)?
+            .to_string();
+        let mut numbers = parts.map(|part| part.parse::<usize>().ok().filter(|&n| n > 0));
+        let field = numbers.next().flatten().ok_or_else(invalid)?;
+        let component = numbers.next().map(|n| n.ok_or_else(invalid)).transpose()?;
+        let sub_component = numbers.next().map(|n| n.ok_or_else(invalid)).transpose()?;
+        if numbers.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(RulePath {
+            segment,
+            field,
+            component,
+            sub_component,
+        })
+    }
+
+    /// The value at the path in a segment, if it's present
+    fn value<'m>(&self, segment: &Segment<'m>) -> Option<(&'m str, Range<usize>)> {
+        let field = segment.field(self.field)?;
+        let Some(component) = self.component else {
+            return Some((field.raw_value(), field.range.clone()));
+        };
+        let component = field.repeat(1)?.component(component)?;
+        let Some(sub_component) = self.sub_component else {
+            return Some((component.raw_value(), component.range.clone()));
+        };
+        let sub_component = component.subcomponent(sub_component)?;
+        Some((sub_component.raw_value(), sub_component.range.clone()))
+    }
+}
+
+impl fmt::Display for RulePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.segment, self.field)?;
+        if let Some(component) = self.component {
+            write!(f, ".{component}")?;
+        }
+        if let Some(sub_component) = self.sub_component {
+            write!(f, ".{sub_component}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The datatypes that rules can require values to be valid for
+const DATATYPES: &[&str] = &["NM", "DT", "TM", "TS", "DTM"];
+
+/// Check that a rule can be evaluated, so that a spec with a rule that can't
+/// be fails to load rather than the rule being silently ignored
+pub fn check_rule(rule: &RuleSpec) -> Result<()> {
+    RulePath::parse(&rule.when)?;
+    RulePath::parse(&rule.then)?;
+    rule.pattern().transpose()?;
+    if let Some(datatype) = rule.datatype.as_deref() {
+        if !DATATYPES.contains(&datatype) {
+            return Err(eyre!(
+                "Rules can't check the `{datatype}` datatype, only {}",
+                DATATYPES.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check the workspace's conditional rules, such as "PID.29 is required when
+/// PID.30 is `Y`"
+///
+/// Each segment the rule's `then` path is in is checked, against the value of
+/// its `when` path in the same segment if they're in the same segment, or
+/// else in the first segment it's in.
+#[instrument(level = "debug", skip(uri, message, workspace_specs))]
+pub fn validate_message(
+    uri: &Uri,
+    message: &Message,
+    workspace_specs: &WorkspaceSpecs,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    for (spec_name, rule) in workspace_specs.rules(uri) {
+        // invalid rules stop the spec from loading
+        let (Ok(when), Ok(then)) = (RulePath::parse(&rule.when), RulePath::parse(&rule.then))
+        else {
+            continue;
+        };
+        let Ok(pattern) = rule.pattern().transpose() else {
+            continue;
+        };
+
+        for segment in message.segments().filter(|s| s.name == then.segment) {
+            let condition = if when.segment == then.segment {
+                when.value(segment)
+            } else {
+                message
+                    .segment(&when.segment)
+                    .and_then(|segment| when.value(segment))
+            };
+            let condition = condition.map(|(value, _)| value).unwrap_or_default();
+            let applies = match &rule.equals {
+                Some(equals) => condition == equals,
+                None => !condition.is_empty(),
+            };
+            if !applies {
+                continue;
+            }
+            let reason = match &rule.equals {
+                Some(equals) => format!("when {when} is `{equals}`"),
+                None => format!("when {when} is populated"),
+            };
+
+            let (value, range) = then.value(segment).unwrap_or_else(|| {
+                // point at the segment's name when the value is missing
+                let start = segment.range.start;
+                ("", start..start + segment.name.len())
+            });
+            let problem = if value.is_empty() {
+                rule.required
+                    .unwrap_or(false)
+                    .then(|| format!("{then} is required {reason}"))
+            } else {
+                check_value(&rule, pattern.as_ref(), value, &range)
+                    .map(|problem| format!("{then} {problem} {reason}"))
+            };
+            if let Some(problem) = problem {
+                let message = match &rule.description {
+                    Some(description) => format!("{description} ({problem}, {spec_name} spec)"),
+                    None => format!("{problem} ({spec_name} spec)"),
+                };
+                errors.push(ValidationError::new(
+                    ValidationCode::Condition,
+                    message,
+                    range,
+                    DiagnosticSeverity::WARNING,
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// What's wrong with a populated value, if anything
+fn check_value(
+    rule: &RuleSpec,
+    pattern: Option<&regex::Regex>,
+    value: &str,
+    range: &Range<usize>,
+) -> Option<String> {
+    if let Some(datatype) = &rule.datatype {
+        let mut errors = Vec::new();
+        datatypes::check_value(datatype, value, range, &mut errors);
+        if let Some(error) = errors.first() {
+            return Some(format!("must be {datatype} ({})", error.message));
+        }
+    }
+    if let Some(pattern) = pattern {
+        if !pattern.is_match(value) {
+            let expected = rule
+                .pattern_description
+                .clone()
+                .or_else(|| rule.pattern.as_ref().map(|p| format!("`{p}`")))
+                .unwrap_or_default();
+            return Some(format!("must match {expected}"));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::specs::WorkspaceSpec;
+    use std::path::PathBuf;
+
+    #[test]
+    fn rules_apply_when_their_condition_is_met() {
+        let text = "MSH|^~\\&|App\rOBX|1|NM|CODE||12.5\rOBX|2|NM|CODE||high\rOBX|3|ST|CODE||high";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let uri = "file:///tmp/message.hl7".parse().unwrap();
+
+        let specs = WorkspaceSpecs::new(std::iter::empty::<PathBuf>()).unwrap();
+        specs.specs.insert(
+            PathBuf::from("/missing/site.hl7v.toml"),
+            WorkspaceSpec {
+                name: "Site".to_string(),
+                rules: vec![RuleSpec {
+                    when: "OBX.2".to_string(),
+                    equals: Some("NM".to_string()),
+                    then: "OBX.5".to_string(),
+                    datatype: Some("NM".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        );
+        specs.assign_profile(&uri, Some("Site".to_string()));
+
+        let errors = validate_message(&uri, &message, &specs)
+            .into_iter()
+            .map(|error| (error.range.start, error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![(
+                text.find("high").unwrap(),
+                "OBX.5 must be NM (Invalid numeric value: high) when OBX.2 is `NM` (Site spec)"
+                    .to_string()
+            )]
+        );
+        assert_eq!(
+            RulePath::parse("PID-3.1").unwrap().to_string(),
+            "PID.3.1".to_string()
+        );
+        assert!(RulePath::parse("PID.x").is_err());
+    }
+}
@@ -0,0 +1,562 @@
+use super::{
+    structure::{self, Element, Kind},
+    ValidationCode, ValidationError,
+};
+use color_eyre::eyre::{eyre, Context, Result};
+use hl7_parser::{message::Segment, Message};
+use lsp_types::DiagnosticSeverity;
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+/// How a profile says an element is to be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    /// `R`: must be present
+    Required,
+    /// `RE`: must be supported, but may be empty
+    RequiredOrEmpty,
+    /// `C` / `CE`: depends on a condition, which isn't checked
+    Conditional,
+    /// `O`: not profiled
+    Optional,
+    /// `X` (or `B`/`W`, which profiles shouldn't use for new elements): must
+    /// not be sent
+    NotSupported,
+}
+
+impl Usage {
+    fn parse(usage: Option<&str>) -> Usage {
+        match usage {
+            Some("R") => Usage::Required,
+            Some("RE") => Usage::RequiredOrEmpty,
+            Some("C") | Some("CE") => Usage::Conditional,
+            Some("X") | Some("B") | Some("W") => Usage::NotSupported,
+            _ => Usage::Optional,
+        }
+    }
+}
+
+/// A field, component, or sub-component as constrained by a profile
+#[derive(Debug, Clone)]
+pub struct ElementProfile {
+    pub name: String,
+    pub usage: Usage,
+    pub min: usize,
+    /// The most times a field may repeat, if it's limited
+    pub max: Option<usize>,
+    pub length: Option<usize>,
+    /// The standard table the value is bound to
+    pub table: Option<u16>,
+    pub components: Vec<ElementProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentProfile {
+    pub name: String,
+    pub usage: Usage,
+    pub min: usize,
+    pub max: Option<usize>,
+    pub fields: Vec<ElementProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub enum StructureProfile {
+    Segment(SegmentProfile),
+    Group {
+        name: String,
+        usage: Usage,
+        min: usize,
+        max: Option<usize>,
+        elements: Vec<StructureProfile>,
+    },
+}
+
+/// An HL7 v2 conformance profile (the `HL7v2xConformanceProfile` XML that
+/// vendors publish their interfaces as), constraining the messages of a single
+/// type beyond what the standard requires
+#[derive(Debug, Clone)]
+pub struct ConformanceProfile {
+    /// The name from the profile's metadata, or else its message structure
+    pub name: String,
+    pub message_type: Option<String>,
+    pub event_type: Option<String>,
+    pub elements: Vec<StructureProfile>,
+}
+
+/// The root element of a conformance profile
+const PROFILE_ROOT: &str = "HL7v2xConformanceProfile";
+
+impl ConformanceProfile {
+    /// Parse a profile, returning `None` if the document is some other XML
+    pub fn parse(xml: &str) -> Result<Option<Self>> {
+        // don't bother parsing (or complaining about) unrelated XML
+        if !xml.contains(PROFILE_ROOT) {
+            return Ok(None);
+        }
+        let document = roxmltree::Document::parse(xml).wrap_err("Failed to parse XML")?;
+        let root = document.root_element();
+        if root.tag_name().name() != PROFILE_ROOT {
+            return Ok(None);
+        }
+        let static_def = root
+            .descendants()
+            .find(|node| node.has_tag_name("HL7v2xStaticDef"))
+            .ok_or_else(|| eyre!("Profile has no HL7v2xStaticDef"))?;
+
+        let name = root
+            .children()
+            .find(|node| node.has_tag_name("MetaData"))
+            .and_then(|metadata| metadata.attribute("Name"))
+            .or(static_def.attribute("MsgStructID"))
+            .unwrap_or("conformance profile")
+            .to_string();
+
+        Ok(Some(ConformanceProfile {
+            name,
+            message_type: static_def.attribute("MsgType").map(str::to_string),
+            event_type: static_def.attribute("EventType").map(str::to_string),
+            elements: parse_structure(static_def),
+        }))
+    }
+
+    /// Whether the profile constrains messages of the same type (and trigger
+    /// event) as `message`
+    pub fn applies_to(&self, message: &Message) -> bool {
+        let matches = |profiled: &Option<String>, path: &str| match profiled {
+            None => true,
+            Some(profiled) => message
+                .query(path)
+                .is_some_and(|value| value.raw_value() == profiled),
+        };
+        // profiles of acknowledgements don't name a trigger event
+        matches(&self.message_type, "MSH.9.1")
+            && (self.event_type.as_deref().is_none_or(|event| event == "*")
+                || matches(&self.event_type, "MSH.9.2"))
+    }
+
+    /// The profile of each segment, by name, taking the first one where a
+    /// segment appears in more than one group
+    fn segments(&self) -> HashMap<&str, &SegmentProfile> {
+        fn collect<'p>(
+            elements: &'p [StructureProfile],
+            segments: &mut HashMap<&'p str, &'p SegmentProfile>,
+        ) {
+            for element in elements {
+                match element {
+                    StructureProfile::Segment(segment) => {
+                        segments.entry(segment.name.as_str()).or_insert(segment);
+                    }
+                    StructureProfile::Group { elements, .. } => collect(elements, segments),
+                }
+            }
+        }
+        let mut segments = HashMap::new();
+        collect(&self.elements, &mut segments);
+        segments
+    }
+}
+
+fn count(value: Option<&str>, default: usize) -> usize {
+    value.and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// `*` (or anything that isn't a number) means the element repeats without
+/// limit
+fn max_count(value: Option<&str>) -> Option<usize> {
+    value.and_then(|v| v.parse().ok())
+}
+
+/// Tables are named by number, with or without the `HL7` prefix (e.g.
+/// `HL70001` or `0001`)
+fn table_number(value: Option<&str>) -> Option<u16> {
+    let value = value?;
+    value.strip_prefix("HL7").unwrap_or(value).parse().ok()
+}
+
+fn parse_structure(node: roxmltree::Node) -> Vec<StructureProfile> {
+    node.children()
+        .filter_map(|child| {
+            let usage = Usage::parse(child.attribute("Usage"));
+            let min = count(child.attribute("Min"), 0);
+            let max = max_count(child.attribute("Max"));
+            let name = child.attribute("Name").unwrap_or_default().to_string();
+            match child.tag_name().name() {
+                "Segment" => Some(StructureProfile::Segment(SegmentProfile {
+                    name,
+                    usage,
+                    min,
+                    max,
+                    fields: parse_elements(child, "Field"),
+                })),
+                "SegGroup" => Some(StructureProfile::Group {
+                    name,
+                    usage,
+                    min,
+                    max,
+                    elements: parse_structure(child),
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn parse_elements(node: roxmltree::Node, tag: &str) -> Vec<ElementProfile> {
+    let child_tag = match tag {
+        "Field" => "Component",
+        _ => "SubComponent",
+    };
+    node.children()
+        .filter(|child| child.has_tag_name(tag))
+        .map(|child| ElementProfile {
+            name: child.attribute("Name").unwrap_or_default().to_string(),
+            usage: Usage::parse(child.attribute("Usage")),
+            min: count(child.attribute("Min"), 0),
+            max: max_count(child.attribute("Max")),
+            length: child.attribute("Length").and_then(|v| v.parse().ok()),
+            table: table_number(child.attribute("Table")),
+            components: if tag == "SubComponent" {
+                Vec::new()
+            } else {
+                parse_elements(child, child_tag)
+            },
+        })
+        .collect()
+}
+
+/// The profile's structure in the form the standard structures are matched
+/// with
+fn structure_elements(elements: &[StructureProfile]) -> Vec<Element<'_>> {
+    elements
+        .iter()
+        .filter_map(|element| {
+            let (kind, usage, min, max) = match element {
+                StructureProfile::Segment(segment) => (
+                    Kind::Segment(segment.name.as_str()),
+                    segment.usage,
+                    segment.min,
+                    segment.max,
+                ),
+                StructureProfile::Group {
+                    elements,
+                    usage,
+                    min,
+                    max,
+                    ..
+                } => (
+                    Kind::Group(structure_elements(elements)),
+                    *usage,
+                    *min,
+                    *max,
+                ),
+            };
+            // segments that mustn't be sent are reported separately
+            (usage != Usage::NotSupported).then_some(Element {
+                kind,
+                required: usage == Usage::Required || min > 0,
+                repeats: max != Some(1),
+            })
+        })
+        .collect()
+}
+
+/// Check a message against a conformance profile: that its segments are
+/// where the profile allows, and that their fields and components are used,
+/// repeated, sized, and valued the way it says
+#[instrument(level = "debug", skip_all, fields(profile = profile.name))]
+pub fn validate_message(profile: &ConformanceProfile, message: &Message) -> Vec<ValidationError> {
+    let error = |message: String, range: Range<usize>, severity| {
+        ValidationError::new(ValidationCode::Conformance, message, range, severity)
+    };
+    let name = &profile.name;
+    let mut errors = Vec::new();
+
+    let elements = structure_elements(&profile.elements);
+    let (unexpected, missing) = structure::match_segments(message, &elements);
+    for (segment, range) in &unexpected {
+        errors.push(error(
+            format!("{segment} segment is out of order or repeated too often for {name}"),
+            range.clone(),
+            DiagnosticSeverity::WARNING,
+        ));
+    }
+    for (segment, previous) in missing {
+        if unexpected.iter().any(|(n, _)| *n == segment) {
+            continue;
+        }
+        let (message, range) = match previous {
+            Some((previous, range)) => (
+                format!("Missing {segment} segment after {previous}, which {name} requires"),
+                range,
+            ),
+            None => (
+                format!("Missing {segment} segment, which {name} requires"),
+                0..0,
+            ),
+        };
+        errors.push(error(message, range, DiagnosticSeverity::WARNING));
+    }
+
+    let segments = profile.segments();
+    for segment in message.segments() {
+        let Some(segment_profile) = segments.get(segment.name) else {
+            continue;
+        };
+        if segment_profile.usage == Usage::NotSupported {
+            errors.push(error(
+                format!("{} segment isn't supported by {name}", segment.name),
+                segment.range.clone(),
+                DiagnosticSeverity::WARNING,
+            ));
+            continue;
+        }
+        validate_segment(name, segment, segment_profile, &mut errors);
+    }
+
+    errors
+}
+
+fn validate_segment(
+    profile: &str,
+    segment: &Segment,
+    segment_profile: &SegmentProfile,
+    errors: &mut Vec<ValidationError>,
+) {
+    for (fi, field_profile) in segment_profile.fields.iter().enumerate() {
+        // MSH-1 and MSH-2 are the separators themselves
+        if segment.name == "MSH" && fi < 2 {
+            continue;
+        }
+        let path = format!("{}.{}", segment.name, fi + 1);
+        let field = segment.field(fi + 1).filter(|field| !field.is_empty());
+        let range = field
+            .map(|field| field.range.clone())
+            .unwrap_or(segment.range.end..segment.range.end);
+        if !check_usage(
+            profile,
+            &path,
+            field_profile,
+            field.is_some(),
+            &range,
+            errors,
+        ) {
+            continue;
+        }
+        let Some(field) = field else {
+            continue;
+        };
+
+        let repeats = field.repeats().count();
+        if field_profile.max.is_some_and(|max| repeats > max) || repeats < field_profile.min {
+            let expected = match field_profile.max {
+                Some(max) if max == field_profile.min => max.to_string(),
+                Some(max) => format!("{}-{max}", field_profile.min),
+                None => format!("at least {}", field_profile.min),
+            };
+            errors.push(ValidationError::new(
+                ValidationCode::Conformance,
+                format!(
+                    "{path} ({description}) repeats {repeats} times, {profile} allows {expected}",
+                    description = field_profile.name
+                ),
+                field.range.clone(),
+                DiagnosticSeverity::WARNING,
+            ));
+        }
+
+        for repeat in field.repeats() {
+            if field_profile.components.is_empty() {
+                check_value(
+                    profile,
+                    &path,
+                    field_profile,
+                    repeat.raw_value(),
+                    &repeat.range,
+                    errors,
+                );
+                continue;
+            }
+            if repeat.is_empty() {
+                continue;
+            }
+            for (ci, component_profile) in field_profile.components.iter().enumerate() {
+                let path = format!("{path}.{}", ci + 1);
+                let component = repeat.component(ci + 1).filter(|c| !c.is_empty());
+                let range = component
+                    .map(|component| component.range.clone())
+                    .unwrap_or(repeat.range.end..repeat.range.end);
+                let present = component.is_some();
+                if !check_usage(profile, &path, component_profile, present, &range, errors) {
+                    continue;
+                }
+                let Some(component) = component else {
+                    continue;
+                };
+                if component_profile.components.is_empty() {
+                    check_value(
+                        profile,
+                        &path,
+                        component_profile,
+                        component.raw_value(),
+                        &component.range,
+                        errors,
+                    );
+                    continue;
+                }
+                let sub_components = component.subcomponents().collect::<Vec<_>>();
+                for (si, sub_profile) in component_profile.components.iter().enumerate() {
+                    let path = format!("{path}.{}", si + 1);
+                    let sub_component = sub_components.get(si).filter(|s| !s.is_empty());
+                    let range = sub_component
+                        .map(|sub_component| sub_component.range.clone())
+                        .unwrap_or(component.range.end..component.range.end);
+                    let present = sub_component.is_some();
+                    if !check_usage(profile, &path, sub_profile, present, &range, errors) {
+                        continue;
+                    }
+                    if let Some(sub_component) = sub_component {
+                        check_value(
+                            profile,
+                            &path,
+                            sub_profile,
+                            sub_component.raw_value(),
+                            &sub_component.range,
+                            errors,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Report an element that's missing but required, or present but not
+/// supported, returning whether the element is worth checking any further
+fn check_usage(
+    profile: &str,
+    path: &str,
+    element: &ElementProfile,
+    present: bool,
+    range: &Range<usize>,
+    errors: &mut Vec<ValidationError>,
+) -> bool {
+    let problem = match (element.usage, present) {
+        (Usage::Required, false) => "is required by",
+        (Usage::NotSupported, true) => "isn't supported by",
+        _ => return present,
+    };
+    errors.push(ValidationError::new(
+        ValidationCode::Conformance,
+        format!(
+            "{path} ({description}) {problem} {profile}",
+            description = element.name
+        ),
+        range.clone(),
+        DiagnosticSeverity::WARNING,
+    ));
+    false
+}
+
+/// Check a value against the length and table the profile gives it
+fn check_value(
+    profile: &str,
+    path: &str,
+    element: &ElementProfile,
+    value: &str,
+    range: &Range<usize>,
+    errors: &mut Vec<ValidationError>,
+) {
+    if value.is_empty() {
+        return;
+    }
+    let description = &element.name;
+    if let Some(length) = element.length.filter(|&length| value.len() > length) {
+        errors.push(ValidationError::new(
+            ValidationCode::Conformance,
+            format!("{path} ({description}) is too long for {profile} (max: {length})"),
+            range.clone(),
+            DiagnosticSeverity::INFORMATION,
+        ));
+    }
+    let Some(table) = element.table else {
+        return;
+    };
+    let Some(table_values) = hl7_definitions::table_values(table).filter(|v| !v.is_empty()) else {
+        return;
+    };
+    if table_values.iter().all(|(v, _)| *v != value) {
+        errors.push(
+            ValidationError::new(
+                ValidationCode::Conformance,
+                format!(
+                    "`{value}` isn't in table {table:04}, which {profile} binds {path} \
+                     ({description}) to"
+                ),
+                range.clone(),
+                DiagnosticSeverity::WARNING,
+            )
+            .with_table(table),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<HL7v2xConformanceProfile HL7Version="2.5.1" ProfileType="Implementation">
+  <MetaData Name="Acme ADT" OrgName="Acme" Version="1"/>
+  <HL7v2xStaticDef MsgType="ADT" EventType="A01" MsgStructID="ADT_A01">
+    <Segment Name="MSH" Usage="R" Min="1" Max="1"/>
+    <Segment Name="EVN" Usage="R" Min="1" Max="1"/>
+    <Segment Name="PID" Usage="R" Min="1" Max="1">
+      <Field Name="Set ID - PID" Usage="O" Min="0" Max="1" Datatype="SI" Length="4"/>
+      <Field Name="Patient ID" Usage="X" Min="0" Max="0" Datatype="CX"/>
+      <Field Name="Patient Identifier List" Usage="R" Min="1" Max="2" Datatype="CX">
+        <Component Name="ID Number" Usage="R" Datatype="ST" Length="10"/>
+      </Field>
+      <Field Name="Alternate Patient ID - PID" Usage="O" Datatype="CX"/>
+      <Field Name="Patient Name" Usage="R" Min="1" Max="1" Datatype="XPN"/>
+      <Field Name="Mother's Maiden Name" Usage="O" Datatype="XPN"/>
+      <Field Name="Date/Time of Birth" Usage="O" Datatype="TS"/>
+      <Field Name="Administrative Sex" Usage="RE" Min="0" Max="1" Datatype="IS" Table="HL70001"/>
+    </Segment>
+    <Segment Name="NK1" Usage="X" Min="0" Max="0"/>
+    <SegGroup Name="INSURANCE" Usage="O" Min="0" Max="*">
+      <Segment Name="IN1" Usage="R" Min="1" Max="1"/>
+    </SegGroup>
+  </HL7v2xStaticDef>
+</HL7v2xConformanceProfile>"#;
+
+    #[test]
+    fn messages_are_checked_against_conformance_profiles() {
+        let profile = ConformanceProfile::parse(PROFILE).unwrap().unwrap();
+        assert_eq!(profile.name, "Acme ADT");
+        assert!(ConformanceProfile::parse("<Other/>").unwrap().is_none());
+
+        let validate = |segments: &[&str]| {
+            let text = segments.join("\r");
+            let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+            assert!(profile.applies_to(&message));
+            validate_message(&profile, &message)
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+        };
+        let msh = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|1|P|2.5.1";
+
+        assert!(validate(&[msh, "EVN|A01", "PID|1||123^^^Hosp||Doe^John|||F", "IN1|1"]).is_empty());
+        assert_eq!(
+            validate(&[msh, "PID|1|9|12345678901~2~3||Doe|||Q", "NK1|1"]),
+            vec![
+                "Missing EVN segment after MSH, which Acme ADT requires",
+                "PID.2 (Patient ID) isn't supported by Acme ADT",
+                "PID.3 (Patient Identifier List) repeats 3 times, Acme ADT allows 1-2",
+                "PID.3.1 (ID Number) is too long for Acme ADT (max: 10)",
+                "`Q` isn't in table 0001, which Acme ADT binds PID.8 (Administrative Sex) to",
+                "NK1 segment isn't supported by Acme ADT",
+            ]
+        );
+    }
+}
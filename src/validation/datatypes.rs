@@ -1,71 +1,24 @@
 use super::{ValidationCode, ValidationError};
-use hl7_parser::Message;
+use hl7_parser::message::{Repeat, Segment};
 use lsp_types::DiagnosticSeverity;
 use std::ops::Range;
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(message))]
-pub fn validate_message(message: &Message, version: &str) -> Vec<ValidationError> {
+#[instrument(level = "trace", skip(segment), fields(segment = segment.name))]
+pub fn validate_segment(segment: &Segment, version: &str) -> Vec<ValidationError> {
     let mut errors = Vec::new();
 
-    for segment in message.segments() {
-        if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
-            for (fi, field) in segment.fields().enumerate() {
-                if field.is_empty() {
-                    continue;
-                }
-                for repeat in field.repeats() {
-                    if repeat.is_empty() {
-                        continue;
-                    }
-                    if let Some(field_definition) = segment_definition.fields.get(fi) {
-                        match field_definition.datatype {
-                            "NM" => check_numeric(repeat.raw_value(), &repeat.range, &mut errors),
-                            "TS" | "DTM" => {
-                                check_timestamp(repeat.raw_value(), &repeat.range, &mut errors)
-                            }
-                            "DT" => check_date(repeat.raw_value(), &repeat.range, &mut errors),
-                            "TM" => check_time(repeat.raw_value(), &repeat.range, &mut errors),
-                            _ => {
-                                for (ci, component) in repeat.components().enumerate() {
-                                    if component.is_empty() {
-                                        continue;
-                                    }
-                                    let field_datatype = field_definition.datatype;
-                                    if let Some(component_definition) =
-                                        hl7_definitions::get_field(version, field_datatype)
-                                            .and_then(|f| f.subfields.get(ci))
-                                    {
-                                        match component_definition.datatype {
-                                            "NM" => {
-                                                check_numeric(
-                                                    component.raw_value(),
-                                                    &component.range,
-                                                    &mut errors,
-                                                );
-                                            }
-                                            "TS" | "DTM" => check_timestamp(
-                                                repeat.raw_value(),
-                                                &repeat.range,
-                                                &mut errors,
-                                            ),
-                                            "DT" => check_date(
-                                                repeat.raw_value(),
-                                                &repeat.range,
-                                                &mut errors,
-                                            ),
-                                            "TM" => check_time(
-                                                repeat.raw_value(),
-                                                &repeat.range,
-                                                &mut errors,
-                                            ),
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+    if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
+        for (fi, field) in segment.fields().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+            let Some(field_definition) = segment_definition.fields.get(fi) else {
+                continue;
+            };
+            for repeat in field.repeats() {
+                if !repeat.is_empty() {
+                    check_repeat(version, field_definition.datatype, repeat, &mut errors);
                 }
             }
         }
@@ -74,6 +27,77 @@ pub fn validate_message(message: &Message, version: &str) -> Vec<ValidationError
     errors
 }
 
+/// Check a repeat of a field with the given datatype, down to its
+/// sub-components if it's a composite
+pub(super) fn check_repeat(
+    version: &str,
+    datatype: &str,
+    repeat: &Repeat,
+    errors: &mut Vec<ValidationError>,
+) {
+    if check_value(datatype, repeat.raw_value(), &repeat.range, errors) {
+        return;
+    }
+    let Some(datatype) = hl7_definitions::get_field(version, datatype) else {
+        return;
+    };
+    for (ci, component) in repeat.components().enumerate() {
+        if component.is_empty() {
+            continue;
+        }
+        let Some(component_definition) = datatype.subfields.get(ci) else {
+            continue;
+        };
+        if check_value(
+            component_definition.datatype,
+            component.raw_value(),
+            &component.range,
+            errors,
+        ) {
+            continue;
+        }
+
+        // e.g. the sub-components of a CX's assigning authority
+        let Some(component_datatype) =
+            hl7_definitions::get_field(version, component_definition.datatype)
+        else {
+            continue;
+        };
+        for (si, sub_component) in component.subcomponents().enumerate() {
+            if sub_component.is_empty() {
+                continue;
+            }
+            if let Some(sub_component_definition) = component_datatype.subfields.get(si) {
+                check_value(
+                    sub_component_definition.datatype,
+                    sub_component.raw_value(),
+                    &sub_component.range,
+                    errors,
+                );
+            }
+        }
+    }
+}
+
+/// Check a value with a primitive datatype, returning whether the datatype
+/// was one that could be checked (as opposed to a composite one, whose parts
+/// need checking instead)
+pub(super) fn check_value(
+    datatype: &str,
+    value: &str,
+    range: &Range<usize>,
+    errors: &mut Vec<ValidationError>,
+) -> bool {
+    match datatype {
+        "NM" => check_numeric(value, range, errors),
+        "TS" | "DTM" => check_timestamp(datatype, value, range, errors),
+        "DT" => check_date(value, range, errors),
+        "TM" => check_time(value, range, errors),
+        _ => return false,
+    }
+    true
+}
+
 fn check_numeric(value: &str, range: &Range<usize>, errors: &mut Vec<ValidationError>) {
     if value.parse::<f64>().is_err() {
         errors.push(ValidationError::new(
@@ -85,35 +109,65 @@ fn check_numeric(value: &str, range: &Range<usize>, errors: &mut Vec<ValidationE
     }
 }
 
-fn check_timestamp(value: &str, range: &Range<usize>, errors: &mut Vec<ValidationError>) {
+fn check_timestamp(
+    datatype: &str,
+    value: &str,
+    range: &Range<usize>,
+    errors: &mut Vec<ValidationError>,
+) {
     if let Err(e) = hl7_parser::datetime::parse_timestamp(value, false) {
-        errors.push(ValidationError::new(
-            ValidationCode::InvalidTimestamp,
-            format!("Invalid timestamp: {e:#}"),
-            range.clone(),
-            DiagnosticSeverity::WARNING,
-        ));
+        errors.push(
+            ValidationError::new(
+                ValidationCode::InvalidTimestamp,
+                format!("Invalid timestamp: {e:#}"),
+                range.clone(),
+                DiagnosticSeverity::WARNING,
+            )
+            .with_datatype(datatype),
+        );
     }
 }
 
 fn check_date(value: &str, range: &Range<usize>, errors: &mut Vec<ValidationError>) {
     if let Err(e) = hl7_parser::datetime::parse_date(value, false) {
-        errors.push(ValidationError::new(
-            ValidationCode::InvalidTimestamp,
-            format!("Invalid date: {e:#}"),
-            range.clone(),
-            DiagnosticSeverity::WARNING,
-        ));
+        errors.push(
+            ValidationError::new(
+                ValidationCode::InvalidTimestamp,
+                format!("Invalid date: {e:#}"),
+                range.clone(),
+                DiagnosticSeverity::WARNING,
+            )
+            .with_datatype("DT"),
+        );
     }
 }
 
 fn check_time(value: &str, range: &Range<usize>, errors: &mut Vec<ValidationError>) {
     if let Err(e) = hl7_parser::datetime::parse_time(value, false) {
-        errors.push(ValidationError::new(
-            ValidationCode::InvalidTimestamp,
-            format!("Invalid time: {e:#}"),
-            range.clone(),
-            DiagnosticSeverity::WARNING,
-        ));
+        errors.push(
+            ValidationError::new(
+                ValidationCode::InvalidTimestamp,
+                format!("Invalid time: {e:#}"),
+                range.clone(),
+                DiagnosticSeverity::WARNING,
+            )
+            .with_datatype("TM"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sub_components_are_checked() {
+        let text = "MSH|^~\\&|App\rPID|1||123^^^Hosp&1.2&ISO^MR||Doe^John^^^^^^^^20200101&2021x";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+
+        let errors = validate_segment(pid, "2.5.1");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&text[errors[0].range.clone()], "2021x");
     }
 }
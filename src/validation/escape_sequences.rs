@@ -0,0 +1,78 @@
+use crate::escapes::{find_escapes, Charset, Decoded};
+use hl7_parser::message::{Segment, Separators};
+use lsp_types::DiagnosticSeverity;
+use tracing::instrument;
+
+use super::{ValidationCode, ValidationError};
+
+/// Check that the hex and character set escape sequences in the segment
+/// decode to characters in the message's declared character set, and that
+/// the truncation character (if the message declares one) only ends values
+#[instrument(level = "debug", skip(segment, separators))]
+pub fn validate_segment(
+    segment: &Segment,
+    separators: &Separators,
+    charset: &Charset,
+    truncation: Option<char>,
+) -> Vec<ValidationError> {
+    let start = segment.range.start;
+    let mut errors = find_escapes(segment.raw_value(), separators, charset)
+        .into_iter()
+        .filter_map(|escape| match escape.decoded {
+            Decoded::Invalid(problem) => Some(ValidationError::new(
+                ValidationCode::InvalidEscapeSequence,
+                format!(
+                    "Invalid escape sequence `{sequence}`: {problem}",
+                    sequence = escape.sequence
+                ),
+                start + escape.range.start..start + escape.range.end,
+                DiagnosticSeverity::WARNING,
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if let Some(truncation) = truncation {
+        errors.extend(misplaced_truncation_characters(
+            segment,
+            truncation,
+            separators.escape,
+        ));
+    }
+    errors
+}
+
+/// Report truncation characters anywhere but at the end of a value, where
+/// they'd wrongly mark the value as truncated rather than being part of it
+fn misplaced_truncation_characters(
+    segment: &Segment,
+    truncation: char,
+    escape: char,
+) -> Vec<ValidationError> {
+    // MSH-1 and MSH-2 hold the separators themselves
+    let skip = if segment.name == "MSH" { 2 } else { 0 };
+    segment
+        .fields()
+        .skip(skip)
+        .flat_map(|field| field.repeats())
+        .flat_map(|repeat| repeat.components())
+        .flat_map(|component| component.subcomponents())
+        .flat_map(|sub_component| {
+            let value = sub_component.raw_value();
+            let last = value.len() - value.chars().next_back().map_or(0, char::len_utf8);
+            value
+                .match_indices(truncation)
+                .filter(move |&(i, _)| i < last)
+                .map(move |(i, _)| sub_component.range.start + i)
+        })
+        .map(|offset| {
+            ValidationError::new(
+                ValidationCode::InvalidEscapeSequence,
+                format!(
+                    "The truncation character `{truncation}` can only end a value, escape it as `{escape}P{escape}` elsewhere"
+                ),
+                offset..offset + truncation.len_utf8(),
+                DiagnosticSeverity::WARNING,
+            )
+        })
+        .collect()
+}
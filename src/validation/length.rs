@@ -1,28 +1,95 @@
-use super::{ValidationCode, ValidationError};
-use hl7_parser::Message;
+use super::{msh::allows_truncation_character, ValidationCode, ValidationError};
+use hl7_parser::message::{Segment, Separators};
 use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(message))]
-pub fn validate_message(message: &Message, version: &str) -> Vec<ValidationError> {
+/// Check the lengths of the segment's fields, components, and
+/// sub-components
+///
+/// From v2.7 lengths are conformance lengths, which receivers may truncate
+/// longer values to, rather than hard limits; values ending with the
+/// message's truncation character have already been truncated and aren't
+/// checked.
+///
+/// Each value that's too long comes with a fix that truncates it, ending it
+/// with the truncation character if the message declares one.
+#[instrument(level = "trace", skip(segment, separators), fields(segment = segment.name))]
+pub fn validate_segment(
+    segment: &Segment,
+    separators: &Separators,
+    version: &str,
+    truncation: Option<char>,
+) -> Vec<ValidationError> {
     let mut errors = Vec::new();
+    let limits = Limits {
+        conformance: allows_truncation_character(version),
+        truncation,
+        escape: separators.escape,
+    };
 
-    for segment in message.segments() {
-        if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
-            for (fi, field) in segment.fields().enumerate() {
-                if field.repeats().next().map(|r| r.components().count() > 1) == Some(true) {
-                    continue;
-                }
-                if let Some(field_definition) = segment_definition.fields.get(fi) {
-                    if let Some(max_length) = field_definition.max_length {
-                        if field.raw_value().len() > max_length {
-                            errors.push(ValidationError::new(
+    if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
+        for (fi, field) in segment.fields().enumerate() {
+            let Some(field_definition) = segment_definition.fields.get(fi) else {
+                continue;
+            };
+            if field.repeats().next().map(|r| r.components().count() > 1) != Some(true) {
+                if let Some(max_length) = field_definition.max_length {
+                    if let Some(problem) = limits.problem(field.raw_value(), max_length, None) {
+                        errors.push(limits.with_truncation(
+                            ValidationError::new(
                                 ValidationCode::InvalidLength,
-                                format!("Field is too long (max: {})", max_length),
+                                format!("Field {problem}"),
                                 field.range.clone(),
                                 DiagnosticSeverity::INFORMATION,
-                            ));
-                        }
+                            ),
+                            field.raw_value(),
+                            max_length,
+                        ));
+                    }
+                }
+            }
+
+            // components are checked against the field's datatype, and
+            // sub-components against the component's
+            let Some(datatype) = hl7_definitions::get_field(version, field_definition.datatype)
+            else {
+                continue;
+            };
+            let components = field
+                .repeats()
+                .flat_map(|repeat| repeat.components().enumerate());
+            for (ci, component) in components {
+                let Some(component_definition) = datatype.subfields.get(ci) else {
+                    continue;
+                };
+                check_length(
+                    &limits,
+                    "Component",
+                    component_definition,
+                    component.raw_value(),
+                    &component.range,
+                    &mut errors,
+                );
+
+                if component.subcomponents().count() < 2 {
+                    continue;
+                }
+                let Some(component_datatype) =
+                    hl7_definitions::get_field(version, component_definition.datatype)
+                else {
+                    continue;
+                };
+                for (si, sub_component) in component.subcomponents().enumerate() {
+                    if let Some(sub_component_definition) = component_datatype.subfields.get(si) {
+                        check_length(
+                            &limits,
+                            "Sub-component",
+                            sub_component_definition,
+                            sub_component.raw_value(),
+                            &sub_component.range,
+                            &mut errors,
+                        );
                     }
                 }
             }
@@ -31,3 +98,152 @@ pub fn validate_message(message: &Message, version: &str) -> Vec<ValidationError
 
     errors
 }
+
+/// How lengths are interpreted for a message's version
+struct Limits {
+    /// Whether lengths are conformance lengths (from v2.7) rather than
+    /// maximum lengths
+    conformance: bool,
+    /// The message's truncation character, if it declares one
+    truncation: Option<char>,
+    /// The message's escape character, so that escape sequences aren't cut
+    /// in half
+    escape: char,
+}
+
+impl Limits {
+    /// What's wrong with the length of a value, if anything
+    fn problem(&self, value: &str, length: usize, description: Option<&str>) -> Option<String> {
+        if value.len() <= length {
+            return None;
+        }
+        let description = description
+            .map(|description| format!("{description}, "))
+            .unwrap_or_default();
+        if !self.conformance {
+            return Some(format!("is too long ({description}max: {length})"));
+        }
+        if self.truncation.is_some_and(|c| value.ends_with(c)) {
+            return None;
+        }
+        Some(format!(
+            "is longer than its conformance length ({description}C.LEN: {length}) and may be truncated"
+        ))
+    }
+
+    /// Offer to truncate a value that's too long, to the length it should be
+    /// including the truncation character, if there is one
+    fn with_truncation(
+        &self,
+        error: ValidationError,
+        value: &str,
+        length: usize,
+    ) -> ValidationError {
+        let marker = self.truncation.filter(|_| self.conformance);
+        let kept = length.saturating_sub(marker.map_or(0, char::len_utf8));
+        let mut end = kept.min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        // an odd number of escape characters means the cut is inside an
+        // escape sequence, which has to go entirely
+        let kept = &value[..end];
+        let kept = match kept.matches(self.escape).count() % 2 {
+            0 => kept,
+            _ => &kept[..kept.rfind(self.escape).unwrap_or_default()],
+        };
+        let (title, replacement) = match marker {
+            Some(marker) => (
+                format!("Truncate to {length} characters, ending with `{marker}`"),
+                format!("{kept}{marker}"),
+            ),
+            None => (format!("Truncate to {length} characters"), kept.to_string()),
+        };
+        error.with_fix(&title, replacement)
+    }
+}
+
+/// Report a component or sub-component that's longer than its definition
+/// allows
+fn check_length(
+    limits: &Limits,
+    kind: &str,
+    definition: &hl7_definitions::Field,
+    value: &str,
+    range: &Range<usize>,
+    errors: &mut Vec<ValidationError>,
+) {
+    let Some(max_length) = definition.max_length else {
+        return;
+    };
+    if let Some(problem) = limits.problem(value, max_length, Some(definition.description)) {
+        errors.push(limits.with_truncation(
+            ValidationError::new(
+                ValidationCode::InvalidLength,
+                format!("{kind} {problem}"),
+                range.clone(),
+                DiagnosticSeverity::INFORMATION,
+            ),
+            value,
+            max_length,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_are_checked_against_their_datatype() {
+        let family_name = "X".repeat(195);
+        let text = format!("MSH|^~\\&|App\rPID|1||12345678901234567^^^Hosp^MR||{family_name}^John");
+        let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+        let pid = message.segment("PID").unwrap();
+
+        let errors = validate_segment(pid, &message.separators, "2.5.1", None);
+        assert_eq!(
+            errors[0].data.suggestions,
+            vec!["123456789012345".to_string()]
+        );
+        let errors = errors
+            .into_iter()
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "12345678901234567".to_string(),
+                    "Component is too long (ID Number, max: 15)".to_string()
+                ),
+                (
+                    family_name,
+                    "Component is too long (Family Name, max: 194)".to_string()
+                ),
+            ]
+        );
+
+        // from v2.7 lengths are conformance lengths, and truncated values are
+        // already as short as they need to be
+        let text = "MSH|^~\\&#|App\rPID|1||12345678901234567^^^Hosp^MR~12345678901234#^^^Hosp^MR";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+        let errors = validate_segment(pid, &message.separators, "2.7", Some('#'));
+        assert_eq!(
+            errors[0].data.suggestions,
+            vec!["12345678901234#".to_string()]
+        );
+        let errors = errors
+            .into_iter()
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![(
+                "12345678901234567".to_string(),
+                "Component is longer than its conformance length (ID Number, C.LEN: 15) and may be truncated".to_string()
+            )]
+        );
+    }
+}
@@ -1,23 +1,127 @@
-use crate::{utils::position_from_offset, workspace::specs::WorkspaceSpecs, Opts};
-use hl7_parser::Message;
-use lsp_types::{Diagnostic, DiagnosticSeverity, Uri};
-use std::{fmt, ops::Range};
+use crate::{
+    escapes::{self, Charset},
+    utils::{std_range_to_lsp_range, PositionEncoding},
+    workspace::specs::WorkspaceSpecs,
+    Opts, SeverityOverride,
+};
+use hl7_parser::{message::Segment, Message};
+use lsp_types::{
+    CodeDescription, Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, Location, Uri,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::Range,
+    time::Instant,
+};
 use tracing::instrument;
 
+mod batch;
+mod coded_elements;
+mod conditions;
+mod conformance;
 mod datatypes;
+mod escape_sequences;
 mod length;
 mod msh;
+mod observations;
 mod optionality;
+mod patterns;
+mod plausibility;
+mod repeatability;
+mod set_ids;
+mod structure;
+mod style;
 mod table_values;
+mod value_types;
+
+pub use batch::{is_batch, is_batch_file, validate_batch, BatchValidation};
+pub use conditions::{check_rule, RulePath};
+pub use conformance::ConformanceProfile;
+pub use observations::{observations, Observation, ObservationPart};
+pub use optionality::segment_template;
+pub use set_ids::renumber_set_ids;
+pub use structure::{canonical_order, required_segments, structure_segments};
 
 #[derive(Debug, Copy, Clone)]
 pub enum ValidationCode {
     MessageStructure,
+    MessageHeader,
+    SegmentStructure,
     InvalidTableValue,
     InvalidTimestamp,
     InvalidLength,
     InvalidOptionality,
     InvalidDataType(&'static str),
+    InvalidEscapeSequence,
+    InvalidSetId,
+    InvalidRepeatCount,
+    ObservationSubId,
+    Conformance,
+    Pattern,
+    Condition,
+    ValueType,
+    CodedElement,
+    Plausibility,
+    ControlId,
+    Style,
+    ValidationBudget,
+}
+
+impl ValidationCode {
+    /// The keys of every validation code, see [ValidationCode::key]
+    pub const KEYS: &'static [&'static str] = &[
+        "message-structure",
+        "message-header",
+        "segment-structure",
+        "table-value",
+        "timestamp",
+        "length",
+        "optionality",
+        "data-type",
+        "escape-sequence",
+        "set-id",
+        "repetition",
+        "observation-sub-id",
+        "conformance",
+        "pattern",
+        "condition",
+        "value-type",
+        "coded-element",
+        "plausibility",
+        "control-id",
+        "style",
+        "validation-budget",
+    ];
+
+    /// The name the code is configured by, e.g. when overriding the severity
+    /// it's reported at
+    pub fn key(&self) -> &'static str {
+        match self {
+            ValidationCode::MessageStructure => "message-structure",
+            ValidationCode::MessageHeader => "message-header",
+            ValidationCode::SegmentStructure => "segment-structure",
+            ValidationCode::InvalidTableValue => "table-value",
+            ValidationCode::InvalidTimestamp => "timestamp",
+            ValidationCode::InvalidLength => "length",
+            ValidationCode::InvalidOptionality => "optionality",
+            ValidationCode::InvalidDataType(_) => "data-type",
+            ValidationCode::InvalidEscapeSequence => "escape-sequence",
+            ValidationCode::InvalidSetId => "set-id",
+            ValidationCode::InvalidRepeatCount => "repetition",
+            ValidationCode::ObservationSubId => "observation-sub-id",
+            ValidationCode::Conformance => "conformance",
+            ValidationCode::Pattern => "pattern",
+            ValidationCode::Condition => "condition",
+            ValidationCode::ValueType => "value-type",
+            ValidationCode::CodedElement => "coded-element",
+            ValidationCode::Plausibility => "plausibility",
+            ValidationCode::ControlId => "control-id",
+            ValidationCode::Style => "style",
+            ValidationCode::ValidationBudget => "validation-budget",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +130,42 @@ pub struct ValidationError {
     pub message: String,
     pub range: Range<usize>,
     pub severity: DiagnosticSeverity,
+    /// Other locations in the message that explain this error
+    pub related_information: Vec<(Range<usize>, String)>,
+    /// Link to documentation describing the rule that was violated
+    pub href: Option<String>,
+    /// What clients need to offer quick fixes for the error
+    pub data: DiagnosticData,
+}
+
+/// Data attached to diagnostics so that clients can send it back with code
+/// action requests, to offer quick fixes without validating the message again
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiagnosticData {
+    /// Values the range of the diagnostic can be replaced with, most likely
+    /// first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<String>,
+    /// The standard table the value in the range of the diagnostic should
+    /// come from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<u16>,
+    /// The title of a quick fix that replaces the range of the diagnostic
+    /// with the first suggestion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+    /// Whether the quick fix inserts the current time after the suggestion,
+    /// as the time is only known once the fix is applied
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fix_inserts_now: bool,
+    /// The primitive datatype the value in the range of the diagnostic
+    /// should be, e.g. `DTM` for a timestamp that can't be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datatype: Option<String>,
+    /// The required segment that is missing from after the range of the
+    /// diagnostic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing_segment: Option<String>,
 }
 
 impl ValidationError {
@@ -40,23 +180,123 @@ impl ValidationError {
             message,
             range,
             severity,
+            related_information: Vec::new(),
+            href: None,
+            data: DiagnosticData::default(),
+        }
+    }
+
+    pub fn with_related_information(mut self, related: Option<(Range<usize>, String)>) -> Self {
+        self.related_information.extend(related);
+        self
+    }
+
+    pub fn with_href(mut self, href: Option<String>) -> Self {
+        self.href = href;
+        self
+    }
+
+    pub fn with_suggestions(mut self, suggestions: Vec<String>) -> Self {
+        self.data.suggestions = suggestions;
+        self
+    }
+
+    pub fn with_table(mut self, table: u16) -> Self {
+        self.data.table = Some(table);
+        self
+    }
+
+    pub fn with_datatype(mut self, datatype: &str) -> Self {
+        self.data.datatype = Some(datatype.to_string());
+        self
+    }
+
+    pub fn with_missing_segment(mut self, segment: &str) -> Self {
+        self.data.missing_segment = Some(segment.to_string());
+        self
+    }
+
+    /// Offer a quick fix, with the given title, that replaces the range of
+    /// the error with `replacement`
+    pub fn with_fix(mut self, title: &str, replacement: String) -> Self {
+        self.data.fix = Some(title.to_string());
+        self.data.suggestions = vec![replacement];
+        self
+    }
+
+    /// The same error for a segment that has moved from `from` to start at
+    /// `to`, leaving any related information outside of the segment in place
+    fn moved(&self, from: Range<usize>, to: usize) -> Self {
+        let reposition = |range: &Range<usize>| {
+            if from.start <= range.start && range.end <= from.end {
+                range.start - from.start + to..range.end - from.start + to
+            } else {
+                range.clone()
+            }
+        };
+        ValidationError {
+            range: reposition(&self.range),
+            related_information: self
+                .related_information
+                .iter()
+                .map(|(range, message)| (reposition(range), message.clone()))
+                .collect(),
+            ..self.clone()
         }
     }
 
-    pub fn into_diagnostic(self, text: &str) -> Diagnostic {
+    pub fn into_diagnostic(self, uri: &Uri, text: &str, encoding: PositionEncoding) -> Diagnostic {
+        let related_information = self
+            .related_information
+            .into_iter()
+            .map(|(range, message)| DiagnosticRelatedInformation {
+                location: Location {
+                    uri: uri.clone(),
+                    range: std_range_to_lsp_range(text, range, encoding),
+                },
+                message,
+            })
+            .collect::<Vec<_>>();
+        let code_description = self
+            .href
+            .and_then(|href| href.parse().ok())
+            .map(|href| CodeDescription { href });
+
         Diagnostic {
-            range: lsp_types::Range {
-                start: position_from_offset(text, self.range.start),
-                end: position_from_offset(text, self.range.end),
-            },
+            range: std_range_to_lsp_range(text, self.range, encoding),
             severity: Some(self.severity),
             message: self.message,
             code: Some(lsp_types::NumberOrString::String(self.code.to_string())),
+            code_description,
+            related_information: if related_information.is_empty() {
+                None
+            } else {
+                Some(related_information)
+            },
+            data: if self.data == DiagnosticData::default() {
+                None
+            } else {
+                Some(serde_json::to_value(self.data).expect("can serialize diagnostic data"))
+            },
             ..Default::default()
         }
     }
 }
 
+/// Points back at MSH-12, since that is what determined which version of the
+/// standard a message was validated against
+fn version_related_information(message: &Message) -> Option<(Range<usize>, String)> {
+    message.query("MSH.12").map(|version| {
+        (
+            version.range(),
+            format!(
+                "Message declares HL7 version `{version}`",
+                version = version.raw_value()
+            ),
+        )
+    })
+}
+
 #[instrument(level = "debug", skip(message, workspace_specs, opts))]
 pub fn validate_message(
     uri: &Uri,
@@ -64,6 +304,74 @@ pub fn validate_message(
     workspace_specs: &Option<&WorkspaceSpecs>,
     opts: &Opts,
 ) -> Vec<ValidationError> {
+    validate_message_unless_cancelled(uri, message, workspace_specs, opts, &|| false)
+        .expect("validation is never cancelled")
+}
+
+/// Validate a message, giving up and returning `None` if `is_cancelled`
+/// returns true, which is checked between each segment
+#[instrument(level = "debug", skip(message, workspace_specs, opts, is_cancelled))]
+pub fn validate_message_unless_cancelled(
+    uri: &Uri,
+    message: &Message,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    opts: &Opts,
+    is_cancelled: &dyn Fn() -> bool,
+) -> Option<Vec<ValidationError>> {
+    validate_message_incrementally(
+        uri,
+        message,
+        workspace_specs,
+        opts,
+        &mut ValidationCache::default(),
+        is_cancelled,
+    )
+}
+
+/// The results of validating each segment of a previous version of a
+/// document, so that only the segments that have changed since need to be
+/// validated again
+///
+/// Results depend on the message header, the version the message is validated
+/// against, and the workspace specs, so the cache must be cleared if the specs
+/// change; a change to the header or version invalidates it automatically.
+#[derive(Debug, Default)]
+pub struct ValidationCache {
+    /// The version and header the errors were found with
+    header: String,
+    /// The errors for each segment, by the segment's text, along with where
+    /// the segment started when they were found
+    segments: HashMap<String, (usize, Vec<ValidationError>)>,
+}
+
+impl ValidationCache {
+    pub fn clear(&mut self) {
+        self.header.clear();
+        self.segments.clear();
+    }
+}
+
+/// Validate a message, reusing the results for any segments that are
+/// unchanged since the cache was last updated and giving up (leaving the
+/// cache as it was) if `is_cancelled` returns true
+#[instrument(
+    level = "debug",
+    skip(message, workspace_specs, opts, cache, is_cancelled)
+)]
+pub fn validate_message_incrementally(
+    uri: &Uri,
+    message: &Message,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    opts: &Opts,
+    cache: &mut ValidationCache,
+    is_cancelled: &dyn Fn() -> bool,
+) -> Option<Vec<ValidationError>> {
+    // only read the clock when asked to, as it isn't available on every
+    // target (e.g. wasm32-unknown-unknown)
+    let start = opts.log_validation_stats.then(Instant::now);
+    let deadline = opts
+        .validation_budget
+        .map(|budget| (budget, Instant::now() + budget));
     let mut errors = Vec::new();
     if message.segments().count() < 2 {
         errors.push(ValidationError::new(
@@ -74,40 +382,336 @@ pub fn validate_message(
         ));
     }
 
-    let (version, msh_errors) = msh::validate_message(message);
-    let version = version.unwrap_or("2.7.1");
+    let pinned_version = opts.pinned_version(uri, *workspace_specs);
+    let (version, msh_errors) = msh::validate_message(
+        message,
+        opts.fallback_version.as_deref(),
+        pinned_version.as_deref(),
+    );
+    let version = version.version;
     errors.extend(msh_errors);
+    errors.extend(structure::validate_message(message, version));
+    errors.extend(set_ids::validate_message(message));
+    errors.extend(observations::validate_message(message));
+    errors.extend(plausibility::validate_message(message, plausibility::now()));
+    if let Some(specs) = workspace_specs {
+        for profile in specs.conformance_profiles(uri) {
+            if profile.applies_to(message) {
+                errors.extend(conformance::validate_message(&profile, message));
+            }
+        }
+        errors.extend(conditions::validate_message(uri, message, specs));
+    }
+    let charset = Charset::declared(message);
 
-    // TODO: these all iterate over the message multiple times; maybe it would
-    // be more performant to iterate once and check each rule at the same time?
-    errors.extend(optionality::validate_message(
-        message,
+    let header = format!(
+        "{version}\n{header}",
+        header = message
+            .segments()
+            .next()
+            .map(|segment| segment.raw_value())
+            .unwrap_or_default()
+    );
+    let reusable = if cache.header == header {
+        std::mem::take(&mut cache.segments)
+    } else {
+        HashMap::new()
+    };
+
+    let mut segments = HashMap::new();
+    let mut revalidated = 0;
+    let mut checked = 0;
+    let mut truncated = false;
+    let segment_count = message.segments().count();
+    for (si, segment) in message.segments().enumerate() {
+        if let Some((budget, _)) = deadline.filter(|(_, deadline)| Instant::now() >= *deadline) {
+            errors.push(ValidationError::new(
+                ValidationCode::ValidationBudget,
+                format!(
+                    "Validation took longer than {budget} ms, so this segment and the {rest} \
+                     after it weren't checked",
+                    budget = budget.as_millis(),
+                    rest = segment_count - si - 1
+                ),
+                segment.range.clone(),
+                DiagnosticSeverity::INFORMATION,
+            ));
+            truncated = true;
+            break;
+        }
+        if is_cancelled() {
+            tracing::debug!("validation cancelled");
+            if cache.header == header {
+                cache.segments = reusable;
+            }
+            return None;
+        }
+
+        let source = segment.raw_value();
+        let start = segment.range.start;
+        let segment_errors = match segments.get(source).or_else(|| reusable.get(source)) {
+            Some((previous_start, previous_errors)) => previous_errors
+                .iter()
+                .map(|error| error.moved(*previous_start..*previous_start + source.len(), start))
+                .collect(),
+            None => {
+                revalidated += 1;
+                validate_segment(
+                    uri,
+                    message,
+                    segment,
+                    version,
+                    &charset,
+                    workspace_specs,
+                    opts,
+                )
+            }
+        };
+        errors.extend(segment_errors.iter().cloned());
+        segments.insert(source.to_string(), (start, segment_errors));
+        checked += 1;
+    }
+    tracing::debug!(
+        revalidated,
+        reused = checked - revalidated,
+        truncated,
+        "validated segments"
+    );
+    // the segments that weren't reached are still valid for the next pass
+    if truncated {
+        for (source, results) in reusable {
+            segments.entry(source).or_insert(results);
+        }
+    }
+    cache.header = header;
+    cache.segments = segments;
+
+    if let Some(start) = start {
+        let profile = workspace_specs.and_then(|specs| specs.profile(uri));
+        log_validation_stats(message, version, profile.as_deref(), &errors, start);
+    }
+
+    // workspace specs take precedence over the client's settings
+    let mut overrides = opts.severity_overrides.clone();
+    if let Some(specs) = workspace_specs {
+        overrides.extend(specs.severity_overrides(uri));
+    }
+    Some(override_severities(errors, &overrides))
+}
+
+/// Report each error at the severity configured for its code, dropping those
+/// whose code is turned off
+fn override_severities(
+    errors: Vec<ValidationError>,
+    overrides: &HashMap<String, SeverityOverride>,
+) -> Vec<ValidationError> {
+    if overrides.is_empty() {
+        return errors;
+    }
+    errors
+        .into_iter()
+        .filter_map(|error| match overrides.get(error.code.key()) {
+            Some(severity) => severity.severity().map(|severity| ValidationError {
+                severity: severity.into(),
+                ..error
+            }),
+            None => Some(error),
+        })
+        .collect()
+}
+
+/// Check the rules that only depend on a single segment
+fn validate_segment(
+    uri: &Uri,
+    message: &Message,
+    segment: &Segment,
+    version: &str,
+    charset: &Charset,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    opts: &Opts,
+) -> Vec<ValidationError> {
+    let mut errors = optionality::validate_segment(uri, message, segment, version, workspace_specs);
+    let truncation = escapes::truncation_character(message)
+        .filter(|_| msh::allows_truncation_character(version));
+    errors.extend(length::validate_segment(
+        segment,
+        &message.separators,
         version,
-        workspace_specs,
+        truncation,
     ));
-    errors.extend(length::validate_message(message, version));
-    errors.extend(table_values::validate_message(
+    errors.extend(repeatability::validate_segment(segment, version));
+    errors.extend(table_values::validate_segment(
         uri,
         message,
+        segment,
         version,
         workspace_specs,
         opts,
     ));
-    errors.extend(datatypes::validate_message(message, version));
-    // TODO: message schema validation
-
+    errors.extend(datatypes::validate_segment(segment, version));
+    errors.extend(value_types::validate_segment(segment, version));
+    errors.extend(coded_elements::validate_segment(
+        uri,
+        segment,
+        version,
+        workspace_specs,
+        opts.check_coding_systems,
+    ));
+    errors.extend(patterns::validate_segment(uri, segment, workspace_specs));
+    errors.extend(escape_sequences::validate_segment(
+        segment,
+        &message.separators,
+        charset,
+        truncation,
+    ));
+    if opts.lint_style {
+        errors.extend(style::validate_segment(segment, &message.separators));
+    }
     errors
 }
 
+/// Emit a single structured record summarizing a validation pass, intended to
+/// be aggregated across many users to see which rules fire most often
+fn log_validation_stats(
+    message: &Message,
+    version: &str,
+    profile: Option<&str>,
+    errors: &[ValidationError],
+    start: Instant,
+) {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for error in errors {
+        *counts.entry(error.code.to_string()).or_default() += 1;
+    }
+    let counts = serde_json::to_string(&counts).unwrap_or_default();
+    let message_type = message
+        .query("MSH.9")
+        .map(|v| v.raw_value())
+        .unwrap_or_default();
+
+    tracing::info!(
+        message_type,
+        version,
+        profile,
+        total = errors.len(),
+        counts = %counts,
+        duration_us = start.elapsed().as_micros() as u64,
+        "validation pass complete"
+    );
+}
+
 impl fmt::Display for ValidationCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ValidationCode::MessageStructure => write!(f, "message structure"),
+            ValidationCode::MessageHeader => write!(f, "message header"),
+            ValidationCode::SegmentStructure => write!(f, "segment structure"),
             ValidationCode::InvalidTableValue => write!(f, "table value"),
             ValidationCode::InvalidTimestamp => write!(f, "timestamp"),
             ValidationCode::InvalidLength => write!(f, "length"),
             ValidationCode::InvalidOptionality => write!(f, "optionality"),
             ValidationCode::InvalidDataType(description) => write!(f, "data type ({description})"),
+            ValidationCode::InvalidEscapeSequence => write!(f, "escape sequence"),
+            ValidationCode::InvalidSetId => write!(f, "set ID"),
+            ValidationCode::ObservationSubId => write!(f, "observation sub-ID"),
+            ValidationCode::InvalidRepeatCount => write!(f, "repetition"),
+            ValidationCode::Conformance => write!(f, "conformance"),
+            ValidationCode::Pattern => write!(f, "pattern"),
+            ValidationCode::Condition => write!(f, "condition"),
+            ValidationCode::ValueType => write!(f, "value type"),
+            ValidationCode::CodedElement => write!(f, "coded element"),
+            ValidationCode::Plausibility => write!(f, "plausibility"),
+            ValidationCode::ControlId => write!(f, "control ID"),
+            ValidationCode::Style => write!(f, "style"),
+            ValidationCode::ValidationBudget => write!(f, "validation budget"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(text: &str, cache: &mut ValidationCache) -> Vec<(Range<usize>, String)> {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        validate_message_incrementally(&uri, &message, &None, &Opts::default(), cache, &|| false)
+            .unwrap()
+            .into_iter()
+            .map(|error| (error.range, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_segments_keep_their_errors_when_they_move() {
+        let before = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|1|P|2.5.1\rPID|x||||||notadate\rPV1|1";
+        let after = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|1|P|2.5.1\rEVN||20240102\rPID|x||||||notadate\rPV1|1";
+
+        let mut cache = ValidationCache::default();
+        let first = validate(before, &mut cache);
+        assert!(!first.is_empty());
+
+        let incremental = validate(after, &mut cache);
+        let fresh = validate(after, &mut ValidationCache::default());
+        assert_eq!(incremental, fresh);
+        let date = after.find("notadate").unwrap();
+        assert!(incremental
+            .iter()
+            .any(|(range, _)| range.start == date && range.end == date + "notadate".len()));
+    }
+
+    #[test]
+    fn validation_stops_when_it_runs_out_of_time() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let text = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|1|P|2.5.1\rPID|x\rPV1|1";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let opts = Opts {
+            validation_budget: Some(std::time::Duration::ZERO),
+            ..Default::default()
+        };
+
+        let errors = validate_message(&uri, &message, &None, &opts);
+        let truncated = errors
+            .iter()
+            .filter(|error| error.code.key() == "validation-budget")
+            .map(|error| (&text[error.range.clone()], error.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            truncated,
+            vec![(
+                &text[..text.find('\r').unwrap()],
+                "Validation took longer than 0 ms, so this segment and the 2 after it weren't \
+                 checked"
+            )]
+        );
+    }
+
+    #[test]
+    fn severities_can_be_overridden_by_code() {
+        let error =
+            |code| ValidationError::new(code, String::new(), 0..0, DiagnosticSeverity::WARNING);
+        let overrides = HashMap::from([
+            ("length".to_string(), SeverityOverride::Error),
+            ("data-type".to_string(), SeverityOverride::Off),
+        ]);
+
+        let errors = override_severities(
+            vec![
+                error(ValidationCode::InvalidLength),
+                error(ValidationCode::InvalidDataType("NM")),
+                error(ValidationCode::InvalidOptionality),
+            ],
+            &overrides,
+        )
+        .into_iter()
+        .map(|error| (error.code.key(), error.severity))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                ("length", DiagnosticSeverity::ERROR),
+                ("optionality", DiagnosticSeverity::WARNING),
+            ]
+        );
+    }
+}
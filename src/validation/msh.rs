@@ -1,27 +1,414 @@
 use crate::spec;
+use hl7_definitions::FieldOptionality;
 use hl7_parser::Message;
 use lsp_types::DiagnosticSeverity;
 use tracing::instrument;
 
-use super::{ValidationCode, ValidationError};
+use super::{optionality, ValidationCode, ValidationError};
 
+/// Check the message header, returning the version of the standard to
+/// validate the rest of the message against: the pinned version if there is
+/// one, or else the version the message declares
 #[instrument(level = "debug", skip(message))]
-pub fn validate_message<'m>(message: &'m Message) -> (Option<&'m str>, Vec<ValidationError>) {
-    let version_range = message.query("MSH.12").map(|v| (v.raw_value(), v.range()));
+pub fn validate_message<'m>(
+    message: &'m Message,
+    fallback_version: Option<&str>,
+    pinned_version: Option<&str>,
+) -> (spec::ResolvedVersion<'m>, Vec<ValidationError>) {
+    let version = spec::pinned_message_version(message, pinned_version, fallback_version);
+    let version_range = message.query("MSH.12").map(|v| v.range());
 
     let mut errors = Vec::new();
-    if let Some((version, range)) = version_range.as_ref() {
-        if !spec::is_valid_version(version) {
+    if let (Some(declared), Some(range)) = (version.declared, version_range) {
+        if pinned_version.is_some_and(|pinned| pinned == version.version) {
+            if declared != version.version {
+                errors.push(
+                    ValidationError::new(
+                        ValidationCode::MessageHeader,
+                        format!(
+                            "MSH.12 (Version ID) is `{declared}`, but the workspace expects HL7 v{pinned}",
+                            pinned = version.version
+                        ),
+                        range,
+                        DiagnosticSeverity::WARNING,
+                    )
+                    .with_fix(
+                        &format!("Change the version to {}", version.version),
+                        version.version.to_string(),
+                    ),
+                );
+            }
+        } else if !spec::is_valid_version(declared) {
+            // a newer (or older) version than the definitions cover is only
+            // worth noting, as the nearest version is a good stand-in for it
+            let (problem, severity) = if looks_like_version(declared) {
+                ("Unknown", DiagnosticSeverity::INFORMATION)
+            } else {
+                ("Malformed", DiagnosticSeverity::WARNING)
+            };
             errors.push(ValidationError::new(
-                ValidationCode::InvalidTableValue,
-                format!("Unknown HL7 version `{}`", version),
-                range.clone(),
-                DiagnosticSeverity::WARNING,
+                ValidationCode::MessageHeader,
+                format!(
+                    "{problem} HL7 version `{declared}`, validating against HL7 v{fallback} instead",
+                    fallback = version.version
+                ),
+                range,
+                severity,
             ));
         }
     }
 
-    // TODO: more MSH errors
+    errors.extend(validate_encoding_characters(message, version.version));
+    errors.extend(validate_routing(message, version.version));
+    errors.extend(validate_control_id(message, version.version));
 
-    (version_range.map(|v| v.0), errors)
+    (version, errors)
+}
+
+/// Check that MSH-1 and MSH-2 declare a usable set of separators: a single
+/// field separator followed by the component, repetition, escape, and
+/// subcomponent characters (and, from v2.7, the truncation character), none of
+/// which may be used twice
+fn validate_encoding_characters(message: &Message, version: &str) -> Vec<ValidationError> {
+    let Some(msh) = message.segment("MSH") else {
+        return Vec::new();
+    };
+    let source = msh.raw_value();
+    let Some(field_separator) = source.get(3..).and_then(|rest| rest.chars().next()) else {
+        return vec![ValidationError::new(
+            ValidationCode::MessageHeader,
+            "MSH.1 (Field Separator) is missing".to_string(),
+            msh.range.clone(),
+            DiagnosticSeverity::ERROR,
+        )
+        .with_href(Some(spec::field_url(version, "MSH", 1)))];
+    };
+
+    let start = 3 + field_separator.len_utf8();
+    let end = source[start..]
+        .find(field_separator)
+        .map(|i| start + i)
+        .unwrap_or(source.len());
+    let encoding_characters = &source[start..end];
+    let range = msh.range.start + start..msh.range.start + end;
+
+    let expected = if allows_truncation_character(version) {
+        "4 or 5"
+    } else {
+        "4"
+    };
+    let count = encoding_characters.chars().count();
+    let problem = if count != 4 && !(count == 5 && allows_truncation_character(version)) {
+        Some(format!(
+            "MSH.2 (Encoding Characters) must have {expected} characters, found {count}"
+        ))
+    } else if encoding_characters.contains(field_separator) {
+        Some(format!(
+            "MSH.2 (Encoding Characters) can't contain the field separator `{field_separator}`"
+        ))
+    } else {
+        encoding_characters
+            .char_indices()
+            .find(|&(i, c)| encoding_characters[..i].contains(c))
+            .map(|(_, c)| format!("MSH.2 (Encoding Characters) uses `{c}` more than once"))
+    };
+
+    problem
+        .map(|problem| {
+            ValidationError::new(
+                ValidationCode::MessageHeader,
+                problem,
+                range,
+                DiagnosticSeverity::ERROR,
+            )
+            .with_href(Some(spec::field_url(version, "MSH", 2)))
+        })
+        .into_iter()
+        .collect()
+}
+
+/// Whether MSH-2 may end with a truncation character, which was added in v2.7
+pub(super) fn allows_truncation_character(version: &str) -> bool {
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0)) >= (2, 7)
+}
+
+/// The MSH fields that interface engines rely on to route (and acknowledge) a
+/// message
+pub(super) const ROUTING_FIELDS: &[usize] = &[3, 4, 5, 6, 7, 9, 10, 11, 12];
+
+/// Processing IDs (table 0103)
+const PROCESSING_ID_TABLE: u16 = 103;
+
+/// Check that everything needed to route the message is in its header, as a
+/// message without e.g. a control ID will be rejected by every engine it is
+/// sent to
+fn validate_routing(message: &Message, version: &str) -> Vec<ValidationError> {
+    let Some(msh) = message.segment("MSH") else {
+        return Vec::new();
+    };
+    let msh_definition = hl7_definitions::get_segment(version, "MSH");
+
+    let mut errors = Vec::new();
+    for &fi in ROUTING_FIELDS {
+        let field = msh.field(fi);
+        if field.is_some_and(|field| !field.is_empty()) {
+            continue;
+        }
+        // empty required fields are already reported by the optionality rules,
+        // but fields missing from the end of the segment aren't
+        let is_required = msh_definition
+            .and_then(|msh| msh.fields.get(fi - 1))
+            .is_some_and(|field| field.optionality == FieldOptionality::Required);
+        if field.is_some() && is_required {
+            continue;
+        }
+
+        let error = ValidationError::new(
+            ValidationCode::MessageHeader,
+            format!(
+                "MSH.{fi} ({description}) is needed to route the message",
+                description = spec::describe_field(version, "MSH", fi)
+            ),
+            field
+                .map(|field| field.range.clone())
+                .unwrap_or(msh.range.end..msh.range.end),
+            DiagnosticSeverity::WARNING,
+        )
+        .with_href(Some(spec::field_url(version, "MSH", fi)));
+        errors.push(match field {
+            Some(field) if !field.is_empty() => error,
+            _ => optionality::with_insertion(error, msh, fi, version),
+        });
+    }
+
+    let first_component = |fi: usize| {
+        msh.field(fi)
+            .and_then(|field| field.repeat(1))
+            .and_then(|repeat| repeat.component(1))
+            .filter(|component| !component.is_empty())
+    };
+
+    if let Some(message_type) = msh.field(9).and_then(|field| field.repeat(1)) {
+        let is_set = |ci: usize| message_type.component(ci).is_some_and(|c| !c.is_empty());
+        let problem = if message_type.is_empty() {
+            None
+        } else if !is_set(1) {
+            Some("is missing its message code")
+        } else if !is_set(2) && first_component(9).map(|c| c.raw_value()) != Some("ACK") {
+            Some("is missing its trigger event")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            errors.push(
+                ValidationError::new(
+                    ValidationCode::MessageHeader,
+                    format!("MSH.9 (Message Type) {problem}"),
+                    message_type.range.clone(),
+                    DiagnosticSeverity::WARNING,
+                )
+                .with_href(Some(spec::field_url(version, "MSH", 9))),
+            );
+        }
+    }
+
+    if let (Some(processing_id), Some(table_values)) = (
+        first_component(11),
+        hl7_definitions::table_values(PROCESSING_ID_TABLE),
+    ) {
+        if table_values
+            .iter()
+            .all(|(value, _)| *value != processing_id.raw_value())
+        {
+            errors.push(
+                ValidationError::new(
+                    ValidationCode::MessageHeader,
+                    format!(
+                        "Unknown processing ID `{value}`, expected one of: {expected}",
+                        value = processing_id.raw_value(),
+                        expected = table_values
+                            .iter()
+                            .map(|(value, description)| format!("`{value}` ({description})"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                    processing_id.range.clone(),
+                    DiagnosticSeverity::WARNING,
+                )
+                .with_href(Some(spec::table_url(version, PROCESSING_ID_TABLE))),
+            );
+        }
+    }
+
+    errors
+}
+
+/// The longest control ID that every interface engine can be relied on to
+/// store, as MSH-10 was limited to 20 characters before v2.7
+const CONTROL_ID_MAX_LENGTH: usize = 20;
+
+/// Check that the control ID (MSH-10) fits in 20 characters, which engines
+/// built against earlier versions of the standard may truncate it to,
+/// mismatching acknowledgements with the messages they acknowledge
+///
+/// Versions that already limit MSH-10 to 20 characters report it with the
+/// other length rules.
+fn validate_control_id(message: &Message, version: &str) -> Vec<ValidationError> {
+    let Some(control_id) = message.query("MSH.10") else {
+        return Vec::new();
+    };
+    let length = control_id.raw_value().chars().count();
+    let max_length = hl7_definitions::get_segment(version, "MSH")
+        .and_then(|msh| msh.fields.get(9))
+        .and_then(|field| field.max_length);
+    if length <= CONTROL_ID_MAX_LENGTH || max_length.is_some_and(|max| max <= CONTROL_ID_MAX_LENGTH)
+    {
+        return Vec::new();
+    }
+    vec![ValidationError::new(
+        ValidationCode::ControlId,
+        format!(
+            "MSH.10 (Message Control ID) is {length} characters long, which some interface engines truncate to {CONTROL_ID_MAX_LENGTH}"
+        ),
+        control_id.range(),
+        DiagnosticSeverity::WARNING,
+    )
+    .with_href(Some(spec::field_url(version, "MSH", 10)))]
+}
+
+/// Whether a version is shaped like an HL7 version (e.g. `2.5.1`), whether or
+/// not it is one we know about
+fn looks_like_version(version: &str) -> bool {
+    let mut parts = version.split('.');
+    parts.clone().count() >= 2
+        && parts.all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing_errors(message: &str) -> Vec<String> {
+        let message = hl7_parser::parse_message_with_lenient_newlines(message).unwrap();
+        validate_routing(&message, "2.5.1")
+            .into_iter()
+            .map(|error| error.message)
+            .collect()
+    }
+
+    #[test]
+    fn routing_fields_must_be_present() {
+        assert!(routing_errors(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|123|P|2.5.1\rPID|1"
+        )
+        .is_empty());
+
+        let errors = routing_errors("MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT\rPID|1");
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].starts_with("MSH.10 "));
+        assert!(errors[1].starts_with("MSH.11 "));
+        assert!(errors[2].starts_with("MSH.12 "));
+        assert_eq!(
+            errors[3],
+            "MSH.9 (Message Type) is missing its trigger event"
+        );
+    }
+
+    #[test]
+    fn processing_ids_must_be_known() {
+        let errors = routing_errors(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ACK|123|X|2.5.1\rMSA|AA|123",
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Unknown processing ID `X`"));
+    }
+
+    #[test]
+    fn encoding_characters_must_be_distinct() {
+        let errors = |message: &str, version: &str| {
+            let message = hl7_parser::parse_message_with_lenient_newlines(message).unwrap();
+            validate_encoding_characters(&message, version)
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(errors("MSH|^~\\&|App\rPID|1", "2.5.1").is_empty());
+        assert!(errors("MSH|^~\\&#|App\rPID|1", "2.7.1").is_empty());
+        assert_eq!(
+            errors("MSH|^~\\&#|App\rPID|1", "2.5.1"),
+            vec!["MSH.2 (Encoding Characters) must have 4 characters, found 5"]
+        );
+        assert_eq!(
+            errors("MSH|^~\\&#!|App\rPID|1", "2.7.1"),
+            vec!["MSH.2 (Encoding Characters) must have 4 or 5 characters, found 6"]
+        );
+        assert_eq!(
+            errors("MSH|^~\\^|App\rPID|1", "2.5.1"),
+            vec!["MSH.2 (Encoding Characters) uses `^` more than once"]
+        );
+    }
+
+    #[test]
+    fn messages_must_match_the_pinned_version() {
+        let message = hl7_parser::parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|123|P|2.3\rPID|1",
+        )
+        .unwrap();
+
+        let (version, errors) = validate_message(&message, None, Some("2.5.1"));
+        assert_eq!(version.version, "2.5.1");
+        assert_eq!(version.declared, Some("2.3"));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "MSH.12 (Version ID) is `2.3`, but the workspace expects HL7 v2.5.1"
+        );
+        assert_eq!(
+            errors[0].data.fix.as_deref(),
+            Some("Change the version to 2.5.1")
+        );
+
+        let (version, errors) = validate_message(&message, None, None);
+        assert_eq!(version.version, "2.3");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn unknown_versions_are_noted() {
+        let errors = |version: &str| {
+            let text = format!(
+                "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A01|123|P|{version}\rPID|1"
+            );
+            let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+            validate_message(&message, None, None)
+                .1
+                .into_iter()
+                .map(|error| (error.code, error.severity, error.message))
+                .collect::<Vec<_>>()
+        };
+
+        let unknown = errors("2.9.9");
+        assert_eq!(unknown.len(), 1);
+        assert!(matches!(unknown[0].0, ValidationCode::MessageHeader));
+        assert_eq!(unknown[0].1, DiagnosticSeverity::INFORMATION);
+        assert!(unknown[0].2.starts_with("Unknown HL7 version `2.9.9`"));
+
+        let malformed = errors("v2");
+        assert_eq!(malformed.len(), 1);
+        assert_eq!(malformed[0].1, DiagnosticSeverity::WARNING);
+        assert!(malformed[0].2.starts_with("Malformed HL7 version `v2`"));
+    }
+
+    #[test]
+    fn versions_must_look_like_versions() {
+        assert!(looks_like_version("2.5.1"));
+        assert!(looks_like_version("2.9"));
+        assert!(!looks_like_version("2"));
+        assert!(!looks_like_version("2.5."));
+        assert!(!looks_like_version("v2.5"));
+    }
 }
@@ -0,0 +1,203 @@
+use hl7_parser::{message::Segment, Message};
+use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
+use tracing::instrument;
+
+use super::{ValidationCode, ValidationError};
+
+/// Segments that start a new order, within which OBX segments are grouped
+const ORDER_SEGMENTS: &[&str] = &["PID", "ORC", "OBR", "SPM"];
+
+/// An observation reported across several OBX segments of the same order that
+/// share an observation identifier (OBX-3), each carrying part of its value
+/// and told apart by their observation sub-ID (OBX-4), as e.g. text reports
+/// split into one OBX per line are
+#[derive(Debug, Clone)]
+pub struct Observation<'s, 'm> {
+    /// The observation identifier (the first component of OBX-3)
+    pub identifier: &'m str,
+    /// The text of the observation identifier (the second component of
+    /// OBX-3), if any
+    pub text: Option<&'m str>,
+    /// The OBX segments of the observation, in the order they appear in the
+    /// message
+    pub parts: Vec<ObservationPart<'s, 'm>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObservationPart<'s, 'm> {
+    pub segment: &'s Segment<'m>,
+    /// The observation sub-ID (OBX-4), if it's set
+    pub sub_id: Option<&'m str>,
+}
+
+impl Observation<'_, '_> {
+    /// The range of the message from the start of the first part to the end of
+    /// the last
+    pub fn range(&self) -> Range<usize> {
+        let start = self.parts.first().map(|p| p.segment.range.start);
+        let end = self.parts.last().map(|p| p.segment.range.end);
+        start.unwrap_or_default()..end.unwrap_or_default()
+    }
+
+    /// The index of the part that is the given segment, if it's one of them
+    pub fn part_of(&self, segment: &Segment) -> Option<usize> {
+        self.parts
+            .iter()
+            .position(|part| part.segment.range == segment.range)
+    }
+}
+
+/// The observations in a message that are split across more than one OBX
+/// segment
+pub fn observations<'s, 'm>(message: &'s Message<'m>) -> Vec<Observation<'s, 'm>> {
+    let mut observations = Vec::new();
+    let mut order: Vec<Observation> = Vec::new();
+    for segment in message.segments() {
+        if ORDER_SEGMENTS.contains(&segment.name) {
+            observations.extend(order.drain(..).filter(|o| o.parts.len() > 1));
+            continue;
+        }
+        if segment.name != "OBX" {
+            continue;
+        }
+
+        let identifier = segment.field(3).and_then(|field| field.repeat(1));
+        let component = |ci: usize| {
+            identifier
+                .and_then(|identifier| identifier.component(ci))
+                .map(|component| component.raw_value())
+                .filter(|value| !value.is_empty())
+        };
+        let Some(id) = component(1) else {
+            continue;
+        };
+        let part = ObservationPart {
+            segment,
+            sub_id: segment
+                .field(4)
+                .map(|field| field.raw_value())
+                .filter(|value| !value.is_empty()),
+        };
+        match order.iter_mut().find(|o| o.identifier == id) {
+            Some(observation) => observation.parts.push(part),
+            None => order.push(Observation {
+                identifier: id,
+                text: component(2),
+                parts: vec![part],
+            }),
+        }
+    }
+    observations.extend(order.into_iter().filter(|o| o.parts.len() > 1));
+    observations.sort_by_key(|o| o.range().start);
+    observations
+}
+
+/// Check that the parts of each observation split across OBX segments can be
+/// told apart by their observation sub-IDs (OBX-4)
+#[instrument(level = "debug", skip(message))]
+pub fn validate_message(message: &Message) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    for observation in observations(message) {
+        let id = observation.identifier;
+        let uses_sub_ids = observation.parts.iter().any(|p| p.sub_id.is_some());
+        let first = &observation.parts[0];
+        for (i, part) in observation.parts.iter().enumerate() {
+            let sub_id_range = part
+                .segment
+                .field(4)
+                .map(|field| field.range.clone())
+                .unwrap_or(part.segment.range.end..part.segment.range.end);
+
+            match part.sub_id {
+                None if uses_sub_ids => errors.push(ValidationError::new(
+                    ValidationCode::ObservationSubId,
+                    format!(
+                        "OBX.4 (Observation Sub-ID) is missing, but other parts of observation `{id}` have one"
+                    ),
+                    sub_id_range,
+                    DiagnosticSeverity::WARNING,
+                )),
+                None if i > 0 => errors.push(
+                    ValidationError::new(
+                        ValidationCode::ObservationSubId,
+                        format!(
+                            "Observation `{id}` is repeated without an OBX.4 (Observation Sub-ID) to tell its parts apart"
+                        ),
+                        sub_id_range,
+                        DiagnosticSeverity::INFORMATION,
+                    )
+                    .with_related_information(Some((
+                        first.segment.range.clone(),
+                        "The observation's first part".to_string(),
+                    ))),
+                ),
+                None => {}
+                Some(sub_id) => {
+                    let earlier = observation.parts[..i]
+                        .iter()
+                        .find(|earlier| earlier.sub_id == Some(sub_id));
+                    if let Some(earlier) = earlier {
+                        errors.push(
+                            ValidationError::new(
+                                ValidationCode::ObservationSubId,
+                                format!(
+                                    "Observation sub-ID `{sub_id}` is already used by another part of observation `{id}`"
+                                ),
+                                sub_id_range,
+                                DiagnosticSeverity::WARNING,
+                            )
+                            .with_related_information(Some((
+                                earlier.segment.range.clone(),
+                                format!("Sub-ID `{sub_id}` is first used here"),
+                            ))),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_of_observations_are_grouped_and_checked() {
+        let text = "MSH|^~\\&|App\r\
+            OBR|1\r\
+            OBX|1|TX|RPT^Report||Line one\r\
+            OBX|2|NM|HR^Heart rate||60\r\
+            OBX|3|TX|RPT^Report||Line two\r\
+            OBR|2\r\
+            OBX|1|TX|RPT^Report|1|Line one\r\
+            OBX|2|TX|RPT^Report||Line two\r\
+            OBX|3|TX|RPT^Report|1|Line three\r\
+            OBX|4|TX|HR^Heart rate|1|60";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+
+        let observations = observations(&message);
+        assert_eq!(
+            observations
+                .iter()
+                .map(|o| (o.identifier, o.text, o.parts.len()))
+                .collect::<Vec<_>>(),
+            vec![("RPT", Some("Report"), 2), ("RPT", Some("Report"), 3)]
+        );
+
+        let errors = validate_message(&message)
+            .into_iter()
+            .map(|error| error.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Observation `RPT` is repeated without an OBX.4 (Observation Sub-ID) to tell its parts apart",
+                "OBX.4 (Observation Sub-ID) is missing, but other parts of observation `RPT` have one",
+                "Observation sub-ID `1` is already used by another part of observation `RPT`",
+            ]
+        );
+    }
+}
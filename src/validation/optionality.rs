@@ -1,54 +1,370 @@
-use crate::workspace::specs::WorkspaceSpecs;
+use crate::{spec, workspace::specs::WorkspaceSpecs};
 
-use super::ValidationError;
+use super::{msh, version_related_information, ValidationError};
 use hl7_definitions::FieldOptionality;
-use hl7_parser::Message;
-use lsp_types::DiagnosticSeverity;
+use hl7_parser::{
+    message::{Repeat, Segment},
+    Message,
+};
+use lsp_types::{DiagnosticSeverity, Uri};
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(message))]
-pub fn validate_message(
+/// What fields that are filled in by a quick fix are set to, when there's no
+/// sensible default
+const PLACEHOLDER: &str = "?";
+
+#[instrument(level = "trace", skip(message, segment), fields(segment = segment.name))]
+pub fn validate_segment(
+    uri: &Uri,
     message: &Message,
+    segment: &Segment,
     version: &str,
     workspace_specs: &Option<&WorkspaceSpecs>,
 ) -> Vec<ValidationError> {
     let mut errors = Vec::new();
 
-    for segment in message.segments() {
-        if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
-            for (fi, field) in segment.fields().enumerate() {
-                for repeat in field.repeats() {
-                    // workspace fields
-                    if let Some(workspace_specs) = *workspace_specs {
-                        if repeat.is_empty()
-                            && workspace_specs.is_field_required(segment.name, fi + 1)
-                        {
-                            errors.push(ValidationError::new(
-                                super::ValidationCode::InvalidOptionality,
-                                "Field is required".to_string(),
-                                field.range.clone(),
-                                DiagnosticSeverity::WARNING,
-                            ));
-                        }
+    if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
+        for (fi, field) in segment.fields().enumerate() {
+            for repeat in field.repeats() {
+                // workspace fields
+                if let Some(workspace_specs) = *workspace_specs {
+                    if repeat.is_empty()
+                        && workspace_specs.is_field_required(uri, segment.name, fi + 1)
+                    {
+                        let error = ValidationError::new(
+                            super::ValidationCode::InvalidOptionality,
+                            "Field is required by the workspace spec".to_string(),
+                            field.range.clone(),
+                            DiagnosticSeverity::WARNING,
+                        );
+                        errors.push(match field.is_empty() {
+                            true => with_insertion(error, segment, fi + 1, version),
+                            false => error,
+                        });
                     }
+                }
 
-                    // standard fields
-                    if let Some(field_definition) = segment_definition.fields.get(fi) {
-                        if field_definition.optionality == FieldOptionality::Required
-                            && repeat.is_empty()
-                        {
-                            errors.push(ValidationError::new(
+                // standard fields
+                if let Some(field_definition) = segment_definition.fields.get(fi) {
+                    if field_definition.optionality == FieldOptionality::Required
+                        && repeat.is_empty()
+                    {
+                        let error =
+                            ValidationError::new(
                                 super::ValidationCode::InvalidOptionality,
-                                "Field is required".to_string(),
+                                format!(
+                                    "Field is required ({description})",
+                                    description = field_definition.description
+                                ),
                                 field.range.clone(),
                                 DiagnosticSeverity::WARNING,
-                            ));
-                        }
+                            )
+                            .with_related_information(version_related_information(message))
+                            .with_href(Some(spec::field_url(version, segment.name, fi + 1)));
+                        errors.push(match field.is_empty() {
+                            true => with_insertion(error, segment, fi + 1, version),
+                            false => error,
+                        });
+                    }
+
+                    // MSH.9 is checked by the message header rules, which allow
+                    // e.g. ACKs without a trigger event
+                    let is_message_type = segment.name == "MSH" && fi + 1 == 9;
+                    if !repeat.is_empty() && !is_message_type {
+                        errors.extend(validate_components(
+                            message,
+                            repeat,
+                            version,
+                            (segment.name, fi + 1),
+                            field_definition.datatype,
+                        ));
                     }
                 }
             }
         }
+
+        // fields past the end of the segment, e.g. after changing the message
+        // to an earlier version
+        for (fi, field) in segment
+            .fields()
+            .enumerate()
+            .skip(segment_definition.fields.len())
+        {
+            if field.is_empty() {
+                continue;
+            }
+            errors.push(
+                ValidationError::new(
+                    super::ValidationCode::SegmentStructure,
+                    format!(
+                        "{segment}.{field} isn't defined in HL7 {version}",
+                        segment = segment.name,
+                        field = fi + 1,
+                    ),
+                    field.range.clone(),
+                    DiagnosticSeverity::WARNING,
+                )
+                .with_related_information(version_related_information(message)),
+            );
+        }
+
+        // fields missing from the end of the segment; those needed to route
+        // the message are reported by the message header rules
+        let present = segment.fields().count();
+        for (fi, field_definition) in segment_definition.fields.iter().enumerate().skip(present) {
+            let is_routing = segment.name == "MSH" && msh::ROUTING_FIELDS.contains(&(fi + 1));
+            if field_definition.optionality != FieldOptionality::Required || is_routing {
+                continue;
+            }
+            let error = ValidationError::new(
+                super::ValidationCode::InvalidOptionality,
+                format!(
+                    "{segment}.{field} is required ({description})",
+                    segment = segment.name,
+                    field = fi + 1,
+                    description = field_definition.description
+                ),
+                segment.range.end..segment.range.end,
+                DiagnosticSeverity::WARNING,
+            )
+            .with_related_information(version_related_information(message))
+            .with_href(Some(spec::field_url(version, segment.name, fi + 1)));
+            errors.push(with_insertion(error, segment, fi + 1, version));
+        }
+    }
+
+    errors
+}
+
+/// Offer to fill in an empty or missing field of a segment, adding the
+/// separators needed to reach it if it's missing from the end of the segment
+///
+/// The field is filled in with a sensible default where there is one (the
+/// current time for timestamps, the version for MSH-12), or else a
+/// placeholder.
+pub(super) fn with_insertion(
+    error: ValidationError,
+    segment: &Segment,
+    field: usize,
+    version: &str,
+) -> ValidationError {
+    let missing = field.saturating_sub(segment.fields().count());
+    let separators = segment
+        .raw_value()
+        .chars()
+        .nth(3)
+        .map(|separator| separator.to_string().repeat(missing))
+        .unwrap_or_default();
+    let definition = hl7_definitions::get_segment(version, segment.name)
+        .and_then(|definition| definition.fields.get(field - 1));
+    let is_timestamp = spec::is_field_a_timestamp(version, segment.name, field);
+    let value = match is_timestamp {
+        true => "",
+        false => placeholder(segment.name, field, version),
+    };
+    let title = match definition {
+        Some(definition) => format!(
+            "Fill in {segment}.{field} ({description})",
+            segment = segment.name,
+            description = definition.description
+        ),
+        None => format!("Fill in {segment}.{field}", segment = segment.name),
+    };
+    let mut error = error.with_fix(&title, format!("{separators}{value}"));
+    error.data.fix_inserts_now = is_timestamp;
+    error
+}
+
+/// What to fill in a required field that isn't a timestamp with: the default
+/// for the fields that have one, or else a placeholder
+fn placeholder<'v>(segment: &str, field: usize, version: &'v str) -> &'v str {
+    let definition = hl7_definitions::get_segment(version, segment)
+        .and_then(|definition| definition.fields.get(field - 1));
+    match (segment, field) {
+        ("MSH", 11) => "P",
+        ("MSH", 12) => version,
+        _ if definition.is_some_and(|definition| definition.datatype == "SI") => "1",
+        _ => PLACEHOLDER,
+    }
+}
+
+/// A skeleton of a segment to insert into a message, with the separators to
+/// reach its last required field (by the standard or the workspace spec) and
+/// its required fields filled in as a quick fix for a missing field would,
+/// with timestamps set to `now`
+///
+/// There is no skeleton for MSH, which defines the separators, or for
+/// segments that aren't in the standard.
+pub fn segment_template(
+    uri: &Uri,
+    segment: &str,
+    version: &str,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+    field_separator: char,
+    now: &str,
+) -> Option<String> {
+    if segment == "MSH" {
+        return None;
+    }
+    let definition = hl7_definitions::get_segment(version, segment)?;
+    let is_required = |field: usize| {
+        definition.fields[field - 1].optionality == FieldOptionality::Required
+            || workspace_specs.is_some_and(|specs| specs.is_field_required(uri, segment, field))
+    };
+    let last_required = (1..=definition.fields.len())
+        .rev()
+        .find(|&field| is_required(field))
+        .unwrap_or_default();
+
+    let mut template = segment.to_string();
+    for field in 1..=last_required {
+        template.push(field_separator);
+        if !is_required(field) {
+            continue;
+        }
+        match spec::is_field_a_timestamp(version, segment, field) {
+            true => template.push_str(now),
+            false => template.push_str(placeholder(segment, field, version)),
+        }
     }
+    Some(template)
+}
 
+/// Check that the required components of a populated field (and the required
+/// sub-components of its populated components) are present
+fn validate_components(
+    message: &Message,
+    repeat: &Repeat,
+    version: &str,
+    (segment, field): (&str, usize),
+    datatype: &str,
+) -> Vec<ValidationError> {
+    let Some(datatype) = hl7_definitions::get_field(version, datatype) else {
+        return Vec::new();
+    };
+
+    let mut errors = Vec::new();
+    for (ci, component_definition) in datatype.subfields.iter().enumerate() {
+        let component = repeat.component(ci + 1);
+        let href = spec::component_url(version, segment, field, ci + 1);
+        match component.filter(|component| !component.is_empty()) {
+            None if component_definition.optionality == FieldOptionality::Required => {
+                errors.push(
+                    ValidationError::new(
+                        super::ValidationCode::InvalidOptionality,
+                        format!(
+                            "Component {segment}.{field}.{component} is required ({description})",
+                            component = ci + 1,
+                            description = component_definition.description
+                        ),
+                        component
+                            .map(|component| component.range.clone())
+                            .unwrap_or(repeat.range.clone()),
+                        DiagnosticSeverity::WARNING,
+                    )
+                    .with_related_information(version_related_information(message))
+                    .with_href(Some(href)),
+                );
+            }
+            None => {}
+            Some(component) => {
+                let Some(component_datatype) =
+                    hl7_definitions::get_field(version, component_definition.datatype)
+                else {
+                    continue;
+                };
+                for (si, sub_component_definition) in
+                    component_datatype.subfields.iter().enumerate()
+                {
+                    if sub_component_definition.optionality != FieldOptionality::Required {
+                        continue;
+                    }
+                    let sub_component = component.subcomponent(si + 1);
+                    if sub_component.is_some_and(|sub_component| !sub_component.is_empty()) {
+                        continue;
+                    }
+                    errors.push(
+                        ValidationError::new(
+                            super::ValidationCode::InvalidOptionality,
+                            format!(
+                                "Sub-component {segment}.{field}.{component}.{sub_component} is required ({description})",
+                                component = ci + 1,
+                                sub_component = si + 1,
+                                description = sub_component_definition.description
+                            ),
+                            sub_component
+                                .map(|sub_component| sub_component.range.clone())
+                                .unwrap_or(component.range.clone()),
+                            DiagnosticSeverity::WARNING,
+                        )
+                        .with_related_information(version_related_information(message))
+                        .with_href(Some(href.clone())),
+                    );
+                }
+            }
+        }
+    }
     errors
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_components_of_populated_fields_are_checked() {
+        let text = "MSH|^~\\&|App\rPID|1||^^^Hosp^MR~123^^^Hosp||Doe";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+        let uri = "file:///message.hl7".parse().unwrap();
+
+        let errors = validate_segment(&uri, &message, pid, "2.5.1", &None)
+            .into_iter()
+            .map(|error| (&text[error.range], error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                ("", "Component PID.3.1 is required (ID Number)".to_string()),
+                (
+                    "123^^^Hosp",
+                    "Component PID.3.5 is required (Identifier Type Code)".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_required_fields_can_be_filled_in() {
+        let text = "MSH|^~\\&|App\rEVN|A01\rPID|1";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let uri = "file:///message.hl7".parse().unwrap();
+
+        let errors = message
+            .segments()
+            .skip(1)
+            .flat_map(|segment| validate_segment(&uri, &message, segment, "2.5.1", &None))
+            .map(|error| {
+                (
+                    error.range.start,
+                    error.data.suggestions[0].clone(),
+                    error.data.fix_inserts_now,
+                )
+            })
+            .collect::<Vec<_>>();
+        let end = |segment: &str| text.find(segment).unwrap() + segment.len();
+        assert_eq!(
+            errors,
+            vec![
+                (end("EVN|A01"), "|".to_string(), true),
+                (end("PID|1"), "||?".to_string(), false),
+                (end("PID|1"), "||||?".to_string(), false),
+            ]
+        );
+
+        let template = |segment| segment_template(&uri, segment, "2.5.1", &None, '|', "20240102");
+        assert_eq!(template("EVN").as_deref(), Some("EVN||20240102"));
+        assert_eq!(template("PID").as_deref(), Some("PID|||?||?"));
+        assert_eq!(template("MSH"), None);
+    }
+}
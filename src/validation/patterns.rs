@@ -0,0 +1,104 @@
+use super::{ValidationCode, ValidationError};
+use crate::workspace::specs::WorkspaceSpecs;
+use hl7_parser::message::Segment;
+use lsp_types::{DiagnosticSeverity, Uri};
+use tracing::instrument;
+
+/// Check each repeat of the fields that workspace specs give a pattern to
+/// against it, e.g. for site-specific MRN or account number formats
+///
+/// Segments don't need to be defined by the standard, so custom (`Z`)
+/// segments can be checked too.
+#[instrument(level = "trace", skip(uri, segment, workspace_specs), fields(segment = segment.name))]
+pub fn validate_segment(
+    uri: &Uri,
+    segment: &Segment,
+    workspace_specs: &Option<&WorkspaceSpecs>,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let Some(workspace_specs) = workspace_specs else {
+        return errors;
+    };
+
+    for (fi, field) in segment.fields().enumerate() {
+        if field.is_empty() {
+            continue;
+        }
+        for (spec_name, field_spec) in workspace_specs.field_specs(uri, segment.name, fi + 1) {
+            // invalid patterns stop the spec from loading
+            let Some(Ok(pattern)) = field_spec.pattern() else {
+                continue;
+            };
+            let expected = match (&field_spec.pattern_description, &field_spec.pattern) {
+                (Some(description), _) => description.clone(),
+                (None, Some(pattern)) => format!("`{pattern}`"),
+                (None, None) => continue,
+            };
+            for repeat in field.repeats().filter(|repeat| !repeat.is_empty()) {
+                if !pattern.is_match(repeat.raw_value()) {
+                    errors.push(ValidationError::new(
+                        ValidationCode::Pattern,
+                        format!(
+                            "Value doesn't match {expected}, as required by the {spec_name} spec"
+                        ),
+                        repeat.range.clone(),
+                        DiagnosticSeverity::WARNING,
+                    ));
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::specs::{FieldSpec, SegmentSpec, WorkspaceSpec};
+    use std::path::PathBuf;
+
+    #[test]
+    fn values_must_match_the_whole_pattern() {
+        let text = "MSH|^~\\&|App\rPID|1||12345678~1234567890||Doe^John";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+        let uri = "file:///tmp/message.hl7".parse().unwrap();
+
+        let specs = WorkspaceSpecs::new(std::iter::empty::<PathBuf>()).unwrap();
+        specs.specs.insert(
+            PathBuf::from("/missing/site.hl7v.toml"),
+            WorkspaceSpec {
+                name: "Site".to_string(),
+                segments: vec![SegmentSpec {
+                    name: "PID".to_string(),
+                    description: None,
+                    fields: [(
+                        3,
+                        FieldSpec {
+                            pattern: Some("[0-9]{8}".to_string()),
+                            pattern_description: Some("an 8 digit MRN".to_string()),
+                            ..Default::default()
+                        },
+                    )]
+                    .into_iter()
+                    .collect(),
+                }],
+                ..Default::default()
+            },
+        );
+        specs.assign_profile(&uri, Some("Site".to_string()));
+
+        let errors = validate_segment(&uri, pid, &Some(&specs))
+            .into_iter()
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![(
+                "1234567890".to_string(),
+                "Value doesn't match an 8 digit MRN, as required by the Site spec".to_string()
+            )]
+        );
+    }
+}
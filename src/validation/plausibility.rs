@@ -0,0 +1,251 @@
+use super::{ValidationCode, ValidationError};
+use hl7_parser::{datetime::parse_timestamp, message::Segment, Message};
+use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
+use tracing::instrument;
+
+const DAY: i64 = 24 * 60 * 60;
+/// How far in the future a timestamp can be before it's reported, to allow
+/// for clocks that are a little out and timestamps without an offset
+const FUTURE_TOLERANCE: i64 = DAY;
+/// How old a message header's timestamp can be before it's reported
+const MESSAGE_AGE_LIMIT: i64 = 10 * 365 * DAY;
+
+/// The earliest and latest instants (in seconds since the Unix epoch) that a
+/// timestamp could mean, as e.g. `1980` could be any time that year
+///
+/// Timestamps without an offset are taken to be in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bounds {
+    earliest: i64,
+    latest: i64,
+}
+
+/// The bounds of a timestamp in a value, if it's populated and valid (invalid
+/// timestamps are reported by the datatype checks)
+fn bounds(value: &str) -> Option<Bounds> {
+    if value.is_empty() {
+        return None;
+    }
+    let ts = parse_timestamp(value, false).ok()?;
+    let year = ts.year as i64;
+    let offset = ts
+        .offset
+        .map(|offset| {
+            let minutes =
+                offset.hours as i64 * 60 + offset.minutes as i64 * offset.hours.signum() as i64;
+            minutes * 60
+        })
+        .unwrap_or(0);
+    let instant = |month: u8, day: u8, hour: u8, minute: u8, second: u8| {
+        days_from_civil(year, month as i64, day as i64) * DAY
+            + hour as i64 * 3600
+            + minute as i64 * 60
+            + second as i64
+            - offset
+    };
+    let last_month = ts.month.unwrap_or(12);
+    let earliest = instant(
+        ts.month.unwrap_or(1),
+        ts.day.unwrap_or(1),
+        ts.hour.unwrap_or(0),
+        ts.minute.unwrap_or(0),
+        ts.second.unwrap_or(0),
+    );
+    let latest = instant(
+        last_month,
+        ts.day.unwrap_or_else(|| days_in_month(year, last_month)),
+        ts.hour.unwrap_or(23),
+        ts.minute.unwrap_or(59),
+        ts.second.unwrap_or(59),
+    );
+    Some(Bounds { earliest, latest })
+}
+
+/// The number of days from the Unix epoch to a date in the proleptic
+/// Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The current time in seconds since the Unix epoch, if there's a clock to
+/// read (there isn't on wasm32-unknown-unknown)
+pub(super) fn now() -> Option<i64> {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs() as i64)
+    }
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    {
+        None
+    }
+}
+
+/// A field of a segment, with its timestamp's bounds
+fn timestamp_field(segment: &Segment, field: usize) -> Option<(Bounds, Range<usize>)> {
+    let field = segment.field(field)?;
+    Some((bounds(field.raw_value())?, field.range.clone()))
+}
+
+/// Check that timestamps make sense, beyond being valid: that patients aren't
+/// born in the future or die before they're born, that visits aren't
+/// discharged before they're admitted, and that the message wasn't sent long
+/// ago or in the future
+///
+/// Checks against the current time are skipped if `now` is `None`.
+#[instrument(level = "debug", skip(message))]
+pub fn validate_message(message: &Message, now: Option<i64>) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let error = |message: &str, range: Range<usize>, severity| {
+        ValidationError::new(
+            ValidationCode::Plausibility,
+            message.to_string(),
+            range,
+            severity,
+        )
+    };
+
+    if let (Some(now), Some(msh)) = (now, message.segment("MSH")) {
+        if let Some((sent, range)) = timestamp_field(msh, 7) {
+            if sent.earliest > now + FUTURE_TOLERANCE {
+                errors.push(error(
+                    "MSH.7 (Date/Time of Message) is in the future",
+                    range,
+                    DiagnosticSeverity::WARNING,
+                ));
+            } else if sent.latest < now - MESSAGE_AGE_LIMIT {
+                errors.push(error(
+                    "MSH.7 (Date/Time of Message) is more than 10 years ago",
+                    range,
+                    DiagnosticSeverity::INFORMATION,
+                ));
+            }
+        }
+    }
+
+    for pid in message.segments().filter(|segment| segment.name == "PID") {
+        let birth = timestamp_field(pid, 7);
+        let death = timestamp_field(pid, 29);
+        if let Some(now) = now {
+            for (timestamp, description) in [
+                (&birth, "PID.7 (Date/Time of Birth) is in the future"),
+                (
+                    &death,
+                    "PID.29 (Patient Death Date and Time) is in the future",
+                ),
+            ] {
+                if let Some((bounds, range)) = timestamp {
+                    if bounds.earliest > now + FUTURE_TOLERANCE {
+                        errors.push(error(
+                            description,
+                            range.clone(),
+                            DiagnosticSeverity::WARNING,
+                        ));
+                    }
+                }
+            }
+        }
+        if let (Some((birth, birth_range)), Some((death, death_range))) = (birth, death) {
+            if death.latest < birth.earliest {
+                errors.push(
+                    error(
+                        "PID.29 (Patient Death Date and Time) is before the patient was born",
+                        death_range,
+                        DiagnosticSeverity::WARNING,
+                    )
+                    .with_related_information(Some((
+                        birth_range,
+                        "The patient's date of birth".to_string(),
+                    ))),
+                );
+            }
+        }
+    }
+
+    for pv1 in message.segments().filter(|segment| segment.name == "PV1") {
+        let admitted = timestamp_field(pv1, 44);
+        let discharged = timestamp_field(pv1, 45);
+        if let (Some((admitted, admit_range)), Some((discharged, discharge_range))) =
+            (admitted, discharged)
+        {
+            if discharged.latest < admitted.earliest {
+                errors.push(
+                    error(
+                        "PV1.45 (Discharge Date/Time) is before the patient was admitted",
+                        discharge_range,
+                        DiagnosticSeverity::WARNING,
+                    )
+                    .with_related_information(Some((
+                        admit_range,
+                        "The patient's admit date/time".to_string(),
+                    ))),
+                );
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn implausible_timestamps_are_reported() {
+        // 2024-06-01T00:00:00Z
+        let now = days_from_civil(2024, 6, 1) * DAY;
+        assert_eq!(bounds("1970").unwrap().earliest, 0);
+        assert_eq!(bounds("19700101").unwrap().latest, DAY - 1);
+
+        let text = "MSH|^~\\&|App|Fac|||20100101||ADT^A03|1|P|2.5.1\r\
+            PID|1||123||Doe^John||2030||||||||||||||||||||||19790615\r\
+            PID|2||456||Doe^Jane||198006||||||||||||||||||||||19800615\r\
+            PV1|1|I||||||||||||||||||||||||||||||||||||||||||202405021200|20240501";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+
+        let errors = validate_message(&message, Some(now))
+            .into_iter()
+            .map(|error| (&text[error.range], error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "20100101",
+                    "MSH.7 (Date/Time of Message) is more than 10 years ago".to_string()
+                ),
+                (
+                    "2030",
+                    "PID.7 (Date/Time of Birth) is in the future".to_string()
+                ),
+                (
+                    "19790615",
+                    "PID.29 (Patient Death Date and Time) is before the patient was born"
+                        .to_string()
+                ),
+                (
+                    "20240501",
+                    "PV1.45 (Discharge Date/Time) is before the patient was admitted".to_string()
+                ),
+            ]
+        );
+    }
+}
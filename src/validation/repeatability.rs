@@ -0,0 +1,79 @@
+use crate::spec;
+use hl7_definitions::FieldRepeatability;
+use hl7_parser::message::Segment;
+use lsp_types::DiagnosticSeverity;
+use tracing::instrument;
+
+use super::{ValidationCode, ValidationError};
+
+/// Check that fields don't repeat more often than their definition allows,
+/// reporting the first repeat past the limit
+#[instrument(level = "trace", skip(segment), fields(segment = segment.name))]
+pub fn validate_segment(segment: &Segment, version: &str) -> Vec<ValidationError> {
+    let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) else {
+        return Vec::new();
+    };
+
+    let mut errors = Vec::new();
+    for (fi, field) in segment.fields().enumerate() {
+        // the encoding characters include the repetition separator itself
+        if segment.name == "MSH" && fi < 2 {
+            continue;
+        }
+        let Some(field_definition) = segment_definition.fields.get(fi) else {
+            continue;
+        };
+        let max_repeats = match field_definition.repeatability {
+            FieldRepeatability::Unbounded => continue,
+            FieldRepeatability::Single => 1,
+            FieldRepeatability::Bounded(max_repeats) => max_repeats,
+        };
+        let Some(excess) = field.repeats().nth(max_repeats) else {
+            continue;
+        };
+
+        let fi = fi + 1;
+        let description = field_definition.description;
+        let problem = if max_repeats == 1 {
+            "can't repeat".to_string()
+        } else {
+            format!(
+                "can repeat at most {max_repeats} times, found {repeats}",
+                repeats = field.repeats().count()
+            )
+        };
+        errors.push(
+            ValidationError::new(
+                ValidationCode::InvalidRepeatCount,
+                format!(
+                    "{segment}.{fi} ({description}) {problem}",
+                    segment = segment.name
+                ),
+                excess.range.clone(),
+                DiagnosticSeverity::WARNING,
+            )
+            .with_href(Some(spec::field_url(version, segment.name, fi))),
+        );
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_only_repeat_as_often_as_allowed() {
+        let text = "MSH|^~\\&|App\rPID|1||123~456||Doe~Roe||19700101~19700102|F";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+
+        let errors = validate_segment(pid, "2.5.1");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "PID.7 (Date/Time of Birth) can't repeat");
+        assert_eq!(&text[errors[0].range.clone()], "19700102");
+
+        let msh = message.segment("MSH").unwrap();
+        assert!(validate_segment(msh, "2.5.1").is_empty());
+    }
+}
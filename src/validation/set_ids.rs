@@ -0,0 +1,186 @@
+use hl7_parser::Message;
+use lsp_types::DiagnosticSeverity;
+use std::{collections::HashMap, ops::Range};
+use tracing::instrument;
+
+use super::{ValidationCode, ValidationError};
+
+/// What starts a new run of Set IDs for a segment
+enum Restart {
+    /// Any segment other than the segment itself, e.g. NTEs number the notes
+    /// on whichever segment they follow
+    AnyOther,
+    /// Any of the given segments (the segment's parents)
+    After(&'static [&'static str]),
+}
+
+/// Segments whose first field is a Set ID, which should count up from 1
+/// within their group
+const SET_ID_SEGMENTS: &[(&str, Restart)] = &[
+    ("AIG", Restart::After(&["RGS"])),
+    ("AIL", Restart::After(&["RGS"])),
+    ("AIP", Restart::After(&["RGS"])),
+    ("AIS", Restart::After(&["RGS"])),
+    ("AL1", Restart::After(&["PID"])),
+    ("DG1", Restart::After(&["PID", "PV1", "ORC", "OBR"])),
+    ("FT1", Restart::After(&["PID", "ORC", "OBR"])),
+    ("GT1", Restart::After(&["PID"])),
+    ("IN1", Restart::After(&["PID"])),
+    ("NK1", Restart::After(&["PID"])),
+    ("NTE", Restart::AnyOther),
+    ("OBR", Restart::After(&["PID"])),
+    ("OBX", Restart::After(&["PID", "ORC", "OBR", "SPM"])),
+    ("PID", Restart::After(&[])),
+    ("PR1", Restart::After(&["PID"])),
+    ("RGS", Restart::After(&[])),
+    ("SPM", Restart::After(&["PID", "OBR"])),
+    ("TQ1", Restart::After(&["ORC", "OBR"])),
+];
+
+/// The numeric Set IDs in the message, grouped into the runs that should
+/// each count up from 1
+fn set_id_runs<'m>(message: &'m Message) -> Vec<Vec<(&'m str, u64, Range<usize>)>> {
+    // the index of the current run of each segment
+    let mut current: HashMap<&str, usize> = HashMap::new();
+    let mut runs: Vec<Vec<(&str, u64, Range<usize>)>> = Vec::new();
+
+    for segment in message.segments() {
+        for (name, restart) in SET_ID_SEGMENTS {
+            let restarts = match restart {
+                Restart::AnyOther => segment.name != *name,
+                Restart::After(parents) => parents.contains(&segment.name),
+            };
+            if restarts {
+                current.remove(name);
+            }
+        }
+
+        if !SET_ID_SEGMENTS
+            .iter()
+            .any(|(name, _)| *name == segment.name)
+        {
+            continue;
+        }
+        let Some(field) = segment.field(1) else {
+            continue;
+        };
+        let Ok(set_id) = field.raw_value().parse::<u64>() else {
+            continue;
+        };
+
+        let run = *current.entry(segment.name).or_insert_with(|| {
+            runs.push(Vec::new());
+            runs.len() - 1
+        });
+        runs[run].push((segment.name, set_id, field.range.clone()));
+    }
+    runs
+}
+
+/// Check that Set IDs count up from 1 without duplicates or gaps, as many
+/// receiving systems reject messages with bad Set IDs
+///
+/// Empty or non-numeric Set IDs are left to the optionality and data type
+/// checks.
+#[instrument(level = "debug", skip(message))]
+pub fn validate_message(message: &Message) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    for run in set_id_runs(message) {
+        check_run(&run, &mut errors);
+    }
+    errors.sort_by_key(|error| error.range.start);
+    errors
+}
+
+/// Report the duplicates and gaps in a run of Set IDs
+fn check_run(run: &[(&str, u64, Range<usize>)], errors: &mut Vec<ValidationError>) {
+    for (i, &(segment, set_id, ref range)) in run.iter().enumerate() {
+        let earlier = &run[..i];
+        // each Set ID is expected to follow the one before it, so that one gap
+        // isn't reported for every segment after it
+        let expected = earlier.last().map(|(_, last, _)| last + 1).unwrap_or(1);
+        if let Some((_, _, previous)) = earlier.iter().find(|(_, seen, _)| *seen == set_id) {
+            errors.push(
+                ValidationError::new(
+                    ValidationCode::InvalidSetId,
+                    format!("Duplicate Set ID {set_id} for {segment}, expected {expected}"),
+                    range.clone(),
+                    DiagnosticSeverity::WARNING,
+                )
+                .with_related_information(Some((
+                    previous.clone(),
+                    format!("Set ID {set_id} is first used here"),
+                ))),
+            );
+        } else if set_id != expected {
+            errors.push(ValidationError::new(
+                ValidationCode::InvalidSetId,
+                format!("Set ID {set_id} for {segment} is out of sequence, expected {expected}"),
+                range.clone(),
+                DiagnosticSeverity::WARNING,
+            ));
+        }
+    }
+}
+
+/// The replacements, in order, that would number every run of Set IDs from 1
+pub fn renumber_set_ids(message: &Message) -> Vec<(Range<usize>, String)> {
+    let mut replacements = set_id_runs(message)
+        .into_iter()
+        .flat_map(|run| {
+            run.into_iter()
+                .zip(1..)
+                .filter(|((_, set_id, _), expected)| set_id != expected)
+                .map(|((_, _, range), expected)| (range, expected.to_string()))
+        })
+        .collect::<Vec<_>>();
+    replacements.sort_by_key(|(range, _)| range.start);
+    replacements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_ids_count_up_within_their_group() {
+        let message = hl7_parser::parse_message_with_lenient_newlines(
+            "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01|1|P|2.5.1\r\
+             PID|1\r\
+             OBR|1\rNTE|1\rNTE|2\rOBX|1\rNTE|1\rOBX|2\rOBX|2\r\
+             OBR|2\rOBX|1\rOBX|3\rNTE|2",
+        )
+        .unwrap();
+
+        let errors = validate_message(&message)
+            .into_iter()
+            .map(|error| error.message)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "Duplicate Set ID 2 for OBX, expected 3",
+                "Set ID 3 for OBX is out of sequence, expected 2",
+                "Set ID 2 for NTE is out of sequence, expected 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn set_ids_can_be_renumbered() {
+        let text = "MSH|^~\\&\rPID|1\rOBR|1\rOBX|2\rOBX|2\rNTE|3\rOBR|1";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let replacements = renumber_set_ids(&message)
+            .into_iter()
+            .map(|(range, value)| (&text[range.start - 4..range.end], value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replacements,
+            vec![
+                ("OBX|2", "1".to_string()),
+                ("NTE|3", "1".to_string()),
+                ("OBR|1", "2".to_string()),
+            ]
+        );
+    }
+}
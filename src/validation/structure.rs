@@ -0,0 +1,831 @@
+use crate::spec;
+use hl7_parser::Message;
+use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
+use tracing::instrument;
+
+use super::{ValidationCode, ValidationError};
+
+/// Abstract message structures, written as they are in the standard: `[X]` is
+/// optional, `{X}` repeats, and brackets around several segments make a group
+///
+/// These follow v2.5.1; segments that other versions add to a structure are
+/// never reported, as only segments named in the structure are checked.
+const STRUCTURES: &[(&str, &str)] = &[
+    ("ACK", "MSH [{SFT}] MSA [{ERR}]"),
+    (
+        "ADT_A01",
+        "MSH [{SFT}] EVN PID [PD1] [{ROL}] [{NK1}] PV1 [PV2] [{ROL}] [{DB1}] [{OBX}] [{AL1}] \
+         [{DG1}] [DRG] [{PR1 [{ROL}]}] [{GT1}] [{IN1 [IN2] [{IN3}] [{ROL}]}] [ACC] [UB1] [UB2] \
+         [PDA]",
+    ),
+    (
+        "ADT_A02",
+        "MSH [{SFT}] EVN PID [PD1] [{ROL}] PV1 [PV2] [{ROL}] [{DB1}] [{OBX}] [PDA]",
+    ),
+    (
+        "ADT_A03",
+        "MSH [{SFT}] EVN PID [PD1] [{ROL}] [{NK1}] PV1 [PV2] [{ROL}] [{DB1}] [{AL1}] [{DG1}] \
+         [DRG] [{PR1 [{ROL}]}] [{OBX}] [{GT1}] [{IN1 [IN2] [{IN3}] [{ROL}]}] [ACC] [PDA]",
+    ),
+    (
+        "ADT_A05",
+        "MSH [{SFT}] EVN PID [PD1] [{ROL}] [{NK1}] PV1 [PV2] [{ROL}] [{DB1}] [{OBX}] [{AL1}] \
+         [{DG1}] [DRG] [{PR1 [{ROL}]}] [{GT1}] [{IN1 [IN2] [{IN3}] [{ROL}]}] [ACC] [UB1] [UB2]",
+    ),
+    (
+        "MDM_T01",
+        "MSH [{SFT}] EVN PID PV1 [{ORC [{TQ1}] [OBR]}] TXA [{CON}]",
+    ),
+    (
+        "MDM_T02",
+        "MSH [{SFT}] EVN PID PV1 [{ORC [{TQ1}] [OBR]}] TXA [{CON}] {OBX [{NTE}]}",
+    ),
+    (
+        "ORM_O01",
+        "MSH [{NTE}] [PID [PD1] [{NTE}] [PV1 [PV2]] [{IN1 [IN2] [IN3]}] [GT1] [{AL1}]] \
+         {ORC [OBR [{NTE}] [CTD] [{DG1}] [{OBX [{NTE}]}]] [{FT1}] [{CTI}] [BLG]}",
+    ),
+    (
+        "ORU_R01",
+        "MSH [{SFT}] {[PID [PD1] [{NTE}] [{NK1}] [PV1 [PV2]]] {[ORC] OBR [{NTE}] [{TQ1 [{TQ2}]}] \
+         [CTD] [{OBX [{NTE}]}] [{FT1}] [{CTI}] [{SPM [{OBX}]}]}} [DSC]",
+    ),
+    (
+        "SIU_S12",
+        "MSH SCH [{TQ1}] [{NTE}] [{PID [PD1] [PV1] [PV2] [{OBX}] [{DG1}]}] {RGS [{AIS [{NTE}]}] \
+         [{AIG [{NTE}]}] [{AIL [{NTE}]}] [{AIP [{NTE}]}]}",
+    ),
+];
+
+/// The structures trigger events share when MSH-9.3 doesn't name one
+const EVENT_STRUCTURES: &[(&str, &[&str], &str)] = &[
+    ("ADT", &["A01", "A04", "A08", "A13"], "ADT_A01"),
+    ("ADT", &["A02"], "ADT_A02"),
+    ("ADT", &["A03"], "ADT_A03"),
+    ("ADT", &["A05", "A14", "A28", "A31"], "ADT_A05"),
+    (
+        "MDM",
+        &["T01", "T03", "T05", "T07", "T09", "T11"],
+        "MDM_T01",
+    ),
+    ("MDM", &["T02", "T04", "T06", "T08", "T10"], "MDM_T02"),
+    ("ORM", &["O01"], "ORM_O01"),
+    ("ORU", &["R01"], "ORU_R01"),
+    (
+        "SIU",
+        &[
+            "S12", "S13", "S14", "S15", "S16", "S17", "S18", "S19", "S20", "S21", "S22", "S23",
+            "S24", "S26",
+        ],
+        "SIU_S12",
+    ),
+];
+
+/// The segments that notes (NTE) can be about, which they directly follow
+/// (after any other notes)
+const NOTE_ANCHORS: &[&str] = &[
+    "MSH", "SFT", "PID", "PD1", "ORC", "OBR", "OBX", "SCH", "TQ1", "AIS", "AIG", "AIL", "AIP",
+    "SPM", "RXO", "RXE", "RXA", "TXA",
+];
+
+/// Segments that notes can be about from the given version on
+const VERSIONED_NOTE_ANCHORS: &[(&str, &str)] = &[("PRT", "2.7")];
+
+/// Segments that belong to a group started by one of the leading segments,
+/// which must come before them, only in messages with the given structure if
+/// there is one
+const GROUP_LEADERS: &[(Option<&str>, &str, &[&str])] = &[
+    (None, "PD1", &["PID"]),
+    (None, "PV2", &["PV1"]),
+    (None, "IN2", &["IN1"]),
+    (None, "IN3", &["IN1"]),
+    (None, "TQ2", &["TQ1"]),
+    (Some("ORU_R01"), "OBX", &["OBR"]),
+];
+
+/// A segment or group of segments in a message structure
+#[derive(Debug)]
+pub(super) struct Element<'s> {
+    pub kind: Kind<'s>,
+    pub required: bool,
+    pub repeats: bool,
+}
+
+#[derive(Debug)]
+pub(super) enum Kind<'s> {
+    Segment(&'s str),
+    Group(Vec<Element<'s>>),
+}
+
+impl<'s> Element<'s> {
+    /// Whether the element can start with a segment named `name`
+    fn starts_with(&self, name: &str) -> bool {
+        match &self.kind {
+            Kind::Segment(segment) => *segment == name,
+            Kind::Group(elements) => {
+                for element in elements {
+                    if element.starts_with(name) {
+                        return true;
+                    }
+                    if element.required {
+                        break;
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// The segment to report as missing if the element is missing
+    fn first_segment(&self) -> &'s str {
+        match &self.kind {
+            Kind::Segment(segment) => segment,
+            Kind::Group(elements) => elements
+                .iter()
+                .find(|e| e.required)
+                .or(elements.first())
+                .map(|e| e.first_segment())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether a segment named `name` can be anywhere in the element
+    fn contains(&self, name: &str) -> bool {
+        match &self.kind {
+            Kind::Segment(segment) => *segment == name,
+            Kind::Group(elements) => elements.iter().any(|e| e.contains(name)),
+        }
+    }
+
+    /// The segments the element needs to be present, if it's required
+    fn required_segment_names(&self, names: &mut Vec<&'s str>) {
+        if !self.required {
+            return;
+        }
+        match &self.kind {
+            Kind::Segment(segment) => names.push(segment),
+            Kind::Group(elements) => elements
+                .iter()
+                .for_each(|e| e.required_segment_names(names)),
+        }
+    }
+
+    fn segment_names(&self, names: &mut Vec<&'s str>) {
+        match &self.kind {
+            Kind::Segment(segment) => names.push(segment),
+            Kind::Group(elements) => elements.iter().for_each(|e| e.segment_names(names)),
+        }
+    }
+}
+
+/// Parse a structure written in the standard's notation (see [STRUCTURES])
+fn parse_structure(grammar: &'static str) -> Vec<Element<'static>> {
+    let mut tokens = Vec::new();
+    let mut name_start = None;
+    for (i, c) in grammar.char_indices() {
+        if c.is_ascii_alphanumeric() {
+            name_start.get_or_insert(i);
+            continue;
+        }
+        if let Some(start) = name_start.take() {
+            tokens.push(&grammar[start..i]);
+        }
+        if !c.is_whitespace() {
+            tokens.push(&grammar[i..i + c.len_utf8()]);
+        }
+    }
+    if let Some(start) = name_start {
+        tokens.push(&grammar[start..]);
+    }
+
+    parse_elements(&mut tokens.into_iter())
+}
+
+fn parse_elements(tokens: &mut impl Iterator<Item = &'static str>) -> Vec<Element<'static>> {
+    let mut elements = Vec::new();
+    while let Some(token) = tokens.next() {
+        let (required, repeats) = match token {
+            "]" | "}" => break,
+            "[" => (false, false),
+            "{" => (true, true),
+            name => {
+                elements.push(Element {
+                    kind: Kind::Segment(name),
+                    required: true,
+                    repeats: false,
+                });
+                continue;
+            }
+        };
+
+        let mut inner = parse_elements(tokens);
+        let element = if inner.len() == 1 {
+            let mut element = inner.pop().expect("has an element");
+            element.required &= required;
+            element.repeats |= repeats;
+            element
+        } else {
+            Element {
+                kind: Kind::Group(inner),
+                required,
+                repeats,
+            }
+        };
+        elements.push(element);
+    }
+    elements
+}
+
+/// A segment's name and where it is in the message
+pub(super) type NamedSegment<'m> = (&'m str, Range<usize>);
+
+/// A required segment that wasn't found, and the segment it should have
+/// followed
+pub(super) type MissingSegment<'s, 'm> = (&'s str, Option<NamedSegment<'m>>);
+
+/// Walks the segments of a message through a structure, noting the segments
+/// that are missing or out of place
+struct Matcher<'s, 'm> {
+    /// Only the segments that are named in the structure; any others (e.g.
+    /// Z-segments) may appear anywhere
+    segments: Vec<NamedSegment<'m>>,
+    position: usize,
+    previous: Option<NamedSegment<'m>>,
+    missing: Vec<MissingSegment<'s, 'm>>,
+    unexpected: Vec<NamedSegment<'m>>,
+}
+
+impl<'s, 'm> Matcher<'s, 'm> {
+    fn peek(&self) -> Option<&'m str> {
+        self.segments.get(self.position).map(|(name, _)| *name)
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.segments.get(self.position).cloned();
+        self.position += 1;
+    }
+
+    fn match_sequence(&mut self, elements: &[Element<'s>], top_level: bool) {
+        let mut i = 0;
+        let mut last = None;
+        while i < elements.len() {
+            let Some(name) = self.peek() else {
+                break;
+            };
+            match elements[i..].iter().position(|e| e.starts_with(name)) {
+                Some(skipped) => {
+                    for element in &elements[i..i + skipped] {
+                        self.expect(element);
+                    }
+                    i += skipped;
+                    self.consume(&elements[i]);
+                    last = Some(i);
+                    i += 1;
+                }
+                None if top_level => {
+                    self.unexpected.push(self.segments[self.position].clone());
+                    self.position += 1;
+                    // a repeating element may pick up again after the
+                    // segment (e.g. another OBR after a stray OBX in ORU_R01)
+                    if let Some(last) = last.filter(|&last| last + 1 == i) {
+                        if elements[last].repeats {
+                            i = last;
+                        }
+                    }
+                }
+                // the segment may belong to an enclosing group
+                None => break,
+            }
+        }
+        for element in &elements[i..] {
+            self.expect(element);
+        }
+
+        if top_level {
+            self.unexpected
+                .extend(self.segments[self.position..].iter().cloned());
+            self.position = self.segments.len();
+        }
+    }
+
+    fn consume(&mut self, element: &Element<'s>) {
+        loop {
+            match &element.kind {
+                Kind::Segment(_) => self.advance(),
+                Kind::Group(elements) => self.match_sequence(elements, false),
+            }
+            if !element.repeats || !self.peek().is_some_and(|name| element.starts_with(name)) {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, element: &Element<'s>) {
+        if element.required {
+            self.missing
+                .push((element.first_segment(), self.previous.clone()));
+        }
+    }
+}
+
+/// The name of the abstract message structure the message should follow,
+/// from MSH-9.3 or else from its message code and trigger event
+fn structure_name<'m>(message: &'m Message) -> Option<&'m str> {
+    let declared = message
+        .query("MSH.9.3")
+        .map(|v| v.raw_value())
+        .filter(|v| !v.is_empty());
+    if declared.is_some() {
+        return declared;
+    }
+
+    let code = message.query("MSH.9.1").map(|v| v.raw_value())?;
+    if code == "ACK" {
+        return Some("ACK");
+    }
+    let event = message.query("MSH.9.2").map(|v| v.raw_value())?;
+    event_structure(code, event)
+}
+
+/// The structure a trigger event's messages have, e.g. `ADT_A01` for
+/// `ADT^A04`
+fn event_structure(code: &str, event: &str) -> Option<&'static str> {
+    EVENT_STRUCTURES
+        .iter()
+        .find(|(c, events, _)| *c == code && events.contains(&event))
+        .map(|(_, _, structure)| *structure)
+}
+
+/// The structure of a message type (e.g. `ADT` and `A04`), and the segments
+/// it requires in order, starting with the message header
+pub fn required_segments(code: &str, event: &str) -> Option<(&'static str, Vec<&'static str>)> {
+    let structure = match code {
+        "ACK" => "ACK",
+        _ => event_structure(code, event)?,
+    };
+    let (_, grammar) = STRUCTURES.iter().find(|(name, _)| *name == structure)?;
+    let mut segments = Vec::new();
+    for element in parse_structure(grammar) {
+        element.required_segment_names(&mut segments);
+    }
+    Some((structure, segments))
+}
+
+/// The segments named in a message's structure, in the order they're first
+/// named, leaving out the message header
+pub fn structure_segments(message: &Message) -> Vec<&'static str> {
+    let Some(structure) = structure_name(message) else {
+        return Vec::new();
+    };
+    let Some((_, grammar)) = STRUCTURES.iter().find(|(name, _)| *name == structure) else {
+        return Vec::new();
+    };
+    let mut names = Vec::new();
+    for element in parse_structure(grammar) {
+        element.segment_names(&mut names);
+    }
+    let mut segments = Vec::new();
+    for name in names {
+        if name != "MSH" && !segments.contains(&name) {
+            segments.push(name);
+        }
+    }
+    segments
+}
+
+/// Walk the segments of a message through a structure, returning the segments
+/// that are out of place and the required segments that are missing
+///
+/// Segments that aren't named anywhere in the structure are ignored.
+pub(super) fn match_segments<'s, 'm>(
+    message: &'m Message,
+    elements: &[Element<'s>],
+) -> (Vec<NamedSegment<'m>>, Vec<MissingSegment<'s, 'm>>) {
+    let mut names = Vec::new();
+    elements.iter().for_each(|e| e.segment_names(&mut names));
+    let mut matcher = Matcher {
+        segments: message
+            .segments()
+            .filter(|segment| names.contains(&segment.name))
+            .map(|segment| (segment.name, segment.range.clone()))
+            .collect(),
+        position: 0,
+        previous: None,
+        missing: Vec::new(),
+        unexpected: Vec::new(),
+    };
+    matcher.match_sequence(elements, true);
+    (matcher.unexpected, matcher.missing)
+}
+
+/// A segment, with the segments that go wherever it goes (notes, Z-segments,
+/// and segments the structure doesn't name), by their index in the message
+struct Unit<'m> {
+    name: &'m str,
+    segments: Vec<usize>,
+}
+
+/// A segment or an instance of a group of segments, placed in the structure
+/// by the index of the element of its group that it matches
+enum Placed {
+    Unit(usize),
+    Group(Vec<(usize, Placed)>),
+}
+
+/// Place units into an instance of a group of elements for as long as they
+/// fit, starting a new instance of any repeating group that's already full
+fn place(
+    elements: &[Element],
+    units: &[Unit],
+    next: &mut usize,
+    placed: &mut Vec<(usize, Placed)>,
+) {
+    while let Some(unit) = units.get(*next) {
+        let last = placed.last().map(|(i, _)| *i).unwrap_or_default();
+        // segments named more than once in a group (e.g. ROL) go in the
+        // first place after what has been placed so far
+        let position = |from: usize| {
+            elements
+                .iter()
+                .enumerate()
+                .skip(from)
+                .find(|(_, e)| e.contains(unit.name))
+                .map(|(i, _)| i)
+        };
+        let Some(i) = position(last).or_else(|| position(0)) else {
+            return;
+        };
+        let element = &elements[i];
+        if !element.repeats && placed.iter().any(|(placed, _)| *placed == i) {
+            return;
+        }
+        match &element.kind {
+            Kind::Segment(_) => {
+                placed.push((i, Placed::Unit(*next)));
+                *next += 1;
+            }
+            Kind::Group(group) => {
+                let mut instance = Vec::new();
+                place(group, units, next, &mut instance);
+                placed.push((i, Placed::Group(instance)));
+            }
+        }
+    }
+}
+
+/// Write out placed units in the order of the elements they're placed by,
+/// keeping the order of those placed by the same element
+fn flatten(mut placed: Vec<(usize, Placed)>, units: &[Unit], order: &mut Vec<usize>) {
+    placed.sort_by_key(|(i, _)| *i);
+    for (_, placed) in placed {
+        match placed {
+            Placed::Unit(unit) => order.extend(&units[unit].segments),
+            Placed::Group(instance) => flatten(instance, units, order),
+        }
+    }
+}
+
+/// The order the segments of a message (by their index) should be in for its
+/// message structure, if it has a known one
+///
+/// Groups keep the segments they were in (e.g. each OBX stays with the OBR
+/// before it), and notes and Z-segments stay with the segment before them.
+pub fn canonical_order(message: &Message) -> Option<Vec<usize>> {
+    let structure = structure_name(message)?;
+    let (_, grammar) = STRUCTURES.iter().find(|(name, _)| *name == structure)?;
+    let elements = parse_structure(grammar);
+    let mut names = Vec::new();
+    elements.iter().for_each(|e| e.segment_names(&mut names));
+
+    let mut units: Vec<Unit> = Vec::new();
+    for (si, segment) in message.segments().enumerate() {
+        let follows = segment.name == "NTE"
+            || segment.name.starts_with('Z')
+            || !names.contains(&segment.name);
+        match units.last_mut() {
+            Some(unit) if follows => unit.segments.push(si),
+            _ => units.push(Unit {
+                name: segment.name,
+                segments: vec![si],
+            }),
+        }
+    }
+
+    let mut next = 0;
+    let mut placed = Vec::new();
+    while next < units.len() {
+        place(&elements, &units, &mut next, &mut placed);
+        // a unit that doesn't fit anywhere (e.g. a repeated segment that
+        // can't repeat) stays after the unit before it
+        if next < units.len() {
+            match (0..next).rev().find(|&p| !units[p].segments.is_empty()) {
+                Some(previous) => {
+                    let segments = std::mem::take(&mut units[next].segments);
+                    units[previous].segments.extend(segments);
+                }
+                None => placed.push((0, Placed::Unit(next))),
+            }
+            next += 1;
+        }
+    }
+
+    let mut order = Vec::new();
+    flatten(placed, &units, &mut order);
+    Some(order)
+}
+
+/// Check that notes follow a segment they can be about, and that segments
+/// belonging to a group come after the segment that starts it, which holds
+/// whatever the message's structure
+fn validate_placement(message: &Message, version: &str) -> Vec<ValidationError> {
+    let structure = structure_name(message);
+    let is_note_anchor = |name: &str| {
+        NOTE_ANCHORS.contains(&name)
+            || VERSIONED_NOTE_ANCHORS
+                .iter()
+                .any(|(anchor, since)| *anchor == name && spec::is_at_least(version, since))
+    };
+    let segments = message.segments().collect::<Vec<_>>();
+    let error = |message: String, range: Range<usize>, related: Option<(Range<usize>, String)>| {
+        ValidationError::new(
+            ValidationCode::SegmentStructure,
+            message,
+            range,
+            DiagnosticSeverity::WARNING,
+        )
+        .with_related_information(related)
+    };
+
+    let mut errors = Vec::new();
+    for (si, segment) in segments.iter().enumerate() {
+        if segment.name == "NTE" {
+            // notes can follow other notes, and Z-segments can go anywhere
+            let previous = segments[..si]
+                .iter()
+                .rev()
+                .find(|previous| previous.name != "NTE" && !previous.name.starts_with('Z'));
+            if let Some(previous) = previous.filter(|p| !is_note_anchor(p.name)) {
+                errors.push(error(
+                    format!(
+                        "NTE segment follows {previous}, which notes can't be about; notes must \
+                         directly follow the segment they're about (e.g. OBX, OBR, or PID)",
+                        previous = previous.name
+                    ),
+                    segment.range.clone(),
+                    Some((
+                        previous.range.clone(),
+                        "The segment before the note".to_string(),
+                    )),
+                ));
+            }
+            continue;
+        }
+
+        let leaders = GROUP_LEADERS
+            .iter()
+            .filter(|(only_in, _, _)| only_in.is_none() || *only_in == structure)
+            .find(|(_, member, _)| *member == segment.name)
+            .map(|(_, _, leaders)| *leaders);
+        let Some(leaders) = leaders else {
+            continue;
+        };
+        if segments[..si]
+            .iter()
+            .any(|previous| leaders.contains(&previous.name))
+        {
+            continue;
+        }
+        let message = match structure.filter(|_| segment.name == "OBX") {
+            Some(structure) => format!(
+                "OBX segment comes before any {leaders} in an {structure} message; observations must \
+                 follow the {leaders} they're results of",
+                leaders = leaders.join(" or ")
+            ),
+            None => format!(
+                "{name} segment is orphaned; it belongs to the group started by {leaders}, which \
+                 must come before it",
+                name = segment.name,
+                leaders = leaders.join(" or ")
+            ),
+        };
+        errors.push(error(message, segment.range.clone(), None));
+    }
+    errors
+}
+
+/// Check that the segments in the message are in the order, and appear as
+/// many times as, the abstract message structure for its message type allows,
+/// and are placed where they make sense (see [validate_placement])
+///
+/// Message types without a known structure only have their placement checked.
+#[instrument(level = "debug", skip(message))]
+pub fn validate_message(message: &Message, version: &str) -> Vec<ValidationError> {
+    let mut errors = validate_placement(message, version);
+    let Some(structure) = structure_name(message) else {
+        return errors;
+    };
+    let Some((structure, grammar)) = STRUCTURES.iter().find(|(name, _)| *name == structure) else {
+        tracing::trace!(structure, "unknown message structure");
+        return errors;
+    };
+    let elements = parse_structure(grammar);
+    let (unexpected, missing) = match_segments(message, &elements);
+
+    let related = message.query("MSH.9").map(|message_type| {
+        (
+            message_type.range(),
+            format!("Message type `{}`", message_type.raw_value()),
+        )
+    });
+    let href = message
+        .query("MSH.9.1")
+        .zip(message.query("MSH.9.2"))
+        .map(|(code, event)| spec::trigger_event_url(version, code.raw_value(), event.raw_value()));
+    let error = |message: String, range: Range<usize>| {
+        ValidationError::new(
+            ValidationCode::SegmentStructure,
+            message,
+            range,
+            DiagnosticSeverity::WARNING,
+        )
+        .with_related_information(related.clone())
+        .with_href(href.clone())
+    };
+
+    // segments that are out of place have already been explained
+    let placed = errors
+        .iter()
+        .map(|error| error.range.clone())
+        .collect::<Vec<_>>();
+    for (name, range) in &unexpected {
+        if placed.contains(range) {
+            continue;
+        }
+        errors.push(error(
+            format!("{name} segment is out of order or repeated too often for {structure}"),
+            range.clone(),
+        ));
+    }
+    // a segment that is out of order is also missing from where it should
+    // be, which is only worth reporting once
+    for (name, previous) in missing {
+        if unexpected.iter().any(|(n, _)| *n == name) {
+            continue;
+        }
+        let (message, range) = match previous {
+            Some((previous, range)) => (
+                format!("Missing {name} segment after {previous}, which {structure} requires"),
+                range,
+            ),
+            None => (
+                format!("Missing {name} segment, which {structure} requires"),
+                0..0,
+            ),
+        };
+        errors.push(error(message, range).with_missing_segment(name));
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(segments: &[&str]) -> Vec<String> {
+        let text = segments.join("\r");
+        let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+        validate_message(&message, "2.5.1")
+            .into_iter()
+            .map(|error| error.message)
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn segments_are_checked_against_the_message_structure() {
+        let msh = |message_type: &str| {
+            format!("MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||{message_type}|1|P|2.5.1")
+        };
+
+        let adt = msh("ADT^A04");
+        assert!(
+            validate(&[&adt, "EVN|A04", "PID|1", "ZPI|1", "PV1|1", "AL1|1", "AL1|2"]).is_empty()
+        );
+        assert_eq!(
+            validate(&[&adt, "PID|1", "PV1|1"]),
+            vec!["Missing EVN segment after MSH, which ADT_A01 requires"]
+        );
+        assert_eq!(
+            validate(&[&adt, "PID|1", "EVN|A04", "PV1|1"]),
+            vec!["EVN segment is out of order or repeated too often for ADT_A01"]
+        );
+
+        let oru = msh("ORU^R01^ORU_R01");
+        assert!(
+            validate(&[&oru, "PID|1", "OBR|1", "OBX|1", "NTE|1", "OBX|2", "OBR|2", "OBX|1"])
+                .is_empty()
+        );
+        assert_eq!(
+            validate(&[&oru, "PID|1", "OBX|1", "OBR|1", "OBX|1"]),
+            vec![
+                "OBX segment comes before any OBR in an ORU_R01 message; observations must follow \
+                 the OBR they're results of",
+                "Missing OBR segment after PID, which ORU_R01 requires",
+            ]
+        );
+
+        // placement is checked whatever the structure
+        assert_eq!(
+            validate(&[
+                &msh("ZZZ^Z01"),
+                "PID|1",
+                "NTE|1",
+                "EVN|A01",
+                "NTE|2",
+                "IN2|1"
+            ]),
+            vec![
+                "NTE segment follows EVN, which notes can't be about; notes must directly follow \
+                 the segment they're about (e.g. OBX, OBR, or PID)",
+                "IN2 segment is orphaned; it belongs to the group started by IN1, which must come \
+                 before it",
+            ]
+        );
+
+        // unknown structures aren't checked
+        assert!(validate(&[&msh("ZZZ^Z01"), "OBX|1"]).is_empty());
+    }
+
+    #[test]
+    fn valid_placements_arent_reported() {
+        let validate = |version: &str, segments: &[&str]| {
+            let text = segments.join("\r");
+            let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+            validate_message(&message, version)
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+        };
+        let msh = |message_type: &str, version: &str| {
+            format!("MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||{message_type}|1|P|{version}")
+        };
+
+        // notes about the message's software
+        let oml = msh("OML^O21^OML_O21", "2.5.1");
+        assert!(validate("2.5.1", &[&oml, "SFT|Vendor", "NTE|1", "PID|1"]).is_empty());
+
+        // patient observations come before any order in ORU_R30
+        let oru = msh("ORU^R30^ORU_R30", "2.5.1");
+        assert!(validate("2.5.1", &[&oru, "PID|1", "OBX|1", "ORC|NW", "OBR|1"]).is_empty());
+
+        // notes about participants, which PRT was added for in v2.7
+        let participation = [
+            msh("ORU^R30^ORU_R30", "2.7"),
+            "PRT|1".to_string(),
+            "NTE|1".to_string(),
+        ];
+        let participation = participation.iter().map(String::as_str).collect::<Vec<_>>();
+        assert!(validate("2.7", &participation).is_empty());
+        assert_eq!(
+            validate("2.5.1", &participation),
+            vec![
+                "NTE segment follows PRT, which notes can't be about; notes must directly follow \
+                 the segment they're about (e.g. OBX, OBR, or PID)"
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_can_be_put_in_order() {
+        let order = |segments: &[&str]| {
+            let text = segments.join("\r");
+            let message = hl7_parser::parse_message_with_lenient_newlines(&text).unwrap();
+            canonical_order(&message).map(|order| {
+                order
+                    .into_iter()
+                    .map(|i| segments[i].to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let msh = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ORU^R01^ORU_R01|1|P|2.5.1";
+        assert_eq!(
+            order(&[msh, "OBR|1", "OBX|1", "NTE|1", "PID|1", "OBR|2", "OBX|1"]).unwrap(),
+            vec![msh, "PID|1", "OBR|1", "OBX|1", "NTE|1", "OBR|2", "OBX|1"]
+        );
+        assert_eq!(
+            order(&[msh, "PID|1", "OBX|1", "ZOB|1", "OBR|1", "OBX|2"]).unwrap(),
+            vec![msh, "PID|1", "OBR|1", "OBX|1", "ZOB|1", "OBX|2"]
+        );
+
+        let msh = "MSH|^~\\&|App|Fac|Rcv|RcvFac|20240102030405||ADT^A04|1|P|2.5.1";
+        assert_eq!(
+            order(&[msh, "PID|1", "PV1|1", "EVN|A04", "AL1|1", "PV1|2"]).unwrap(),
+            vec![msh, "EVN|A04", "PID|1", "PV1|1", "AL1|1", "PV1|2"]
+        );
+        assert_eq!(
+            order(&["MSH|^~\\&|App|Fac|||20240102||ZZZ^Z01", "OBX|1"]),
+            None
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use super::{ValidationCode, ValidationError};
+use crate::utils::trim_trailing_separators;
+use hl7_parser::message::{Segment, Separators};
+use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
+use tracing::instrument;
+
+/// Point out separators and whitespace that don't mean anything, each with a
+/// fix that removes them: whitespace and empty fields (doubled field
+/// separators) at the end of the segment, and empty components and repeats
+/// at the end of each field
+///
+/// None of these change what the message means, so they're only reported if
+/// asked for (see [crate::Opts::lint_style]).
+#[instrument(level = "trace", skip(segment, separators), fields(segment = segment.name))]
+pub fn validate_segment(segment: &Segment, separators: &Separators) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let source = segment.raw_value();
+    let start = segment.range.start;
+    let lint = |message: &str, fix: &str, range: Range<usize>| {
+        ValidationError::new(
+            ValidationCode::Style,
+            message.to_string(),
+            range,
+            DiagnosticSeverity::HINT,
+        )
+        .with_fix(fix, String::new())
+    };
+
+    let without_whitespace = source.trim_end_matches([' ', '\t']);
+    if without_whitespace.len() < source.len() {
+        errors.push(lint(
+            "Segment ends with whitespace",
+            "Remove trailing whitespace",
+            start + without_whitespace.len()..start + source.len(),
+        ));
+    }
+
+    let trimmed = trim_trailing_separators(without_whitespace, separators);
+    let trailing = &without_whitespace[trimmed.len()..];
+    if trailing.contains(separators.field) {
+        errors.push(lint(
+            "Segment ends with empty fields",
+            "Remove empty fields from the end of the segment",
+            start + trimmed.len()..start + without_whitespace.len(),
+        ));
+    } else if !trailing.is_empty() {
+        errors.push(lint(
+            "Segment ends with empty components",
+            "Remove empty components from the end of the segment",
+            start + trimmed.len()..start + without_whitespace.len(),
+        ));
+    }
+
+    // MSH.1 and MSH.2 are made of separators
+    let skip = if segment.name == "MSH" { 2 } else { 0 };
+    // the end of the last field is already covered by the end of the segment
+    let end = start + trimmed.len();
+    for field in segment.fields().skip(skip) {
+        for repeat in field.repeats() {
+            let value = repeat.raw_value();
+            let kept = value.trim_end_matches([separators.component, separators.subcomponent]);
+            if kept.len() < value.len() && repeat.range.end < end {
+                errors.push(lint(
+                    "Field ends with empty components",
+                    "Remove empty components from the end of the field",
+                    repeat.range.start + kept.len()..repeat.range.end,
+                ));
+            }
+        }
+        let value = field.raw_value();
+        let kept = value.trim_end_matches(separators.repetition);
+        if kept.len() < value.len() && field.range.end < end {
+            errors.push(lint(
+                "Field ends with empty repeats",
+                "Remove empty repeats from the end of the field",
+                field.range.start + kept.len()..field.range.end,
+            ));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redundant_separators_are_reported_with_fixes() {
+        let text = "MSH|^~\\&|App\rPID|1||123^^^Hosp^MR^^~||Doe^John^^||||  \rPV1|1|||";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+
+        let errors = message
+            .segments()
+            .flat_map(|segment| validate_segment(segment, &message.separators))
+            .map(|error| {
+                (
+                    &text[error.range],
+                    error.message,
+                    error.data.fix.unwrap_or_default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "  ",
+                    "Segment ends with whitespace".to_string(),
+                    "Remove trailing whitespace".to_string()
+                ),
+                (
+                    "^^||||",
+                    "Segment ends with empty fields".to_string(),
+                    "Remove empty fields from the end of the segment".to_string()
+                ),
+                (
+                    "^^",
+                    "Field ends with empty components".to_string(),
+                    "Remove empty components from the end of the field".to_string()
+                ),
+                (
+                    "~",
+                    "Field ends with empty repeats".to_string(),
+                    "Remove empty repeats from the end of the field".to_string()
+                ),
+                (
+                    "|||",
+                    "Segment ends with empty fields".to_string(),
+                    "Remove empty fields from the end of the segment".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,73 +1,90 @@
-use super::{ValidationCode, ValidationError};
-use crate::{workspace::specs::WorkspaceSpecs, Opts};
-use hl7_definitions::table_values;
-use hl7_parser::Message;
+use super::{version_related_information, ValidationCode, ValidationError};
+use crate::{
+    spec,
+    workspace::specs::{TableSpec, WorkspaceSpecs},
+    Opts,
+};
+use hl7_parser::{
+    message::{Field, Segment},
+    Message,
+};
 use lsp_types::{DiagnosticSeverity, Uri};
+use std::ops::Range;
 use tracing::instrument;
 
-#[instrument(level = "debug", skip(uri, message, version, workspace_specs, opts))]
-pub fn validate_message(
+#[instrument(
+    level = "trace",
+    skip(uri, message, segment, version, workspace_specs, opts),
+    fields(segment = segment.name)
+)]
+pub fn validate_segment(
     uri: &Uri,
     message: &Message,
+    segment: &Segment,
     version: &str,
     workspace_specs: &Option<&WorkspaceSpecs>,
     opts: &Opts,
 ) -> Vec<ValidationError> {
     let mut errors = Vec::new();
+    let tables = Tables {
+        uri,
+        workspace_specs,
+        standard: !opts.disable_std_table_validations,
+    };
 
-    for segment in message.segments() {
-        if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
-            for (fi, field) in segment.fields().enumerate() {
-                if field.is_empty() {
-                    continue;
-                }
+    if let Some(segment_definition) = hl7_definitions::get_segment(version, segment.name) {
+        for (fi, field) in segment.fields().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
 
-                let workspace_table_values = workspace_specs
-                    .as_ref()
-                    .map(|specs| specs.table_values(uri, segment.name, fi + 1))
-                    .unwrap_or_default();
+            if let Some(field_definition) = segment_definition.fields.get(fi) {
+                errors.extend(validate_components(
+                    message,
+                    field,
+                    field_definition,
+                    version,
+                    &tables,
+                ));
+            }
 
-                if workspace_table_values.is_empty() {
-                    if opts.disable_std_table_validations {
-                        continue;
-                    }
+            let workspace_table_values = workspace_specs
+                .as_ref()
+                .map(|specs| specs.table_values(uri, segment.name, fi + 1))
+                .unwrap_or_default();
 
-                    // use the default table values
-                    if let Some(field_definition) = segment_definition.fields.get(fi) {
-                        if let Some(table) = field_definition.table {
-                            if let Some(table_values) = table_values(table as u16) {
-                                for repeat in field.repeats() {
-                                    if table_values.iter().all(|v| v.0 != repeat.raw_value()) {
-                                        errors.push(ValidationError::new(
-                                            ValidationCode::InvalidTableValue,
-                                            format!(
-                                                "Invalid table value, expected one of:\n{table_values}",
-                                                table_values = table_values
-                                                    .iter()
-                                                    .map(|v| format!(
-                                                        "  - `{value}` ({description})",
-                                                        value = v.0,
-                                                        description = v.1
-                                                    ))
-                                                    .collect::<Vec<String>>()
-                                                    .join("\n")
-                                            ),
-                                            field.range.clone(),
-                                            DiagnosticSeverity::INFORMATION,
-                                        ));
-                                    }
-                                }
-                            }
+            if workspace_table_values.is_empty() {
+                // use the values of the table the field is bound to
+                if let Some(field_definition) = segment_definition.fields.get(fi) {
+                    if let Some(table) = field_definition.table {
+                        // values with components are checked component by
+                        // component instead
+                        for repeat in field.repeats().filter(|r| r.components().count() < 2) {
+                            errors.extend(check_table_value(
+                                message,
+                                version,
+                                &tables,
+                                table as u16,
+                                field_definition.description,
+                                repeat.raw_value(),
+                                &repeat.range,
+                            ));
                         }
                     }
-                } else {
-                    // use the workspace table values
-                    for repeat in field.repeats() {
-                        if workspace_table_values
-                            .iter()
-                            .all(|v| v.0 != repeat.raw_value())
-                        {
-                            errors.push(ValidationError::new(
+                }
+            } else {
+                // use the workspace table values
+                for repeat in field.repeats() {
+                    if workspace_table_values
+                        .iter()
+                        .all(|v| v.0 != repeat.raw_value())
+                    {
+                        let suggestions = suggest_values(
+                            repeat.raw_value(),
+                            workspace_table_values.iter().map(|v| v.0.as_str()),
+                        );
+                        errors.push(
+                            ValidationError::new(
                                 ValidationCode::InvalidTableValue,
                                 format!(
                                     "Invalid table value, expected one of:\n{table_values}",
@@ -81,10 +98,11 @@ pub fn validate_message(
                                         .collect::<Vec<String>>()
                                         .join("\n")
                                 ),
-                                field.range.clone(),
+                                repeat.range.clone(),
                                 DiagnosticSeverity::INFORMATION,
-                            ));
-                        }
+                            )
+                            .with_suggestions(suggestions),
+                        );
                     }
                 }
             }
@@ -93,3 +111,294 @@ pub fn validate_message(
 
     errors
 }
+
+/// Check the components (and sub-components) of a field that are bound to
+/// tables by the field's datatype, e.g. the identifier type code of a `CX`
+fn validate_components(
+    message: &Message,
+    field: &Field,
+    field_definition: &hl7_definitions::Field,
+    version: &str,
+    tables: &Tables,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    let Some(datatype) = hl7_definitions::get_field(version, field_definition.datatype) else {
+        return errors;
+    };
+
+    for repeat in field.repeats() {
+        // a value without any components is checked against the field's own
+        // table, if it has one
+        if field_definition.table.is_some() && repeat.components().count() < 2 {
+            continue;
+        }
+        for (ci, component) in repeat.components().enumerate() {
+            if component.is_empty() {
+                continue;
+            }
+            let Some(component_definition) = datatype.subfields.get(ci) else {
+                continue;
+            };
+            // likewise, a component with sub-components is checked sub-component
+            // by sub-component
+            if component.subcomponents().count() < 2 {
+                if let Some(table) = component_definition.table {
+                    errors.extend(check_table_value(
+                        message,
+                        version,
+                        tables,
+                        table as u16,
+                        component_definition.description,
+                        component.raw_value(),
+                        &component.range,
+                    ));
+                }
+                continue;
+            }
+            let Some(component_datatype) =
+                hl7_definitions::get_field(version, component_definition.datatype)
+            else {
+                continue;
+            };
+            for (si, sub_component) in component.subcomponents().enumerate() {
+                let Some(sub_component_definition) = component_datatype.subfields.get(si) else {
+                    continue;
+                };
+                if sub_component.is_empty() {
+                    continue;
+                }
+                if let Some(table) = sub_component_definition.table {
+                    errors.extend(check_table_value(
+                        message,
+                        version,
+                        tables,
+                        table as u16,
+                        sub_component_definition.description,
+                        sub_component.raw_value(),
+                        &sub_component.range,
+                    ));
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+/// Where the values of the tables that fields are bound to come from
+struct Tables<'a> {
+    uri: &'a Uri,
+    workspace_specs: &'a Option<&'a WorkspaceSpecs>,
+    /// Whether the standard's tables are checked
+    standard: bool,
+}
+
+/// The values of a table, either defined by the workspace or by the standard
+enum TableValues {
+    Workspace(TableSpec),
+    Standard(&'static [(&'static str, &'static str)]),
+}
+
+impl Tables<'_> {
+    /// The values of a table, preferring the workspace's definition of it
+    ///
+    /// Tables without any values (e.g. user-defined tables that the
+    /// workspace doesn't define) can't be checked.
+    fn get(&self, table: u16) -> Option<TableValues> {
+        if let Some(values) = self
+            .workspace_specs
+            .and_then(|specs| specs.table(self.uri, table))
+            .filter(|values| !values.values.is_empty())
+        {
+            return Some(TableValues::Workspace(values));
+        }
+        hl7_definitions::table_values(table)
+            .filter(|values| self.standard && !values.is_empty())
+            .map(TableValues::Standard)
+    }
+}
+
+impl TableValues {
+    fn codes(&self) -> Vec<&str> {
+        match self {
+            TableValues::Workspace(table) => table.values.iter().map(|v| v.0.as_str()).collect(),
+            TableValues::Standard(values) => values.iter().map(|v| v.0).collect(),
+        }
+    }
+}
+
+/// Report a value that isn't in the table it's bound to
+fn check_table_value(
+    message: &Message,
+    version: &str,
+    tables: &Tables,
+    table: u16,
+    description: &str,
+    value: &str,
+    range: &Range<usize>,
+) -> Option<ValidationError> {
+    let table_values = tables.get(table)?;
+    let codes = table_values.codes();
+    if codes.contains(&value) {
+        return None;
+    }
+
+    let suggestions = suggest_values(value, codes.into_iter());
+    let did_you_mean = match suggestions.as_slice() {
+        [] => String::new(),
+        [suggestion] => format!(", did you mean `{suggestion}`?"),
+        [rest @ .., last] => format!(
+            ", did you mean {rest} or `{last}`?",
+            rest = rest
+                .iter()
+                .map(|s| format!("`{s}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let error = ValidationError::new(
+        ValidationCode::InvalidTableValue,
+        format!("Invalid table value `{value}` for table {table:04} ({description}){did_you_mean}"),
+        range.clone(),
+        DiagnosticSeverity::INFORMATION,
+    )
+    .with_suggestions(suggestions);
+    Some(match table_values {
+        // the standard's description of the table doesn't describe the
+        // workspace's values
+        TableValues::Workspace(_) => error,
+        TableValues::Standard(_) => error
+            .with_related_information(version_related_information(message))
+            .with_href(Some(spec::table_url(version, table)))
+            .with_table(table),
+    })
+}
+
+/// The most values suggested in place of an invalid one
+const MAX_SUGGESTIONS: usize = 3;
+
+/// The valid values closest to an invalid one, ignoring case, most similar
+/// first
+///
+/// Values are only suggested if they share something with the invalid value,
+/// as otherwise every single character code would be suggested for every
+/// invalid single character value.
+fn suggest_values<'v>(value: &str, valid_values: impl Iterator<Item = &'v str>) -> Vec<String> {
+    let value = value.to_uppercase();
+    let mut candidates = valid_values
+        .filter_map(|valid| {
+            let distance = edit_distance(&value, &valid.to_uppercase());
+            let max_distance = value.chars().count().max(valid.chars().count()) / 2;
+            (distance <= max_distance).then_some((distance, valid))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, valid)| valid.to_string())
+        .collect()
+}
+
+/// The Levenshtein distance between two strings, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similar_values_are_suggested() {
+        let values = ["MR", "PI", "PT", "SS"];
+        assert_eq!(suggest_values("mr", values.into_iter()), vec!["MR"]);
+        assert_eq!(suggest_values("P", values.into_iter()), vec!["PI", "PT"]);
+        assert!(suggest_values("XX", values.into_iter()).is_empty());
+
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "MR"), 2);
+    }
+
+    #[test]
+    fn components_are_checked_against_their_tables() {
+        let text = "MSH|^~\\&|App\rPID|1||123^^^Hosp&1.2&XYZ^XX||Doe^John|||M";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+        let uri = "file:///message.hl7".parse().unwrap();
+
+        let errors = validate_segment(&uri, &message, pid, "2.5.1", &None, &Opts::default())
+            .into_iter()
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "XYZ".to_string(),
+                    "Invalid table value `XYZ` for table 0301 (Universal ID Type)".to_string()
+                ),
+                (
+                    "XX".to_string(),
+                    "Invalid table value `XX` for table 0203 (Identifier Type Code)".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn workspace_tables_are_checked_in_place_of_the_standards() {
+        use crate::workspace::specs::WorkspaceSpec;
+        use std::{collections::HashMap, path::PathBuf};
+
+        let text = "MSH|^~\\&|App\rPID|1||123^^^HSP&1.2&ISO^MR||Doe^John|||X";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+        let pid = message.segment("PID").unwrap();
+        let uri = "file:///tmp/message.hl7".parse().unwrap();
+
+        let specs = WorkspaceSpecs::new(std::iter::empty::<PathBuf>()).unwrap();
+        specs.specs.insert(
+            PathBuf::from("/missing/site.hl7v.toml"),
+            WorkspaceSpec {
+                name: "Site".to_string(),
+                tables: HashMap::from([(
+                    300,
+                    TableSpec {
+                        description: None,
+                        values: vec![("HOSP".to_string(), "General Hospital".to_string())],
+                    },
+                )]),
+                ..Default::default()
+            },
+        );
+        specs.assign_profile(&uri, Some("Site".to_string()));
+
+        // the workspace's tables are still checked when the standard's aren't
+        let opts = Opts {
+            disable_std_table_validations: true,
+            ..Default::default()
+        };
+        let errors = validate_segment(&uri, &message, pid, "2.5.1", &Some(&specs), &opts)
+            .into_iter()
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![(
+                "HSP".to_string(),
+                "Invalid table value `HSP` for table 0300 (Namespace ID), did you mean `HOSP`?"
+                    .to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use super::{datatypes, ValidationCode, ValidationError};
+use hl7_parser::message::{Repeat, Segment};
+use lsp_types::DiagnosticSeverity;
+use std::ops::Range;
+use tracing::instrument;
+
+/// The comparators a structured numeric (SN) value can start with
+const SN_COMPARATORS: &[&str] = &[">", "<", ">=", "<=", "=", "<>"];
+/// The separators between the numbers of a structured numeric (SN) value
+const SN_SEPARATORS: &[&str] = &["-", "+", "/", ".", ":"];
+/// The encodings of encapsulated data (ED), from table 0299
+const ED_ENCODINGS: &[&str] = &["A", "Hex", "Base64"];
+
+/// Check the observation value (OBX-5) against the value type (OBX-2) that
+/// says what it is, which the datatype checks can't do as OBX-5 is `varies`
+#[instrument(level = "trace", skip(segment), fields(segment = segment.name))]
+pub fn validate_segment(segment: &Segment, version: &str) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if segment.name != "OBX" {
+        return errors;
+    }
+    let Some(value) = segment.field(5).filter(|field| !field.is_empty()) else {
+        return errors;
+    };
+
+    let value_type = segment.field(2).filter(|field| !field.is_empty());
+    let Some(value_type) = value_type else {
+        errors.push(ValidationError::new(
+            ValidationCode::ValueType,
+            "OBX.5 (Observation Value) is populated without an OBX.2 (Value Type) to say what it is"
+                .to_string(),
+            value.range.clone(),
+            DiagnosticSeverity::WARNING,
+        ));
+        return errors;
+    };
+    let type_range = value_type.range.clone();
+    let value_type = value_type.raw_value();
+
+    let mut problems = Vec::new();
+    for repeat in value.repeats().filter(|repeat| !repeat.is_empty()) {
+        match value_type {
+            "CE" | "CNE" | "CWE" => check_coded(version, value_type, repeat, &mut problems),
+            "SN" => check_structured_numeric(repeat, &mut problems),
+            "ED" => check_encapsulated_data(repeat, &mut problems),
+            _ => {
+                let mut datatype_errors = Vec::new();
+                datatypes::check_repeat(version, value_type, repeat, &mut datatype_errors);
+                problems.extend(
+                    datatype_errors
+                        .into_iter()
+                        .map(|error| (error.message, error.range)),
+                );
+            }
+        }
+    }
+
+    errors.extend(problems.into_iter().map(|(problem, range)| {
+        ValidationError::new(
+            ValidationCode::ValueType,
+            format!("{problem}, for an OBX.2 (Value Type) of `{value_type}`"),
+            range,
+            DiagnosticSeverity::WARNING,
+        )
+        .with_related_information(Some((type_range.clone(), "The value type".to_string())))
+    }));
+    errors
+}
+
+/// The value of a component of a repeat, if it's populated
+fn component<'m>(repeat: &Repeat<'m>, ci: usize) -> Option<(&'m str, Range<usize>)> {
+    repeat
+        .component(ci)
+        .filter(|component| !component.is_empty())
+        .map(|component| (component.raw_value(), component.range.clone()))
+}
+
+/// Coded values need an identifier or text, and no more components than
+/// their datatype has
+fn check_coded(
+    version: &str,
+    value_type: &str,
+    repeat: &Repeat,
+    problems: &mut Vec<(String, Range<usize>)>,
+) {
+    if component(repeat, 1).is_none() && component(repeat, 2).is_none() {
+        problems.push((
+            "Coded value has neither an identifier nor text".to_string(),
+            repeat.range.clone(),
+        ));
+    }
+    let Some(datatype) = hl7_definitions::get_field(version, value_type) else {
+        return;
+    };
+    let expected = datatype.subfields.len();
+    if expected == 0 {
+        return;
+    }
+    if let Some(extra) = repeat.components().nth(expected) {
+        let end = repeat.range.end;
+        problems.push((
+            format!("Coded value has more than the {expected} components of a {value_type}"),
+            extra.range.start..end,
+        ));
+    }
+}
+
+/// Structured numerics are a comparator, a number, and optionally a
+/// separator and a second number, e.g. `>^100` or `^1^:^128`
+fn check_structured_numeric(repeat: &Repeat, problems: &mut Vec<(String, Range<usize>)>) {
+    if let Some((comparator, range)) = component(repeat, 1) {
+        if !SN_COMPARATORS.contains(&comparator) {
+            problems.push((
+                format!(
+                    "Invalid comparator `{comparator}`, expected one of {}",
+                    SN_COMPARATORS.join(" ")
+                ),
+                range,
+            ));
+        }
+    }
+    for ci in [2, 4] {
+        if let Some((number, range)) = component(repeat, ci) {
+            if number.parse::<f64>().is_err() {
+                problems.push((format!("Invalid numeric value: {number}"), range));
+            }
+        }
+    }
+    if let Some((separator, range)) = component(repeat, 3) {
+        if !SN_SEPARATORS.contains(&separator) {
+            problems.push((
+                format!(
+                    "Invalid separator `{separator}`, expected one of {}",
+                    SN_SEPARATORS.join(" ")
+                ),
+                range,
+            ));
+        }
+    }
+}
+
+/// Encapsulated data is a source application, a type and subtype, an
+/// encoding, and the data in that encoding
+fn check_encapsulated_data(repeat: &Repeat, problems: &mut Vec<(String, Range<usize>)>) {
+    let Some((encoding, encoding_range)) = component(repeat, 4) else {
+        problems.push((
+            "Encapsulated data has no encoding (the fourth component)".to_string(),
+            repeat.range.clone(),
+        ));
+        return;
+    };
+    let Some((data, range)) = component(repeat, 5) else {
+        return;
+    };
+    let valid = match encoding {
+        "Base64" => {
+            data.len() % 4 == 0
+                && data
+                    .trim_end_matches('=')
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+        }
+        "Hex" => data.len() % 2 == 0 && data.chars().all(|c| c.is_ascii_hexdigit()),
+        "A" => true,
+        _ => {
+            problems.push((
+                format!(
+                    "Invalid encoding `{encoding}`, expected one of {}",
+                    ED_ENCODINGS.join(", ")
+                ),
+                encoding_range,
+            ));
+            true
+        }
+    };
+    if !valid {
+        problems.push((format!("Data isn't valid {encoding}"), range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observation_values_are_checked_against_their_value_type() {
+        let text = "MSH|^~\\&|App\r\
+            OBX|1|NM|HR||high\r\
+            OBX|2|SN|RATIO||^1^:^x\r\
+            OBX|3|CWE|CODE||^^LN\r\
+            OBX|4|ED|PDF||App^AP^PDF^Base64^SGVsbG8*\r\
+            OBX|5|NM|HR||60\r\
+            OBX|6||HR||60";
+        let message = hl7_parser::parse_message_with_lenient_newlines(text).unwrap();
+
+        let errors = message
+            .segments()
+            .flat_map(|segment| validate_segment(segment, "2.5.1"))
+            .map(|error| (text[error.range].to_string(), error.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "high".to_string(),
+                    "Invalid numeric value: high, for an OBX.2 (Value Type) of `NM`".to_string()
+                ),
+                (
+                    "x".to_string(),
+                    "Invalid numeric value: x, for an OBX.2 (Value Type) of `SN`".to_string()
+                ),
+                (
+                    "^^LN".to_string(),
+                    "Coded value has neither an identifier nor text, for an OBX.2 (Value Type) of `CWE`".to_string()
+                ),
+                (
+                    "SGVsbG8*".to_string(),
+                    "Data isn't valid Base64, for an OBX.2 (Value Type) of `ED`".to_string()
+                ),
+                (
+                    "60".to_string(),
+                    "OBX.5 (Observation Value) is populated without an OBX.2 (Value Type) to say what it is".to_string()
+                ),
+            ]
+        );
+    }
+}
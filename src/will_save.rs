@@ -0,0 +1,65 @@
+use color_eyre::{eyre::ContextCompat, Result};
+use hl7_ls::{utils::range_from_offsets, Opts};
+use lsp_textdocument::TextDocuments;
+use lsp_types::{TextEdit, WillSaveTextDocumentParams};
+use tracing::instrument;
+
+/// Convert every segment terminator in the document to the configured one
+/// before it is saved, as many interface engines reject messages that aren't
+/// separated by the terminator they expect
+#[instrument(level = "debug", skip(params, documents, opts))]
+pub fn handle_will_save_wait_until_request(
+    params: WillSaveTextDocumentParams,
+    documents: &TextDocuments,
+    opts: &Opts,
+) -> Result<Option<Vec<TextEdit>>> {
+    let Some(terminator) = opts.segment_terminator.as_str() else {
+        return Ok(None);
+    };
+
+    let uri = params.text_document.uri;
+    let text = documents
+        .get_document_content(&uri, None)
+        .wrap_err_with(|| format!("no document found for uri: {:?}", uri))?;
+
+    let edits = line_endings(text)
+        .filter(|range| &text[range.clone()] != terminator)
+        .map(|range| TextEdit {
+            range: range_from_offsets(text, range.start, range.end, opts.position_encoding),
+            new_text: terminator.to_string(),
+        })
+        .collect::<Vec<_>>();
+    tracing::trace!(count = edits.len(), "normalizing segment terminators");
+
+    Ok(if edits.is_empty() { None } else { Some(edits) })
+}
+
+/// The byte ranges of every line ending (`\r`, `\n`, or `\r\n`) in the text
+fn line_endings(text: &str) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        let start = i + bytes[i..].iter().position(|&b| b == b'\r' || b == b'\n')?;
+        let end = if bytes[start..].starts_with(b"\r\n") {
+            start + 2
+        } else {
+            start + 1
+        };
+        i = end;
+        Some(start..end)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_find_line_endings() {
+        let text = "MSH|^~\\&\r\nPID|1\nPV1|1\rOBX|1\n\n";
+        let endings = line_endings(text)
+            .map(|range| &text[range])
+            .collect::<Vec<_>>();
+        assert_eq!(endings, vec!["\r\n", "\n", "\r", "\n", "\n"]);
+    }
+}
@@ -0,0 +1,132 @@
+use crossbeam_channel::Sender;
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    thread::{self, JoinHandle},
+};
+
+/// Work for a worker, along with what to do instead if it panics (e.g. tell
+/// the client that the request it was answering failed)
+struct Job {
+    run: Box<dyn FnOnce() + Send>,
+    recover: Box<dyn FnOnce() + Send>,
+}
+
+/// A fixed number of threads that requests are answered on, so that a slow
+/// request (e.g. sending a message to a host that never responds) doesn't
+/// hold up every other request and notification behind it
+pub struct WorkerPool {
+    jobs: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (jobs, queue) = crossbeam_channel::unbounded::<Job>();
+        let threads = (0..size.max(1))
+            .map(|i| {
+                let queue = queue.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{i}"))
+                    .spawn(move || {
+                        for job in queue {
+                            // a bug in one handler shouldn't take the worker
+                            // (and every request queued behind it) down too
+                            if catch_unwind(AssertUnwindSafe(job.run)).is_err() {
+                                tracing::error!("worker job panicked");
+                                (job.recover)();
+                            }
+                        }
+                    })
+                    .expect("can spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    /// Run the job on the next free worker, then `recover` if the job panics
+    pub fn execute(
+        &self,
+        job: impl FnOnce() + Send + 'static,
+        recover: impl FnOnce() + Send + 'static,
+    ) {
+        self.jobs
+            .as_ref()
+            .expect("workers are running")
+            .send(Job {
+                run: Box::new(job),
+                recover: Box::new(recover),
+            })
+            .expect("workers are running");
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Wait for any jobs that are still queued or running to finish
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                tracing::error!("worker thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn blocked_jobs_dont_hold_up_others() {
+        let (unblock, blocked) = crossbeam_channel::bounded::<()>(0);
+        let (result, was_unblocked) = crossbeam_channel::unbounded();
+        let workers = WorkerPool::new(2);
+
+        // the first job can only be unblocked by the second one running
+        // alongside it
+        workers.execute(
+            move || {
+                let unblocked = blocked.recv_timeout(Duration::from_secs(5)).is_ok();
+                result.send(unblocked).unwrap();
+            },
+            || {},
+        );
+        workers.execute(
+            move || {
+                let _ = unblock.send_timeout((), Duration::from_secs(5));
+            },
+            || {},
+        );
+        drop(workers);
+
+        assert_eq!(was_unblocked.try_recv(), Ok(true));
+    }
+
+    #[test]
+    fn panicking_jobs_are_recovered_from() {
+        let (result, recovered) = crossbeam_channel::unbounded();
+        let workers = WorkerPool::new(1);
+
+        let recover = result.clone();
+        workers.execute(
+            || panic!("bug in a handler"),
+            move || recover.send("recovered").unwrap(),
+        );
+        // the worker carries on with the next job
+        workers.execute(
+            move || result.send("ran").unwrap(),
+            || panic!("only run if the job panics"),
+        );
+        drop(workers);
+
+        assert_eq!(
+            recovered.try_iter().collect::<Vec<_>>(),
+            ["recovered", "ran"]
+        );
+    }
+}
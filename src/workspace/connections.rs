@@ -0,0 +1,148 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
+use tracing::instrument;
+
+/// A named destination that messages can be sent to (e.g. a test
+/// interface engine), so that its details needn't be given each time
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Connection {
+    pub description: Option<String>,
+    /// May reference environment variables as `${NAME}`
+    pub host: String,
+    pub port: Port,
+    /// Whether to secure the connection with TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// How long to wait for a response, in seconds
+    pub timeout: Option<f64>,
+    /// What wraps each message, if not the standard MLLP framing
+    #[serde(default)]
+    pub frame: Framing,
+}
+
+/// A port, which may be given as a string so that it can reference an
+/// environment variable
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Port {
+    Number(u16),
+    Text(String),
+}
+
+/// The characters that mark the start and end of each message sent or
+/// received, which MLLP defines as VT (0x0B) before it and FS CR (0x1C 0x0D)
+/// after it
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Framing {
+    pub start: String,
+    pub end: String,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            start: "\x0B".to_string(),
+            end: "\x1C\r".to_string(),
+        }
+    }
+}
+
+impl Framing {
+    /// Check that the end of a message can be found, and that the frame is
+    /// made up of single bytes
+    pub fn validate(&self) -> Result<()> {
+        if self.end.is_empty() {
+            return Err(eyre!("The end of the frame can't be empty"));
+        }
+        if !self.start.is_ascii() || !self.end.is_ascii() {
+            return Err(eyre!("The frame can only be made of ASCII characters"));
+        }
+        Ok(())
+    }
+}
+
+/// Check that a timeout, in seconds, is something that can be waited for
+pub fn validate_timeout(timeout: f64) -> Result<()> {
+    if !timeout.is_finite() || timeout <= 0.0 {
+        return Err(eyre!(
+            "The timeout must be a positive number of seconds, not `{timeout}`"
+        ));
+    }
+    Ok(())
+}
+
+/// A library of connections by name, loaded from a `.hl7connections.toml`
+/// file
+pub type ConnectionLibrary = HashMap<String, Connection>;
+
+/// Load a library of connections, whose names are its top-level tables
+#[instrument(level = "debug")]
+pub fn load_connections<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<ConnectionLibrary> {
+    let text = fs::read_to_string(path).wrap_err("Failed to read file")?;
+    let library: ConnectionLibrary = toml::from_str(&text).wrap_err("Failed to parse TOML")?;
+    for (name, connection) in library.iter() {
+        connection
+            .frame
+            .validate()
+            .wrap_err_with(|| format!("Invalid frame for connection `{name}`"))?;
+        if let Some(timeout) = connection.timeout {
+            validate_timeout(timeout)
+                .wrap_err_with(|| format!("Invalid timeout for connection `{name}`"))?;
+        }
+    }
+    tracing::trace!(?library, "Loaded connections");
+    Ok(library)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_default_to_standard_framing() {
+        let library: ConnectionLibrary = toml::from_str(
+            r#"
+            [test-engine]
+            host = "localhost"
+            port = 2575
+
+            [staging]
+            description = "Staging, over TLS"
+            host = "${STAGING_HOST}"
+            port = "${STAGING_PORT}"
+            tls = true
+            frame = { end = "\u001C" }
+            "#,
+        )
+        .unwrap();
+
+        let test_engine = &library["test-engine"];
+        assert_eq!(test_engine.port, Port::Number(2575));
+        assert!(!test_engine.tls);
+        assert_eq!(test_engine.frame, Framing::default());
+
+        let staging = &library["staging"];
+        assert_eq!(staging.port, Port::Text("${STAGING_PORT}".to_string()));
+        assert!(staging.tls);
+        assert_eq!(staging.frame.start, "\x0B");
+        assert_eq!(staging.frame.end, "\x1C");
+        assert!(staging.frame.validate().is_ok());
+        assert!(Framing {
+            start: String::new(),
+            end: String::new(),
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn timeouts_must_be_positive_numbers_of_seconds() {
+        assert!(validate_timeout(30.0).is_ok());
+        assert!(validate_timeout(0.5).is_ok());
+        for timeout in [0.0, -1.0, f64::INFINITY, f64::NAN] {
+            assert!(validate_timeout(timeout).is_err(), "{timeout}");
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use super::hl7_files;
+use crate::{
+    messages::split_messages,
+    utils::{std_range_to_lsp_range, PositionEncoding},
+};
+use dashmap::DashMap;
+use hl7_parser::parse_message_with_lenient_newlines;
+use lsp_types::Range;
+use std::path::{Path, PathBuf};
+use tracing::instrument;
+
+/// The control ID (MSH-10) of a message in a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlId {
+    pub id: String,
+    /// Where the control ID is in its file
+    pub range: Range,
+}
+
+/// The control IDs of every message in the HL7 files of the workspace, so
+/// that messages sharing one can be found (which some interface engines
+/// silently drop as duplicates)
+///
+/// Files are indexed from disk when their folder is opened, and from the
+/// editor as they're changed, so that unsaved changes are seen.
+#[derive(Debug)]
+pub struct ControlIds {
+    encoding: PositionEncoding,
+    pub files: DashMap<PathBuf, Vec<ControlId>>,
+}
+
+/// The control IDs of the messages in a document, in order
+pub fn control_ids(text: &str, encoding: PositionEncoding) -> Vec<ControlId> {
+    split_messages(text, encoding)
+        .into_iter()
+        .filter_map(|message| {
+            let parsed = parse_message_with_lenient_newlines(message.text).ok()?;
+            let control_id = parsed
+                .query("MSH.10")
+                .filter(|id| !id.raw_value().is_empty())?;
+            let range = std_range_to_lsp_range(message.text, control_id.range(), encoding);
+            Some(ControlId {
+                id: control_id.raw_value().to_string(),
+                range: message.range_in_document(range),
+            })
+        })
+        .collect()
+}
+
+impl ControlIds {
+    pub fn new(encoding: PositionEncoding) -> Self {
+        ControlIds {
+            encoding,
+            files: DashMap::new(),
+        }
+    }
+
+    /// Index every HL7 file in the folders
+    #[instrument(level = "debug", skip(self))]
+    pub fn index_folders(&self, folders: &[PathBuf]) {
+        for path in hl7_files(folders) {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => self.update(&path, &text),
+                Err(e) => tracing::warn!(?path, "Failed to read file: {e}"),
+            }
+        }
+        tracing::debug!(files = self.files.len(), "Indexed control IDs");
+    }
+
+    /// Forget the files in a folder that has been closed
+    pub fn unindex_folder(&self, folder: &Path) {
+        self.files.retain(|path, _| !path.starts_with(folder));
+    }
+
+    /// Index a file's messages again, with its latest contents
+    pub fn update(&self, path: &Path, text: &str) {
+        self.files
+            .insert(path.to_path_buf(), control_ids(text, self.encoding));
+    }
+
+    pub fn remove(&self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// The other messages with the same control ID as each message in a
+    /// file, either earlier in the file or in any other file
+    pub fn duplicates(&self, path: &Path) -> Vec<(ControlId, Vec<(PathBuf, Range)>)> {
+        let Some(ids) = self.files.get(path).map(|ids| ids.clone()) else {
+            return Vec::new();
+        };
+        let mut duplicates = Vec::new();
+        for (i, control_id) in ids.iter().enumerate() {
+            let mut others: Vec<(PathBuf, Range)> = ids[..i]
+                .iter()
+                .filter(|other| other.id == control_id.id)
+                .map(|other| (path.to_path_buf(), other.range))
+                .collect();
+            for entry in self.files.iter().filter(|entry| entry.key() != path) {
+                others.extend(
+                    entry
+                        .value()
+                        .iter()
+                        .filter(|other| other.id == control_id.id)
+                        .map(|other| (entry.key().clone(), other.range)),
+                );
+            }
+            if !others.is_empty() {
+                others.sort_by(|a, b| a.0.cmp(&b.0));
+                duplicates.push((control_id.clone(), others));
+            }
+        }
+        duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_control_ids_are_found_across_files() {
+        let index = ControlIds::new(PositionEncoding::Utf16);
+        index.update(
+            Path::new("/tmp/a.hl7"),
+            "MSH|^~\\&|App|Fac|||20240102||ADT^A01|1|P|2.5.1\r\n\r\n\
+             MSH|^~\\&|App|Fac|||20240102||ADT^A01|2|P|2.5.1\r\n\r\n\
+             MSH|^~\\&|App|Fac|||20240102||ADT^A01|1|P|2.5.1",
+        );
+        index.update(
+            Path::new("/tmp/b.hl7"),
+            "MSH|^~\\&|App|Fac|||20240102||ADT^A01|2|P|2.5.1",
+        );
+
+        let duplicates = index
+            .duplicates(Path::new("/tmp/a.hl7"))
+            .into_iter()
+            .map(|(control_id, others)| {
+                (
+                    control_id.id,
+                    control_id.range.start.line,
+                    others
+                        .into_iter()
+                        .map(|(path, range)| (path, range.start.line))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            duplicates,
+            vec![
+                ("2".to_string(), 2, vec![(PathBuf::from("/tmp/b.hl7"), 0)]),
+                ("1".to_string(), 4, vec![(PathBuf::from("/tmp/a.hl7"), 0)]),
+            ]
+        );
+
+        index.unindex_folder(Path::new("/tmp"));
+        assert!(index.files.is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+use crate::utils::{lsp_range_to_std_range, slice_text, PositionEncoding};
+use lsp_types::{
+    AnnotatedTextEdit, DocumentChangeOperation, DocumentChanges, OneOf, Range, ResourceOp,
+    TextEdit, Uri, WorkspaceEdit,
+};
+use serde::Serialize;
+use std::{fs::OpenOptions, io::Write, path::PathBuf, sync::Mutex};
+
+/// A workspace edit generated by a command, recorded so that automated changes
+/// to test data can be explained after the fact
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditRecord {
+    /// When the edit was sent to the client, in RFC 3339 format
+    pub timestamp: String,
+    /// The command that generated the edit, e.g. `hl7.setTimestampToNow`
+    pub command: String,
+    pub label: String,
+    pub documents: Vec<DocumentEditRecord>,
+}
+
+/// The changes an edit makes to a single document
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentEditRecord {
+    pub uri: Uri,
+    /// Whether the document is created by the edit
+    pub created: bool,
+    pub changes: Vec<ChangeRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeRecord {
+    pub range: Range,
+    /// The text that was replaced, if the document's text was known
+    pub before: Option<String>,
+    pub after: String,
+}
+
+impl EditRecord {
+    /// Describe an edit, looking up the text of each document it changes (as
+    /// it was before the edit) to record the values that are replaced
+    pub fn new<F>(
+        command: &str,
+        label: &str,
+        edit: &WorkspaceEdit,
+        text_of: F,
+        encoding: PositionEncoding,
+    ) -> Self
+    where
+        F: Fn(&Uri) -> Option<String>,
+    {
+        let mut documents = Vec::new();
+        let mut document = |uri: &Uri, created: bool, edits: Vec<&TextEdit>| {
+            let text = if created { None } else { text_of(uri) };
+            let changes = edits
+                .into_iter()
+                .map(|edit| ChangeRecord {
+                    range: edit.range,
+                    before: text.as_deref().and_then(|text| {
+                        let range = lsp_range_to_std_range(text, edit.range, encoding)?;
+                        slice_text(text, range).ok().map(str::to_string)
+                    }),
+                    after: edit.new_text.clone(),
+                })
+                .collect();
+            documents.push(DocumentEditRecord {
+                uri: uri.clone(),
+                created,
+                changes,
+            });
+        };
+
+        if let Some(changes) = &edit.changes {
+            for (uri, edits) in changes {
+                document(uri, false, edits.iter().collect());
+            }
+        }
+        fn text_edits(edits: &[OneOf<TextEdit, AnnotatedTextEdit>]) -> Vec<&TextEdit> {
+            edits
+                .iter()
+                .map(|edit| match edit {
+                    OneOf::Left(edit) => edit,
+                    OneOf::Right(edit) => &edit.text_edit,
+                })
+                .collect()
+        }
+        match &edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => {
+                for edit in edits {
+                    document(&edit.text_document.uri, false, text_edits(&edit.edits));
+                }
+            }
+            Some(DocumentChanges::Operations(operations)) => {
+                let mut created = Vec::new();
+                for operation in operations {
+                    match operation {
+                        DocumentChangeOperation::Op(ResourceOp::Create(create)) => {
+                            created.push(&create.uri);
+                        }
+                        DocumentChangeOperation::Op(_) => {}
+                        DocumentChangeOperation::Edit(edit) => {
+                            let uri = &edit.text_document.uri;
+                            document(uri, created.contains(&uri), text_edits(&edit.edits));
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+
+        EditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            command: command.to_string(),
+            label: label.to_string(),
+            documents,
+        }
+    }
+}
+
+/// The edits generated for a workspace, kept for the life of the server and
+/// optionally appended to a file as JSON lines
+#[derive(Debug, Default)]
+pub struct EditHistory {
+    records: Mutex<Vec<EditRecord>>,
+    file: Option<PathBuf>,
+}
+
+impl EditHistory {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        EditHistory {
+            records: Mutex::new(Vec::new()),
+            file,
+        }
+    }
+
+    pub fn record(&self, record: EditRecord) {
+        if let Some(file) = &self.file {
+            let line = serde_json::to_string(&record).expect("records can be serialized");
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut file| writeln!(file, "{line}"));
+            if let Err(e) = written {
+                tracing::error!(?file, "Failed to write to edit history: {e}");
+            }
+        }
+        self.records
+            .lock()
+            .expect("history lock isn't poisoned")
+            .push(record);
+    }
+
+    /// The recorded edits, oldest first, optionally only those that changed
+    /// the given document
+    pub fn records(&self, uri: Option<&Uri>) -> Vec<EditRecord> {
+        self.records
+            .lock()
+            .expect("history lock isn't poisoned")
+            .iter()
+            .filter(|record| uri.is_none_or(|uri| record.documents.iter().any(|d| &d.uri == uri)))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::Position;
+    use std::collections::HashMap;
+
+    #[test]
+    fn edits_are_recorded_with_the_values_they_replace() {
+        let uri: Uri = "file:///message.hl7".parse().unwrap();
+        let edit = WorkspaceEdit {
+            changes: Some(HashMap::from([(
+                uri.clone(),
+                vec![TextEdit {
+                    range: Range {
+                        start: Position::new(0, 4),
+                        end: Position::new(0, 7),
+                    },
+                    new_text: "NEW".to_string(),
+                }],
+            )])),
+            document_changes: None,
+            change_annotations: None,
+        };
+        let text = |_: &Uri| Some("MSH|OLD|".to_string());
+        let record = EditRecord::new("hl7.test", "Test", &edit, text, PositionEncoding::default());
+
+        let history = EditHistory::default();
+        history.record(record);
+        let records = history.records(Some(&uri));
+        assert_eq!(records.len(), 1);
+        let change = &records[0].documents[0].changes[0];
+        assert_eq!(change.before.as_deref(), Some("OLD"));
+        assert_eq!(change.after, "NEW");
+
+        let other: Uri = "file:///other.hl7".parse().unwrap();
+        assert!(history.records(Some(&other)).is_empty());
+    }
+}
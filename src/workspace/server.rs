@@ -0,0 +1,402 @@
+use super::{control_ids::ControlIds, history::EditHistory, specs::WorkspaceSpecs};
+use crate::{utils::file_path, Opts};
+use color_eyre::eyre::{eyre, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use lsp_types::{FileChangeType, FileEvent, Uri, WorkspaceFolder, WorkspaceFoldersChangeEvent};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::instrument;
+
+/// The workspace folders open in the editor, along with their specs, which
+/// are reloaded whenever they change on disk
+///
+/// Changes are reported by the client through [Workspace::files_changed] if
+/// it can watch files for us, otherwise (if the `watcher` feature is enabled)
+/// the folders are watched directly.
+pub struct Workspace {
+    folders: Mutex<Vec<PathBuf>>,
+    #[cfg(feature = "watcher")]
+    watcher: Mutex<Option<super::watcher::SpecWatcher>>,
+    pub specs: Arc<WorkspaceSpecs>,
+    /// The edits commands have made to documents in the workspace
+    pub history: EditHistory,
+    /// The control IDs of the messages in the workspace, if they're checked
+    /// for uniqueness (see [Opts::unique_control_ids])
+    pub control_ids: Option<Arc<ControlIds>>,
+    custom_spec_changes_tx: Sender<()>,
+    pub _custom_spec_changes: Receiver<()>,
+}
+
+/// The path of a workspace folder, or `None` if it isn't on disk (e.g. a
+/// folder on a remote filesystem)
+fn folder_path(folder: &WorkspaceFolder) -> Option<PathBuf> {
+    let path = file_path(&folder.uri);
+    if path.is_none() {
+        tracing::debug!(uri = ?folder.uri, "Ignoring workspace folder that isn't on disk");
+    }
+    path
+}
+
+/// Extensions of the HL7 files in the workspace
+const HL7_EXTENSIONS: &[&str] = &["hl7"];
+
+/// Whether a path is an HL7 file, by its extension
+fn is_hl7_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            HL7_EXTENSIONS
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Every HL7 file within the folders, skipping hidden directories (e.g.
+/// `.git`)
+pub fn hl7_files(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = folders.to_vec();
+    while let Some(folder) = pending.pop() {
+        let entries = match std::fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!(?folder, "Failed to read directory: {e}");
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            // don't follow symlinks, which could loop back on themselves
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(path);
+            } else if is_hl7_file(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Specs set up with the options' profiles, before any folders are loaded
+fn configured_specs(opts: &Opts) -> Result<WorkspaceSpecs> {
+    Ok(WorkspaceSpecs::new(std::iter::empty::<PathBuf>())
+        .wrap_err("Failed to load custom specs")?
+        .with_profiles(opts.validation_profiles.clone())
+        .with_non_file_specs(opts.non_file_specs))
+}
+
+/// Load the specs in the folders as a workspace of them would, without
+/// watching them for changes (e.g. to validate a message from the command
+/// line)
+pub fn load_specs(folders: &[PathBuf], opts: &Opts) -> Result<WorkspaceSpecs> {
+    let specs = configured_specs(opts)?;
+    for folder in folders {
+        specs
+            .load_folder(folder)
+            .wrap_err("Failed to load custom specs")?;
+    }
+    Ok(specs)
+}
+
+impl Workspace {
+    #[instrument(level = "debug", skip(opts))]
+    pub fn new(
+        workspace_folders: Vec<WorkspaceFolder>,
+        client_watches_files: bool,
+        opts: &Opts,
+    ) -> Result<Self> {
+        let folders: Vec<PathBuf> = workspace_folders
+            .iter()
+            .filter_map(folder_path)
+            .filter(|path| path.exists() && path.is_dir())
+            .collect();
+
+        let specs = Arc::new(configured_specs(opts)?);
+        let (tx_specs, custom_spec_changes) = crossbeam_channel::unbounded();
+
+        // start watching before loading so that no changes are missed
+        #[cfg(feature = "watcher")]
+        let watcher = if client_watches_files {
+            None
+        } else {
+            let mut watcher = super::watcher::SpecWatcher::new(specs.clone(), tx_specs.clone())?;
+            tracing::debug!(?folders, "Watching workspace folders recursively");
+            for folder in folders.iter() {
+                watcher.watch(folder.as_path())?;
+            }
+            Some(watcher)
+        };
+        #[cfg(not(feature = "watcher"))]
+        let _ = client_watches_files;
+
+        for folder in folders.iter() {
+            specs
+                .load_folder(folder)
+                .wrap_err("Failed to load custom specs")?;
+        }
+        tracing::debug!(?specs, "Loaded specs");
+
+        // relative to the first folder, so that each workspace gets its own
+        let history_file = opts
+            .edit_history
+            .as_ref()
+            .map(|file| match folders.first() {
+                Some(folder) if file.is_relative() => folder.join(file),
+                _ => file.clone(),
+            });
+
+        // indexed in the background as there may be a lot of files, with open
+        // documents revalidated once it's done
+        let control_ids = opts.unique_control_ids.then(|| {
+            let control_ids = Arc::new(ControlIds::new(opts.position_encoding));
+            let (index, folders, tx) = (control_ids.clone(), folders.clone(), tx_specs.clone());
+            std::thread::spawn(move || {
+                index.index_folders(&folders);
+                if let Err(e) = tx.send(()) {
+                    tracing::error!(?e, "Failed to send update notification");
+                }
+            });
+            control_ids
+        });
+
+        let workspace = Workspace {
+            folders: Mutex::new(folders),
+            #[cfg(feature = "watcher")]
+            watcher: Mutex::new(watcher),
+            specs,
+            history: EditHistory::new(history_file),
+            control_ids,
+            custom_spec_changes_tx: tx_specs,
+            _custom_spec_changes: custom_spec_changes,
+        };
+
+        Ok(workspace)
+    }
+
+    /// The workspace folders currently open in the editor
+    pub fn folders(&self) -> Vec<PathBuf> {
+        self.folders
+            .lock()
+            .expect("folders lock isn't poisoned")
+            .clone()
+    }
+
+    /// Start or stop watching folders as they are added to or removed from the
+    /// editor's workspace, loading or unloading their specs along the way
+    #[instrument(level = "debug", skip(self))]
+    pub fn change_folders(&self, event: WorkspaceFoldersChangeEvent) -> Result<()> {
+        let mut folders = self.folders.lock().expect("folders lock isn't poisoned");
+        #[cfg(feature = "watcher")]
+        let mut watcher = self.watcher.lock().expect("watcher lock isn't poisoned");
+        let mut changed = false;
+
+        for folder in event.removed.iter().filter_map(folder_path) {
+            let Some(i) = folders.iter().position(|f| f == &folder) else {
+                continue;
+            };
+            tracing::debug!(?folder, "Removing workspace folder");
+            folders.remove(i);
+            #[cfg(feature = "watcher")]
+            if let Some(watcher) = watcher.as_mut() {
+                watcher.unwatch(folder.as_path())?;
+            }
+            changed |= self.specs.unload_folder(&folder);
+            if let Some(control_ids) = &self.control_ids {
+                control_ids.unindex_folder(&folder);
+                changed = true;
+            }
+        }
+
+        for folder in event
+            .added
+            .iter()
+            .filter_map(folder_path)
+            .filter(|path| path.exists() && path.is_dir())
+        {
+            if folders.contains(&folder) {
+                continue;
+            }
+            tracing::debug!(?folder, "Adding workspace folder");
+            #[cfg(feature = "watcher")]
+            if let Some(watcher) = watcher.as_mut() {
+                watcher.watch(folder.as_path())?;
+            }
+            self.specs
+                .load_folder(&folder)
+                .wrap_err("Failed to load custom specs")?;
+            if let Some(control_ids) = &self.control_ids {
+                control_ids.index_folders(std::slice::from_ref(&folder));
+            }
+            folders.push(folder);
+            changed = true;
+        }
+
+        if changed {
+            self.notify_spec_changes();
+        }
+
+        Ok(())
+    }
+
+    /// Load or unload specs as reported by the client's file watchers
+    #[instrument(level = "debug", skip(self))]
+    pub fn files_changed(&self, changes: Vec<FileEvent>) {
+        let mut changed = false;
+        for change in changes {
+            let Some(path) = file_path(&change.uri) else {
+                continue;
+            };
+            if let Some(control_ids) = self.control_ids.as_ref().filter(|_| is_hl7_file(&path)) {
+                match change.typ {
+                    FileChangeType::DELETED => control_ids.remove(&path),
+                    _ => match std::fs::read_to_string(&path) {
+                        Ok(text) => control_ids.update(&path, &text),
+                        Err(e) => tracing::warn!(?path, "Failed to read file: {e}"),
+                    },
+                }
+                changed = true;
+                continue;
+            }
+            changed |= match change.typ {
+                FileChangeType::CREATED | FileChangeType::CHANGED => self.specs.reload_spec(&path),
+                FileChangeType::DELETED => self.specs.remove_spec(&path),
+                _ => false,
+            };
+        }
+
+        // failures are reported along with changes so they can be shown
+        if changed || self.specs.has_failures() {
+            self.notify_spec_changes();
+        }
+    }
+
+    /// Validate a document with the named profile (or with the specs in its
+    /// folder again, if `None`), revalidating open documents
+    #[instrument(level = "debug", skip(self))]
+    pub fn assign_profile(&self, uri: &Uri, profile: Option<String>) -> Result<()> {
+        if let Some(profile) = profile.as_ref() {
+            let profiles = self.specs.profile_names();
+            if !profiles.contains(profile) {
+                return Err(eyre!(
+                    "Unknown validation profile `{profile}`, expected one of: {profiles}",
+                    profiles = profiles.join(", ")
+                ));
+            }
+        }
+        self.specs.assign_profile(uri, profile);
+        self.notify_spec_changes();
+        Ok(())
+    }
+
+    fn notify_spec_changes(&self) {
+        tracing::info!("Specs updated");
+        if let Err(e) = self.custom_spec_changes_tx.send(()) {
+            tracing::error!(?e, "Failed to send update notification");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder(path: &Path) -> WorkspaceFolder {
+        WorkspaceFolder {
+            uri: format!("file://{}", path.display()).parse().unwrap(),
+            name: path.display().to_string(),
+        }
+    }
+
+    #[test]
+    fn folders_can_be_added_and_removed_at_runtime() {
+        let root = std::env::temp_dir().join(format!("hl7-ls-folders-{}", std::process::id()));
+        // `a` is a prefix of `ab`'s name, but not of its path
+        let (a, ab) = (root.join("a"), root.join("ab"));
+        for (path, name) in [(&a, "A"), (&ab, "AB")] {
+            std::fs::create_dir_all(path).unwrap();
+            std::fs::write(path.join("spec.hl7v.toml"), format!("name = \"{name}\"\n")).unwrap();
+        }
+        let opts = Opts {
+            unique_control_ids: false,
+            ..Default::default()
+        };
+        let workspace = Workspace::new(vec![folder(&a)], true, &opts).unwrap();
+        assert_eq!(workspace.specs.profile_names(), vec!["A"]);
+
+        let change = |added: &[&Path], removed: &[&Path]| {
+            workspace
+                .change_folders(WorkspaceFoldersChangeEvent {
+                    added: added.iter().map(|path| folder(path)).collect(),
+                    removed: removed.iter().map(|path| folder(path)).collect(),
+                })
+                .unwrap();
+            workspace._custom_spec_changes.try_iter().count() > 0
+        };
+
+        // folders that are already open, or aren't there, change nothing
+        assert!(!change(&[&a, &root.join("missing")], &[&ab]));
+        assert_eq!(workspace.folders(), vec![a.clone()]);
+
+        assert!(change(&[&ab], &[]));
+        assert_eq!(workspace.folders(), vec![a.clone(), ab.clone()]);
+        assert_eq!(workspace.specs.profile_names(), vec!["A", "AB"]);
+
+        assert!(change(&[], &[&a]));
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(workspace.folders(), vec![ab]);
+        assert_eq!(workspace.specs.profile_names(), vec!["AB"]);
+    }
+
+    #[test]
+    fn specs_follow_the_changes_the_client_reports() {
+        let root = std::env::temp_dir().join(format!("hl7-ls-changes-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let opts = Opts {
+            unique_control_ids: false,
+            ..Default::default()
+        };
+        let workspace = Workspace::new(vec![folder(&root)], true, &opts).unwrap();
+        let spec = root.join("spec.hl7v.toml");
+        let change = |path: &Path, typ: FileChangeType| {
+            workspace.files_changed(vec![FileEvent {
+                uri: format!("file://{}", path.display()).parse().unwrap(),
+                typ,
+            }]);
+            workspace._custom_spec_changes.try_iter().count() > 0
+        };
+
+        std::fs::write(&spec, "name = \"A\"\n").unwrap();
+        assert!(change(&spec, FileChangeType::CREATED));
+        assert_eq!(workspace.specs.profile_names(), vec!["A"]);
+
+        // a spec that breaks keeps its last good version, and is reported
+        std::fs::write(&spec, "name = ").unwrap();
+        assert!(change(&spec, FileChangeType::CHANGED));
+        assert_eq!(workspace.specs.profile_names(), vec!["A"]);
+        assert_eq!(workspace.specs.take_failures().len(), 1);
+
+        std::fs::write(&spec, "name = \"B\"\n").unwrap();
+        assert!(change(&spec, FileChangeType::CHANGED));
+        assert_eq!(workspace.specs.profile_names(), vec!["B"]);
+
+        std::fs::remove_file(&spec).unwrap();
+        assert!(change(&spec, FileChangeType::DELETED));
+        assert!(workspace.specs.profile_names().is_empty());
+
+        // files that aren't specs, or were never loaded, change nothing
+        assert!(!change(&root.join("notes.txt"), FileChangeType::CREATED));
+        assert!(!change(&spec, FileChangeType::DELETED));
+        workspace.files_changed(vec![FileEvent {
+            uri: "untitled:spec.hl7v.toml".parse().unwrap(),
+            typ: FileChangeType::CREATED,
+        }]);
+        assert_eq!(workspace._custom_spec_changes.try_iter().count(), 0);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,124 @@
+#[cfg(feature = "server")]
+use crate::{spec, utils::line_ranges, validation::renumber_set_ids, TimeZone};
+use color_eyre::eyre::{Context, Result};
+#[cfg(feature = "server")]
+use hl7_parser::{datetime::TimeStamp, parse_message_with_lenient_newlines};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use std::ops::Range;
+use std::{collections::HashMap, fs, path::Path};
+use tracing::instrument;
+
+/// A named fragment of a message (e.g. a standard insurance block, or a
+/// canned panel of observations) that can be inserted into documents
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+pub struct Snippet {
+    pub description: Option<String>,
+    /// The snippet's segments, one per line
+    pub segments: String,
+}
+
+/// A library of snippets by name, loaded from a `.hl7snippets.toml` file
+pub type SnippetLibrary = HashMap<String, Snippet>;
+
+/// Load a library of snippets, whose names are its top-level tables
+#[instrument(level = "debug")]
+pub fn load_snippets<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<SnippetLibrary> {
+    let text = fs::read_to_string(path).wrap_err("Failed to read file")?;
+    let library: SnippetLibrary = toml::from_str(&text).wrap_err("Failed to parse TOML")?;
+    tracing::trace!(?library, "Loaded snippets");
+    Ok(library)
+}
+
+// needs the clock, which is only read by the server
+#[cfg(feature = "server")]
+impl Snippet {
+    /// The snippet's segments as they'd be inserted between `before` and
+    /// `after` in a document, separated by `line_ending`
+    ///
+    /// Set IDs are renumbered to follow on from the segments before the
+    /// snippet, and timestamps are set to now (in the given timezone). The
+    /// segments are inserted as they are if the resulting message can't be
+    /// parsed.
+    pub fn adjust(
+        &self,
+        before: &str,
+        after: &str,
+        line_ending: &str,
+        fallback_version: Option<&str>,
+        timezone: TimeZone,
+    ) -> String {
+        let segments = line_ranges(&self.segments)
+            .map(|line| self.segments[line].trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(line_ending);
+
+        let text = format!("{before}{segments}{after}");
+        let Ok(message) = parse_message_with_lenient_newlines(&text) else {
+            return segments;
+        };
+        let snippet = before.len()..before.len() + segments.len();
+        let within = |range: &Range<usize>| {
+            snippet.start <= range.start && range.end <= snippet.end && !range.is_empty()
+        };
+
+        let mut replacements = renumber_set_ids(&message)
+            .into_iter()
+            .filter(|(range, _)| within(range))
+            .collect::<Vec<_>>();
+        let version = spec::message_version(&message, fallback_version).version;
+        let now: TimeStamp = timezone.now().into();
+        for segment in message.segments().filter(|s| within(&s.range)) {
+            for (fi, field) in segment.fields().enumerate() {
+                if within(&field.range) && spec::is_field_a_timestamp(version, segment.name, fi + 1)
+                {
+                    replacements.push((field.range.clone(), now.to_string()));
+                }
+            }
+        }
+
+        // apply from the back so that earlier ranges stay valid
+        replacements.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+        let mut segments = segments;
+        for (range, value) in replacements {
+            segments.replace_range(
+                range.start - snippet.start..range.end - snippet.start,
+                &value,
+            );
+        }
+        segments
+    }
+}
+
+/// The line ending used by a document, so that inserted segments match it
+pub fn line_ending(text: &str) -> &'static str {
+    if text.contains("\r\n") {
+        "\r\n"
+    } else if text.contains('\n') {
+        "\n"
+    } else {
+        "\r"
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_follow_on_from_the_message() {
+        let snippet = Snippet {
+            description: None,
+            segments: "\nEVN|A01|20200101\nOBX|1|ST|CODE||Other\n".to_string(),
+        };
+        let before = "MSH|^~\\&|App|Fac|||20240102||ORU^R01|1|P|2.5.1\nOBX|1|ST|CODE||First\n";
+        let adjusted = snippet.adjust(before, "", "\n", None, TimeZone::Utc);
+
+        let lines = adjusted.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("EVN|A01|"));
+        assert_ne!(lines[0], "EVN|A01|20200101");
+        assert_eq!(lines[1], "OBX|2|ST|CODE||Other");
+    }
+}
@@ -1,13 +1,26 @@
-use color_eyre::eyre::{Context, Result};
-use dashmap::DashMap;
+use super::{
+    connections::{load_connections, Connection, ConnectionLibrary},
+    snippets::{load_snippets, Snippet, SnippetLibrary},
+};
+use crate::{
+    spec,
+    utils::{file_path, glob_matches},
+    validation::{check_rule, ConformanceProfile, RulePath, ValidationCode},
+    NonFileSpecs, SeverityOverride,
+};
+use color_eyre::eyre::{eyre, Context, Result};
+use dashmap::{DashMap, DashSet};
 use lsp_types::Uri;
+#[cfg(feature = "watcher")]
 use notify::{Event, EventKind};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
     collections::HashMap,
     fs::{self, read_dir},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use tracing::instrument;
 
@@ -20,13 +33,75 @@ fn is_a_validator<P: AsRef<Path>>(path: P) -> bool {
             .unwrap_or(false)
 }
 
+/// Tables are named by their number, e.g. `0300.tbl.csv`
+fn is_a_table<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_file()
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(".tbl.csv"))
+}
+
+fn is_a_snippet_library<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_file()
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(".hl7snippets.toml"))
+}
+
+fn is_a_connection_library<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_file()
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(".hl7connections.toml"))
+}
+
+/// Conformance profiles can be named anything, so every XML file is checked
+/// for being one when it's loaded
+fn is_a_conformance_profile<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("xml"))
+}
+
+#[serde_as]
 #[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
 pub struct WorkspaceSpec {
     /// Name of the custom spec
     pub name: String,
 
+    /// The HL7 version documents must declare, which definitions are looked
+    /// up against whatever the documents declare
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
     /// Custom segments
+    #[serde(default)]
     pub segments: Vec<SegmentSpec>,
+
+    /// User-defined tables (e.g. local code sets for the tables the standard
+    /// leaves to each site), by table number
+    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tables: HashMap<u16, TableSpec>,
+
+    /// Rules that apply to one field depending on the value of another
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleSpec>,
+
+    /// Severities to report validation codes at, by
+    /// [crate::validation::ValidationCode::key]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub severities: HashMap<String, SeverityOverride>,
+
+    /// Paths (e.g. `ZPI.3` or `OBX.5.1`) of PHI to replace when anonymizing
+    /// messages, in addition to the standard's identifying fields
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub phi: Vec<String>,
 }
 
 #[serde_as]
@@ -44,14 +119,151 @@ pub struct FieldSpec {
     pub datatype: Option<String>,
     pub required: Option<bool>,
     pub allowed_values: Option<Vec<(String, String)>>,
+    /// A regular expression that the whole of each value must match, e.g.
+    /// for site-specific identifier formats
+    pub pattern: Option<String>,
+    /// What the pattern describes, e.g. "an 8 digit MRN", to report values
+    /// that don't match it with
+    pub pattern_description: Option<String>,
+}
+
+impl FieldSpec {
+    /// The field's pattern, anchored so that it matches whole values
+    pub fn pattern(&self) -> Option<Result<Regex>> {
+        self.pattern.as_ref().map(|pattern| {
+            Regex::new(&format!("^(?:{pattern})$"))
+                .wrap_err_with(|| format!("Invalid pattern `{pattern}`"))
+        })
+    }
+}
+
+/// A rule that applies to a value depending on the value of another, e.g.
+/// that PID.29 is required when PID.30 is `Y`
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+pub struct RuleSpec {
+    /// The path of the value the rule depends on, e.g. `PID.30`
+    pub when: String,
+    /// The value that `when` must have for the rule to apply, or else the
+    /// rule applies whenever `when` is populated
+    pub equals: Option<String>,
+    /// The path of the value the rule applies to, e.g. `PID.29`
+    pub then: String,
+    pub required: Option<bool>,
+    /// A primitive datatype (e.g. `NM`) that the value must be valid for
+    pub datatype: Option<String>,
+    /// A regular expression that the whole value must match
+    pub pattern: Option<String>,
+    pub pattern_description: Option<String>,
+    /// What the rule is for, to report values that break it with
+    pub description: Option<String>,
+}
+
+impl RuleSpec {
+    /// The rule's pattern, anchored so that it matches whole values
+    pub fn pattern(&self) -> Option<Result<Regex>> {
+        self.pattern.as_ref().map(|pattern| {
+            Regex::new(&format!("^(?:{pattern})$"))
+                .wrap_err_with(|| format!("Invalid pattern `{pattern}`"))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq)]
+pub struct TableSpec {
+    pub description: Option<String>,
+    /// Codes and their descriptions
+    pub values: Vec<(String, String)>,
+}
+
+impl TableSpec {
+    /// Load a table from a CSV file of `code,description` lines, numbered by
+    /// the start of the file's name (e.g. `0300.tbl.csv` or `HL70300.tbl.csv`)
+    ///
+    /// Blank lines and lines starting with `#` are skipped, and values may be
+    /// quoted.
+    #[instrument(level = "debug")]
+    pub fn load_csv<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<(u16, Self)> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let number = name.trim_end_matches(".tbl.csv");
+        let table = number
+            .strip_prefix("HL7")
+            .unwrap_or(number)
+            .parse()
+            .wrap_err_with(|| format!("Expected a table number, not `{number}`"))?;
+
+        let text = fs::read_to_string(path).wrap_err("Failed to read file")?;
+        let unquote = |value: &str| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+                .to_string()
+        };
+        let values = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once(',') {
+                Some((code, description)) => (unquote(code), unquote(description)),
+                None => (unquote(line), String::new()),
+            })
+            .collect();
+
+        Ok((
+            table,
+            TableSpec {
+                description: None,
+                values,
+            },
+        ))
+    }
+
+    /// The description of a code in the table
+    pub fn describe(&self, code: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(value, _)| value == code)
+            .map(|(_, description)| description.as_str())
+    }
 }
 
 impl WorkspaceSpec {
     #[instrument(level = "debug")]
     pub fn load_spec<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self> {
-        let spec = toml::from_str(&fs::read_to_string(path).wrap_err("Failed to read file")?)
-            .wrap_err("Failed to parse TOML")?;
+        let text = fs::read_to_string(path).wrap_err("Failed to read file")?;
+        let spec: WorkspaceSpec = toml::from_str(&text).wrap_err("Failed to parse TOML")?;
         tracing::trace!(?spec, "Loaded spec");
+        for segment in spec.segments.iter() {
+            for (field, field_spec) in segment.fields.iter() {
+                if let Some(Err(e)) = field_spec.pattern() {
+                    return Err(e.wrap_err(format!("Invalid spec for {}.{field}", segment.name)));
+                }
+            }
+        }
+        for rule in spec.rules.iter() {
+            check_rule(rule).wrap_err_with(|| format!("Invalid rule for {}", rule.then))?;
+        }
+        for path in spec.phi.iter() {
+            RulePath::parse(path).wrap_err("Invalid PHI path")?;
+        }
+        if let Some(version) = spec.version.as_deref() {
+            if !spec::is_valid_version(version) {
+                return Err(eyre!(
+                    "Unknown HL7 version `{version}`, expected one of: {versions}",
+                    versions = hl7_definitions::VERSIONS.join(", ")
+                ));
+            }
+        }
+        for code in spec.severities.keys() {
+            if !ValidationCode::KEYS.contains(&code.as_str()) {
+                tracing::warn!(code, "Unknown validation code in spec severities");
+            }
+        }
 
         Ok(spec)
     }
@@ -60,6 +272,29 @@ impl WorkspaceSpec {
 #[derive(Debug)]
 pub struct WorkspaceSpecs {
     pub specs: DashMap<PathBuf, WorkspaceSpec>,
+    /// HL7 conformance profiles found alongside the specs, which apply to
+    /// documents the same way
+    pub conformance_profiles: DashMap<PathBuf, Arc<ConformanceProfile>>,
+    /// Tables loaded from their own files, by table number, which apply to
+    /// documents in their folder
+    pub tables: DashMap<PathBuf, (u16, TableSpec)>,
+    /// Libraries of snippets, which apply to documents in their folder
+    pub snippets: DashMap<PathBuf, SnippetLibrary>,
+    /// Libraries of connections that messages can be sent to, which apply
+    /// to documents in their folder
+    pub connections: DashMap<PathBuf, ConnectionLibrary>,
+    /// The (canonical) folders that specs were loaded from, whose specs apply
+    /// to documents that aren't files
+    roots: DashSet<PathBuf>,
+    /// Profiles (spec names) assigned to individual documents by URI, which
+    /// override the folders that specs otherwise apply to
+    profiles: DashMap<String, String>,
+    /// Globs matching documents to the profile they're validated with unless
+    /// one is assigned to them
+    profile_globs: Vec<(String, String)>,
+    non_file_specs: NonFileSpecs,
+    /// Why specs failed to load, kept until they're shown to the user
+    failures: DashMap<PathBuf, String>,
 }
 
 impl WorkspaceSpecs {
@@ -69,41 +304,154 @@ impl WorkspaceSpecs {
         I: Iterator<Item = P>,
         P: AsRef<Path> + std::fmt::Debug,
     {
-        let specs = DashMap::new();
-
+        let specs = WorkspaceSpecs {
+            specs: DashMap::new(),
+            conformance_profiles: DashMap::new(),
+            tables: DashMap::new(),
+            snippets: DashMap::new(),
+            connections: DashMap::new(),
+            roots: DashSet::new(),
+            profiles: DashMap::new(),
+            profile_globs: Vec::new(),
+            non_file_specs: NonFileSpecs::default(),
+            failures: DashMap::new(),
+        };
         for folder in workspace_folders {
-            let folder = folder.as_ref();
-            let folder_span = tracing::debug_span!("folder", folder = ?folder);
-            let _folder_guard = folder_span.enter();
-
-            tracing::debug!(?folder, "Reading directory for custom validator scripts");
-            for entry in read_dir(folder)
-                .wrap_err_with(|| format!("Failed to read directory: {folder:?}"))?
-            {
-                let entry_span = tracing::debug_span!("entry");
-                let _entry_guard = entry_span.enter();
-
-                let entry = entry.wrap_err("Failed to read directory entry")?;
-                let path = entry.path();
-
-                if is_a_validator(&path) {
-                    match WorkspaceSpec::load_spec(&path) {
-                        Ok(spec) => {
-                            tracing::debug!(?path, "Custom validator script found");
-                            tracing::trace!(?spec, "Loaded spec");
-                            specs.insert(path.clone(), spec);
-                        }
-                        Err(e) => {
-                            tracing::error!(?e, ?path, "Failed to load spec");
-                        }
+            specs.load_folder(folder)?;
+        }
+
+        Ok(specs)
+    }
+
+    /// Validate documents matching each glob with the given profile, see
+    /// [WorkspaceSpecs::profile]
+    pub fn with_profiles(mut self, profile_globs: Vec<(String, String)>) -> Self {
+        self.profile_globs = profile_globs;
+        self
+    }
+
+    /// Choose which specs apply to documents that aren't files
+    pub fn with_non_file_specs(mut self, non_file_specs: NonFileSpecs) -> Self {
+        self.non_file_specs = non_file_specs;
+        self
+    }
+
+    /// The names of the specs that can be used as profiles
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names = self
+            .specs
+            .iter()
+            .map(|spec| spec.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The profile a document is validated with: the one assigned to it, or
+    /// else the first one whose glob matches it
+    ///
+    /// Only the specs named by a document's profile apply to it, wherever they
+    /// are in the workspace; documents without a profile use the specs in the
+    /// folders they're in.
+    pub fn profile(&self, uri: &Uri) -> Option<String> {
+        let path = uri.path().as_str();
+        self.profiles
+            .get(uri.as_str())
+            .map(|profile| profile.clone())
+            .or_else(|| {
+                self.profile_globs
+                    .iter()
+                    .find(|(glob, _)| glob_matches(glob, path))
+                    .map(|(_, profile)| profile.clone())
+            })
+    }
+
+    /// Assign a profile to a document, or remove the one assigned to it
+    pub fn assign_profile(&self, uri: &Uri, profile: Option<String>) {
+        match profile {
+            Some(profile) => {
+                self.profiles.insert(uri.as_str().to_string(), profile);
+            }
+            None => {
+                self.profiles.remove(uri.as_str());
+            }
+        }
+    }
+
+    /// Load all the specs found directly in the given folder
+    #[instrument(level = "debug", skip(self))]
+    pub fn load_folder<P: AsRef<Path> + std::fmt::Debug>(&self, folder: P) -> Result<()> {
+        let folder = folder.as_ref();
+        tracing::debug!(?folder, "Reading directory for custom validator scripts");
+        self.roots.insert(
+            folder
+                .canonicalize()
+                .unwrap_or_else(|_| folder.to_path_buf()),
+        );
+        for entry in
+            read_dir(folder).wrap_err_with(|| format!("Failed to read directory: {folder:?}"))?
+        {
+            let entry_span = tracing::debug_span!("entry");
+            let _entry_guard = entry_span.enter();
+
+            let entry = entry.wrap_err("Failed to read directory entry")?;
+            let path = entry.path();
+
+            if is_a_validator(&path) {
+                match WorkspaceSpec::load_spec(&path) {
+                    Ok(spec) => {
+                        tracing::debug!(?path, "Custom validator script found");
+                        tracing::trace!(?spec, "Loaded spec");
+                        self.specs.insert(path.clone(), spec);
+                    }
+                    Err(e) => {
+                        tracing::error!(?e, ?path, "Failed to load spec");
+                        self.failures.insert(path.clone(), format!("{e:#}"));
                     }
                 }
+            } else if is_a_conformance_profile(&path) {
+                self.load_conformance_profile(&path);
+            } else if is_a_table(&path) {
+                self.load_table(&path);
+            } else if is_a_snippet_library(&path) {
+                self.load_snippet_library(&path);
+            } else if is_a_connection_library(&path) {
+                self.load_connection_library(&path);
             }
         }
 
-        Ok(WorkspaceSpecs { specs })
+        Ok(())
     }
 
+    /// Forget all the specs that were loaded from within the given folder,
+    /// returning whether any were removed
+    #[instrument(level = "debug", skip(self))]
+    pub fn unload_folder<P: AsRef<Path> + std::fmt::Debug>(&self, folder: P) -> bool {
+        let folder = folder.as_ref();
+        self.roots.remove(
+            &folder
+                .canonicalize()
+                .unwrap_or_else(|_| folder.to_path_buf()),
+        );
+        let count = |specs: &Self| {
+            specs.specs.len()
+                + specs.conformance_profiles.len()
+                + specs.tables.len()
+                + specs.snippets.len()
+                + specs.connections.len()
+        };
+        let before = count(self);
+        self.specs.retain(|path, _| !path.starts_with(folder));
+        self.conformance_profiles
+            .retain(|path, _| !path.starts_with(folder));
+        self.tables.retain(|path, _| !path.starts_with(folder));
+        self.snippets.retain(|path, _| !path.starts_with(folder));
+        self.connections.retain(|path, _| !path.starts_with(folder));
+        before != count(self)
+    }
+
+    #[cfg(feature = "watcher")]
     #[instrument(level = "debug", skip(self))]
     pub fn update(&self, event: Event) -> Result<bool> {
         let mut changed = false;
@@ -111,27 +459,12 @@ impl WorkspaceSpecs {
         match kind {
             EventKind::Create(_) | EventKind::Modify(_) => {
                 for path in paths.iter() {
-                    if is_a_validator(path) {
-                        tracing::debug!(?path, "Custom validator script created/modified");
-                        match WorkspaceSpec::load_spec(path) {
-                            Ok(spec) => {
-                                self.specs.insert(path.clone(), spec);
-                                changed = true;
-                            }
-                            Err(e) => {
-                                tracing::error!(?e, ?path, "Failed to load custom spec");
-                            }
-                        }
-                    }
+                    changed |= self.reload_spec(path);
                 }
             }
             EventKind::Remove(_) => {
                 for path in paths.iter() {
-                    if self.specs.contains_key(path) {
-                        tracing::debug!(?path, "Custom validator script removed");
-                        self.specs.remove(path);
-                        changed = true;
-                    }
+                    changed |= self.remove_spec(path);
                 }
             }
             _ => {}
@@ -140,13 +473,197 @@ impl WorkspaceSpecs {
         Ok(changed)
     }
 
-    fn spec_applies_to_uri(spec_path: &Path, uri: &Uri) -> bool {
-        let path = PathBuf::from(uri.path().as_str());
-        let spec_path = spec_path.canonicalize().ok();
-        spec_path
+    /// (Re-)load the spec at `path` after it was created or modified, returning
+    /// whether it was loaded
+    pub fn reload_spec(&self, path: &Path) -> bool {
+        if is_a_conformance_profile(path) {
+            return self.load_conformance_profile(path);
+        }
+        if is_a_table(path) {
+            return self.load_table(path);
+        }
+        if is_a_snippet_library(path) {
+            return self.load_snippet_library(path);
+        }
+        if is_a_connection_library(path) {
+            return self.load_connection_library(path);
+        }
+        if !is_a_validator(path) {
+            return false;
+        }
+
+        tracing::debug!(?path, "Custom validator script created/modified");
+        match WorkspaceSpec::load_spec(path) {
+            Ok(spec) => {
+                self.failures.remove(path);
+                self.specs.insert(path.to_path_buf(), spec);
+                true
+            }
+            Err(e) => {
+                tracing::error!(?e, ?path, "Failed to load custom spec");
+                self.failures.insert(path.to_path_buf(), format!("{e:#}"));
+                false
+            }
+        }
+    }
+
+    /// Forget the spec at `path` after it was removed, returning whether one
+    /// was loaded
+    pub fn remove_spec(&self, path: &Path) -> bool {
+        self.failures.remove(path);
+        if self.conformance_profiles.remove(path).is_some() {
+            tracing::debug!(?path, "Conformance profile removed");
+            return true;
+        }
+        if self.tables.remove(path).is_some() {
+            tracing::debug!(?path, "Table removed");
+            return true;
+        }
+        if self.snippets.remove(path).is_some() {
+            tracing::debug!(?path, "Snippets removed");
+            return true;
+        }
+        if self.connections.remove(path).is_some() {
+            tracing::debug!(?path, "Connections removed");
+            return true;
+        }
+        if self.specs.remove(path).is_some() {
+            tracing::debug!(?path, "Custom validator script removed");
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Load the conformance profile at `path` if it is one, returning whether
+    /// the loaded profiles changed
+    fn load_conformance_profile(&self, path: &Path) -> bool {
+        let profile = fs::read_to_string(path)
+            .wrap_err("Failed to read file")
+            .and_then(|xml| ConformanceProfile::parse(&xml));
+        match profile {
+            Ok(Some(profile)) => {
+                tracing::debug!(?path, name = profile.name, "Conformance profile found");
+                self.failures.remove(path);
+                self.conformance_profiles
+                    .insert(path.to_path_buf(), Arc::new(profile));
+                true
+            }
+            // it may have been a profile before it was changed
+            Ok(None) => self.conformance_profiles.remove(path).is_some(),
+            Err(e) => {
+                tracing::error!(?e, ?path, "Failed to load conformance profile");
+                self.failures.insert(path.to_path_buf(), format!("{e:#}"));
+                false
+            }
+        }
+    }
+
+    /// Load the table at `path`, returning whether it was loaded
+    fn load_table(&self, path: &Path) -> bool {
+        match TableSpec::load_csv(path) {
+            Ok((table, values)) => {
+                tracing::debug!(?path, table, "Table found");
+                self.failures.remove(path);
+                self.tables.insert(path.to_path_buf(), (table, values));
+                true
+            }
+            Err(e) => {
+                tracing::error!(?e, ?path, "Failed to load table");
+                self.failures.insert(path.to_path_buf(), format!("{e:#}"));
+                false
+            }
+        }
+    }
+
+    /// Load the library of snippets at `path`, returning whether it was
+    /// loaded
+    fn load_snippet_library(&self, path: &Path) -> bool {
+        match load_snippets(path) {
+            Ok(library) => {
+                tracing::debug!(?path, snippets = library.len(), "Snippets found");
+                self.failures.remove(path);
+                self.snippets.insert(path.to_path_buf(), library);
+                true
+            }
+            Err(e) => {
+                tracing::error!(?e, ?path, "Failed to load snippets");
+                self.failures.insert(path.to_path_buf(), format!("{e:#}"));
+                false
+            }
+        }
+    }
+
+    /// Load the library of connections at `path`, returning whether it was
+    /// loaded
+    fn load_connection_library(&self, path: &Path) -> bool {
+        match load_connections(path) {
+            Ok(library) => {
+                tracing::debug!(?path, connections = library.len(), "Connections found");
+                self.failures.remove(path);
+                self.connections.insert(path.to_path_buf(), library);
+                true
+            }
+            Err(e) => {
+                tracing::error!(?e, ?path, "Failed to load connections");
+                self.failures.insert(path.to_path_buf(), format!("{e:#}"));
+                false
+            }
+        }
+    }
+
+    /// Whether any specs have failed to load since [WorkspaceSpecs::take_failures]
+    /// was last called
+    pub fn has_failures(&self) -> bool {
+        !self.failures.is_empty()
+    }
+
+    /// The specs that failed to load, and why, since this was last called
+    pub fn take_failures(&self) -> Vec<(PathBuf, String)> {
+        let paths = self
+            .failures
+            .iter()
+            .map(|failure| failure.key().clone())
+            .collect::<Vec<_>>();
+        let mut failures = paths
+            .into_iter()
+            .filter_map(|path| self.failures.remove(&path))
+            .collect::<Vec<_>>();
+        failures.sort();
+        failures
+    }
+
+    fn spec_applies(
+        &self,
+        spec_path: &Path,
+        spec_name: &str,
+        uri: &Uri,
+        profile: Option<&str>,
+    ) -> bool {
+        match profile {
+            Some(profile) => spec_name == profile,
+            None => self.spec_applies_to_uri(spec_path, uri),
+        }
+    }
+
+    /// Whether a spec applies to a document, which it does if the document is
+    /// in the spec's folder (or, for documents that aren't files, if the spec
+    /// is in a workspace folder and those specs are configured to apply)
+    fn spec_applies_to_uri(&self, spec_path: &Path, uri: &Uri) -> bool {
+        let Some(spec_folder) = spec_path
+            .canonicalize()
+            .ok()
             .and_then(|spec_path| spec_path.parent().map(|p| p.to_path_buf()))
-            .filter(|p| path.starts_with(p))
-            .is_some()
+        else {
+            return false;
+        };
+        match file_path(uri) {
+            Some(path) => path.starts_with(spec_folder),
+            None => match self.non_file_specs {
+                NonFileSpecs::WorkspaceRoot => self.roots.contains(&spec_folder),
+                NonFileSpecs::None => false,
+            },
+        }
     }
 
     // TODO: rewrite this without cloning
@@ -164,12 +681,15 @@ impl WorkspaceSpecs {
     //         .collect()
     // }
 
-    pub fn describe_field(&self, uri: &Uri, segment: &str, field: usize) -> String {
+    /// The workspace specs for a field that apply to the given document, along
+    /// with the name of the spec each one comes from
+    pub fn field_specs(&self, uri: &Uri, segment: &str, field: usize) -> Vec<(String, FieldSpec)> {
+        let profile = self.profile(uri);
         (&self.specs)
             .into_iter()
             .filter_map(|x| {
                 let (path, spec) = x.pair();
-                if !WorkspaceSpecs::spec_applies_to_uri(path, uri) {
+                if !self.spec_applies(path, &spec.name, uri, profile.as_deref()) {
                     return None;
                 }
 
@@ -177,59 +697,75 @@ impl WorkspaceSpecs {
                     .iter()
                     .find(|s| s.name == segment)
                     .and_then(|s| s.fields.get(&field))
-                    .map(|f| {
-                        let description = f.description.clone();
-                        let datatype = f.datatype.as_ref().map(|d| format!("({d})"));
-                        let required = match f.required {
-                            Some(true) => Some("[*required*]".to_string()),
-                            Some(false) => Some("[*optional*]".to_string()),
-                            None => None,
-                        };
-                        let table_values = f
-                            .allowed_values
-                            .as_ref()
-                            .map(|v| {
-                                v.iter()
-                                    .map(|(k, v)| format!("        `{k}` ({v})"))
-                                    .collect::<Vec<String>>()
-                                    .join("\n")
-                            })
-                            .unwrap_or_default();
-                        let table_values = if table_values.is_empty() {
-                            None
-                        } else {
-                            Some(format!("\n      Table values:\n{table_values}"))
-                        };
-
-                        if description.is_none()
-                            && datatype.is_none()
-                            && required.is_none()
-                            && table_values.is_none()
-                        {
-                            return None;
-                        }
-                        Some(format!(
-                            "\n    {spec_name}:\n      {desc}",
-                            spec_name = spec.name,
-                            desc = [description, datatype, required, table_values]
-                                .into_iter()
-                                .flatten()
-                                .collect::<Vec<String>>()
-                                .join(" ")
-                        ))
+                    .map(|f| (spec.name.clone(), f.clone()))
+            })
+            .collect()
+    }
+
+    pub fn describe_field(&self, uri: &Uri, segment: &str, field: usize) -> String {
+        self.field_specs(uri, segment, field)
+            .into_iter()
+            .filter_map(|(spec_name, f)| {
+                let description = f.description.clone();
+                let datatype = f.datatype.as_ref().map(|d| format!("({d})"));
+                let required = match f.required {
+                    Some(true) => Some("[*required*]".to_string()),
+                    Some(false) => Some("[*optional*]".to_string()),
+                    None => None,
+                };
+                let table_values = f
+                    .allowed_values
+                    .as_ref()
+                    .map(|v| {
+                        v.iter()
+                            .map(|(k, v)| format!("        `{k}` ({v})"))
+                            .collect::<Vec<String>>()
+                            .join("\n")
                     })
+                    .unwrap_or_default();
+                let table_values = if table_values.is_empty() {
+                    None
+                } else {
+                    Some(format!("\n      Table values:\n{table_values}"))
+                };
+                let pattern = f
+                    .pattern
+                    .as_ref()
+                    .map(|pattern| match &f.pattern_description {
+                        Some(description) => {
+                            format!("\n      Matching: {description} (`{pattern}`)")
+                        }
+                        None => format!("\n      Matching: `{pattern}`"),
+                    });
+
+                if description.is_none()
+                    && datatype.is_none()
+                    && required.is_none()
+                    && table_values.is_none()
+                    && pattern.is_none()
+                {
+                    return None;
+                }
+                Some(format!(
+                    "\n    {spec_name}:\n      {desc}",
+                    desc = [description, datatype, required, table_values, pattern]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ))
             })
-            .flatten()
             .collect::<Vec<String>>()
             .join("\n")
     }
 
     pub fn table_values(&self, uri: &Uri, segment: &str, field: usize) -> Vec<(String, String)> {
+        let profile = self.profile(uri);
         (&self.specs)
             .into_iter()
             .filter_map(|x| {
                 let (path, spec) = x.pair();
-                if !WorkspaceSpecs::spec_applies_to_uri(path, uri) {
+                if !self.spec_applies(path, &spec.name, uri, profile.as_deref()) {
                     return None;
                 }
 
@@ -244,11 +780,161 @@ impl WorkspaceSpecs {
             .unwrap_or_default()
     }
 
-    pub fn is_field_required(&self, segment: &str, field: usize) -> bool {
+    /// The severities that the specs applying to a document report validation
+    /// codes at
+    pub fn severity_overrides(&self, uri: &Uri) -> HashMap<String, SeverityOverride> {
+        let profile = self.profile(uri);
+        (&self.specs)
+            .into_iter()
+            .filter(|x| {
+                let (path, spec) = x.pair();
+                self.spec_applies(path, &spec.name, uri, profile.as_deref())
+            })
+            .flat_map(|x| x.severities.clone())
+            .collect()
+    }
+
+    /// The HL7 version pinned by the specs that apply to a document, if any
+    /// of them pin one
+    pub fn pinned_version(&self, uri: &Uri) -> Option<String> {
+        let profile = self.profile(uri);
+        (&self.specs)
+            .into_iter()
+            .filter(|x| {
+                let (path, spec) = x.pair();
+                self.spec_applies(path, &spec.name, uri, profile.as_deref())
+            })
+            .find_map(|x| x.version.clone())
+    }
+
+    /// The conditional rules that apply to a document, along with the name of
+    /// the spec each one comes from
+    pub fn rules(&self, uri: &Uri) -> Vec<(String, RuleSpec)> {
+        let profile = self.profile(uri);
+        (&self.specs)
+            .into_iter()
+            .filter(|x| {
+                let (path, spec) = x.pair();
+                self.spec_applies(path, &spec.name, uri, profile.as_deref())
+            })
+            .flat_map(|x| {
+                x.rules
+                    .iter()
+                    .map(|rule| (x.name.clone(), rule.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The paths of PHI the specs that apply to a document add to the ones
+    /// that are replaced when anonymizing it
+    pub fn phi_paths(&self, uri: &Uri) -> Vec<String> {
+        let profile = self.profile(uri);
+        (&self.specs)
+            .into_iter()
+            .filter(|x| {
+                let (path, spec) = x.pair();
+                self.spec_applies(path, &spec.name, uri, profile.as_deref())
+            })
+            .flat_map(|x| x.phi.clone())
+            .collect()
+    }
+
+    /// The user-defined table with the given number that applies to a
+    /// document, from the specs that apply to it or else from the table files
+    /// in its folder
+    ///
+    /// Tables the workspace defines are used in place of the standard's.
+    pub fn table(&self, uri: &Uri, table: u16) -> Option<TableSpec> {
+        let profile = self.profile(uri);
+        (&self.specs)
+            .into_iter()
+            .find_map(|x| {
+                let (path, spec) = x.pair();
+                if !self.spec_applies(path, &spec.name, uri, profile.as_deref()) {
+                    return None;
+                }
+                spec.tables.get(&table).cloned()
+            })
+            .or_else(|| {
+                (&self.tables).into_iter().find_map(|x| {
+                    let (path, (number, values)) = x.pair();
+                    (*number == table && self.spec_applies_to_uri(path, uri))
+                        .then(|| values.clone())
+                })
+            })
+    }
+
+    /// The snippets that can be inserted into a document, by name
+    ///
+    /// Where libraries define snippets with the same name, the library nearest
+    /// the document wins.
+    pub fn snippets(&self, uri: &Uri) -> Vec<(String, Snippet)> {
+        let mut libraries = (&self.snippets)
+            .into_iter()
+            .filter(|x| self.spec_applies_to_uri(x.key(), uri))
+            .map(|x| (x.key().components().count(), x.value().clone()))
+            .collect::<Vec<_>>();
+        libraries.sort_by_key(|(depth, _)| *depth);
+
+        let mut snippets = HashMap::new();
+        for (_, library) in libraries {
+            snippets.extend(library);
+        }
+        let mut snippets = snippets.into_iter().collect::<Vec<_>>();
+        snippets.sort_by(|a, b| a.0.cmp(&b.0));
+        snippets
+    }
+
+    /// The connections that messages can be sent to, by name: those that
+    /// apply to a document, or else every one in the workspace
+    ///
+    /// Where libraries define connections with the same name, the library
+    /// nearest the document (or the workspace folder) wins.
+    pub fn connections(&self, uri: Option<&Uri>) -> Vec<(String, Connection)> {
+        let mut libraries = (&self.connections)
+            .into_iter()
+            .filter(|x| uri.is_none_or(|uri| self.spec_applies_to_uri(x.key(), uri)))
+            .map(|x| (x.key().components().count(), x.value().clone()))
+            .collect::<Vec<_>>();
+        // deepest first when there's no document, so the shallowest wins
+        libraries.sort_by_key(|(depth, _)| match uri {
+            Some(_) => *depth as isize,
+            None => -(*depth as isize),
+        });
+
+        let mut connections = HashMap::new();
+        for (_, library) in libraries {
+            connections.extend(library);
+        }
+        let mut connections = connections.into_iter().collect::<Vec<_>>();
+        connections.sort_by(|a, b| a.0.cmp(&b.0));
+        connections
+    }
+
+    /// The conformance profiles that apply to a document, whatever type of
+    /// message it is
+    pub fn conformance_profiles(&self, uri: &Uri) -> Vec<Arc<ConformanceProfile>> {
+        let profile = self.profile(uri);
+        (&self.conformance_profiles)
+            .into_iter()
+            .filter(|x| {
+                let (path, conformance_profile) = x.pair();
+                self.spec_applies(path, &conformance_profile.name, uri, profile.as_deref())
+            })
+            .map(|x| x.value().clone())
+            .collect()
+    }
+
+    pub fn is_field_required(&self, uri: &Uri, segment: &str, field: usize) -> bool {
+        let profile = self.profile(uri);
         (&self.specs)
             .into_iter()
             .filter_map(|x| {
-                let (_, spec) = x.pair();
+                let (path, spec) = x.pair();
+                if !self.spec_applies(path, &spec.name, uri, profile.as_deref()) {
+                    return None;
+                }
                 spec.segments
                     .iter()
                     .find(|s| s.name == segment)
@@ -283,6 +969,8 @@ mod tests {
                                 ("^".to_string(), "Caret".to_string()),
                                 ("~".to_string(), "Tilde".to_string()),
                             ]),
+                            pattern: None,
+                            pattern_description: None,
                         },
                     )]
                     .into_iter()
@@ -298,12 +986,31 @@ mod tests {
                             datatype: Some("CX".to_string()),
                             required: Some(true),
                             allowed_values: None,
+                            pattern: Some("[0-9]{8}".to_string()),
+                            pattern_description: Some("an 8 digit MRN".to_string()),
                         },
                     )]
                     .into_iter()
                     .collect(),
                 },
             ],
+            tables: HashMap::from([(
+                300,
+                TableSpec {
+                    description: Some("Namespace ID".to_string()),
+                    values: vec![("HOSP".to_string(), "General Hospital".to_string())],
+                },
+            )]),
+            rules: vec![RuleSpec {
+                when: "PID.30".to_string(),
+                equals: Some("Y".to_string()),
+                then: "PID.29".to_string(),
+                required: Some(true),
+                ..Default::default()
+            }],
+            severities: HashMap::from([("length".to_string(), SeverityOverride::Off)]),
+            version: Some("2.5.1".to_string()),
+            phi: vec!["ZPI.3".to_string()],
         };
 
         let toml_spec = toml::to_string(&my_spec).expect("Can serialize spec");
@@ -313,6 +1020,57 @@ mod tests {
         assert_eq!(my_spec, roundtripped_spec);
     }
 
+    #[test]
+    fn profiles_override_the_specs_in_a_documents_folder() {
+        let spec = |name: &str, value: &str| WorkspaceSpec {
+            name: name.to_string(),
+            segments: vec![SegmentSpec {
+                name: "PV1".to_string(),
+                description: None,
+                fields: [(
+                    2,
+                    FieldSpec {
+                        allowed_values: Some(vec![(value.to_string(), String::new())]),
+                        ..Default::default()
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            }],
+            ..Default::default()
+        };
+        let specs = WorkspaceSpecs::new(std::iter::empty::<PathBuf>())
+            .unwrap()
+            .with_profiles(vec![("partner-b/**".to_string(), "B".to_string())]);
+        specs
+            .specs
+            .insert(PathBuf::from("/missing/a.hl7v.toml"), spec("A", "I"));
+        specs
+            .specs
+            .insert(PathBuf::from("/missing/b.hl7v.toml"), spec("B", "O"));
+        assert_eq!(specs.profile_names(), vec!["A", "B"]);
+
+        let value = |uri: &Uri| {
+            specs
+                .table_values(uri, "PV1", 2)
+                .into_iter()
+                .map(|(value, _)| value)
+                .collect::<Vec<_>>()
+        };
+        let uri: Uri = "file:///tmp/message.hl7".parse().unwrap();
+        assert_eq!(specs.profile(&uri), None);
+        assert!(value(&uri).is_empty());
+        specs.assign_profile(&uri, Some("A".to_string()));
+        assert_eq!(value(&uri), vec!["I"]);
+
+        let uri: Uri = "file:///tmp/partner-b/message.hl7".parse().unwrap();
+        assert_eq!(value(&uri), vec!["O"]);
+        specs.assign_profile(&uri, Some("A".to_string()));
+        assert_eq!(value(&uri), vec!["I"]);
+        specs.assign_profile(&uri, None);
+        assert_eq!(specs.profile(&uri).as_deref(), Some("B"));
+    }
+
     #[test]
     fn the_sample_spec_can_be_loaded() {
         WorkspaceSpec::load_spec("sample.hl7v.toml").expect("Can load sample spec");
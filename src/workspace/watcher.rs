@@ -0,0 +1,67 @@
+use super::specs::WorkspaceSpecs;
+use color_eyre::eyre::{Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use notify::{Event, RecommendedWatcher, Watcher};
+use std::{path::Path, sync::Arc, thread::JoinHandle};
+
+/// Watches workspace folders for specs being created, modified, or removed,
+/// and keeps the loaded specs up to date
+pub(super) struct SpecWatcher {
+    watcher: RecommendedWatcher,
+    _watch_handle: JoinHandle<()>,
+}
+
+impl SpecWatcher {
+    pub fn new(specs: Arc<WorkspaceSpecs>, tx_specs: Sender<()>) -> Result<Self> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let watcher =
+            notify::recommended_watcher(tx).wrap_err("Failed to create file system watcher")?;
+        let watch_handle = SpecWatcher::watch_events(rx, specs, tx_specs);
+
+        Ok(SpecWatcher {
+            watcher,
+            _watch_handle: watch_handle,
+        })
+    }
+
+    pub fn watch(&mut self, folder: &Path) -> Result<()> {
+        self.watcher
+            .watch(folder, notify::RecursiveMode::Recursive)
+            .wrap_err_with(|| format!("Failed to watch directory: {folder:?}"))
+    }
+
+    pub fn unwatch(&mut self, folder: &Path) -> Result<()> {
+        self.watcher
+            .unwatch(folder)
+            .wrap_err_with(|| format!("Failed to stop watching directory: {folder:?}"))
+    }
+
+    fn watch_events(
+        rx: Receiver<Result<Event, notify::Error>>,
+        specs: Arc<WorkspaceSpecs>,
+        tx_specs: Sender<()>,
+    ) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            for event in rx {
+                match event {
+                    Ok(event) => match specs.update(event) {
+                        Ok(changed) => {
+                            if changed || specs.has_failures() {
+                                tracing::info!("Specs updated");
+                                if let Err(e) = tx_specs.send(()) {
+                                    tracing::error!(?e, "Failed to send update notification");
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            tracing::error!(?e, "Failed to update specs");
+                        }
+                    },
+                    Err(e) => {
+                        tracing::error!(?e, "Failed to receive event");
+                    }
+                }
+            }
+        })
+    }
+}